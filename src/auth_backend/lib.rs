@@ -1,139 +1,220 @@
-use ic_cdk_macros::{query, update};
-use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey};
-use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
-
-#[derive(Serialize, Deserialize, Clone)]
-pub enum UserRole {
-    Admin,
-    DeviceManager,
-    Viewer,
-}
+use candid::Deserialize;
+use candid::Principal;
+use ic_cdk::storage;
+use ic_cdk_macros::{post_upgrade, pre_upgrade, query, update};
+use icutil_common::access::{self, Permission, Role, RoleAssignments};
+use icutil_common::audit::{self, AuditEntry, AuditFilter, AuditHead, AuditLog};
+use icutil_common::http::{HttpRequest, HttpResponse};
+use icutil_common::metrics::{self, CallTimer, RuntimeStats};
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+use serde::Serialize;
+use std::cell::RefCell;
+
+mod tokens;
+
+use tokens::{Claims, IssuedToken, SigningKey, SigningKeyInfo, TokenInfo, TokenRequest, TokenStore};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct User {
     pub principal: Principal,
-    pub roles: Vec<UserRole>,
+    pub roles: Vec<Role>,
     pub session_token: String,
     pub expires_at: u64,
 }
 
-#[derive(Serialize, Deserialize)]
-struct AuthState {
-    users: HashMap<Principal, User>,
-    jwt_secret: String,
-}
-
-// JWT claims structure
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: Principal,
-    exp: u64,
-    roles: Vec<UserRole>,
+thread_local! {
+    static TOKENS: RefCell<TokenStore> = RefCell::new(TokenStore::default());
 }
 
-// Inside AuthState
-#[init]
-fn init() {
-    let mut state = AuthState {
-        users: HashMap::new(),
-        jwt_secret: generate_secure_secret(),
-    };
-    // Initialize admin user
+fn now() -> u64 {
+    ic_cdk::api::time() / 1_000_000_000
 }
 
-fn generate_secure_secret() -> String {
-    let mut rng = rand::thread_rng();
-    let bytes: [u8; 32] = rng.gen();
-    hex::encode(bytes)
+// Issue a token for an integration. The scope must be within the caller's
+// own permissions; the token is shown once and only its metadata is kept.
+#[update(guard = "is_user")]
+async fn issue_token(request: TokenRequest) -> Result<IssuedToken, String> {
+    let _timer = CallTimer::start("issue_token", instructions);
+    let random = tokens::random_bytes().await?;
+    let needs_key = TOKENS.with(|t| t.borrow().signing_key().is_none());
+    let key_secret = if needs_key { Some(tokens::random_bytes().await?) } else { None };
+
+    let issuer = ic_cdk::caller();
+    let is_controller = ic_cdk::api::is_controller(&issuer);
+    let subject = request.subject.unwrap_or(issuer);
+    let issued = TOKENS.with(|t| {
+        let mut store = t.borrow_mut();
+        if let Some(secret) = key_secret {
+            if store.signing_key().is_none() {
+                store.add_key(SigningKey::new(secret, now()), now());
+            }
+        }
+        store.issue(request, issuer, is_controller, tokens::token_id(&random), now())
+    })?;
+    metrics::counter_add("auth_tokens_issued", "API tokens issued", &[], 1);
+    record_audit("token.issue", Some(&issued.token_id), vec![subject.to_text()]);
+    Ok(issued)
 }
 
+// Check a token and record that it was used. Canisters accepting tokens
+// call this, so it is an update call.
 #[update]
-fn generate_token(principal: Principal) -> String {
-    let expiration = ic_cdk::api::time() + 86_400_000_000_000; // 24 hours
-    let claims = Claims {
-        sub: principal,
-        exp: expiration,
-        roles: vec![UserRole::DeviceManager],
-    };
-    
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(state.jwt_secret.as_bytes()))
-        .expect("Failed to generate token")
+fn validate_token(token: String) -> Result<Claims, String> {
+    TOKENS.with(|t| {
+        let mut store = t.borrow_mut();
+        let claims = store.verify(&token, now())?;
+        store.record_use(&claims.jti, now());
+        Ok(claims)
+    })
 }
 
-#[query]
-fn validate_token(token: &str) -> Result<Claims, String> {
-    decode::<Claims>(token, &DecodingKey::from_secret(state.jwt_secret.as_bytes()), &Validation::default())
-        .map(|data| data.claims)
-        .map_err(|e| format!("Invalid token: {:?}", e))
+#[update(guard = "is_user")]
+fn revoke_token(token_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let is_admin = can_admin_users().is_ok();
+    TOKENS.with(|t| t.borrow_mut().revoke(&token_id, &caller.to_text(), is_admin, now()))?;
+    record_audit("token.revoke", Some(&token_id), Vec::new());
+    Ok(())
 }
 
-// Role storage
-thread_local! {
-    static ROLE_STORE: RefCell<HashMap<Principal, Vec<String>>> = RefCell::new(HashMap::new());
+// Tokens that have not expired yet. User admins see every token, other
+// users the tokens they issued.
+#[query(guard = "is_user")]
+fn list_tokens(request: PageRequest) -> Result<Page<TokenInfo>, String> {
+    let caller = ic_cdk::caller().to_text();
+    let is_admin = can_admin_users().is_ok();
+    let now = now();
+    let tokens = TOKENS.with(|t| {
+        t.borrow()
+            .tokens
+            .values()
+            .filter(|token| token.expires_at > now && (is_admin || token.issued_by == caller))
+            .map(|token| ((token.issued_at, token.id.clone()), token.clone()))
+            .collect()
+    });
+    pagination::paginate(tokens, Order::Descending, &request)
 }
 
-// Role management endpoints
-#[ic_cdk::update]
-fn assign_roles(user: Principal, roles: Vec<String>) {
-    ROLE_STORE.with(|store| {
-        store.borrow_mut().insert(user, roles);
+// Start signing with a new key. Tokens signed with the old key stay valid
+// until they expire.
+#[update(guard = "can_admin_users")]
+async fn rotate_signing_key() -> Result<SigningKeyInfo, String> {
+    let secret = tokens::random_bytes().await?;
+    let key = SigningKey::new(secret, now());
+    let info = SigningKeyInfo { kid: key.kid.clone(), created_at: key.created_at, retired_at: None };
+    TOKENS.with(|t| {
+        let mut store = t.borrow_mut();
+        store.add_key(key, now());
+        store.prune(now());
     });
+    record_audit("token.rotate_key", Some(&info.kid), Vec::new());
+    Ok(info)
 }
 
-#[ic_cdk::query]
-fn get_roles(user: Principal) -> Vec<String> {
-    ROLE_STORE.with(|store| {
-        store.borrow().get(&user).cloned().unwrap_or_default()
+#[query(guard = "can_admin_users")]
+fn list_signing_keys() -> Vec<SigningKeyInfo> {
+    TOKENS.with(|t| t.borrow().key_infos())
+}
+
+// Signing keys have to survive upgrades, or every token breaks
+#[pre_upgrade]
+fn pre_upgrade() {
+    let tokens = TOKENS.with(|t| t.borrow().clone());
+    if storage::stable_save((tokens, access::export_state(), audit::export_state())).is_err() {
+        ic_cdk::trap("Failed to save state before upgrade");
+    }
+}
+
+// Versions before API tokens saved nothing, so empty stable memory is a
+// fresh start. Anything else that fails to decode would drop every key.
+#[post_upgrade]
+fn post_upgrade() {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return;
+    }
+    let (tokens, roles, log): (TokenStore, RoleAssignments, Option<AuditLog>) = storage::stable_restore()
+        .unwrap_or_else(|_| ic_cdk::trap("Failed to restore state after upgrade"));
+    TOKENS.with(|t| *t.borrow_mut() = tokens);
+    audit::import_state(log.unwrap_or_default());
+    access::import_state(roles);
+}
+
+fn instructions() -> u64 {
+    ic_cdk::api::performance_counter(0)
+}
+
+// Prometheus scrapes /metrics through the HTTP gateway
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    metrics::serve(&request, RuntimeStats {
+        cycles: ic_cdk::api::canister_balance128(),
+        stable_pages: ic_cdk::api::stable::stable64_size(),
     })
 }
 
-// Updated JWT generation with roles
-async fn generate_token(user: &User) -> String {
-    let roles = get_roles(user.principal);
-    let claims = Claims {
-        sub: user.principal.to_string(),
-        exp: (ic_cdk::api::time() + 3600_000_000_000) as usize,
-        roles,
-    };
-    
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(state.jwt_secret.as_bytes()))
-        .expect("Failed to generate token")
+// Role names of a user, from the shared role model
+#[query(guard = "can_admin_users")]
+fn get_roles(user: Principal) -> Vec<String> {
+    access::roles_of(&user.to_text()).iter().map(|role| role.name().to_string()).collect()
 }
 
-// Audit Log Storage
-thread_local! {
-    static AUDIT_LOGS: RefCell<Vec<AuditLog>> = RefCell::new(Vec::new());
+// Replace a user's roles
+#[update(guard = "can_admin_users")]
+fn assign_roles(user: Principal, roles: Vec<String>) -> Result<(), String> {
+    let parsed = roles.iter().map(|name| Role::parse(name)).collect::<Result<Vec<Role>, String>>()?;
+    access::set_roles(&user.to_text(), parsed);
+    record_audit("role.set", Some(&user.to_text()), roles);
+    Ok(())
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct AuditLog {
-    timestamp: u64,
-    admin_principal: Principal,
-    target_user: Principal,
-    action: String,
-    details: Vec<String>,
+// Record a privileged action. The caller comes from the runtime, never
+// from the arguments.
+fn record_audit(action: &str, target: Option<&str>, details: Vec<String>) {
+    audit::record(
+        &ic_cdk::caller().to_text(),
+        action,
+        target,
+        details,
+        ic_cdk::api::time() / 1_000_000_000,
+    );
 }
 
-// Updated role assignment with audit logging
-#[ic_cdk::update]
-fn assign_roles(admin: Principal, user: Principal, roles: Vec<String>) {
-    ROLE_STORE.with(|store| {
-        store.borrow_mut().insert(user, roles.clone());
-    });
-    
-    AUDIT_LOGS.with(|logs| {
-        logs.borrow_mut().push(AuditLog {
-            timestamp: ic_cdk::api::time(),
-            admin_principal: admin,
-            target_user: user,
-            action: "role_update".to_string(),
-            details: vec![format!("Assigned roles: {:?}", roles)],
-        });
-    });
+// Hash-chained log of privileged actions, oldest first
+#[query(guard = "can_read_audit")]
+fn get_audit_logs(filter: AuditFilter, request: PageRequest) -> Result<Page<AuditEntry>, String> {
+    audit::query(&filter, &request)
+}
+
+#[query(guard = "can_read_audit")]
+fn get_audit_head() -> AuditHead {
+    audit::head()
+}
+
+#[query(guard = "can_read_audit")]
+fn verify_audit_log() -> Result<u64, String> {
+    audit::verify()
+}
+
+fn authorize(permission: Permission) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    access::require(&caller.to_string(), ic_cdk::api::is_controller(&caller), permission)
+}
+
+// Any principal with at least one role
+fn is_user() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) || !access::roles_of(&caller.to_text()).is_empty() {
+        Ok(())
+    } else {
+        Err("Unauthorized access".to_string())
+    }
+}
+
+fn can_admin_users() -> Result<(), String> {
+    authorize(Permission::UsersAdmin)
+}
+
+fn can_read_audit() -> Result<(), String> {
+    authorize(Permission::AuditRead)
 }
 
-#[ic_cdk::query]
-fn get_audit_logs() -> Vec<AuditLog> {
-    AUDIT_LOGS.with(|logs| logs.borrow().clone())
-}
\ No newline at end of file
@@ -0,0 +1,436 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::main::raw_rand;
+use candid::{CandidType, Deserialize};
+use candid::Principal;
+use icutil_common::access::{self, Permission};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const DEFAULT_TTL: u64 = 24 * 3600; // Seconds
+pub const MIN_TTL: u64 = 60;
+pub const MAX_TTL: u64 = 90 * 24 * 3600;
+const MAX_ACTIVE_TOKENS: usize = 10_000;
+const ISSUER: &str = "icutil-auth";
+
+// What a token may be used for. Canisters accepting tokens check the
+// permissions, and restrict calls to the tenant and devices when given.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct TokenScope {
+    pub tenant_id: Option<String>,
+    pub device_ids: Vec<String>, // Empty means every device of the tenant
+    pub permissions: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenRequest {
+    pub name: String,
+    pub subject: Option<Principal>, // Defaults to the caller
+    pub scope: TokenScope,
+    pub expires_in: Option<u64>, // Seconds, defaults to 24 hours
+}
+
+// JWT payload. `kid` travels in the header so keys can be rotated.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Claims {
+    pub jti: String,
+    pub iss: String,
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub tenant: Option<String>,
+    pub devices: Vec<String>,
+    pub scope: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+// Metadata of an issued token. The token itself is never stored.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct TokenInfo {
+    pub id: String,
+    pub name: String,
+    pub subject: String,
+    pub issued_by: String,
+    pub scope: TokenScope,
+    pub kid: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub last_used_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+}
+
+// Returned once by `issue_token`
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IssuedToken {
+    pub token_id: String,
+    pub token: String,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SigningKey {
+    pub kid: String,
+    pub secret: Vec<u8>,
+    pub created_at: u64,
+    pub retired_at: Option<u64>, // Retired keys still verify until their tokens expire
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SigningKeyInfo {
+    pub kid: String,
+    pub created_at: u64,
+    pub retired_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct TokenStore {
+    pub keys: Vec<SigningKey>, // The last key signs, the others only verify
+    pub tokens: BTreeMap<String, TokenInfo>, // jti -> token
+    pub revoked: BTreeMap<String, u64>,      // jti -> expiry, dropped once expired
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Randomness comes from the management canister; `rand::thread_rng` has
+// no entropy source in wasm
+pub async fn random_bytes() -> Result<Vec<u8>, String> {
+    raw_rand()
+        .await
+        .map(|(bytes,)| bytes)
+        .map_err(|(_, e)| format!("Failed to get randomness: {}", e))
+}
+
+fn sign(secret: &[u8], input: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(input.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl SigningKey {
+    pub fn new(secret: Vec<u8>, now: u64) -> Self {
+        let kid = format!("k{}", &hex(&Sha256::digest(&secret))[..12]);
+        SigningKey { kid, secret, created_at: now, retired_at: None }
+    }
+
+    fn info(&self) -> SigningKeyInfo {
+        SigningKeyInfo { kid: self.kid.clone(), created_at: self.created_at, retired_at: self.retired_at }
+    }
+}
+
+impl TokenScope {
+    // A token never grants more than its issuer holds
+    fn validate(&self, issuer: &Principal, is_controller: bool) -> Result<Vec<Permission>, String> {
+        if self.permissions.is_empty() {
+            return Err("A token needs at least one permission".into());
+        }
+        let permissions = self
+            .permissions
+            .iter()
+            .map(|name| Permission::parse(name))
+            .collect::<Result<BTreeSet<Permission>, String>>()?;
+        for permission in &permissions {
+            access::require(&issuer.to_text(), is_controller, *permission)
+                .map_err(|_| format!("You cannot grant {} to a token", permission.name()))?;
+        }
+        if !self.device_ids.is_empty() && self.tenant_id.is_none() {
+            return Err("Device restrictions need a tenant".into());
+        }
+        Ok(permissions.into_iter().collect())
+    }
+}
+
+impl TokenStore {
+    pub fn signing_key(&self) -> Option<&SigningKey> {
+        self.keys.last().filter(|key| key.retired_at.is_none())
+    }
+
+    pub fn add_key(&mut self, key: SigningKey, now: u64) {
+        for old in self.keys.iter_mut().filter(|k| k.retired_at.is_none()) {
+            old.retired_at = Some(now);
+        }
+        self.keys.push(key);
+    }
+
+    // Drop expired tokens, their revocations, and retired keys that no
+    // longer sign any live token
+    pub fn prune(&mut self, now: u64) {
+        self.tokens.retain(|_, token| token.expires_at > now);
+        self.revoked.retain(|_, expires_at| *expires_at > now);
+        let in_use: BTreeSet<&str> = self.tokens.values().map(|t| t.kid.as_str()).collect();
+        self.keys
+            .retain(|key| key.retired_at.is_none() || in_use.contains(key.kid.as_str()));
+    }
+
+    pub fn issue(
+        &mut self,
+        request: TokenRequest,
+        issuer: Principal,
+        is_controller: bool,
+        jti: String,
+        now: u64,
+    ) -> Result<IssuedToken, String> {
+        let name = request.name.trim().to_string();
+        if name.is_empty() || name.len() > 64 {
+            return Err("Token name must be 1 to 64 characters".into());
+        }
+        let ttl = request.expires_in.unwrap_or(DEFAULT_TTL);
+        if !(MIN_TTL..=MAX_TTL).contains(&ttl) {
+            return Err(format!("Tokens expire after {} to {} seconds", MIN_TTL, MAX_TTL));
+        }
+        let permissions = request.scope.validate(&issuer, is_controller)?;
+        self.prune(now);
+        if self.tokens.len() >= MAX_ACTIVE_TOKENS {
+            return Err("Too many active tokens".into());
+        }
+        let key = self.signing_key().ok_or("No signing key")?.clone();
+
+        let scope = TokenScope {
+            permissions: permissions.iter().map(|p| p.name().to_string()).collect(),
+            ..request.scope
+        };
+        let claims = Claims {
+            jti: jti.clone(),
+            iss: ISSUER.to_string(),
+            sub: request.subject.unwrap_or(issuer).to_text(),
+            iat: now,
+            exp: now + ttl,
+            tenant: scope.tenant_id.clone(),
+            devices: scope.device_ids.clone(),
+            scope: scope.permissions.clone(),
+        };
+        let token = encode(&key, &claims)?;
+        self.tokens.insert(
+            jti.clone(),
+            TokenInfo {
+                id: jti.clone(),
+                name,
+                subject: claims.sub.clone(),
+                issued_by: issuer.to_text(),
+                scope,
+                kid: key.kid,
+                issued_at: now,
+                expires_at: claims.exp,
+                last_used_at: None,
+                revoked_at: None,
+            },
+        );
+        Ok(IssuedToken { token_id: jti, token, expires_at: claims.exp })
+    }
+
+    // Check signature, expiry and revocation
+    pub fn verify(&self, token: &str, now: u64) -> Result<Claims, String> {
+        let invalid = || "Invalid token".to_string();
+        let mut parts = token.split('.');
+        let (header, payload, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(h), Some(p), Some(s), None) => (h, p, s),
+            _ => return Err(invalid()),
+        };
+        let decoded: JwtHeader = BASE64URL
+            .decode(header)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(invalid)?;
+        if decoded.alg != "HS256" {
+            return Err("Unsupported token algorithm".into());
+        }
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.kid == decoded.kid)
+            .ok_or("Unknown signing key")?;
+        let signature = BASE64URL.decode(signature).map_err(|_| invalid())?;
+        let mut mac = HmacSha256::new_from_slice(&key.secret).map_err(|_| invalid())?;
+        mac.update(format!("{}.{}", header, payload).as_bytes());
+        mac.verify_slice(&signature).map_err(|_| "Token signature does not match".to_string())?;
+
+        let claims: Claims = BASE64URL
+            .decode(payload)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(invalid)?;
+        if claims.exp <= now {
+            return Err("Token expired".into());
+        }
+        if self.revoked.contains_key(&claims.jti) {
+            return Err("Token revoked".into());
+        }
+        Ok(claims)
+    }
+
+    pub fn record_use(&mut self, jti: &str, now: u64) {
+        if let Some(token) = self.tokens.get_mut(jti) {
+            token.last_used_at = Some(now);
+        }
+    }
+
+    // Only the issuer or a user admin may revoke a token
+    pub fn revoke(&mut self, jti: &str, caller: &str, is_admin: bool, now: u64) -> Result<(), String> {
+        let token = self.tokens.get_mut(jti).ok_or_else(|| format!("Token {} not found", jti))?;
+        if !is_admin && token.issued_by != caller {
+            return Err("Only the issuer or a user admin can revoke this token".into());
+        }
+        if token.revoked_at.is_none() {
+            token.revoked_at = Some(now);
+            self.revoked.insert(jti.to_string(), token.expires_at);
+        }
+        Ok(())
+    }
+
+    pub fn key_infos(&self) -> Vec<SigningKeyInfo> {
+        self.keys.iter().map(SigningKey::info).collect()
+    }
+}
+
+fn encode(key: &SigningKey, claims: &Claims) -> Result<String, String> {
+    let header = JwtHeader { alg: "HS256".into(), typ: "JWT".into(), kid: key.kid.clone() };
+    let header = serde_json::to_vec(&header).map_err(|e| e.to_string())?;
+    let payload = serde_json::to_vec(claims).map_err(|e| e.to_string())?;
+    let input = format!("{}.{}", BASE64URL.encode(header), BASE64URL.encode(payload));
+    let signature = sign(&key.secret, &input);
+    Ok(format!("{}.{}", input, BASE64URL.encode(signature)))
+}
+
+// Token ids are random so they reveal nothing about other tokens
+pub fn token_id(random: &[u8]) -> String {
+    format!("tok_{}", &hex(&Sha256::digest(random))[..24])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn store() -> TokenStore {
+        let mut store = TokenStore::default();
+        store.add_key(SigningKey::new(vec![7; 32], NOW), NOW);
+        store
+    }
+
+    fn request(expires_in: Option<u64>) -> TokenRequest {
+        TokenRequest {
+            name: "gateway".into(),
+            subject: None,
+            scope: TokenScope {
+                tenant_id: Some("acme".into()),
+                device_ids: vec!["meter-1".into()],
+                permissions: vec!["readings:write".into()],
+            },
+            expires_in,
+        }
+    }
+
+    fn issue(store: &mut TokenStore, jti: &str) -> String {
+        store.issue(request(None), Principal::anonymous(), true, jti.into(), NOW).unwrap().token
+    }
+
+    // Replace one dot-separated part of a token
+    fn with_part(token: &str, index: usize, part: &str) -> String {
+        let mut parts: Vec<&str> = token.split('.').collect();
+        parts[index] = part;
+        parts.join(".")
+    }
+
+    #[test]
+    fn issued_token_verifies() {
+        let mut store = store();
+        let token = issue(&mut store, "tok_1");
+        let claims = store.verify(&token, NOW + 1).unwrap();
+        assert_eq!(claims.jti, "tok_1");
+        assert_eq!(claims.tenant.as_deref(), Some("acme"));
+        assert_eq!(claims.scope, vec!["readings:write".to_string()]);
+        assert_eq!(claims.exp, NOW + DEFAULT_TTL);
+    }
+
+    #[test]
+    fn tampered_claims_are_rejected() {
+        let mut store = store();
+        let token = issue(&mut store, "tok_1");
+        let mut claims = store.verify(&token, NOW).unwrap();
+        claims.scope.push("users:admin".into());
+        claims.exp += MAX_TTL;
+        let payload = BASE64URL.encode(serde_json::to_vec(&claims).unwrap());
+        let forged = with_part(&token, 1, &payload);
+        assert_eq!(store.verify(&forged, NOW).unwrap_err(), "Token signature does not match");
+    }
+
+    #[test]
+    fn tampered_or_malformed_tokens_are_rejected() {
+        let mut store = store();
+        let token = issue(&mut store, "tok_1");
+
+        let other_key = SigningKey { secret: vec![8; 32], ..store.keys[0].clone() };
+        let header = token.split('.').next().unwrap();
+        let payload = token.split('.').nth(1).unwrap();
+        let resigned = BASE64URL.encode(sign(&other_key.secret, &format!("{}.{}", header, payload)));
+        assert!(store.verify(&with_part(&token, 2, &resigned), NOW).is_err());
+
+        let none_header = BASE64URL.encode(r#"{"alg":"none","typ":"JWT","kid":"x"}"#);
+        assert_eq!(store.verify(&with_part(&token, 0, &none_header), NOW).unwrap_err(), "Unsupported token algorithm");
+
+        let unknown_kid = BASE64URL.encode(r#"{"alg":"HS256","typ":"JWT","kid":"k000000000000"}"#);
+        assert_eq!(store.verify(&with_part(&token, 0, &unknown_kid), NOW).unwrap_err(), "Unknown signing key");
+
+        for malformed in ["", "a.b", "a.b.c.d", &token[..token.len() - 2]] {
+            assert!(store.verify(malformed, NOW).is_err(), "accepted {:?}", malformed);
+        }
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let mut store = store();
+        let issued = store.issue(request(Some(MIN_TTL)), Principal::anonymous(), true, "tok_1".into(), NOW).unwrap();
+        assert!(store.verify(&issued.token, issued.expires_at - 1).is_ok());
+        assert_eq!(store.verify(&issued.token, issued.expires_at).unwrap_err(), "Token expired");
+    }
+
+    #[test]
+    fn revoked_tokens_are_rejected() {
+        let mut store = store();
+        let token = issue(&mut store, "tok_1");
+        assert!(store.revoke("tok_1", "someone-else", false, NOW).is_err());
+        store.revoke("tok_1", "someone-else", true, NOW).unwrap();
+        assert_eq!(store.verify(&token, NOW).unwrap_err(), "Token revoked");
+    }
+
+    #[test]
+    fn retired_keys_verify_until_their_tokens_expire() {
+        let mut store = store();
+        let token = issue(&mut store, "tok_1");
+        store.add_key(SigningKey::new(vec![9; 32], NOW + 10), NOW + 10);
+        assert!(store.verify(&token, NOW + 10).is_ok());
+
+        store.prune(NOW + DEFAULT_TTL);
+        assert_eq!(store.keys.len(), 1);
+        assert!(store.verify(&token, NOW).is_err());
+    }
+
+    #[test]
+    fn ttl_and_scope_are_validated() {
+        let mut store = store();
+        let issuer = Principal::anonymous();
+        assert!(store.issue(request(Some(MIN_TTL - 1)), issuer, true, "a".into(), NOW).is_err());
+        assert!(store.issue(request(Some(MAX_TTL + 1)), issuer, true, "b".into(), NOW).is_err());
+        // Without roles the issuer cannot grant any permission
+        assert!(store.issue(request(None), issuer, false, "c".into(), NOW).is_err());
+
+        let mut unscoped = request(None);
+        unscoped.scope.tenant_id = None;
+        assert_eq!(
+            store.issue(unscoped, issuer, true, "d".into(), NOW).unwrap_err(),
+            "Device restrictions need a tenant"
+        );
+    }
+}
@@ -1,4 +1,11 @@
-use ic_cdk_macros::{init, query, update};
+use ic_cdk::storage;
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use candid::Principal;
+use icutil_common::access::{self, Permission, Role, RoleAssignments};
+use icutil_common::audit::{self, AuditEntry, AuditFilter, AuditHead, AuditLog};
+use icutil_common::http::{HttpRequest, HttpResponse};
+use icutil_common::metrics::{self, CallTimer, RuntimeStats};
+use icutil_common::pagination::{self, Order, Page, PageRequest};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 
@@ -28,17 +35,143 @@ fn init() {
     };
 }
 
-#[update]
-fn create_backup(source: String, data: Vec<u8>) -> Result<String, String> {
+fn store_backup(_source: &str, _data: &[u8]) -> Result<String, String> {
     // Implementation logic
+    Err("Backups are not implemented yet".into())
 }
 
-#[query]
-fn list_backups() -> Vec<String> {
+fn load_backup(_backup_id: &str, _timestamp: u64) -> Result<Vec<u8>, String> {
     // Implementation logic
+    Err("Backups are not implemented yet".into())
+}
+
+#[update(guard = "can_admin_backups")]
+fn create_backup(source: String, data: Vec<u8>) -> Result<String, String> {
+    let _timer = CallTimer::start("create_backup", instructions);
+    let details = vec![format!("{} bytes", data.len())];
+    audit_on_success(store_backup(&source, &data), "backup.create", Some(&source), details)
 }
 
-#[update]
+// Backup ids in lexical order
+#[query(guard = "can_admin_backups")]
+fn list_backups(request: PageRequest) -> Result<Page<String>, String> {
+    let ids = unsafe {
+        BACKUPS
+            .as_ref()
+            .unwrap()
+            .keys()
+            .map(|id| (id.clone(), id.clone()))
+            .collect()
+    };
+    pagination::paginate(ids, Order::Ascending, &request)
+}
+
+fn instructions() -> u64 {
+    ic_cdk::api::performance_counter(0)
+}
+
+// Prometheus scrapes /metrics through the HTTP gateway
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let stored = unsafe { BACKUPS.as_ref().map_or(0, |backups| backups.len()) };
+    metrics::gauge_set("backups_stored", "Backups held by the canister", &[], stored as f64);
+    metrics::serve(&request, RuntimeStats {
+        cycles: ic_cdk::api::canister_balance128(),
+        stable_pages: ic_cdk::api::stable::stable64_size(),
+    })
+}
+
+#[update(guard = "can_admin_backups")]
 fn restore_backup(backup_id: String, timestamp: u64) -> Result<Vec<u8>, String> {
-    // Implementation logic
-}
\ No newline at end of file
+    let _timer = CallTimer::start("restore_backup", instructions);
+    let details = vec![format!("version {}", timestamp)];
+    audit_on_success(load_backup(&backup_id, timestamp), "backup.restore", Some(&backup_id), details)
+}
+
+fn authorize(permission: Permission) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    access::require(&caller.to_string(), ic_cdk::api::is_controller(&caller), permission)
+}
+
+fn can_admin_backups() -> Result<(), String> {
+    authorize(Permission::BackupsAdmin)
+}
+
+fn can_admin_users() -> Result<(), String> {
+    authorize(Permission::UsersAdmin)
+}
+
+// Roles are kept per canister. Controllers always have every permission
+// and assign roles to everyone else.
+#[update(guard = "can_admin_users")]
+fn set_roles(principal: Principal, roles: Vec<Role>) {
+    let names = roles.iter().map(|role| role.name().to_string()).collect();
+    access::set_roles(&principal.to_text(), roles);
+    record_audit("role.set", Some(&principal.to_text()), names);
+}
+
+#[query]
+fn get_my_roles() -> Vec<Role> {
+    access::roles_of(&ic_cdk::caller().to_text())
+}
+
+fn can_read_audit() -> Result<(), String> {
+    authorize(Permission::AuditRead)
+}
+
+// Record a privileged action. The caller comes from the runtime, never
+// from the arguments.
+fn record_audit(action: &str, target: Option<&str>, details: Vec<String>) {
+    audit::record(
+        &ic_cdk::caller().to_text(),
+        action,
+        target,
+        details,
+        ic_cdk::api::time() / 1_000_000_000,
+    );
+}
+
+// Record `action` only if `result` is Ok and hand the result back
+fn audit_on_success<T>(result: Result<T, String>, action: &str, target: Option<&str>, details: Vec<String>) -> Result<T, String> {
+    if result.is_ok() {
+        record_audit(action, target, details);
+    }
+    result
+}
+
+// Hash-chained log of privileged actions, oldest first
+#[query(guard = "can_read_audit")]
+fn get_audit_logs(filter: AuditFilter, request: PageRequest) -> Result<Page<AuditEntry>, String> {
+    audit::query(&filter, &request)
+}
+
+#[query(guard = "can_read_audit")]
+fn get_audit_head() -> AuditHead {
+    audit::head()
+}
+
+#[query(guard = "can_read_audit")]
+fn verify_audit_log() -> Result<u64, String> {
+    audit::verify()
+}
+
+// The audit log and roles have to survive upgrades
+#[pre_upgrade]
+fn pre_upgrade() {
+    if storage::stable_save((audit::export_state(), access::export_state())).is_err() {
+        ic_cdk::trap("Failed to save state before upgrade");
+    }
+}
+
+// Versions before the audit log saved nothing, so empty stable memory is a
+// fresh start. Anything else that fails to decode would lose the log.
+#[post_upgrade]
+fn post_upgrade() {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return;
+    }
+    let (log, roles): (AuditLog, RoleAssignments) = storage::stable_restore()
+        .unwrap_or_else(|_| ic_cdk::trap("Failed to restore state after upgrade"));
+    audit::import_state(log);
+    access::import_state(roles);
+}
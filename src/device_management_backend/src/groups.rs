@@ -0,0 +1,211 @@
+use candid::CandidType;
+use icutil_common::selector::{self, Selector};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::registry::{Device, Registry, TenantScope};
+
+const MAX_TAGS: usize = 16; // Per device
+const MAX_GROUPS: usize = 200; // Per tenant
+pub const MAX_BULK: usize = 500; // Devices one bulk operation may touch
+
+// Labels every device has; they cannot be set as tags
+pub const BUILT_IN: [&str; 6] = ["id", "type", "state", "firmware", "serial", "group"];
+
+// A named, hand-picked set of devices
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Group {
+    pub name: String,
+    pub description: String,
+    pub devices: BTreeSet<String>,
+    pub created_by: String,
+    pub created_at: u64,
+    pub tenant_id: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Fleet {
+    pub tags: BTreeMap<String, BTreeMap<String, String>>, // device id -> key -> value
+    pub groups: BTreeMap<String, Group>, // see `group_key`
+}
+
+// Outcome of a bulk operation for one device
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceResult {
+    pub device_id: String,
+    pub error: Option<String>,
+}
+
+fn validate_group_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 32 {
+        return Err("Group name must be 1 to 32 characters".to_string());
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Group name may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+// Groups of a tenant are stored under "{tenant}:{name}", so every tenant
+// has its own names. Tenant admins address them by that key.
+fn group_key(scope: &TenantScope, name: &str) -> String {
+    match scope {
+        TenantScope::All => name.to_string(),
+        TenantScope::Tenant(tenant_id) => format!("{}:{}", tenant_id, name),
+    }
+}
+
+impl Fleet {
+    // Replace the tags of a device and return the previous ones
+    pub fn set_tags(
+        &mut self,
+        device_id: &str,
+        tags: Vec<(String, String)>,
+    ) -> Result<BTreeMap<String, String>, String> {
+        if tags.len() > MAX_TAGS {
+            return Err(format!("At most {} tags per device", MAX_TAGS));
+        }
+        let mut map = BTreeMap::new();
+        for (key, value) in tags {
+            selector::validate_label(&key, &value)?;
+            if BUILT_IN.contains(&key.as_str()) {
+                return Err(format!("'{}' is a built-in label", key));
+            }
+            if map.insert(key.clone(), value).is_some() {
+                return Err(format!("Tag '{}' given twice", key));
+            }
+        }
+        let previous = if map.is_empty() {
+            self.tags.remove(device_id)
+        } else {
+            self.tags.insert(device_id.to_string(), map)
+        };
+        Ok(previous.unwrap_or_default())
+    }
+
+    pub fn create_group(
+        &mut self,
+        scope: &TenantScope,
+        name: &str,
+        description: &str,
+        created_by: &str,
+        now: u64,
+    ) -> Result<Group, String> {
+        validate_group_name(name)?;
+        let key = group_key(scope, name);
+        if self.groups.contains_key(&key) {
+            return Err(format!("Group {} already exists", name));
+        }
+        let tenant_id = scope.tenant_id();
+        if self.groups.values().filter(|g| g.tenant_id == tenant_id).count() >= MAX_GROUPS {
+            return Err(format!("At most {} groups", MAX_GROUPS));
+        }
+        let group = Group {
+            name: name.to_string(),
+            description: description.trim().to_string(),
+            devices: BTreeSet::new(),
+            created_by: created_by.to_string(),
+            created_at: now,
+            tenant_id,
+        };
+        self.groups.insert(key, group.clone());
+        Ok(group)
+    }
+
+    pub fn group_in(&self, scope: &TenantScope, name: &str) -> Result<&Group, String> {
+        self.groups.get(&group_key(scope, name)).ok_or_else(|| format!("Group {} not found", name))
+    }
+
+    pub fn group_mut(&mut self, scope: &TenantScope, name: &str) -> Result<&mut Group, String> {
+        self.groups.get_mut(&group_key(scope, name)).ok_or_else(|| format!("Group {} not found", name))
+    }
+
+    pub fn remove_group(&mut self, scope: &TenantScope, name: &str) -> Result<Group, String> {
+        self.groups.remove(&group_key(scope, name)).ok_or_else(|| format!("Group {} not found", name))
+    }
+
+    // Groups the scope may see, keyed for pagination
+    pub fn groups_in(&self, scope: &TenantScope) -> Vec<(String, Group)> {
+        self.groups
+            .iter()
+            .filter(|(_, g)| scope.allows_tenant(g.tenant_id.as_deref()))
+            .map(|(key, g)| (key.clone(), g.clone()))
+            .collect()
+    }
+
+    // Labels a selector sees for a device: the built-in ones, its tags and
+    // one `group` label per group it belongs to
+    pub fn labels(&self, device: &Device) -> Vec<(String, String)> {
+        let mut labels = vec![
+            ("id".to_string(), device.id.clone()),
+            ("type".to_string(), device.device_type.clone()),
+            ("state".to_string(), device.state.name().to_string()),
+            ("firmware".to_string(), device.firmware_version.clone()),
+        ];
+        labels.extend(device.serial.iter().map(|s| ("serial".to_string(), s.clone())));
+        labels.extend(self.synced_labels(&device.id));
+        labels
+    }
+
+    // Tags and groups of a device; icutil_backend adds its own built-in
+    // labels to these
+    pub fn synced_labels(&self, device_id: &str) -> Vec<(String, String)> {
+        let tags = self.tags.get(device_id).into_iter().flatten().map(|(k, v)| (k.clone(), v.clone()));
+        let groups = self
+            .groups
+            .values()
+            .filter(|g| g.devices.contains(device_id))
+            .map(|g| ("group".to_string(), g.name.clone()));
+        tags.chain(groups).collect()
+    }
+
+    pub fn select<'a>(&self, selector: &Selector, registry: &'a Registry) -> Vec<&'a Device> {
+        registry
+            .devices
+            .values()
+            .filter(|d| selector.matches(&self.labels(d)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant(id: &str) -> TenantScope {
+        TenantScope::Tenant(id.to_string())
+    }
+
+    #[test]
+    fn groups_belong_to_the_tenant_that_created_them() {
+        let mut fleet = Fleet::default();
+        let a = fleet.create_group(&tenant("a"), "pilot", "", "alice", 1).unwrap();
+        assert_eq!(a.tenant_id.as_deref(), Some("a"));
+        // Another tenant may use the same name without seeing the first group
+        assert!(fleet.group_in(&tenant("b"), "pilot").is_err());
+        fleet.create_group(&tenant("b"), "pilot", "", "bob", 1).unwrap();
+        assert!(fleet.create_group(&tenant("a"), "pilot", "", "alice", 2).is_err());
+
+        fleet.group_mut(&tenant("a"), "pilot").unwrap().devices.insert("m-1".into());
+        assert!(fleet.group_in(&tenant("b"), "pilot").unwrap().devices.is_empty());
+        assert!(fleet.remove_group(&tenant("c"), "pilot").is_err());
+
+        let visible: Vec<String> = fleet.groups_in(&tenant("a")).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(visible, ["a:pilot"]);
+        assert_eq!(fleet.groups_in(&TenantScope::All).len(), 2);
+        // Tenant admins reach a tenant's group by its key
+        assert_eq!(fleet.group_in(&TenantScope::All, "a:pilot").unwrap().devices.len(), 1);
+        assert_eq!(fleet.remove_group(&tenant("a"), "pilot").unwrap().name, "pilot");
+        assert!(fleet.group_in(&TenantScope::All, "a:pilot").is_err());
+    }
+
+    #[test]
+    fn the_group_limit_is_per_tenant() {
+        let mut fleet = Fleet::default();
+        for i in 0..MAX_GROUPS {
+            fleet.create_group(&tenant("a"), &format!("g{}", i), "", "alice", 1).unwrap();
+        }
+        assert!(fleet.create_group(&tenant("a"), "extra", "", "alice", 1).is_err());
+        assert!(fleet.create_group(&tenant("b"), "extra", "", "bob", 1).is_ok());
+    }
+}
@@ -1,102 +1,1189 @@
-use ic_cdk_macros::{query, update};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::storage;
+use ic_cdk_macros::{post_upgrade, pre_upgrade, query, update};
+use icutil_common::access::{self, Permission, Role, RoleAssignments};
+use icutil_common::audit::{self, AuditEntry, AuditFilter, AuditHead, AuditLog};
+use icutil_common::http::{HttpRequest, HttpResponse};
+use icutil_common::metrics::{self, CallTimer, RuntimeStats};
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+use ic_cdk::api::management_canister::main::raw_rand;
+use std::cell::RefCell;
 
-#[derive(Serialize, Deserialize, Clone)]
-struct Device {
-    id: String,
-    device_type: String,
-    config: String,
-    firmware_version: String,
-    last_heartbeat: u64,
-    registered_at: u64,
+mod groups;
+mod ota;
+mod pairing;
+mod provisioning;
+mod registry;
+mod twin;
+
+use groups::{DeviceResult, Fleet, Group};
+use icutil_common::lifecycle::DeviceState;
+use icutil_common::selector::Selector;
+use ota::{
+    Campaign, CampaignStatus, CampaignTarget, FirmwareAssignment, FirmwareImage, FirmwareSigner, ImageStatus, Ota,
+    UpdateStatus,
+};
+use pairing::{PairingCode, Pairings};
+use provisioning::{
+    ManifestRecord, ManifestSigner, PlannedDevice, Provisioning, ProvisioningReport, RotatedKey, RowResult, RowStatus,
+    SignedManifest,
+};
+use registry::{Device, DeviceFilter, Memberships, Registry, TenantScope};
+use twin::{ConfigDrift, DesiredConfig, DeviceConfig, Twin, Twins};
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+    static PAIRINGS: RefCell<Pairings> = RefCell::new(Pairings::default());
+    static PROVISIONING: RefCell<Provisioning> = RefCell::new(Provisioning::default());
+    static OTA: RefCell<Ota> = RefCell::new(Ota::default());
+    static TWINS: RefCell<Twins> = RefCell::new(Twins::default());
+    static FLEET: RefCell<Fleet> = RefCell::new(Fleet::default());
+    static MEMBERS: RefCell<Memberships> = RefCell::new(Memberships::default());
+    // icutil_backend, told about every claim and state change so owners can
+    // read their meters and readings follow the ingest policy
+    static READINGS_CANISTER: RefCell<Option<Principal>> = RefCell::new(None);
+}
+
+fn now() -> u64 {
+    ic_cdk::api::time() / 1_000_000_000
+}
+
+// Devices the caller may see and manage, see `TenantScope`
+fn caller_scope() -> Result<TenantScope, String> {
+    if can_admin_tenants().is_ok() {
+        return Ok(TenantScope::All);
+    }
+    MEMBERS
+        .with(|m| m.borrow().members.get(&ic_cdk::caller().to_text()).cloned())
+        .map(TenantScope::Tenant)
+        .ok_or_else(|| "Caller is not a member of any tenant".to_string())
 }
 
-static mut DEVICES: Option<HashMap<String, Device>> = None;
+// Fail unless the device exists in the caller's tenant
+fn require_device(device_id: &str) -> Result<Device, String> {
+    let scope = caller_scope()?;
+    REGISTRY.with(|r| r.borrow().get_in(&scope, device_id).cloned())
+}
 
-#[init]
-fn init() {
-    unsafe { DEVICES = Some(HashMap::new()) };
+#[update(guard = "can_admin_devices")]
+async fn register_device(device_id: String, device_type: String, config: Option<String>) -> Result<Device, String> {
+    let _timer = CallTimer::start("register_device", instructions);
+    let tenant_id = caller_scope()?.tenant_id();
+    let device = REGISTRY.with(|r| {
+        r.borrow_mut()
+            .register(&device_id, &device_type, None, config.unwrap_or_default(), tenant_id, now())
+    })?;
+    // Until the device is activated, icutil_backend rejects its readings
+    if let Err(e) = sync_state(&device_id, device.state).await {
+        REGISTRY.with(|r| r.borrow_mut().devices.remove(&device_id));
+        return Err(e);
+    }
+    record_audit("device.register", Some(&device_id), vec![device.device_type.clone()]);
+    Ok(device)
 }
 
-#[update]
-fn register_device(device_id: String, jwt_token: String) -> Result<String, String> {
-    let claims = auth_backend::validate_token(&jwt_token)?;
-    
-    if !claims.roles.contains(&UserRole::DeviceManager) {
-        return Err("Insufficient permissions for device registration".into());
+// Move a device to another lifecycle state, e.g. "active" or
+// "decommissioned". Only the transitions in the README are allowed.
+#[update(guard = "can_admin_devices")]
+async fn update_device_status(device_id: String, status: String, reason: Option<String>) -> Result<Device, String> {
+    require_device(&device_id)?;
+    change_state(&device_id, DeviceState::parse(&status)?, reason).await
+}
+
+async fn change_state(device_id: &str, to: DeviceState, reason: Option<String>) -> Result<Device, String> {
+    let caller = ic_cdk::caller().to_text();
+    let device = REGISTRY.with(|r| r.borrow_mut().transition(device_id, to, &caller, reason.clone(), now()))?;
+    if let Err(e) = sync_state(device_id, to).await {
+        REGISTRY.with(|r| r.borrow_mut().revert(device_id));
+        return Err(e);
+    }
+    if to == DeviceState::Decommissioned {
+        PROVISIONING.with(|p| p.borrow_mut().credentials.remove(device_id));
     }
-    ic_cdk::println!("Registering device: {}", device_id);
-    
-    unsafe {
-        if DEVICES.as_ref().unwrap().contains_key(&device_id) {
-            return Err("Device already exists".to_string());
+    let from = device.history.last().map_or(to, |c| c.from);
+    let mut details = vec![format!("{} -> {}", from.name(), to.name())];
+    details.extend(reason);
+    record_audit("device.status", Some(device_id), details);
+    Ok(device)
+}
+
+// Called by the device; returns the state so it can stop sending readings
+// while suspended
+#[update(guard = "can_write_readings")]
+fn heartbeat(device_id: String) -> Result<DeviceState, String> {
+    require_device(&device_id)?;
+    REGISTRY.with(|r| r.borrow_mut().heartbeat(&device_id, now()))
+}
+
+// Tell icutil_backend about a state so it applies the matching ingest
+// policy. Skipped until `set_readings_canister` is called.
+async fn sync_state(device_id: &str, state: DeviceState) -> Result<(), String> {
+    let canister = match READINGS_CANISTER.with(|r| *r.borrow()) {
+        Some(canister) => canister,
+        None => return Ok(()),
+    };
+    let (result,): (Result<(), String>,) =
+        ic_cdk::call(canister, "sync_device_state", (device_id.to_string(), state))
+            .await
+            .map_err(|(_, e)| format!("Failed to reach the readings canister: {}", e))?;
+    result
+}
+
+// The part of icutil_backend's meter placement this canister needs
+#[derive(CandidType, Deserialize)]
+struct PlacedMeter {
+    tenant_id: String,
+}
+
+// Put a device at a location in icutil_backend, which also assigns it to
+// the location's tenant. Uploaders from a tenant can only use its
+// locations. Returns the tenant icutil_backend assigned.
+async fn place_device(device_id: &str, location_id: &str, tenant_id: Option<String>) -> Result<String, String> {
+    let canister = READINGS_CANISTER
+        .with(|r| *r.borrow())
+        .ok_or_else(|| "Set the readings canister before placing devices at a location".to_string())?;
+    let (result,): (Result<PlacedMeter, String>,) =
+        ic_cdk::call(canister, "place_device", (device_id.to_string(), location_id.to_string(), tenant_id))
+            .await
+            .map_err(|(_, e)| format!("Failed to reach the readings canister: {}", e))?;
+    result.map(|meter| meter.tenant_id)
+}
+
+// Tell icutil_backend about a device's tags and groups so its alert rules
+// and statistics can use selectors too
+async fn sync_labels(device_id: &str) -> Result<(), String> {
+    let canister = match READINGS_CANISTER.with(|r| *r.borrow()) {
+        Some(canister) => canister,
+        None => return Ok(()),
+    };
+    let labels = FLEET.with(|f| f.borrow().synced_labels(device_id));
+    let (result,): (Result<(), String>,) =
+        ic_cdk::call(canister, "sync_device_labels", (device_id.to_string(), labels))
+            .await
+            .map_err(|(_, e)| format!("Failed to reach the readings canister: {}", e))?;
+    result
+}
+
+// Push every device's state and labels again, e.g. after wiring a new readings canister
+#[update(guard = "can_admin_devices")]
+async fn resync_device_states() -> Result<u64, String> {
+    let states: Vec<(String, DeviceState)> = REGISTRY.with(|r| {
+        r.borrow().devices.values().map(|d| (d.id.clone(), d.state)).collect()
+    });
+    for (device_id, state) in &states {
+        sync_state(device_id, *state).await?;
+        sync_labels(device_id).await?;
+    }
+    Ok(states.len() as u64)
+}
+
+// Open to `devices:read` in the device's tenant and to the device's owner
+#[query(guard = "is_signed_in")]
+fn get_device_info(device_id: String) -> Result<Device, String> {
+    let device = REGISTRY.with(|r| r.borrow().devices.get(&device_id).cloned());
+    let readable = |device: &Device| can_read_devices().is_ok() && caller_scope().map_or(false, |s| s.allows(device));
+    match device {
+        Some(device) if readable(&device) || device.owner == Some(ic_cdk::caller()) => Ok(device),
+        _ => Err("Device not found".to_string()),
+    }
+}
+
+// Issue a one-time code that lets a signed-in user claim the device
+#[update(guard = "can_admin_devices")]
+async fn create_pairing_code(device_id: String, expires_in: Option<u64>) -> Result<PairingCode, String> {
+    let ttl = expires_in.unwrap_or(pairing::DEFAULT_TTL);
+    if ttl == 0 || ttl > pairing::MAX_TTL {
+        return Err(format!("Pairing codes expire after 1 to {} seconds", pairing::MAX_TTL));
+    }
+    if require_device(&device_id)?.state == DeviceState::Decommissioned {
+        return Err("Device is decommissioned".to_string());
+    }
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(_, e)| format!("Failed to generate pairing code: {}", e))?;
+    let code = PAIRINGS.with(|p| {
+        p.borrow_mut()
+            .issue(&device_id, &ic_cdk::caller().to_text(), &random, ttl, now())
+    });
+    record_audit("device.pairing_code", Some(&device_id), vec![format!("expires {}", code.expires_at)]);
+    Ok(code)
+}
+
+// Redeem a pairing code as the signed-in caller, e.g. an Internet Identity
+// principal. The caller becomes the device's owner.
+#[update(guard = "is_signed_in")]
+async fn claim_device(code: String) -> Result<Device, String> {
+    let _timer = CallTimer::start("claim_device", instructions);
+    let caller = ic_cdk::caller();
+    let pairing = PAIRINGS.with(|p| p.borrow_mut().redeem(&code, now()))?;
+    let device_id = pairing.device_id.clone();
+    match REGISTRY.with(|r| r.borrow().devices.get(&device_id).map(|d| d.owner)) {
+        None => return Err("Device not found".to_string()),
+        Some(Some(owner)) if owner != caller => return Err("Device is already claimed".to_string()),
+        Some(_) => {}
+    }
+
+    if let Some(canister) = READINGS_CANISTER.with(|r| *r.borrow()) {
+        let bound = match ic_cdk::call::<_, (Result<(), String>,)>(
+            canister,
+            "bind_device_owner",
+            (device_id.clone(), caller.to_text()),
+        )
+        .await
+        {
+            Ok((result,)) => result,
+            Err((_, e)) => Err(format!("Failed to reach the readings canister: {}", e)),
+        };
+        if let Err(e) = bound {
+            PAIRINGS.with(|p| p.borrow_mut().restore(&code, pairing));
+            return Err(e);
+        }
+    }
+
+    let device = REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        let device = registry.get_mut(&device_id)?;
+        device.owner = Some(caller);
+        Ok::<_, String>(device.clone())
+    })?;
+    metrics::counter_add("devices_claimed", "Devices claimed with a pairing code", &[], 1);
+    record_audit("device.claim", Some(&device_id), Vec::new());
+    Ok(device)
+}
+
+// Give up ownership. Works for the owner and for `devices:admin`.
+#[update(guard = "is_signed_in")]
+async fn release_device(device_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let owner = REGISTRY.with(|r| r.borrow().devices.get(&device_id).map(|d| d.owner))
+        .ok_or_else(|| "Device not found".to_string())?
+        .ok_or_else(|| "Device has no owner".to_string())?;
+    if owner != caller {
+        can_admin_devices()?;
+    }
+    if let Some(canister) = READINGS_CANISTER.with(|r| *r.borrow()) {
+        let (result,): (Result<(), String>,) = ic_cdk::call(canister, "unbind_device_owner", (device_id.clone(),))
+            .await
+            .map_err(|(_, e)| format!("Failed to reach the readings canister: {}", e))?;
+        result?;
+    }
+    REGISTRY.with(|r| {
+        if let Some(device) = r.borrow_mut().devices.get_mut(&device_id) {
+            device.owner = None;
+        }
+    });
+    record_audit("device.release", Some(&device_id), vec![owner.to_text()]);
+    Ok(())
+}
+
+// Devices the caller claimed, ordered by id
+#[query(guard = "is_signed_in")]
+fn list_my_devices(request: PageRequest) -> Result<Page<Device>, String> {
+    let filter = DeviceFilter { owner: Some(ic_cdk::caller()), ..Default::default() };
+    let devices = REGISTRY.with(|r| r.borrow().list(&TenantScope::All, &filter));
+    pagination::paginate(devices, Order::Ascending, &request)
+}
+
+// Enroll every device of a signed manifest. Each row succeeds or fails on
+// its own; with `dry_run` nothing changes and valid rows are reported as
+// `Valid`. Generated HMAC keys appear in the report only, once.
+#[update(guard = "can_admin_devices")]
+async fn provision_devices(signed: SignedManifest, dry_run: bool) -> Result<ProvisioningReport, String> {
+    let tenant_id = caller_scope()?.tenant_id();
+    let manifest = PROVISIONING.with(|p| p.borrow().open(&signed))?;
+    let seed = if dry_run {
+        Vec::new()
+    } else {
+        raw_rand()
+            .await
+            .map(|(bytes,)| bytes)
+            .map_err(|(_, e)| format!("Failed to generate device keys: {}", e))?
+    };
+    let (planned, mut rows) =
+        REGISTRY.with(|r| PROVISIONING.with(|p| p.borrow().plan(&manifest, &r.borrow(), &seed, now())));
+    if dry_run {
+        rows.extend(planned.iter().map(|plan| plan.result(RowStatus::Valid)));
+        return Ok(ProvisioningReport::new(&manifest.manifest_id, true, rows));
+    }
+
+    // Claim the manifest id before the first call so a second upload of the
+    // same manifest fails even while this one is still running
+    let caller = ic_cdk::caller().to_text();
+    PROVISIONING.with(|p| {
+        let mut provisioning = p.borrow_mut();
+        if provisioning.manifests.contains_key(&manifest.manifest_id) {
+            return Err(format!("Manifest {} was already applied", manifest.manifest_id));
         }
-        
-        let device = Device {
-            id: device_id.clone(),
-            device_type,
-            config,
-            firmware_version: "1.0.0".to_string(),
-            last_heartbeat: ic_cdk::api::time(),
-            registered_at: ic_cdk::api::time(),
+        let record = ManifestRecord {
+            manifest_id: manifest.manifest_id.clone(),
+            key_id: signed.key_id.clone(),
+            applied_by: caller,
+            applied_at: now(),
+            created: 0,
+            failed: 0,
         };
-        
-        DEVICES.as_mut().unwrap().insert(device_id, device);
-        Ok("Device registered successfully".to_string())
-    }
-}
-
-#[update]
-fn update_device_status(device_id: String, status: String) -> Result<(), String> {
-    unsafe {
-        match DEVICES.as_mut().unwrap().get_mut(&device_id) {
-            Some(device) => {
-                device.last_heartbeat = ic_cdk::api::time();
-                ic_cdk::println!("Updated status for device: {}", device_id);
-                Ok(())
-            }
-            None => Err("Device not found".to_string()),
+        provisioning.manifests.insert(manifest.manifest_id.clone(), record);
+        Ok(())
+    })?;
+
+    for plan in planned {
+        match enroll(&plan, &manifest.manifest_id, tenant_id.clone()).await {
+            Ok(()) => rows.push(plan.result(RowStatus::Created)),
+            Err(e) => rows.push(RowResult::failed(plan.row, &plan.device.device_id, e)),
         }
     }
+    let report = ProvisioningReport::new(&manifest.manifest_id, false, rows);
+    PROVISIONING.with(|p| {
+        if let Some(record) = p.borrow_mut().manifests.get_mut(&manifest.manifest_id) {
+            record.created = report.created;
+            record.failed = report.failed;
+        }
+    });
+    metrics::counter_add("devices_provisioned", "Devices enrolled from manifests", &[], report.created as u64);
+    record_audit(
+        "device.provision",
+        Some(&manifest.manifest_id),
+        vec![
+            format!("signer {}", signed.key_id),
+            format!("{} created, {} failed", report.created, report.failed),
+        ],
+    );
+    Ok(report)
+}
+
+// Register one manifest row and tell icutil_backend about it. The device is
+// removed again if icutil_backend refuses it.
+async fn enroll(plan: &PlannedDevice, manifest_id: &str, tenant_id: Option<String>) -> Result<(), String> {
+    let row = &plan.device;
+    let device = REGISTRY.with(|r| {
+        r.borrow_mut().register(
+            &row.device_id,
+            &row.device_type,
+            Some(row.serial.trim().to_string()),
+            row.config.clone().unwrap_or_default(),
+            tenant_id.clone(),
+            now(),
+        )
+    })?;
+    let mut synced = sync_state(&device.id, device.state).await;
+    if let (Ok(()), Some(location_id)) = (&synced, plan.location_id.as_deref()) {
+        // Uploaders without a tenant get the location's, as in icutil_backend
+        synced = place_device(&device.id, location_id, tenant_id)
+            .await
+            .and_then(|tenant_id| REGISTRY.with(|r| r.borrow_mut().assign_tenant(&device.id, &tenant_id)));
+    }
+    if let Err(e) = synced {
+        REGISTRY.with(|r| r.borrow_mut().devices.remove(&device.id));
+        return Err(e);
+    }
+    PROVISIONING.with(|p| p.borrow_mut().credentials.insert(device.id.clone(), plan.auth.clone()));
+    record_audit(
+        "device.register",
+        Some(&device.id),
+        vec![device.device_type.clone(), format!("manifest {}", manifest_id)],
+    );
+    Ok(())
+}
+
+// Trust an Ed25519 key to sign manifests, e.g. the factory's
+#[update(guard = "can_admin_users")]
+fn add_manifest_signer(key_id: String, name: String, public_key: Vec<u8>) -> Result<ManifestSigner, String> {
+    let signer = PROVISIONING.with(|p| p.borrow_mut().add_signer(&key_id, &name, public_key, now()))?;
+    record_audit("provisioning.add_signer", Some(&key_id), vec![signer.name.clone()]);
+    Ok(signer)
+}
+
+#[update(guard = "can_admin_users")]
+fn remove_manifest_signer(key_id: String) -> Result<(), String> {
+    PROVISIONING
+        .with(|p| p.borrow_mut().signers.remove(&key_id))
+        .ok_or_else(|| format!("Manifest signer {} not found", key_id))?;
+    record_audit("provisioning.remove_signer", Some(&key_id), Vec::new());
+    Ok(())
+}
+
+#[query(guard = "can_read_devices")]
+fn list_manifest_signers() -> Vec<ManifestSigner> {
+    PROVISIONING.with(|p| p.borrow().signers.values().cloned().collect())
+}
+
+// Applied manifests ordered by id
+#[query(guard = "can_read_devices")]
+fn list_manifests(request: PageRequest) -> Result<Page<ManifestRecord>, String> {
+    let manifests = PROVISIONING.with(|p| {
+        p.borrow()
+            .manifests
+            .values()
+            .map(|m| (m.manifest_id.clone(), m.clone()))
+            .collect()
+    });
+    pagination::paginate(manifests, Order::Ascending, &request)
 }
 
+// The icutil_backend canister to notify of claims. It has to grant this
+// canister `devices:admin`.
+#[update(guard = "can_admin_users")]
+fn set_readings_canister(canister: Option<Principal>) {
+    READINGS_CANISTER.with(|r| *r.borrow_mut() = canister);
+    record_audit("config.readings_canister", canister.map(|c| c.to_text()).as_deref(), Vec::new());
+}
+
+// Devices of the caller's tenant matching the filter, ordered by id
+#[query(guard = "can_read_devices")]
+fn list_devices(filter: DeviceFilter, request: PageRequest) -> Result<Page<Device>, String> {
+    let scope = caller_scope()?;
+    let devices = REGISTRY.with(|r| r.borrow().list(&scope, &filter));
+    pagination::paginate(devices, Order::Ascending, &request)
+}
+
+fn instructions() -> u64 {
+    ic_cdk::api::performance_counter(0)
+}
+
+// Prometheus scrapes /metrics through the HTTP gateway
 #[query]
-fn get_device_info(device_id: String) -> Result<Device, String> {
-    unsafe {
-        DEVICES
-            .as_ref()
-            .unwrap()
-            .get(&device_id)
-            .cloned()
-            .ok_or_else(|| "Device not found".to_string())
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let counts = REGISTRY.with(|r| r.borrow().count_by_state());
+    for state in DeviceState::ALL {
+        let count = counts.get(&state).copied().unwrap_or(0);
+        metrics::gauge_set(
+            "devices_registered",
+            "Devices known to the canister",
+            &[("state", state.name())],
+            count as f64,
+        );
     }
+    metrics::serve(&request, RuntimeStats {
+        cycles: ic_cdk::api::canister_balance128(),
+        stable_pages: ic_cdk::api::stable::stable64_size(),
+    })
 }
 
-#[update]
+#[update(guard = "can_write_firmware")]
 fn update_firmware(device_id: String, version: String) -> Result<String, String> {
-    unsafe {
-        match DEVICES.as_mut().unwrap().get_mut(&device_id) {
-            Some(device) => {
-                device.firmware_version = version;
-                ic_cdk::println!("Updated firmware for device: {} to {}", device_id, device.firmware_version);
-                Ok("Firmware updated successfully".to_string())
-            }
-            None => Err("Device not found".to_string()),
+    let _timer = CallTimer::start("update_firmware", instructions);
+    require_device(&device_id)?;
+    let previous = REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        let device = registry.get_mut(&device_id)?;
+        if device.state == DeviceState::Decommissioned {
+            return Err("Device is decommissioned".to_string());
+        }
+        Ok(std::mem::replace(&mut device.firmware_version, version.clone()))
+    })?;
+    record_audit("firmware.update", Some(&device_id), vec![format!("{} -> {}", previous, version)]);
+    Ok("Firmware updated successfully".to_string())
+}
+
+// Trust an Ed25519 key to sign firmware images
+#[update(guard = "can_admin_users")]
+fn add_firmware_signer(key_id: String, name: String, public_key: Vec<u8>) -> Result<FirmwareSigner, String> {
+    let signer = OTA.with(|o| o.borrow_mut().add_signer(&key_id, &name, public_key, now()))?;
+    record_audit("firmware.add_signer", Some(&key_id), vec![signer.name.clone()]);
+    Ok(signer)
+}
+
+// Start an upload. `signature` is the signer's Ed25519 signature of the
+// image's SHA-256 digest. Send the bytes with `upload_firmware_chunk`.
+#[update(guard = "can_write_firmware")]
+fn create_firmware_image(
+    device_type: String,
+    version: String,
+    size: u64,
+    sha256: String,
+    key_id: String,
+    signature: Vec<u8>,
+) -> Result<FirmwareImage, String> {
+    // Images of tenant admins are shared; a tenant's own images only it sees
+    let tenant_id = caller_scope()?.tenant_id();
+    let id = match &tenant_id {
+        Some(tenant_id) => format!("{}:{}-{}", tenant_id, device_type.trim(), version.trim()),
+        None => format!("{}-{}", device_type.trim(), version.trim()),
+    };
+    let image = FirmwareImage {
+        id,
+        tenant_id,
+        device_type: device_type.trim().to_string(),
+        version: version.trim().to_string(),
+        size,
+        sha256,
+        key_id,
+        signature,
+        status: ImageStatus::Uploading,
+        uploaded_by: ic_cdk::caller().to_text(),
+        created_at: now(),
+    };
+    OTA.with(|o| o.borrow_mut().create_image(image))
+}
+
+// Chunks are `ota::CHUNK_SIZE` bytes, the last one shorter
+#[update(guard = "can_write_firmware")]
+fn upload_firmware_chunk(image_id: String, index: u32, bytes: Vec<u8>) -> Result<(), String> {
+    let scope = caller_scope()?;
+    OTA.with(|o| {
+        let mut ota = o.borrow_mut();
+        ota.own_image(&scope, &image_id)?;
+        ota.upload_chunk(&image_id, index, bytes)
+    })
+}
+
+// Check hash and signature; only ready images can be rolled out
+#[update(guard = "can_write_firmware")]
+fn finalize_firmware_image(image_id: String) -> Result<FirmwareImage, String> {
+    let scope = caller_scope()?;
+    let image = OTA.with(|o| {
+        let mut ota = o.borrow_mut();
+        ota.own_image(&scope, &image_id)?;
+        ota.finalize(&image_id)
+    })?;
+    record_audit(
+        "firmware.upload",
+        Some(&image_id),
+        vec![format!("{} {}", image.device_type, image.version), image.sha256.clone()],
+    );
+    Ok(image)
+}
+
+#[update(guard = "can_write_firmware")]
+fn delete_firmware_image(image_id: String) -> Result<(), String> {
+    let scope = caller_scope()?;
+    OTA.with(|o| {
+        let mut ota = o.borrow_mut();
+        ota.own_image(&scope, &image_id)?;
+        ota.delete_image(&image_id)
+    })?;
+    record_audit("firmware.delete", Some(&image_id), Vec::new());
+    Ok(())
+}
+
+#[query(guard = "can_read_devices")]
+fn list_firmware_images() -> Result<Vec<FirmwareImage>, String> {
+    let scope = caller_scope()?;
+    Ok(OTA.with(|o| o.borrow().images.values().filter(|i| i.visible_to(&scope)).cloned().collect()))
+}
+
+// Create a draft campaign. `stages` are cumulative percentages of the
+// targets, e.g. [5, 25, 100]; it pauses once more than `failure_threshold`
+// percent of the admitted devices failed.
+#[update(guard = "can_write_firmware")]
+fn create_campaign(
+    name: String,
+    image_id: String,
+    target: CampaignTarget,
+    stages: Vec<u8>,
+    failure_threshold: f64,
+) -> Result<Campaign, String> {
+    let tenant_id = caller_scope()?.tenant_id();
+    let caller = ic_cdk::caller().to_text();
+    let campaign = OTA.with(|o| {
+        o.borrow_mut()
+            .create_campaign(tenant_id, &name, &image_id, target, stages, failure_threshold, &caller, now())
+    })?;
+    record_audit("firmware.campaign_create", Some(&campaign.id.to_string()), vec![image_id]);
+    Ok(campaign)
+}
+
+#[update(guard = "can_write_firmware")]
+fn start_campaign(id: u64) -> Result<Campaign, String> {
+    require_campaign(id)?;
+    let campaign =
+        REGISTRY.with(|r| FLEET.with(|f| OTA.with(|o| o.borrow_mut().start(id, &r.borrow(), &f.borrow()))))?;
+    record_audit(
+        "firmware.campaign_start",
+        Some(&id.to_string()),
+        vec![format!("{} devices", campaign.targets.len())],
+    );
+    Ok(campaign)
+}
+
+#[update(guard = "can_write_firmware")]
+fn advance_campaign(id: u64) -> Result<Campaign, String> {
+    require_campaign(id)?;
+    let campaign = OTA.with(|o| o.borrow_mut().advance(id))?;
+    let stage = campaign.stages[campaign.stage as usize];
+    record_audit("firmware.campaign_advance", Some(&id.to_string()), vec![format!("{} %", stage)]);
+    Ok(campaign)
+}
+
+#[update(guard = "can_write_firmware")]
+fn pause_campaign(id: u64, reason: Option<String>) -> Result<Campaign, String> {
+    require_campaign(id)?;
+    let campaign = OTA.with(|o| o.borrow_mut().set_paused(id, true, reason.clone()))?;
+    record_audit("firmware.campaign_pause", Some(&id.to_string()), reason.into_iter().collect());
+    Ok(campaign)
+}
+
+#[update(guard = "can_write_firmware")]
+fn resume_campaign(id: u64) -> Result<Campaign, String> {
+    require_campaign(id)?;
+    let campaign = OTA.with(|o| o.borrow_mut().set_paused(id, false, None))?;
+    record_audit("firmware.campaign_resume", Some(&id.to_string()), Vec::new());
+    Ok(campaign)
+}
+
+#[update(guard = "can_write_firmware")]
+fn cancel_campaign(id: u64) -> Result<Campaign, String> {
+    require_campaign(id)?;
+    let campaign = OTA.with(|o| o.borrow_mut().cancel(id))?;
+    record_audit("firmware.campaign_cancel", Some(&id.to_string()), Vec::new());
+    Ok(campaign)
+}
+
+// Put every device the campaign updated back on its previous version
+#[update(guard = "can_write_firmware")]
+fn rollback_campaign(id: u64) -> Result<Vec<Campaign>, String> {
+    require_campaign(id)?;
+    let caller = ic_cdk::caller().to_text();
+    let rollbacks = OTA.with(|o| o.borrow_mut().rollback(id, &caller, now()))?;
+    let ids = rollbacks.iter().map(|c| format!("campaign {}", c.id)).collect();
+    record_audit("firmware.campaign_rollback", Some(&id.to_string()), ids);
+    Ok(rollbacks)
+}
+
+// Fail unless the campaign belongs to the caller's tenant
+fn require_campaign(id: u64) -> Result<Campaign, String> {
+    let scope = caller_scope()?;
+    OTA.with(|o| o.borrow().campaign_in(&scope, id).cloned())
+}
+
+#[query(guard = "can_read_devices")]
+fn get_campaign(id: u64) -> Result<Campaign, String> {
+    require_campaign(id)
+}
+
+// Campaigns newest first
+#[query(guard = "can_read_devices")]
+fn list_campaigns(status: Option<CampaignStatus>, request: PageRequest) -> Result<Page<Campaign>, String> {
+    let scope = caller_scope()?;
+    let campaigns = OTA.with(|o| {
+        o.borrow()
+            .campaigns
+            .values()
+            .filter(|c| scope.allows_tenant(c.tenant_id.as_deref()))
+            .filter(|c| status.map_or(true, |s| c.status == s))
+            .map(|c| (c.id, c.clone()))
+            .collect()
+    });
+    pagination::paginate(campaigns, Order::Descending, &request)
+}
+
+// Devices ask for their next update, e.g. once an hour
+#[update(guard = "can_write_readings")]
+fn poll_firmware_update(device_id: String) -> Result<Option<FirmwareAssignment>, String> {
+    let device = require_device(&device_id)?;
+    let version = match device.state {
+        DeviceState::Active => device.firmware_version,
+        state => return Err(format!("Device is {}", state.name())),
+    };
+    Ok(OTA.with(|o| o.borrow_mut().poll(&device_id, &version, now())))
+}
+
+#[query(guard = "can_write_readings")]
+fn get_firmware_chunk(image_id: String, index: u32) -> Result<Vec<u8>, String> {
+    let scope = caller_scope()?;
+    OTA.with(|o| {
+        let ota = o.borrow();
+        ota.image_in(&scope, &image_id)?;
+        ota.chunk(&image_id, index)
+    })
+}
+
+// Progress of an update. On success the device's firmware version changes;
+// too many failures pause the campaign.
+#[update(guard = "can_write_readings")]
+fn report_firmware_status(
+    device_id: String,
+    campaign_id: u64,
+    status: UpdateStatus,
+    error: Option<String>,
+) -> Result<(), String> {
+    require_device(&device_id)?;
+    let outcome = OTA.with(|o| o.borrow_mut().report(campaign_id, &device_id, status, error.clone(), now()))?;
+    if status == UpdateStatus::Failed {
+        metrics::counter_add("firmware_updates", "Firmware updates reported by devices", &[("result", "failed")], 1);
+        record_audit(
+            "firmware.update_failed",
+            Some(&device_id),
+            vec![format!("campaign {}", campaign_id), error.unwrap_or_default()],
+        );
+    }
+    if let Some(reason) = outcome.paused {
+        record_audit("firmware.campaign_pause", Some(&campaign_id.to_string()), vec![reason]);
+    }
+    if let Some(version) = outcome.installed {
+        metrics::counter_add("firmware_updates", "Firmware updates reported by devices", &[("result", "succeeded")], 1);
+        let previous = REGISTRY.with(|r| {
+            r.borrow_mut()
+                .get_mut(&device_id)
+                .map(|d| std::mem::replace(&mut d.firmware_version, version.clone()))
+        })?;
+        record_audit(
+            "firmware.update",
+            Some(&device_id),
+            vec![format!("{} -> {}", previous, version), format!("campaign {}", campaign_id)],
+        );
+    }
+    Ok(())
+}
+
+// Devices that are not decommissioned; their configuration can change
+fn configurable(device_id: &str) -> Result<(), String> {
+    REGISTRY.with(|r| match r.borrow().get(device_id)?.state {
+        DeviceState::Decommissioned => Err("Device is decommissioned".to_string()),
+        _ => Ok(()),
+    })
+}
+
+// Set the configuration a device should run with. Each change is a new
+// version; pass the version you edited as `expected_version` so a
+// concurrent change is not overwritten.
+#[update(guard = "can_write_config")]
+fn set_desired_config(
+    device_id: String,
+    config: DeviceConfig,
+    expected_version: Option<u64>,
+) -> Result<DesiredConfig, String> {
+    require_device(&device_id)?;
+    configurable(&device_id)?;
+    let caller = ic_cdk::caller().to_text();
+    let desired =
+        TWINS.with(|t| t.borrow_mut().set_desired(&device_id, config, expected_version, &caller, now()))?;
+    record_audit("device.config", Some(&device_id), vec![format!("version {}", desired.version)]);
+    Ok(desired)
+}
+
+// Make an earlier desired version current again, as a new version
+#[update(guard = "can_write_config")]
+fn revert_desired_config(device_id: String, version: u64) -> Result<DesiredConfig, String> {
+    require_device(&device_id)?;
+    configurable(&device_id)?;
+    let caller = ic_cdk::caller().to_text();
+    let desired = TWINS.with(|t| t.borrow_mut().revert(&device_id, version, &caller, now()))?;
+    record_audit(
+        "device.config",
+        Some(&device_id),
+        vec![format!("version {}", desired.version), format!("reverted to {}", version)],
+    );
+    Ok(desired)
+}
+
+#[query(guard = "can_read_devices")]
+fn get_device_twin(device_id: String) -> Result<Twin, String> {
+    require_device(&device_id)?;
+    Ok(TWINS.with(|t| t.borrow().twins.get(&device_id).cloned().unwrap_or_default()))
+}
+
+#[query(guard = "can_read_devices")]
+fn get_config_drift(device_id: String) -> Result<ConfigDrift, String> {
+    require_device(&device_id)?;
+    Ok(TWINS.with(|t| t.borrow().twins.get(&device_id).cloned().unwrap_or_default().drift(&device_id)))
+}
+
+// Devices whose reported configuration differs from the desired one, or
+// that have not applied the latest version yet
+#[query(guard = "can_read_devices")]
+fn list_config_drift(request: PageRequest) -> Result<Page<ConfigDrift>, String> {
+    let scope = caller_scope()?;
+    let visible = |id: &str| REGISTRY.with(|r| r.borrow().get_in(&scope, id).is_ok());
+    let drifted = TWINS.with(|t| {
+        t.borrow()
+            .twins
+            .iter()
+            .filter(|(id, _)| visible(id))
+            .map(|(id, twin)| (id.clone(), twin.drift(id)))
+            .filter(|(_, drift)| !drift.in_sync)
+            .collect()
+    });
+    pagination::paginate(drifted, Order::Ascending, &request)
+}
+
+// Devices fetch configuration they have not applied yet, e.g. on every
+// upload. None means the device is up to date.
+#[query(guard = "can_write_readings")]
+fn get_pending_config(device_id: String) -> Result<Option<DesiredConfig>, String> {
+    require_device(&device_id)?;
+    configurable(&device_id)?;
+    Ok(TWINS.with(|t| t.borrow().twins.get(&device_id).and_then(|twin| twin.pending().cloned())))
+}
+
+// Devices report the configuration they run with and the desired version
+// it came from, 0 for factory settings
+#[update(guard = "can_write_readings")]
+fn report_config(device_id: String, version: u64, config: DeviceConfig) -> Result<ConfigDrift, String> {
+    require_device(&device_id)?;
+    configurable(&device_id)?;
+    let drift = TWINS.with(|t| t.borrow_mut().report(&device_id, version, config, now()))?;
+    if !drift.in_sync {
+        metrics::counter_add("config_drift_reports", "Config reports that differ from the desired config", &[], 1);
+    }
+    Ok(drift)
+}
+
+// Devices of the caller's tenant a selector matches
+fn select_in_scope(selector: &str) -> Result<Vec<Device>, String> {
+    let selector = Selector::parse(selector)?;
+    let scope = caller_scope()?;
+    Ok(REGISTRY.with(|r| {
+        FLEET.with(|f| {
+            f.borrow()
+                .select(&selector, &r.borrow())
+                .into_iter()
+                .filter(|d| scope.allows(d))
+                .cloned()
+                .collect()
+        })
+    }))
+}
+
+// Ids of the devices a selector matches, for bulk operations
+fn select(selector: &str) -> Result<Vec<String>, String> {
+    let ids: Vec<String> = select_in_scope(selector)?.into_iter().map(|d| d.id).collect();
+    if ids.len() > groups::MAX_BULK {
+        return Err(format!(
+            "Selector matches {} devices; bulk operations take at most {}",
+            ids.len(),
+            groups::MAX_BULK
+        ));
+    }
+    Ok(ids)
+}
+
+fn device_result(device_id: &str, result: Result<(), String>) -> DeviceResult {
+    DeviceResult { device_id: device_id.to_string(), error: result.err() }
+}
+
+// Push labels after a group change. The change stays even if a push
+// fails; `resync_device_states` repairs icutil_backend later.
+async fn sync_members(device_ids: impl IntoIterator<Item = String>) -> Result<(), String> {
+    let mut failed = 0;
+    for device_id in device_ids {
+        if sync_labels(&device_id).await.is_err() {
+            failed += 1;
         }
     }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("Saved, but {} devices could not be updated in the readings canister", n)),
+    }
+}
+
+// Replace the tags of a device, e.g. [("site", "A"), ("floor", "3")]
+#[update(guard = "can_admin_devices")]
+async fn set_device_tags(device_id: String, tags: Vec<(String, String)>) -> Result<(), String> {
+    require_device(&device_id)?;
+    let details = tags.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    let previous = FLEET.with(|f| f.borrow_mut().set_tags(&device_id, tags))?;
+    if let Err(e) = sync_labels(&device_id).await {
+        let previous = previous.into_iter().collect();
+        FLEET.with(|f| f.borrow_mut().set_tags(&device_id, previous))?;
+        return Err(e);
+    }
+    record_audit("device.tags", Some(&device_id), details);
+    Ok(())
+}
+
+#[update(guard = "can_admin_devices")]
+fn create_device_group(name: String, description: String) -> Result<Group, String> {
+    let scope = caller_scope()?;
+    let caller = ic_cdk::caller().to_text();
+    let group = FLEET.with(|f| f.borrow_mut().create_group(&scope, &name, &description, &caller, now()))?;
+    record_audit("device.group_create", Some(&name), Vec::new());
+    Ok(group)
+}
+
+#[update(guard = "can_admin_devices")]
+async fn delete_device_group(name: String) -> Result<(), String> {
+    let scope = caller_scope()?;
+    let group = FLEET.with(|f| f.borrow_mut().remove_group(&scope, &name))?;
+    record_audit("device.group_delete", Some(&name), vec![format!("{} devices", group.devices.len())]);
+    sync_members(group.devices).await
+}
+
+// Add devices to a static group; unknown ids fail the whole call
+#[update(guard = "can_admin_devices")]
+async fn add_group_members(name: String, device_ids: Vec<String>) -> Result<Group, String> {
+    let scope = caller_scope()?;
+    REGISTRY.with(|r| {
+        let registry = r.borrow();
+        device_ids
+            .iter()
+            .try_for_each(|id| registry.get_in(&scope, id).map(|_| ()).map_err(|e| format!("{}: {}", id, e)))
+    })?;
+    let group = FLEET.with(|f| {
+        let mut fleet = f.borrow_mut();
+        let group = fleet.group_mut(&scope, &name)?;
+        group.devices.extend(device_ids.iter().cloned());
+        Ok::<_, String>(group.clone())
+    })?;
+    record_audit("device.group_add", Some(&name), device_ids.clone());
+    sync_members(device_ids).await?;
+    Ok(group)
+}
+
+#[update(guard = "can_admin_devices")]
+async fn remove_group_members(name: String, device_ids: Vec<String>) -> Result<Group, String> {
+    let scope = caller_scope()?;
+    let group = FLEET.with(|f| {
+        let mut fleet = f.borrow_mut();
+        let group = fleet.group_mut(&scope, &name)?;
+        group.devices.retain(|id| !device_ids.contains(id));
+        Ok::<_, String>(group.clone())
+    })?;
+    record_audit("device.group_remove", Some(&name), device_ids.clone());
+    sync_members(device_ids).await?;
+    Ok(group)
+}
+
+#[query(guard = "can_read_devices")]
+fn get_device_group(name: String) -> Result<Group, String> {
+    let scope = caller_scope()?;
+    FLEET.with(|f| f.borrow().group_in(&scope, &name).cloned())
+}
+
+#[query(guard = "can_read_devices")]
+fn list_device_groups(request: PageRequest) -> Result<Page<Group>, String> {
+    let scope = caller_scope()?;
+    let groups = FLEET.with(|f| f.borrow().groups_in(&scope));
+    pagination::paginate(groups, Order::Ascending, &request)
+}
+
+// Preview which devices a selector picks before using it in bulk
+#[query(guard = "can_read_devices")]
+fn select_devices(selector: String, request: PageRequest) -> Result<Page<Device>, String> {
+    let devices = select_in_scope(&selector)?.into_iter().map(|d| (d.id.clone(), d)).collect();
+    pagination::paginate(devices, Order::Ascending, &request)
+}
+
+// `update_device_status` for every device a selector matches. Devices for
+// which the transition is not allowed are reported and skipped.
+#[update(guard = "can_admin_devices")]
+async fn update_devices_status(
+    selector: String,
+    status: String,
+    reason: Option<String>,
+) -> Result<Vec<DeviceResult>, String> {
+    let to = DeviceState::parse(&status)?;
+    let mut results = Vec::new();
+    for device_id in select(&selector)? {
+        let result = change_state(&device_id, to, reason.clone()).await.map(|_| ());
+        results.push(device_result(&device_id, result));
+    }
+    Ok(results)
+}
+
+// Push one desired config to every device a selector matches
+#[update(guard = "can_write_config")]
+fn set_desired_config_by_selector(selector: String, config: DeviceConfig) -> Result<Vec<DeviceResult>, String> {
+    config.validate()?;
+    let caller = ic_cdk::caller().to_text();
+    let mut results = Vec::new();
+    for device_id in select(&selector)? {
+        let result = configurable(&device_id).and_then(|_| {
+            let desired = TWINS.with(|t| t.borrow_mut().set_desired(&device_id, config.clone(), None, &caller, now()))?;
+            let details = vec![format!("version {}", desired.version), selector.clone()];
+            record_audit("device.config", Some(&device_id), details);
+            Ok(())
+        });
+        results.push(device_result(&device_id, result));
+    }
+    Ok(results)
+}
+
+// New HMAC keys for every matching device that has one. The keys are in
+// the response only; hand them to the devices before they next sign.
+#[update(guard = "can_admin_devices")]
+async fn rotate_device_keys(selector: String) -> Result<Vec<RotatedKey>, String> {
+    let device_ids = select(&selector)?;
+    let (seed,) = raw_rand().await.map_err(|(_, e)| format!("Failed to generate device keys: {}", e))?;
+    let rotated: Vec<RotatedKey> = PROVISIONING.with(|p| {
+        let mut provisioning = p.borrow_mut();
+        device_ids.iter().filter_map(|id| provisioning.rotate(id, &seed, now()).ok()).collect()
+    });
+    for key in &rotated {
+        record_audit("device.rotate_key", Some(&key.device_id), vec![format!("key version {}", key.key_version)]);
+    }
+    Ok(rotated)
 }
 
-#[update]
-fn update_device_status(device_id: String, status: String) -> Result<(), String> {
-    unsafe {
-        match DEVICES.as_mut().unwrap().get_mut(&device_id) {
-            Some(device) => {
-                device.last_heartbeat = ic_cdk::api::time();
-                ic_cdk::println!("Updated status for device: {}", device_id);
-                Ok(())
-            }
-            None => Err("Device not found".to_string()),
+fn authorize(permission: Permission) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    access::require(&caller.to_string(), ic_cdk::api::is_controller(&caller), permission)
+}
+
+fn can_read_devices() -> Result<(), String> {
+    authorize(Permission::DevicesRead)
+}
+
+fn can_admin_devices() -> Result<(), String> {
+    authorize(Permission::DevicesAdmin)
+}
+
+fn can_write_firmware() -> Result<(), String> {
+    authorize(Permission::FirmwareWrite)
+}
+
+fn can_write_config() -> Result<(), String> {
+    authorize(Permission::ConfigWrite)
+}
+
+fn can_write_readings() -> Result<(), String> {
+    authorize(Permission::ReadingsWrite)
+}
+
+// Any authenticated principal; residents have no role until they claim
+fn is_signed_in() -> Result<(), String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        Err("Sign in first".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn can_admin_users() -> Result<(), String> {
+    authorize(Permission::UsersAdmin)
+}
+
+// Tenant administration spans every tenant, so a member of a tenant cannot
+// use it even with the admin role
+fn can_admin_tenants() -> Result<(), String> {
+    authorize(Permission::TenantsAdmin)?;
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) && MEMBERS.with(|m| m.borrow().members.contains_key(&caller.to_text())) {
+        return Err("Tenant members cannot administer tenants".into());
+    }
+    Ok(())
+}
+
+// Put a principal in a tenant in this canister, or take them out with None.
+// Use the same tenant ids as in icutil_backend.
+#[update(guard = "can_admin_tenants")]
+fn set_user_tenant(principal: Principal, tenant_id: Option<String>) {
+    let id = principal.to_text();
+    MEMBERS.with(|m| {
+        let mut members = m.borrow_mut();
+        match tenant_id.clone() {
+            Some(tenant_id) => members.members.insert(id.clone(), tenant_id),
+            None => members.members.remove(&id),
         }
+    });
+    record_audit("tenant.add_member", Some(&id), tenant_id.into_iter().collect());
+}
+
+// Assign a device registered by a controller or `tenants:admin`, which
+// has no tenant yet
+#[update(guard = "can_admin_tenants")]
+fn assign_device_to_tenant(device_id: String, tenant_id: String) -> Result<(), String> {
+    REGISTRY.with(|r| r.borrow_mut().assign_tenant(&device_id, &tenant_id))?;
+    record_audit("tenant.assign_device", Some(&device_id), vec![tenant_id]);
+    Ok(())
+}
+
+// Roles are kept per canister. Controllers always have every permission
+// and assign roles to everyone else.
+#[update(guard = "can_admin_users")]
+fn set_roles(principal: Principal, roles: Vec<Role>) {
+    let names = roles.iter().map(|role| role.name().to_string()).collect();
+    access::set_roles(&principal.to_text(), roles);
+    record_audit("role.set", Some(&principal.to_text()), names);
+}
+
+#[query]
+fn get_my_roles() -> Vec<Role> {
+    access::roles_of(&ic_cdk::caller().to_text())
+}
+
+// The log holds the actions of every tenant, so reading it also takes
+// tenant administration
+fn can_read_audit() -> Result<(), String> {
+    authorize(Permission::AuditRead)?;
+    can_admin_tenants()
+}
+
+// Record a privileged action. The caller comes from the runtime, never
+// from the arguments.
+fn record_audit(action: &str, target: Option<&str>, details: Vec<String>) {
+    audit::record(
+        &ic_cdk::caller().to_text(),
+        action,
+        target,
+        details,
+        ic_cdk::api::time() / 1_000_000_000,
+    );
+}
+
+// Hash-chained log of privileged actions, oldest first
+#[query(guard = "can_read_audit")]
+fn get_audit_logs(filter: AuditFilter, request: PageRequest) -> Result<Page<AuditEntry>, String> {
+    audit::query(&filter, &request)
+}
+
+#[query(guard = "can_read_audit")]
+fn get_audit_head() -> AuditHead {
+    audit::head()
+}
+
+#[query(guard = "can_read_audit")]
+fn verify_audit_log() -> Result<u64, String> {
+    audit::verify()
+}
+
+// Heap state has to survive upgrades
+#[pre_upgrade]
+fn pre_upgrade() {
+    let registry = REGISTRY.with(|r| r.borrow().clone());
+    let pairings = PAIRINGS.with(|p| p.borrow().clone());
+    let provisioning = PROVISIONING.with(|p| p.borrow().clone());
+    let ota = OTA.with(|o| o.borrow().clone());
+    let twins = TWINS.with(|t| t.borrow().clone());
+    let fleet = FLEET.with(|f| f.borrow().clone());
+    let members = MEMBERS.with(|m| m.borrow().clone());
+    let readings_canister = READINGS_CANISTER.with(|r| *r.borrow());
+    let state = (
+        audit::export_state(),
+        access::export_state(),
+        Some(registry),
+        Some(pairings),
+        readings_canister,
+        Some(provisioning),
+        Some(ota),
+        Some(twins),
+        Some(fleet),
+        Some(members),
+    );
+    if storage::stable_save(state).is_err() {
+        ic_cdk::trap("Failed to save state before upgrade");
+    }
+}
+
+// Versions before the audit log saved nothing, so empty stable memory is a
+// fresh start. Anything else that fails to decode would wipe the registry.
+#[post_upgrade]
+fn post_upgrade() {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return;
     }
+    type State = (
+        AuditLog,
+        RoleAssignments,
+        Option<Registry>,
+        Option<Pairings>,
+        Option<Principal>,
+        Option<Provisioning>,
+        Option<Ota>,
+        Option<Twins>,
+        Option<Fleet>,
+        Option<Memberships>,
+    );
+    let (log, roles, registry, pairings, readings_canister, provisioning, ota, twins, fleet, members) =
+        storage::stable_restore::<State>().unwrap_or_else(|_| ic_cdk::trap("Failed to restore state after upgrade"));
+    audit::import_state(log);
+    access::import_state(roles);
+    REGISTRY.with(|r| *r.borrow_mut() = registry.unwrap_or_default());
+    PAIRINGS.with(|p| *p.borrow_mut() = pairings.unwrap_or_default());
+    PROVISIONING.with(|p| *p.borrow_mut() = provisioning.unwrap_or_default());
+    OTA.with(|o| *o.borrow_mut() = ota.unwrap_or_default());
+    TWINS.with(|t| *t.borrow_mut() = twins.unwrap_or_default());
+    FLEET.with(|f| *f.borrow_mut() = fleet.unwrap_or_default());
+    MEMBERS.with(|m| *m.borrow_mut() = members.unwrap_or_default());
+    READINGS_CANISTER.with(|r| *r.borrow_mut() = readings_canister);
 }
@@ -0,0 +1,788 @@
+use candid::CandidType;
+use ed25519_dalek::Signature;
+use icutil_common::lifecycle::DeviceState;
+use icutil_common::selector::Selector;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::groups::Fleet;
+use crate::provisioning::verifying_key;
+use crate::registry::{Registry, TenantScope};
+
+pub const CHUNK_SIZE: usize = 1024 * 1024; // Fits in one ingress message
+pub const MAX_IMAGE_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_IMAGES: usize = 10; // Images live on the heap and in every upgrade
+const MAX_STAGES: usize = 10;
+
+// A key trusted to sign firmware images
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FirmwareSigner {
+    pub key_id: String,
+    pub name: String,
+    pub public_key: Vec<u8>, // Ed25519, 32 bytes
+    pub added_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageStatus {
+    Uploading,
+    Ready, // Hash and signature checked
+}
+
+// The signature covers the 32-byte SHA-256 digest of the image
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FirmwareImage {
+    pub id: String,
+    pub tenant_id: Option<String>, // None for images shared by every tenant
+    pub device_type: String,
+    pub version: String,
+    pub size: u64,
+    pub sha256: String, // Hex
+    pub key_id: String,
+    pub signature: Vec<u8>,
+    pub status: ImageStatus,
+    pub uploaded_by: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ImageData {
+    pub chunks: Vec<Option<Vec<u8>>>,
+}
+
+// Devices a campaign goes to, fixed when it starts. Empty fields match
+// every device; only active devices are targeted.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CampaignTarget {
+    pub device_ids: Vec<String>,
+    pub firmware_version: Option<String>, // Only devices running this version
+    pub selector: Option<String>,         // e.g. "group=pilot AND site=A"
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CampaignStatus {
+    Draft,
+    Running,
+    Paused,
+    Completed,
+    Cancelled,
+    RolledBack,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateStatus {
+    Assigned, // Returned by a poll, nothing reported yet
+    Downloading,
+    Installing,
+    Succeeded,
+    Failed,
+}
+
+impl UpdateStatus {
+    fn finished(&self) -> bool {
+        matches!(self, UpdateStatus::Succeeded | UpdateStatus::Failed)
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Assignment {
+    pub device_id: String,
+    pub status: UpdateStatus,
+    pub previous_version: String,
+    pub error: Option<String>,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Campaign {
+    pub id: u64,
+    pub tenant_id: Option<String>, // None for campaigns across tenants
+    pub name: String,
+    pub image_id: String,
+    pub target: CampaignTarget,
+    pub stages: Vec<u8>, // Cumulative percentages of the targets, ending at 100
+    pub stage: u32,      // Index into `stages`
+    pub failure_threshold: f64, // Percent of admitted devices that may fail
+    pub status: CampaignStatus,
+    pub paused_reason: Option<String>,
+    pub targets: Vec<String>, // Rollout order, fixed at start
+    pub assignments: BTreeMap<String, Assignment>,
+    pub rollback_of: Option<u64>,
+    pub created_by: String,
+    pub created_at: u64,
+}
+
+// What a device report changed
+#[derive(Clone, Debug, Default)]
+pub struct ReportOutcome {
+    pub installed: Option<String>, // Version now running on the device
+    pub paused: Option<String>,    // Why the campaign was paused
+}
+
+// What a polling device needs to fetch and check its update
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FirmwareAssignment {
+    pub campaign_id: u64,
+    pub image_id: String,
+    pub version: String,
+    pub size: u64,
+    pub sha256: String,
+    pub signature: Vec<u8>,
+    pub chunk_size: u64,
+    pub chunk_count: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Ota {
+    pub signers: BTreeMap<String, FirmwareSigner>,
+    pub images: BTreeMap<String, FirmwareImage>,
+    pub data: BTreeMap<String, ImageData>,
+    pub campaigns: BTreeMap<u64, Campaign>,
+    pub next_campaign_id: u64,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn chunk_count(size: u64) -> usize {
+    (size as usize).div_ceil(CHUNK_SIZE)
+}
+
+// Spread the rollout order evenly over device ids, differently per campaign
+fn rollout_key(campaign_id: u64, device_id: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(campaign_id.to_be_bytes());
+    hasher.update(device_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+impl FirmwareImage {
+    // Shared images can be rolled out by every tenant
+    pub fn visible_to(&self, scope: &TenantScope) -> bool {
+        self.tenant_id.is_none() || scope.allows_tenant(self.tenant_id.as_deref())
+    }
+
+    fn assignment(&self, campaign_id: u64) -> FirmwareAssignment {
+        FirmwareAssignment {
+            campaign_id,
+            image_id: self.id.clone(),
+            version: self.version.clone(),
+            size: self.size,
+            sha256: self.sha256.clone(),
+            signature: self.signature.clone(),
+            chunk_size: CHUNK_SIZE as u64,
+            chunk_count: chunk_count(self.size) as u32,
+        }
+    }
+}
+
+impl Campaign {
+    // Devices the campaign may reach
+    pub fn scope(&self) -> TenantScope {
+        match &self.tenant_id {
+            Some(tenant_id) => TenantScope::Tenant(tenant_id.clone()),
+            None => TenantScope::All,
+        }
+    }
+
+    // Targets allowed to update at the current stage
+    fn admitted(&self) -> &[String] {
+        let percent = self.stages.get(self.stage as usize).copied().unwrap_or(100) as usize;
+        let count = (self.targets.len() * percent).div_ceil(100);
+        &self.targets[..count.min(self.targets.len())]
+    }
+
+    fn failure_rate(&self) -> f64 {
+        let admitted = self.admitted().len().max(1);
+        let failed = self.assignments.values().filter(|a| a.status == UpdateStatus::Failed).count();
+        failed as f64 * 100.0 / admitted as f64
+    }
+
+    fn stage_finished(&self) -> bool {
+        self.admitted()
+            .iter()
+            .all(|id| self.assignments.get(id).map_or(false, |a| a.status.finished()))
+    }
+}
+
+impl Ota {
+    pub fn add_signer(&mut self, key_id: &str, name: &str, public_key: Vec<u8>, now: u64) -> Result<FirmwareSigner, String> {
+        if key_id.is_empty() || key_id.len() > 64 {
+            return Err("Key id must be 1 to 64 characters".to_string());
+        }
+        verifying_key(&public_key)?;
+        let signer = FirmwareSigner {
+            key_id: key_id.to_string(),
+            name: name.trim().to_string(),
+            public_key,
+            added_at: now,
+        };
+        self.signers.insert(key_id.to_string(), signer.clone());
+        Ok(signer)
+    }
+
+    pub fn create_image(&mut self, image: FirmwareImage) -> Result<FirmwareImage, String> {
+        if image.version.trim().is_empty() || image.device_type.trim().is_empty() {
+            return Err("Version and device type are required".to_string());
+        }
+        if image.size == 0 || image.size > MAX_IMAGE_SIZE {
+            return Err(format!("Images must be 1 to {} bytes", MAX_IMAGE_SIZE));
+        }
+        if image.sha256.len() != 64 || !image.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("SHA-256 must be 64 hex characters".to_string());
+        }
+        if !self.signers.contains_key(&image.key_id) {
+            return Err(format!("Unknown firmware signer {}", image.key_id));
+        }
+        if self.images.contains_key(&image.id) {
+            return Err(format!("Image {} already exists", image.id));
+        }
+        if self
+            .images
+            .values()
+            .any(|i| i.tenant_id == image.tenant_id && i.device_type == image.device_type && i.version == image.version)
+        {
+            return Err(format!("{} {} was already uploaded", image.device_type, image.version));
+        }
+        if self.images.len() >= MAX_IMAGES {
+            return Err(format!("At most {} images can be stored; delete an old one first", MAX_IMAGES));
+        }
+        let image = FirmwareImage { sha256: image.sha256.to_lowercase(), status: ImageStatus::Uploading, ..image };
+        let data = ImageData { chunks: vec![None; chunk_count(image.size)] };
+        self.data.insert(image.id.clone(), data);
+        self.images.insert(image.id.clone(), image.clone());
+        Ok(image)
+    }
+
+    pub fn upload_chunk(&mut self, image_id: &str, index: u32, bytes: Vec<u8>) -> Result<(), String> {
+        let image = self.image(image_id)?;
+        if image.status != ImageStatus::Uploading {
+            return Err("Image is already finalized".to_string());
+        }
+        let count = chunk_count(image.size);
+        let index = index as usize;
+        if index >= count {
+            return Err(format!("Chunk index must be below {}", count));
+        }
+        let expected = if index + 1 == count { image.size as usize - index * CHUNK_SIZE } else { CHUNK_SIZE };
+        if bytes.len() != expected {
+            return Err(format!("Chunk {} must be {} bytes", index, expected));
+        }
+        let data = self.data.get_mut(image_id).ok_or("Image data missing")?;
+        data.chunks[index] = Some(bytes);
+        Ok(())
+    }
+
+    // Check the hash and the signature once every chunk is in
+    pub fn finalize(&mut self, image_id: &str) -> Result<FirmwareImage, String> {
+        let image = self.image(image_id)?.clone();
+        let data = self.data.get(image_id).ok_or("Image data missing")?;
+        let mut hasher = Sha256::new();
+        for (index, chunk) in data.chunks.iter().enumerate() {
+            hasher.update(chunk.as_ref().ok_or_else(|| format!("Chunk {} is missing", index))?);
+        }
+        let digest = hasher.finalize();
+        if hex(&digest) != image.sha256 {
+            return Err("Image does not match its SHA-256".to_string());
+        }
+        let signer = self
+            .signers
+            .get(&image.key_id)
+            .ok_or_else(|| format!("Unknown firmware signer {}", image.key_id))?;
+        let signature = Signature::from_slice(&image.signature).map_err(|_| "Invalid signature".to_string())?;
+        verifying_key(&signer.public_key)?
+            .verify_strict(&digest, &signature)
+            .map_err(|_| "Image signature does not match".to_string())?;
+        let image = self.images.get_mut(image_id).ok_or("Image not found")?;
+        image.status = ImageStatus::Ready;
+        Ok(image.clone())
+    }
+
+    pub fn chunk(&self, image_id: &str, index: u32) -> Result<Vec<u8>, String> {
+        if self.image(image_id)?.status != ImageStatus::Ready {
+            return Err("Image is not ready".to_string());
+        }
+        self.data
+            .get(image_id)
+            .and_then(|d| d.chunks.get(index as usize).cloned().flatten())
+            .ok_or_else(|| format!("Chunk {} not found", index))
+    }
+
+    pub fn delete_image(&mut self, image_id: &str) -> Result<(), String> {
+        self.image(image_id)?;
+        let in_use = self.campaigns.values().any(|c| {
+            c.image_id == image_id && matches!(c.status, CampaignStatus::Draft | CampaignStatus::Running | CampaignStatus::Paused)
+        });
+        if in_use {
+            return Err("Image is used by an unfinished campaign".to_string());
+        }
+        self.images.remove(image_id);
+        self.data.remove(image_id);
+        Ok(())
+    }
+
+    fn image(&self, image_id: &str) -> Result<&FirmwareImage, String> {
+        self.images.get(image_id).ok_or_else(|| format!("Image {} not found", image_id))
+    }
+
+    // An image the scope can roll out; images of other tenants look missing
+    pub fn image_in(&self, scope: &TenantScope, image_id: &str) -> Result<&FirmwareImage, String> {
+        self.image(image_id)
+            .ok()
+            .filter(|image| image.visible_to(scope))
+            .ok_or_else(|| format!("Image {} not found", image_id))
+    }
+
+    // An image the scope uploaded and may change; shared images belong to
+    // tenant admins
+    pub fn own_image(&self, scope: &TenantScope, image_id: &str) -> Result<&FirmwareImage, String> {
+        self.image(image_id)
+            .ok()
+            .filter(|image| scope.allows_tenant(image.tenant_id.as_deref()))
+            .ok_or_else(|| format!("Image {} not found", image_id))
+    }
+
+    pub fn campaign_in(&self, scope: &TenantScope, id: u64) -> Result<&Campaign, String> {
+        self.campaigns
+            .get(&id)
+            .filter(|c| scope.allows_tenant(c.tenant_id.as_deref()))
+            .ok_or_else(|| format!("Campaign {} not found", id))
+    }
+
+    pub fn campaign_mut(&mut self, id: u64) -> Result<&mut Campaign, String> {
+        self.campaigns.get_mut(&id).ok_or_else(|| format!("Campaign {} not found", id))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_campaign(
+        &mut self,
+        tenant_id: Option<String>,
+        name: &str,
+        image_id: &str,
+        target: CampaignTarget,
+        stages: Vec<u8>,
+        failure_threshold: f64,
+        created_by: &str,
+        now: u64,
+    ) -> Result<Campaign, String> {
+        let scope = match &tenant_id {
+            Some(tenant_id) => TenantScope::Tenant(tenant_id.clone()),
+            None => TenantScope::All,
+        };
+        if self.image_in(&scope, image_id)?.status != ImageStatus::Ready {
+            return Err("Image is not ready".to_string());
+        }
+        if stages.is_empty() || stages.len() > MAX_STAGES || stages.last() != Some(&100) {
+            return Err(format!("Give 1 to {} stages ending at 100 %", MAX_STAGES));
+        }
+        if stages.windows(2).any(|w| w[0] >= w[1]) || stages[0] == 0 {
+            return Err("Stage percentages must increase".to_string());
+        }
+        if !(0.0..=100.0).contains(&failure_threshold) {
+            return Err("Failure threshold must be 0-100 %".to_string());
+        }
+        if let Some(selector) = target.selector.as_deref() {
+            Selector::parse(selector)?;
+        }
+        self.next_campaign_id += 1;
+        let campaign = Campaign {
+            id: self.next_campaign_id,
+            tenant_id,
+            name: name.trim().to_string(),
+            image_id: image_id.to_string(),
+            target,
+            stages,
+            stage: 0,
+            failure_threshold,
+            status: CampaignStatus::Draft,
+            paused_reason: None,
+            targets: Vec::new(),
+            assignments: BTreeMap::new(),
+            rollback_of: None,
+            created_by: created_by.to_string(),
+            created_at: now,
+        };
+        self.campaigns.insert(campaign.id, campaign.clone());
+        Ok(campaign)
+    }
+
+    // Fix the targets: active devices of the image's type that match, in
+    // the campaign's tenant
+    pub fn start(&mut self, id: u64, registry: &Registry, fleet: &Fleet) -> Result<Campaign, String> {
+        let device_type = {
+            let campaign = self.campaigns.get(&id).ok_or_else(|| format!("Campaign {} not found", id))?;
+            if campaign.status != CampaignStatus::Draft {
+                return Err("Only draft campaigns can be started".to_string());
+            }
+            self.image(&campaign.image_id)?.device_type.clone()
+        };
+        let campaign = self.campaign_mut(id)?;
+        let scope = campaign.scope();
+        let target = &campaign.target;
+        let selector = target.selector.as_deref().map(Selector::parse).transpose()?;
+        let mut targets: Vec<String> = registry
+            .devices
+            .values()
+            .filter(|d| scope.allows(d))
+            .filter(|d| d.state == DeviceState::Active && d.device_type == device_type)
+            .filter(|d| target.device_ids.is_empty() || target.device_ids.contains(&d.id))
+            .filter(|d| target.firmware_version.as_ref().map_or(true, |v| *v == d.firmware_version))
+            .filter(|d| selector.as_ref().map_or(true, |s| s.matches(&fleet.labels(d))))
+            .map(|d| d.id.clone())
+            .collect();
+        if targets.is_empty() {
+            return Err("No active device matches the campaign".to_string());
+        }
+        targets.sort_by_cached_key(|device_id| rollout_key(id, device_id));
+        campaign.targets = targets;
+        campaign.status = CampaignStatus::Running;
+        Ok(campaign.clone())
+    }
+
+    // Open the next stage once every admitted device has finished
+    pub fn advance(&mut self, id: u64) -> Result<Campaign, String> {
+        let campaign = self.campaign_mut(id)?;
+        if campaign.status != CampaignStatus::Running {
+            return Err("Campaign is not running".to_string());
+        }
+        if !campaign.stage_finished() {
+            return Err("Devices of the current stage are still updating".to_string());
+        }
+        if campaign.stage as usize + 1 >= campaign.stages.len() {
+            return Err("Campaign is already at its last stage".to_string());
+        }
+        campaign.stage += 1;
+        Ok(campaign.clone())
+    }
+
+    pub fn set_paused(&mut self, id: u64, paused: bool, reason: Option<String>) -> Result<Campaign, String> {
+        let campaign = self.campaign_mut(id)?;
+        match (campaign.status, paused) {
+            (CampaignStatus::Running, true) => campaign.status = CampaignStatus::Paused,
+            (CampaignStatus::Paused, false) => campaign.status = CampaignStatus::Running,
+            _ => return Err(format!("Campaign is {:?}", campaign.status)),
+        }
+        campaign.paused_reason = if paused { reason } else { None };
+        Ok(campaign.clone())
+    }
+
+    pub fn cancel(&mut self, id: u64) -> Result<Campaign, String> {
+        let campaign = self.campaign_mut(id)?;
+        if !matches!(campaign.status, CampaignStatus::Draft | CampaignStatus::Running | CampaignStatus::Paused) {
+            return Err(format!("Campaign is {:?}", campaign.status));
+        }
+        campaign.status = CampaignStatus::Cancelled;
+        Ok(campaign.clone())
+    }
+
+    // The update a device should install now, if any. The newest running
+    // campaign wins, so a rollback overrides the campaign it undoes.
+    pub fn poll(&mut self, device_id: &str, current_version: &str, now: u64) -> Option<FirmwareAssignment> {
+        let campaign = self.campaigns.values_mut().rev().find(|c| {
+            c.status == CampaignStatus::Running
+                && c.admitted().iter().any(|id| id == device_id)
+                && c.assignments.get(device_id).map_or(true, |a| !a.status.finished())
+        })?;
+        campaign.assignments.entry(device_id.to_string()).or_insert_with(|| Assignment {
+            device_id: device_id.to_string(),
+            status: UpdateStatus::Assigned,
+            previous_version: current_version.to_string(),
+            error: None,
+            updated_at: now,
+        });
+        let campaign_id = campaign.id;
+        let image_id = campaign.image_id.clone();
+        self.images.get(&image_id).map(|image| image.assignment(campaign_id))
+    }
+
+    // Record a device's progress, and pause the campaign when too many
+    // devices failed
+    pub fn report(
+        &mut self,
+        campaign_id: u64,
+        device_id: &str,
+        status: UpdateStatus,
+        error: Option<String>,
+        now: u64,
+    ) -> Result<ReportOutcome, String> {
+        if status == UpdateStatus::Assigned {
+            return Err("Report Downloading, Installing, Succeeded or Failed".to_string());
+        }
+        let version = self
+            .campaigns
+            .get(&campaign_id)
+            .and_then(|c| self.images.get(&c.image_id))
+            .map(|image| image.version.clone());
+        let campaign = self.campaign_mut(campaign_id)?;
+        let assignment = campaign
+            .assignments
+            .get_mut(device_id)
+            .ok_or("Device has no update in this campaign")?;
+        if assignment.status.finished() {
+            return Err("Update already finished".to_string());
+        }
+        assignment.status = status;
+        assignment.error = error;
+        assignment.updated_at = now;
+
+        let mut outcome = ReportOutcome::default();
+        if status == UpdateStatus::Succeeded {
+            outcome.installed = version;
+        }
+        if campaign.status == CampaignStatus::Running && campaign.failure_rate() > campaign.failure_threshold {
+            let reason = format!(
+                "{:.1} % of devices failed, above the {:.1} % threshold",
+                campaign.failure_rate(),
+                campaign.failure_threshold
+            );
+            campaign.status = CampaignStatus::Paused;
+            campaign.paused_reason = Some(reason.clone());
+            outcome.paused = Some(reason);
+        } else if campaign.status == CampaignStatus::Running
+            && campaign.stage as usize + 1 == campaign.stages.len()
+            && campaign.stage_finished()
+        {
+            campaign.status = CampaignStatus::Completed;
+        }
+        Ok(outcome)
+    }
+
+    // Undo a campaign: every device it updated gets the version it had
+    // before, through one new campaign per previous version. Fails if one of
+    // those versions has no ready image.
+    pub fn rollback(&mut self, id: u64, created_by: &str, now: u64) -> Result<Vec<Campaign>, String> {
+        let campaign = self.campaigns.get(&id).ok_or_else(|| format!("Campaign {} not found", id))?.clone();
+        if matches!(campaign.status, CampaignStatus::Draft | CampaignStatus::RolledBack) {
+            return Err(format!("Campaign is {:?}", campaign.status));
+        }
+        let device_type = self.image(&campaign.image_id)?.device_type.clone();
+        let mut by_version: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for assignment in campaign.assignments.values().filter(|a| a.status == UpdateStatus::Succeeded) {
+            by_version
+                .entry(assignment.previous_version.clone())
+                .or_default()
+                .push(assignment.device_id.clone());
+        }
+        let mut plans = Vec::new();
+        for (version, devices) in by_version {
+            let image = self
+                .images
+                .values()
+                .find(|i| {
+                    i.visible_to(&campaign.scope())
+                        && i.device_type == device_type
+                        && i.version == version
+                        && i.status == ImageStatus::Ready
+                })
+                .ok_or_else(|| format!("No ready image of {} {} to roll back to", device_type, version))?;
+            plans.push((image.id.clone(), version, devices));
+        }
+
+        let mut created = Vec::new();
+        for (image_id, version, devices) in plans {
+            self.next_campaign_id += 1;
+            let mut rollback = Campaign {
+                id: self.next_campaign_id,
+                tenant_id: campaign.tenant_id.clone(),
+                name: format!("Rollback of {} to {}", campaign.name, version),
+                image_id,
+                target: CampaignTarget { device_ids: devices.clone(), firmware_version: None, selector: None },
+                stages: vec![100],
+                stage: 0,
+                failure_threshold: 100.0,
+                status: CampaignStatus::Running,
+                paused_reason: None,
+                targets: devices,
+                assignments: BTreeMap::new(),
+                rollback_of: Some(id),
+                created_by: created_by.to_string(),
+                created_at: now,
+            };
+            rollback.targets.sort();
+            self.campaigns.insert(rollback.id, rollback.clone());
+            created.push(rollback);
+        }
+        let original = self.campaign_mut(id)?;
+        original.status = CampaignStatus::RolledBack;
+        Ok(created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(id: &str, version: &str) -> FirmwareImage {
+        FirmwareImage {
+            id: id.to_string(),
+            tenant_id: None,
+            device_type: "water".to_string(),
+            version: version.to_string(),
+            size: 1,
+            sha256: "00".repeat(32),
+            key_id: "factory".to_string(),
+            signature: Vec::new(),
+            status: ImageStatus::Ready,
+            uploaded_by: "admin".to_string(),
+            created_at: 0,
+        }
+    }
+
+    // `count` active water meters on 1.0.0, with images for 1.0.0 and 2.0.0
+    fn fleet(count: usize) -> (Ota, Registry) {
+        let mut registry = Registry::default();
+        for i in 0..count {
+            let id = format!("m-{}", i);
+            registry.register(&id, "water", None, String::new(), None, 0).unwrap();
+            registry.transition(&id, DeviceState::Active, "admin", None, 0).unwrap();
+        }
+        registry.register("gas-1", "gas", None, String::new(), None, 0).unwrap();
+        registry.transition("gas-1", DeviceState::Active, "admin", None, 0).unwrap();
+        registry.register("idle", "water", None, String::new(), None, 0).unwrap();
+
+        let mut ota = Ota::default();
+        for image in [image("fw-1", "1.0.0"), image("fw-2", "2.0.0")] {
+            ota.images.insert(image.id.clone(), image);
+        }
+        (ota, registry)
+    }
+
+    fn started(ota: &mut Ota, registry: &Registry, stages: Vec<u8>, threshold: f64) -> Campaign {
+        let id = ota
+            .create_campaign(None, "v2", "fw-2", CampaignTarget::default(), stages, threshold, "admin", 0)
+            .unwrap()
+            .id;
+        ota.start(id, registry, &Fleet::default()).unwrap()
+    }
+
+    fn update(ota: &mut Ota, id: u64, device_id: &str, status: UpdateStatus) -> ReportOutcome {
+        assert!(ota.poll(device_id, "1.0.0", 1).is_some(), "{} was not admitted", device_id);
+        ota.report(id, device_id, status, None, 2).unwrap()
+    }
+
+    #[test]
+    fn start_targets_active_devices_of_the_image_type() {
+        let (mut ota, registry) = fleet(4);
+        let campaign = started(&mut ota, &registry, vec![100], 0.0);
+        let mut targets = campaign.targets.clone();
+        targets.sort();
+        assert_eq!(targets, vec!["m-0", "m-1", "m-2", "m-3"]);
+        assert_eq!(campaign.status, CampaignStatus::Running);
+    }
+
+    #[test]
+    fn stages_open_once_admitted_devices_finish() {
+        let (mut ota, registry) = fleet(10);
+        let campaign = started(&mut ota, &registry, vec![10, 50, 100], 100.0);
+        let id = campaign.id;
+        let first = campaign.targets[0].clone();
+        let later = campaign.targets[1].clone();
+
+        assert!(ota.poll(&later, "1.0.0", 1).is_none());
+        assert!(ota.advance(id).is_err());
+        update(&mut ota, id, &first, UpdateStatus::Installing);
+        assert!(ota.advance(id).is_err());
+        let outcome = ota.report(id, &first, UpdateStatus::Succeeded, None, 3).unwrap();
+        assert_eq!(outcome.installed.as_deref(), Some("2.0.0"));
+
+        let campaign = ota.advance(id).unwrap();
+        assert_eq!(campaign.stage, 1);
+        assert_eq!(campaign.admitted().len(), 5);
+        assert!(ota.poll(&later, "1.0.0", 4).is_some());
+    }
+
+    #[test]
+    fn last_stage_completes_the_campaign() {
+        let (mut ota, registry) = fleet(2);
+        let campaign = started(&mut ota, &registry, vec![100], 50.0);
+        for device_id in &campaign.targets {
+            update(&mut ota, campaign.id, device_id, UpdateStatus::Succeeded);
+        }
+        assert_eq!(ota.campaigns[&campaign.id].status, CampaignStatus::Completed);
+        assert!(ota.advance(campaign.id).is_err());
+    }
+
+    #[test]
+    fn campaign_pauses_above_the_failure_threshold() {
+        let (mut ota, registry) = fleet(5);
+        let campaign = started(&mut ota, &registry, vec![100], 20.0);
+        let id = campaign.id;
+
+        // One of five is exactly at the threshold, which is allowed
+        let outcome = update(&mut ota, id, &campaign.targets[0], UpdateStatus::Failed);
+        assert!(outcome.paused.is_none());
+        assert_eq!(ota.campaigns[&id].status, CampaignStatus::Running);
+
+        let outcome = update(&mut ota, id, &campaign.targets[1], UpdateStatus::Failed);
+        assert!(outcome.paused.is_some());
+        assert_eq!(ota.campaigns[&id].status, CampaignStatus::Paused);
+        assert!(ota.poll(&campaign.targets[2], "1.0.0", 3).is_none());
+
+        let resumed = ota.set_paused(id, false, None).unwrap();
+        assert_eq!(resumed.status, CampaignStatus::Running);
+        assert!(resumed.paused_reason.is_none());
+    }
+
+    #[test]
+    fn rollback_targets_updated_devices_per_previous_version() {
+        let (mut ota, registry) = fleet(3);
+        ota.images.insert("fw-0".to_string(), image("fw-0", "0.9.0"));
+        let campaign = started(&mut ota, &registry, vec![100], 100.0);
+        let id = campaign.id;
+        let [a, b, c] = [&campaign.targets[0], &campaign.targets[1], &campaign.targets[2]];
+
+        update(&mut ota, id, a, UpdateStatus::Succeeded);
+        assert!(ota.poll(b, "0.9.0", 1).is_some());
+        ota.report(id, b, UpdateStatus::Succeeded, None, 2).unwrap();
+        update(&mut ota, id, c, UpdateStatus::Failed);
+
+        let rollbacks = ota.rollback(id, "admin", 5).unwrap();
+        let plans: Vec<(&str, Vec<String>)> =
+            rollbacks.iter().map(|r| (r.image_id.as_str(), r.targets.clone())).collect();
+        assert_eq!(plans, vec![("fw-0", vec![b.clone()]), ("fw-1", vec![a.clone()])]);
+        assert!(rollbacks.iter().all(|r| r.rollback_of == Some(id) && r.status == CampaignStatus::Running));
+        assert_eq!(ota.campaigns[&id].status, CampaignStatus::RolledBack);
+
+        // The rollback is newer, so it is what the device gets next
+        assert_eq!(ota.poll(a, "2.0.0", 6).unwrap().version, "1.0.0");
+        assert!(ota.rollback(id, "admin", 7).is_err());
+    }
+
+    #[test]
+    fn rollback_needs_an_image_of_the_previous_version() {
+        let (mut ota, registry) = fleet(1);
+        ota.images.remove("fw-1");
+        let campaign = started(&mut ota, &registry, vec![100], 100.0);
+        update(&mut ota, campaign.id, &campaign.targets[0], UpdateStatus::Succeeded);
+        assert!(ota.rollback(campaign.id, "admin", 5).is_err());
+        assert_eq!(ota.campaigns[&campaign.id].status, CampaignStatus::Completed);
+    }
+
+    #[test]
+    fn tenant_campaigns_stay_within_their_tenant() {
+        let (mut ota, mut registry) = fleet(2);
+        registry.assign_tenant("m-0", "acme").unwrap();
+        registry.assign_tenant("m-1", "globex").unwrap();
+        let mut private = image("fw-3", "3.0.0");
+        private.tenant_id = Some("globex".to_string());
+        ota.images.insert(private.id.clone(), private);
+
+        let acme = TenantScope::Tenant("acme".to_string());
+        let create = |ota: &mut Ota, image_id: &str| {
+            ota.create_campaign(Some("acme".into()), "v", image_id, CampaignTarget::default(), vec![100], 0.0, "admin", 0)
+        };
+        assert_eq!(create(&mut ota, "fw-3").unwrap_err(), "Image fw-3 not found");
+        let id = create(&mut ota, "fw-2").unwrap().id;
+        let campaign = ota.start(id, &registry, &Fleet::default()).unwrap();
+        assert_eq!(campaign.targets, vec!["m-0"]);
+
+        assert!(ota.campaign_in(&acme, id).is_ok());
+        assert!(ota.campaign_in(&TenantScope::Tenant("globex".into()), id).is_err());
+        assert!(ota.campaign_in(&TenantScope::All, id).is_ok());
+        assert!(ota.image_in(&acme, "fw-2").is_ok());
+        assert!(ota.own_image(&acme, "fw-2").is_err());
+        assert!(ota.image_in(&acme, "fw-3").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub const DEFAULT_TTL: u64 = 24 * 3600; // Seconds
+pub const MAX_TTL: u64 = 7 * 24 * 3600;
+
+// Crockford base32: no I, L, O or U, so codes read back unambiguously
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const CODE_LENGTH: usize = 10; // 50 bits
+
+// An unredeemed code. Only a hash of the code is stored.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Pairing {
+    pub device_id: String,
+    pub created_by: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+// Returned once by `create_pairing_code`, e.g. to print on the meter's label
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PairingCode {
+    pub code: String,
+    pub device_id: String,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Pairings {
+    pub codes: BTreeMap<String, Pairing>, // hex sha256 of the normalized code -> pairing
+}
+
+// Ten characters in two groups, e.g. "7KQ4M-2XD9P"
+fn format_code(random: &[u8]) -> String {
+    let mut bits = random.iter().take(8).fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    let mut code = String::with_capacity(CODE_LENGTH + 1);
+    for i in 0..CODE_LENGTH {
+        if i == CODE_LENGTH / 2 {
+            code.push('-');
+        }
+        code.push(ALPHABET[(bits & 31) as usize] as char);
+        bits >>= 5;
+    }
+    code
+}
+
+// Accept codes typed with lowercase letters, spaces, dashes or look-alikes
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        })
+        .collect()
+}
+
+fn hash(code: &str) -> String {
+    Sha256::digest(normalize(code).as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Pairings {
+    fn drop_expired(&mut self, now: u64) {
+        self.codes.retain(|_, pairing| pairing.expires_at > now);
+    }
+
+    // A new code replaces any earlier code of the same device
+    pub fn issue(&mut self, device_id: &str, created_by: &str, random: &[u8], ttl: u64, now: u64) -> PairingCode {
+        self.drop_expired(now);
+        self.codes.retain(|_, pairing| pairing.device_id != device_id);
+        let code = format_code(random);
+        let pairing = Pairing {
+            device_id: device_id.to_string(),
+            created_by: created_by.to_string(),
+            created_at: now,
+            expires_at: now + ttl,
+        };
+        self.codes.insert(hash(&code), pairing.clone());
+        PairingCode { code, device_id: pairing.device_id, expires_at: pairing.expires_at }
+    }
+
+    // Take a code out of the store; each code works once
+    pub fn redeem(&mut self, code: &str, now: u64) -> Result<Pairing, String> {
+        self.drop_expired(now);
+        self.codes
+            .remove(&hash(code))
+            .ok_or_else(|| "Invalid or expired pairing code".to_string())
+    }
+
+    // Put a redeemed code back after the claim failed, unless the device got
+    // a new code in the meantime
+    pub fn restore(&mut self, code: &str, pairing: Pairing) {
+        if !self.codes.values().any(|p| p.device_id == pairing.device_id) {
+            self.codes.insert(hash(code), pairing);
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use candid::CandidType;
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::registry::{self, Registry};
+
+pub const MAX_ROWS: usize = 500;
+const KEY_LENGTH: usize = 32;
+
+// A factory or installer key trusted to sign manifests
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestSigner {
+    pub key_id: String,
+    pub name: String,
+    pub public_key: Vec<u8>, // Ed25519, 32 bytes
+    pub added_at: u64,
+}
+
+// A manifest as uploaded: the JSON text exactly as signed
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SignedManifest {
+    pub key_id: String,
+    pub manifest: String,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    pub manifest_id: String,
+    pub issued_at: u64,
+    pub location_id: Option<String>, // Site, building or unit in icutil_backend
+    pub devices: Vec<ManifestDevice>,
+}
+
+// One row. Give at most one of `hmac_key` and `public_key`, hex encoded;
+// without either a random HMAC key is generated.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestDevice {
+    pub device_id: String,
+    pub device_type: String,
+    pub serial: String,
+    pub config: Option<String>,
+    pub location_id: Option<String>, // Overrides the manifest's location
+    pub hmac_key: Option<String>,
+    pub public_key: Option<String>,
+}
+
+// Credentials a device signs its payloads with
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceAuth {
+    pub hmac_key: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>, // Ed25519
+    pub key_version: u32,
+    pub issued_at: u64,
+}
+
+// A new HMAC key; shown once, like generated keys in provisioning reports
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RotatedKey {
+    pub device_id: String,
+    pub key_version: u32,
+    pub hmac_key: String, // Hex
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RowStatus {
+    Created,
+    Valid, // Dry run: would be created
+    Failed,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RowResult {
+    pub row: u32,
+    pub device_id: String,
+    pub status: RowStatus,
+    pub error: Option<String>,
+    pub generated_key: Option<String>, // Hex; shown once, only when generated
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ProvisioningReport {
+    pub manifest_id: String,
+    pub dry_run: bool,
+    pub created: u32,
+    pub failed: u32,
+    pub rows: Vec<RowResult>,
+}
+
+// A manifest that was applied, kept so it cannot be applied twice
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestRecord {
+    pub manifest_id: String,
+    pub key_id: String,
+    pub applied_by: String,
+    pub applied_at: u64,
+    pub created: u32,
+    pub failed: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Provisioning {
+    pub signers: BTreeMap<String, ManifestSigner>,
+    pub manifests: BTreeMap<String, ManifestRecord>,
+    pub credentials: BTreeMap<String, DeviceAuth>, // device id -> credentials
+}
+
+// A row that passed validation, ready to register
+#[derive(Clone, Debug)]
+pub struct PlannedDevice {
+    pub row: u32,
+    pub device: ManifestDevice,
+    pub location_id: Option<String>,
+    pub auth: DeviceAuth,
+    pub generated: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn key_bytes(text: &str, what: &str) -> Result<Vec<u8>, String> {
+    unhex(text)
+        .filter(|bytes| bytes.len() == KEY_LENGTH)
+        .ok_or_else(|| format!("{} must be {} hex-encoded bytes", what, KEY_LENGTH))
+}
+
+pub(crate) fn verifying_key(public_key: &[u8]) -> Result<VerifyingKey, String> {
+    let bytes: [u8; KEY_LENGTH] = public_key
+        .try_into()
+        .map_err(|_| format!("Public key must be {} bytes", KEY_LENGTH))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| "Invalid Ed25519 public key".to_string())
+}
+
+// Per-device keys come from one random seed; the seed is never stored
+fn derive_key(seed: &[u8], device_id: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(device_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+impl Provisioning {
+    pub fn add_signer(&mut self, key_id: &str, name: &str, public_key: Vec<u8>, now: u64) -> Result<ManifestSigner, String> {
+        if key_id.is_empty() || key_id.len() > 64 {
+            return Err("Key id must be 1 to 64 characters".to_string());
+        }
+        verifying_key(&public_key)?;
+        let signer = ManifestSigner {
+            key_id: key_id.to_string(),
+            name: name.trim().to_string(),
+            public_key,
+            added_at: now,
+        };
+        self.signers.insert(key_id.to_string(), signer.clone());
+        Ok(signer)
+    }
+
+    // Replace a device's HMAC key with one derived from a fresh seed.
+    // Devices with a public key hold their own secret and rotate it
+    // themselves.
+    pub fn rotate(&mut self, device_id: &str, seed: &[u8], now: u64) -> Result<RotatedKey, String> {
+        let auth = self
+            .credentials
+            .get_mut(device_id)
+            .ok_or_else(|| "Device has no credentials".to_string())?;
+        if auth.hmac_key.is_none() {
+            return Err("Device authenticates with a public key".to_string());
+        }
+        let key = derive_key(seed, device_id);
+        let rotated = RotatedKey { device_id: device_id.to_string(), key_version: auth.key_version + 1, hmac_key: hex(&key) };
+        auth.hmac_key = Some(key);
+        auth.key_version = rotated.key_version;
+        auth.issued_at = now;
+        Ok(rotated)
+    }
+
+    // Check the signature over the exact bytes uploaded, then parse them
+    pub fn open(&self, signed: &SignedManifest) -> Result<Manifest, String> {
+        let signer = self
+            .signers
+            .get(&signed.key_id)
+            .ok_or_else(|| format!("Unknown manifest signer {}", signed.key_id))?;
+        let signature = Signature::from_slice(&signed.signature).map_err(|_| "Invalid signature".to_string())?;
+        verifying_key(&signer.public_key)?
+            .verify_strict(signed.manifest.as_bytes(), &signature)
+            .map_err(|_| "Manifest signature does not match".to_string())?;
+        let manifest: Manifest =
+            serde_json::from_str(&signed.manifest).map_err(|e| format!("Invalid manifest: {}", e))?;
+        if manifest.manifest_id.trim().is_empty() {
+            return Err("Manifest id is required".to_string());
+        }
+        if manifest.devices.is_empty() || manifest.devices.len() > MAX_ROWS {
+            return Err(format!("A manifest holds 1 to {} devices", MAX_ROWS));
+        }
+        if self.manifests.contains_key(&manifest.manifest_id) {
+            return Err(format!("Manifest {} was already applied", manifest.manifest_id));
+        }
+        Ok(manifest)
+    }
+
+    // Validate every row against the registry and the other rows. Rows that
+    // fail get a result right away; the rest come back as a plan.
+    pub fn plan(
+        &self,
+        manifest: &Manifest,
+        registry: &Registry,
+        seed: &[u8],
+        now: u64,
+    ) -> (Vec<PlannedDevice>, Vec<RowResult>) {
+        let mut serials: BTreeSet<&str> = registry.devices.values().filter_map(|d| d.serial.as_deref()).collect();
+        let mut ids = BTreeSet::new();
+        let mut planned = Vec::new();
+        let mut failed = Vec::new();
+        for (i, device) in manifest.devices.iter().enumerate() {
+            let row = i as u32 + 1;
+            let check = || -> Result<PlannedDevice, String> {
+                registry::validate_id(&device.device_id)?;
+                if device.device_type.trim().is_empty() {
+                    return Err("Device type is required".to_string());
+                }
+                if device.serial.trim().is_empty() {
+                    return Err("Serial number is required".to_string());
+                }
+                if registry.devices.contains_key(&device.device_id) || ids.contains(device.device_id.as_str()) {
+                    return Err("Device already exists".to_string());
+                }
+                if serials.contains(device.serial.trim()) {
+                    return Err(format!("Serial number {} is already in use", device.serial.trim()));
+                }
+                let (hmac_key, public_key, generated) = match (&device.hmac_key, &device.public_key) {
+                    (Some(_), Some(_)) => return Err("Give either an HMAC key or a public key, not both".to_string()),
+                    (Some(key), None) => (Some(key_bytes(key, "HMAC key")?), None, false),
+                    (None, Some(key)) => {
+                        let key = key_bytes(key, "Public key")?;
+                        verifying_key(&key)?;
+                        (None, Some(key), false)
+                    }
+                    (None, None) => (Some(derive_key(seed, &device.device_id)), None, true),
+                };
+                Ok(PlannedDevice {
+                    row,
+                    device: device.clone(),
+                    location_id: device.location_id.clone().or_else(|| manifest.location_id.clone()),
+                    auth: DeviceAuth { hmac_key, public_key, key_version: 1, issued_at: now },
+                    generated,
+                })
+            };
+            match check() {
+                Ok(plan) => {
+                    ids.insert(device.device_id.as_str());
+                    serials.insert(device.serial.trim());
+                    planned.push(plan);
+                }
+                Err(e) => failed.push(RowResult::failed(row, &device.device_id, e)),
+            }
+        }
+        (planned, failed)
+    }
+}
+
+impl PlannedDevice {
+    pub fn result(&self, status: RowStatus) -> RowResult {
+        let generated_key = match (status, self.generated) {
+            (RowStatus::Created, true) => self.auth.hmac_key.as_deref().map(hex),
+            _ => None,
+        };
+        RowResult { row: self.row, device_id: self.device.device_id.clone(), status, error: None, generated_key }
+    }
+}
+
+impl RowResult {
+    pub fn failed(row: u32, device_id: &str, error: String) -> Self {
+        RowResult { row, device_id: device_id.to_string(), status: RowStatus::Failed, error: Some(error), generated_key: None }
+    }
+}
+
+impl ProvisioningReport {
+    pub fn new(manifest_id: &str, dry_run: bool, mut rows: Vec<RowResult>) -> Self {
+        rows.sort_by_key(|r| r.row);
+        let failed = rows.iter().filter(|r| r.status == RowStatus::Failed).count() as u32;
+        let created = rows.iter().filter(|r| r.status == RowStatus::Created).count() as u32;
+        ProvisioningReport { manifest_id: manifest_id.to_string(), dry_run, created, failed, rows }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; KEY_LENGTH])
+    }
+
+    fn provisioning() -> Provisioning {
+        let mut provisioning = Provisioning::default();
+        provisioning.add_signer("factory", "Factory", signing_key().verifying_key().to_bytes().to_vec(), 0).unwrap();
+        provisioning
+    }
+
+    fn manifest(id: &str, rows: &[(&str, &str)]) -> String {
+        let devices: Vec<String> = rows
+            .iter()
+            .map(|(device_id, serial)| {
+                format!(r#"{{"device_id":"{}","device_type":"water_meter","serial":"{}"}}"#, device_id, serial)
+            })
+            .collect();
+        format!(r#"{{"manifest_id":"{}","issued_at":0,"devices":[{}]}}"#, id, devices.join(","))
+    }
+
+    fn sign(manifest: String) -> SignedManifest {
+        let signature = signing_key().sign(manifest.as_bytes()).to_bytes().to_vec();
+        SignedManifest { key_id: "factory".into(), manifest, signature }
+    }
+
+    #[test]
+    fn manifests_must_carry_a_valid_signature() {
+        let provisioning = provisioning();
+        let signed = sign(manifest("m-1", &[("d-1", "SN-1")]));
+        assert_eq!(provisioning.open(&signed).unwrap().devices.len(), 1);
+
+        let mut tampered = signed.clone();
+        tampered.manifest = tampered.manifest.replace("SN-1", "SN-2");
+        assert_eq!(provisioning.open(&tampered).unwrap_err(), "Manifest signature does not match");
+
+        let mut truncated = signed.clone();
+        truncated.signature.pop();
+        assert_eq!(provisioning.open(&truncated).unwrap_err(), "Invalid signature");
+
+        let mut unknown = signed;
+        unknown.key_id = "installer".into();
+        assert_eq!(provisioning.open(&unknown).unwrap_err(), "Unknown manifest signer installer");
+    }
+
+    #[test]
+    fn a_manifest_is_applied_only_once() {
+        let mut provisioning = provisioning();
+        let signed = sign(manifest("m-1", &[("d-1", "SN-1")]));
+        provisioning.manifests.insert(
+            "m-1".into(),
+            ManifestRecord {
+                manifest_id: "m-1".into(),
+                key_id: "factory".into(),
+                applied_by: "installer".into(),
+                applied_at: 0,
+                created: 1,
+                failed: 0,
+            },
+        );
+        assert_eq!(provisioning.open(&signed).unwrap_err(), "Manifest m-1 was already applied");
+        assert!(provisioning.open(&sign(manifest("m-2", &[("d-1", "SN-1")]))).is_ok());
+        assert!(provisioning.open(&sign(manifest("m-3", &[]))).is_err());
+    }
+
+    #[test]
+    fn duplicate_ids_and_serials_fail_their_row_only() {
+        let provisioning = provisioning();
+        let mut registry = Registry::default();
+        registry.register("d-0", "water_meter", Some("SN-0".into()), String::new(), None, 0).unwrap();
+        let manifest = provisioning
+            .open(&sign(manifest(
+                "m-1",
+                &[("d-1", "SN-1"), ("d-2", "SN-1"), ("d-3", " SN-0 "), ("d-1", "SN-4"), ("d-0", "SN-5"), ("d-6", "SN-6")],
+            )))
+            .unwrap();
+        let (planned, failed) = provisioning.plan(&manifest, &registry, &[1; 32], 0);
+
+        let ids: Vec<&str> = planned.iter().map(|p| p.device.device_id.as_str()).collect();
+        assert_eq!(ids, ["d-1", "d-6"]);
+        assert!(planned.iter().all(|p| p.generated && p.auth.hmac_key.is_some()));
+        let errors: Vec<(u32, &str)> = failed.iter().map(|r| (r.row, r.error.as_deref().unwrap())).collect();
+        assert_eq!(
+            errors,
+            [
+                (2, "Serial number SN-1 is already in use"),
+                (3, "Serial number SN-0 is already in use"),
+                (4, "Device already exists"),
+                (5, "Device already exists"),
+            ]
+        );
+    }
+}
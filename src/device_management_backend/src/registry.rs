@@ -0,0 +1,301 @@
+use candid::{CandidType, Principal};
+use icutil_common::lifecycle::DeviceState;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const MAX_HISTORY: usize = 50; // State changes kept per device
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StateChange {
+    pub from: DeviceState,
+    pub to: DeviceState,
+    pub at: u64, // Seconds
+    pub by: String,
+    pub reason: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Device {
+    pub id: String,
+    pub tenant_id: Option<String>, // None until assigned, see `TenantScope`
+    pub device_type: String,
+    pub serial: Option<String>, // Manufacturer serial number, unique when set
+    pub config: String,
+    pub firmware_version: String,
+    pub state: DeviceState,
+    pub state_changed_at: u64,
+    pub last_heartbeat: Option<u64>, // None until the device reports in
+    pub registered_at: u64,
+    pub owner: Option<Principal>, // Set when a resident claims the device
+    pub history: Vec<StateChange>, // Most recent last
+}
+
+// Leave a field empty to match every device
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DeviceFilter {
+    pub state: Option<DeviceState>,
+    pub device_type: Option<String>,
+    pub owner: Option<Principal>,
+    pub firmware_version: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Registry {
+    pub devices: BTreeMap<String, Device>,
+}
+
+// Tenant of each principal in this canister. Like roles, membership is kept
+// per canister and mirrors icutil_backend's.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Memberships {
+    pub members: BTreeMap<String, String>, // principal -> tenant_id
+}
+
+// Devices a call may touch. Controllers and `tenants:admin` see every
+// device, including unassigned ones; everyone else only their tenant's.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TenantScope {
+    All,
+    Tenant(String),
+}
+
+impl TenantScope {
+    pub fn allows(&self, device: &Device) -> bool {
+        match self {
+            TenantScope::All => true,
+            TenantScope::Tenant(tenant_id) => device.tenant_id.as_ref() == Some(tenant_id),
+        }
+    }
+
+    // Whether data stamped with `tenant_id` is in scope; only tenant admins
+    // see data without a tenant
+    pub fn allows_tenant(&self, tenant_id: Option<&str>) -> bool {
+        match self {
+            TenantScope::All => true,
+            TenantScope::Tenant(own) => tenant_id == Some(own.as_str()),
+        }
+    }
+
+    // Tenant new devices are registered in
+    pub fn tenant_id(&self) -> Option<String> {
+        match self {
+            TenantScope::All => None,
+            TenantScope::Tenant(tenant_id) => Some(tenant_id.clone()),
+        }
+    }
+}
+
+// Same rules as device ids in icutil_backend
+pub fn validate_id(device_id: &str) -> Result<(), String> {
+    if device_id.is_empty() || device_id.len() > 32 {
+        return Err("Device id must be 1 to 32 characters".to_string());
+    }
+    if !device_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Device id may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(())
+}
+
+impl DeviceFilter {
+    fn matches(&self, device: &Device) -> bool {
+        self.state.map_or(true, |s| device.state == s)
+            && self.device_type.as_ref().map_or(true, |t| *t == device.device_type)
+            && self.owner.map_or(true, |o| device.owner == Some(o))
+            && self.firmware_version.as_ref().map_or(true, |v| *v == device.firmware_version)
+    }
+}
+
+impl Registry {
+    pub fn get(&self, device_id: &str) -> Result<&Device, String> {
+        self.devices.get(device_id).ok_or_else(|| "Device not found".to_string())
+    }
+
+    pub fn get_mut(&mut self, device_id: &str) -> Result<&mut Device, String> {
+        self.devices.get_mut(device_id).ok_or_else(|| "Device not found".to_string())
+    }
+
+    // Devices of other tenants look the same as missing ones
+    pub fn get_in(&self, scope: &TenantScope, device_id: &str) -> Result<&Device, String> {
+        self.get(device_id)
+            .ok()
+            .filter(|device| scope.allows(device))
+            .ok_or_else(|| "Device not found".to_string())
+    }
+
+    // Devices never move between tenants, as in icutil_backend
+    pub fn assign_tenant(&mut self, device_id: &str, tenant_id: &str) -> Result<(), String> {
+        let device = self.get_mut(device_id)?;
+        match device.tenant_id.as_deref() {
+            Some(current) if current == tenant_id => Ok(()),
+            Some(_) => Err(format!("Device {} belongs to another tenant", device_id)),
+            None => {
+                device.tenant_id = Some(tenant_id.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    // New devices start out provisioned and send no readings until activated
+    pub fn register(
+        &mut self,
+        device_id: &str,
+        device_type: &str,
+        serial: Option<String>,
+        config: String,
+        tenant_id: Option<String>,
+        now: u64,
+    ) -> Result<Device, String> {
+        validate_id(device_id)?;
+        if device_type.trim().is_empty() {
+            return Err("Device type is required".to_string());
+        }
+        // Decommissioned ids stay in the registry so they are never reused
+        if self.devices.contains_key(device_id) {
+            return Err("Device already exists".to_string());
+        }
+        if let Some(serial) = serial.as_deref() {
+            if self.devices.values().any(|d| d.serial.as_deref() == Some(serial)) {
+                return Err(format!("Serial number {} is already in use", serial));
+            }
+        }
+        let device = Device {
+            id: device_id.to_string(),
+            tenant_id,
+            device_type: device_type.trim().to_string(),
+            serial,
+            config,
+            firmware_version: "1.0.0".to_string(),
+            state: DeviceState::Provisioned,
+            state_changed_at: now,
+            last_heartbeat: None,
+            registered_at: now,
+            owner: None,
+            history: Vec::new(),
+        };
+        self.devices.insert(device_id.to_string(), device.clone());
+        Ok(device)
+    }
+
+    pub fn transition(
+        &mut self,
+        device_id: &str,
+        to: DeviceState,
+        by: &str,
+        reason: Option<String>,
+        now: u64,
+    ) -> Result<Device, String> {
+        let device = self.get_mut(device_id)?;
+        device.state.check_transition(to)?;
+        device.history.push(StateChange { from: device.state, to, at: now, by: by.to_string(), reason });
+        if device.history.len() > MAX_HISTORY {
+            device.history.remove(0);
+        }
+        device.state = to;
+        device.state_changed_at = now;
+        Ok(device.clone())
+    }
+
+    // Undo the last transition after it could not be propagated
+    pub fn revert(&mut self, device_id: &str) {
+        if let Some(device) = self.devices.get_mut(device_id) {
+            if let Some(change) = device.history.pop() {
+                device.state = change.from;
+                device.state_changed_at = device.history.last().map_or(device.registered_at, |c| c.at);
+            }
+        }
+    }
+
+    pub fn heartbeat(&mut self, device_id: &str, now: u64) -> Result<DeviceState, String> {
+        let device = self.get_mut(device_id)?;
+        if device.state == DeviceState::Decommissioned {
+            return Err("Device is decommissioned".to_string());
+        }
+        device.last_heartbeat = Some(now);
+        Ok(device.state)
+    }
+
+    pub fn list(&self, scope: &TenantScope, filter: &DeviceFilter) -> Vec<(String, Device)> {
+        self.devices
+            .values()
+            .filter(|d| scope.allows(d) && filter.matches(d))
+            .map(|d| (d.id.clone(), d.clone()))
+            .collect()
+    }
+
+    pub fn count_by_state(&self) -> BTreeMap<DeviceState, usize> {
+        let mut counts = BTreeMap::new();
+        for device in self.devices.values() {
+            *counts.entry(device.state).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::default();
+        registry.register("m-1", "water_meter", Some("SN-1".into()), String::new(), Some("acme".into()), 10).unwrap();
+        registry
+    }
+
+    #[test]
+    fn only_lifecycle_transitions_are_allowed() {
+        let mut registry = registry();
+        assert!(registry.transition("m-1", DeviceState::Suspended, "op", None, 20).is_err());
+        let device = registry.transition("m-1", DeviceState::Active, "op", Some("installed".into()), 20).unwrap();
+        assert_eq!((device.state, device.state_changed_at), (DeviceState::Active, 20));
+        assert_eq!(device.history.len(), 1);
+        assert_eq!(device.history[0].from, DeviceState::Provisioned);
+        assert_eq!(device.history[0].reason.as_deref(), Some("installed"));
+
+        registry.transition("m-1", DeviceState::Decommissioned, "op", None, 30).unwrap();
+        assert!(registry.transition("m-1", DeviceState::Active, "op", None, 40).is_err());
+        assert!(registry.heartbeat("m-1", 40).is_err());
+        assert!(registry.transition("m-2", DeviceState::Active, "op", None, 40).is_err());
+    }
+
+    #[test]
+    fn revert_restores_the_previous_state_and_time() {
+        let mut registry = registry();
+        registry.transition("m-1", DeviceState::Active, "op", None, 20).unwrap();
+        registry.transition("m-1", DeviceState::Suspended, "op", None, 30).unwrap();
+        registry.revert("m-1");
+        let device = registry.get("m-1").unwrap();
+        assert_eq!((device.state, device.state_changed_at), (DeviceState::Active, 20));
+        registry.revert("m-1");
+        let device = registry.get("m-1").unwrap();
+        assert_eq!((device.state, device.state_changed_at), (DeviceState::Provisioned, 10));
+        assert!(device.history.is_empty());
+        // Nothing left to undo
+        registry.revert("m-1");
+        assert_eq!(registry.get("m-1").unwrap().state, DeviceState::Provisioned);
+    }
+
+    #[test]
+    fn history_keeps_the_latest_changes() {
+        let mut registry = registry();
+        registry.transition("m-1", DeviceState::Active, "op", None, 0).unwrap();
+        for i in 0..MAX_HISTORY as u64 {
+            registry.transition("m-1", DeviceState::Maintenance, "op", None, 2 * i + 1).unwrap();
+            registry.transition("m-1", DeviceState::Active, "op", None, 2 * i + 2).unwrap();
+        }
+        let device = registry.get("m-1").unwrap();
+        assert_eq!(device.history.len(), MAX_HISTORY);
+        assert_eq!(device.history.last().unwrap().at, 2 * MAX_HISTORY as u64);
+        assert_eq!(device.history[0].at, MAX_HISTORY as u64 + 1);
+    }
+
+    #[test]
+    fn ids_and_serials_are_never_reused() {
+        let mut registry = registry();
+        assert!(registry.register("m-1", "water_meter", None, String::new(), None, 0).is_err());
+        assert!(registry.register("m-2", "water_meter", Some("SN-1".into()), String::new(), None, 0).is_err());
+        assert!(registry.register("m 3", "water_meter", None, String::new(), None, 0).is_err());
+        assert!(registry.register("m-3", " ", None, String::new(), None, 0).is_err());
+        assert!(registry.assign_tenant("m-1", "globex").is_err());
+        assert!(registry.assign_tenant("m-1", "acme").is_ok());
+    }
+}
@@ -0,0 +1,286 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const MAX_HISTORY: usize = 20; // Desired versions kept per device
+const MAX_THRESHOLDS: usize = 32;
+const MAX_INTERVAL: u64 = 24 * 3600;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChannelThreshold {
+    pub channel: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+// Applied by the device to raw values: value * scale + offset
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CalibrationSetting {
+    pub scale: f64,
+    pub offset: f64,
+}
+
+// Settings a device runs with
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeviceConfig {
+    pub sampling_interval: u64, // Seconds between measurements
+    pub upload_interval: u64,   // Seconds between uploads
+    pub thresholds: Vec<ChannelThreshold>,
+    pub calibration: CalibrationSetting,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DesiredConfig {
+    pub version: u64,
+    pub config: DeviceConfig,
+    pub set_by: String,
+    pub set_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReportedConfig {
+    pub version: u64, // Desired version the device applied, 0 if none
+    pub config: DeviceConfig,
+    pub reported_at: u64,
+}
+
+// Desired and reported configuration of one device
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Twin {
+    pub desired: Option<DesiredConfig>,
+    pub reported: Option<ReportedConfig>,
+    pub history: Vec<DesiredConfig>, // Earlier desired versions, oldest first
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FieldDiff {
+    pub field: String,
+    pub desired: Option<String>,
+    pub reported: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigDrift {
+    pub device_id: String,
+    pub desired_version: Option<u64>,
+    pub reported_version: Option<u64>,
+    pub in_sync: bool,
+    pub differences: Vec<FieldDiff>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Twins {
+    pub twins: BTreeMap<String, Twin>,
+}
+
+impl DeviceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sampling_interval == 0 || self.sampling_interval > MAX_INTERVAL {
+            return Err(format!("Sampling interval must be 1 to {} seconds", MAX_INTERVAL));
+        }
+        if self.upload_interval < self.sampling_interval || self.upload_interval > MAX_INTERVAL {
+            return Err("Upload interval must be between the sampling interval and one day".into());
+        }
+        if self.thresholds.len() > MAX_THRESHOLDS {
+            return Err(format!("At most {} thresholds", MAX_THRESHOLDS));
+        }
+        for (i, threshold) in self.thresholds.iter().enumerate() {
+            if threshold.channel.trim().is_empty() {
+                return Err("Threshold channel is required".into());
+            }
+            if self.thresholds[..i].iter().any(|t| t.channel == threshold.channel) {
+                return Err(format!("Channel {} has two thresholds", threshold.channel));
+            }
+            let finite = threshold.min.map_or(true, f64::is_finite) && threshold.max.map_or(true, f64::is_finite);
+            if !finite || matches!((threshold.min, threshold.max), (Some(min), Some(max)) if min > max) {
+                return Err(format!("Threshold of {} must be finite with min <= max", threshold.channel));
+            }
+        }
+        let calibration = &self.calibration;
+        if !calibration.offset.is_finite() || !calibration.scale.is_finite() || calibration.scale == 0.0 {
+            return Err("Calibration needs a finite offset and a finite, non-zero scale".into());
+        }
+        Ok(())
+    }
+
+    // One entry per setting, so two configs can be compared field by field
+    fn fields(&self) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        fields.insert("sampling_interval".to_string(), self.sampling_interval.to_string());
+        fields.insert("upload_interval".to_string(), self.upload_interval.to_string());
+        fields.insert("calibration.scale".to_string(), self.calibration.scale.to_string());
+        fields.insert("calibration.offset".to_string(), self.calibration.offset.to_string());
+        for threshold in &self.thresholds {
+            let bound = |value: Option<f64>| value.map_or("none".to_string(), |v| v.to_string());
+            fields.insert(format!("thresholds.{}.min", threshold.channel), bound(threshold.min));
+            fields.insert(format!("thresholds.{}.max", threshold.channel), bound(threshold.max));
+        }
+        fields
+    }
+}
+
+fn diff(desired: &DeviceConfig, reported: &DeviceConfig) -> Vec<FieldDiff> {
+    let (desired, reported) = (desired.fields(), reported.fields());
+    let mut names: Vec<&String> = desired.keys().chain(reported.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| desired.get(*name) != reported.get(*name))
+        .map(|name| FieldDiff {
+            field: name.clone(),
+            desired: desired.get(name).cloned(),
+            reported: reported.get(name).cloned(),
+        })
+        .collect()
+}
+
+impl Twin {
+    // Desired config the device has not applied yet
+    pub fn pending(&self) -> Option<&DesiredConfig> {
+        let applied = self.reported.as_ref().map_or(0, |r| r.version);
+        self.desired.as_ref().filter(|d| d.version > applied)
+    }
+
+    pub fn drift(&self, device_id: &str) -> ConfigDrift {
+        let differences = match (&self.desired, &self.reported) {
+            (Some(desired), Some(reported)) => diff(&desired.config, &reported.config),
+            _ => Vec::new(),
+        };
+        ConfigDrift {
+            device_id: device_id.to_string(),
+            desired_version: self.desired.as_ref().map(|d| d.version),
+            reported_version: self.reported.as_ref().map(|r| r.version),
+            in_sync: self.desired.is_none() || (self.reported.is_some() && differences.is_empty()),
+            differences,
+        }
+    }
+}
+
+impl Twins {
+    // Store a new desired version. `expected_version` guards against
+    // overwriting a change made in the meantime.
+    pub fn set_desired(
+        &mut self,
+        device_id: &str,
+        config: DeviceConfig,
+        expected_version: Option<u64>,
+        set_by: &str,
+        now: u64,
+    ) -> Result<DesiredConfig, String> {
+        config.validate()?;
+        let twin = self.twins.entry(device_id.to_string()).or_default();
+        let current = twin.desired.as_ref().map_or(0, |d| d.version);
+        if let Some(expected) = expected_version {
+            if expected != current {
+                return Err(format!("Desired config is at version {}, not {}", current, expected));
+            }
+        }
+        let desired = DesiredConfig { version: current + 1, config, set_by: set_by.to_string(), set_at: now };
+        if let Some(previous) = twin.desired.replace(desired.clone()) {
+            twin.history.push(previous);
+            if twin.history.len() > MAX_HISTORY {
+                twin.history.remove(0);
+            }
+        }
+        Ok(desired)
+    }
+
+    // Make an earlier version the desired config again, as a new version
+    pub fn revert(&mut self, device_id: &str, version: u64, set_by: &str, now: u64) -> Result<DesiredConfig, String> {
+        let config = self
+            .twins
+            .get(device_id)
+            .and_then(|twin| twin.history.iter().find(|d| d.version == version))
+            .map(|d| d.config.clone())
+            .ok_or_else(|| format!("Config version {} not found", version))?;
+        self.set_desired(device_id, config, None, set_by, now)
+    }
+
+    pub fn report(&mut self, device_id: &str, version: u64, config: DeviceConfig, now: u64) -> Result<ConfigDrift, String> {
+        let twin = self.twins.entry(device_id.to_string()).or_default();
+        let desired = twin.desired.as_ref().map_or(0, |d| d.version);
+        if version > desired {
+            return Err(format!("Config version {} was never issued", version));
+        }
+        twin.reported = Some(ReportedConfig { version, config, reported_at: now });
+        Ok(twin.drift(device_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(sampling_interval: u64) -> DeviceConfig {
+        DeviceConfig {
+            sampling_interval,
+            upload_interval: 3600,
+            thresholds: vec![ChannelThreshold { channel: "flow".into(), min: None, max: Some(50.0) }],
+            calibration: CalibrationSetting { scale: 1.0, offset: 0.0 },
+        }
+    }
+
+    #[test]
+    fn each_change_is_a_new_version() {
+        let mut twins = Twins::default();
+        assert_eq!(twins.set_desired("m-1", config(60), None, "op", 1).unwrap().version, 1);
+        assert_eq!(twins.set_desired("m-1", config(120), Some(1), "op", 2).unwrap().version, 2);
+        let twin = &twins.twins["m-1"];
+        assert_eq!(twin.history.len(), 1);
+        assert_eq!(twin.history[0].config.sampling_interval, 60);
+        assert!(twins.set_desired("m-1", config(0), None, "op", 3).is_err());
+
+        for i in 0..MAX_HISTORY as u64 + 5 {
+            twins.set_desired("m-1", config(60 + i), None, "op", 4).unwrap();
+        }
+        let twin = &twins.twins["m-1"];
+        assert_eq!(twin.history.len(), MAX_HISTORY);
+        assert_eq!(twin.history.last().unwrap().version + 1, twin.desired.as_ref().unwrap().version);
+    }
+
+    #[test]
+    fn a_stale_expected_version_is_a_conflict() {
+        let mut twins = Twins::default();
+        assert!(twins.set_desired("m-1", config(60), Some(1), "op", 1).is_err());
+        twins.set_desired("m-1", config(60), Some(0), "op", 1).unwrap();
+        twins.set_desired("m-1", config(90), Some(1), "other", 2).unwrap();
+        assert_eq!(
+            twins.set_desired("m-1", config(120), Some(1), "op", 3).unwrap_err(),
+            "Desired config is at version 2, not 1"
+        );
+        assert_eq!(twins.twins["m-1"].desired.as_ref().unwrap().config.sampling_interval, 90);
+    }
+
+    #[test]
+    fn revert_reissues_an_earlier_version() {
+        let mut twins = Twins::default();
+        twins.set_desired("m-1", config(60), None, "op", 1).unwrap();
+        twins.set_desired("m-1", config(120), None, "op", 2).unwrap();
+        let reverted = twins.revert("m-1", 1, "op", 3).unwrap();
+        assert_eq!((reverted.version, reverted.config.sampling_interval), (3, 60));
+        assert!(twins.revert("m-1", 3, "op", 4).is_err());
+        assert!(twins.revert("m-2", 1, "op", 4).is_err());
+    }
+
+    #[test]
+    fn drift_compares_the_reported_config_field_by_field() {
+        let mut twins = Twins::default();
+        twins.set_desired("m-1", config(60), None, "op", 1).unwrap();
+        assert!(!twins.twins["m-1"].drift("m-1").in_sync);
+        assert_eq!(twins.twins["m-1"].pending().map(|d| d.version), Some(1));
+        assert!(twins.report("m-1", 2, config(60), 2).is_err());
+
+        let mut reported = config(60);
+        reported.thresholds[0].max = Some(40.0);
+        let drift = twins.report("m-1", 1, reported, 2).unwrap();
+        assert!(!drift.in_sync);
+        let fields: Vec<&str> = drift.differences.iter().map(|d| d.field.as_str()).collect();
+        assert_eq!(fields, ["thresholds.flow.max"]);
+        assert!(twins.twins["m-1"].pending().is_none());
+
+        let drift = twins.report("m-1", 1, config(60), 3).unwrap();
+        assert!(drift.in_sync && drift.differences.is_empty());
+        assert_eq!((drift.desired_version, drift.reported_version), (Some(1), Some(1)));
+    }
+}
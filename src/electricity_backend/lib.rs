@@ -2,10 +2,16 @@
 extern crate serde_derive;
 extern crate serde;
 
-use ic_cdk::export::candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize};
 use ic_cdk::storage;
 use ic_cdk_macros::*;
 use std::collections::HashMap;
+use candid::Principal;
+use icutil_common::access::{self, Permission, Role, RoleAssignments};
+use icutil_common::audit::{self, AuditEntry, AuditFilter, AuditHead, AuditLog};
+use icutil_common::http::{HttpRequest, HttpResponse};
+use icutil_common::metrics::{self, CallTimer, RuntimeStats};
+use icutil_common::pagination::{self, Order, Page, PageRequest};
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 struct ElectricityReading {
@@ -31,8 +37,9 @@ fn init() {
     storage::stable_save((data,)).unwrap();
 }
 
-#[update]
+#[update(guard = "can_write_readings")]
 fn add_electricity_reading(kwh: f64) -> Result<bool, SensorError> {
+    let _timer = CallTimer::start("add_electricity_reading", instructions);
     check_rate_limit(&caller)
         .map_err(|e| SensorError::RateLimit(e))?;
 
@@ -48,6 +55,7 @@ fn add_electricity_reading(kwh: f64) -> Result<bool, SensorError> {
 
     // Existing validation
     if kwh < 0.0 || kwh > 10000.0 {
+        metrics::counter_add("electricity_readings_rejected", "Electricity readings rejected, by reason", &[("reason", "out_of_range")], 1);
         return Err("Invalid electricity reading".to_string());
     }
 
@@ -57,39 +65,46 @@ fn add_electricity_reading(kwh: f64) -> Result<bool, SensorError> {
     data.readings.insert(timestamp, reading);
     data.total_kwh += kwh;
     storage::stable_save((data,)).unwrap();
+    metrics::counter_add("electricity_readings", "Electricity readings accepted", &[], 1);
     Ok(true)
 }
 
-#[query]
+#[query(guard = "can_read_readings")]
 fn get_total_kwh() -> f64 {
     let data: ElectricityData = storage::stable_restore().unwrap().0;
     data.total_kwh
 }
 
-#[query]
-fn get_electricity_readings() -> Vec<ElectricityReading> {
+// Readings in timestamp order; the timestamp is the cursor key
+#[query(guard = "can_read_readings")]
+fn get_electricity_readings(request: PageRequest) -> Result<Page<ElectricityReading>, String> {
     let data: ElectricityData = storage::stable_restore().unwrap().0;
-    data.readings.values().cloned().collect()
+    let readings = data.readings.into_iter().collect();
+    pagination::paginate(readings, Order::Ascending, &request)
+}
+
+fn instructions() -> u64 {
+    ic_cdk::api::performance_counter(0)
+}
+
+// Prometheus scrapes /metrics through the HTTP gateway
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    metrics::serve(&request, RuntimeStats {
+        cycles: ic_cdk::api::canister_balance128(),
+        stable_pages: ic_cdk::api::stable::stable64_size(),
+    })
 }
 
-#[update(guard = "is_authorized")]
+#[update(guard = "can_purge_data")]
 fn reset_electricity_data() -> bool {
     let mut data = ElectricityData::default();
     data.total_kwh = 0.0;
     storage::stable_save((data,)).unwrap();
+    record_audit("data.purge", None, vec!["electricity readings".to_string()]);
     true
 }
 
-fn is_authorized() -> Result<(), String> {
-    let caller = ic_cdk::api::caller();
-    let admins: Vec<Principal> = storage::stable_restore().unwrap().1;
-    if admins.contains(&caller) {
-        Ok(())
-    } else {
-        Err("Unauthorized".to_string())
-    }
-}
-
 fn load_data() -> Result<ElectricityData, String> {
     storage::stable_restore()
         .map(|(data, _)| data)
@@ -129,47 +144,100 @@ fn check_rate_limit(caller: &Principal) -> Result<(), String> {
     })
 }
 
+#[update(guard = "can_write_readings")]
+async fn store_reading(kwh: f64) {
+    // Existing storage logic
+    backup_backend::create_backup("energy_data".into(), reading_data).await;
+}
+
+fn authorize(permission: Permission) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    access::require(&caller.to_string(), ic_cdk::api::is_controller(&caller), permission)
+}
+
+fn can_read_readings() -> Result<(), String> {
+    authorize(Permission::ReadingsRead)
+}
+
+fn can_write_readings() -> Result<(), String> {
+    authorize(Permission::ReadingsWrite)
+}
+
+fn can_purge_data() -> Result<(), String> {
+    authorize(Permission::DataPurge)
+}
+
+fn can_admin_users() -> Result<(), String> {
+    authorize(Permission::UsersAdmin)
+}
+
+// Roles are kept per canister. Controllers always have every permission
+// and assign roles to everyone else.
+#[update(guard = "can_admin_users")]
+fn set_roles(principal: Principal, roles: Vec<Role>) {
+    let names = roles.iter().map(|role| role.name().to_string()).collect();
+    access::set_roles(&principal.to_text(), roles);
+    record_audit("role.set", Some(&principal.to_text()), names);
+}
+
 #[query]
-fn get_electricity_readings() -> Vec<ElectricityReading> {
-    let data: ElectricityData = storage::stable_restore().unwrap().0;
-    data.readings.values().cloned().collect()
+fn get_my_roles() -> Vec<Role> {
+    access::roles_of(&ic_cdk::caller().to_text())
 }
 
-#[update(guard = "is_authorized")]
-fn reset_electricity_data() -> bool {
-    let mut data = ElectricityData::default();
-    data.total_kwh = 0.0;
-    storage::stable_save((data,)).unwrap();
-    true
+fn can_read_audit() -> Result<(), String> {
+    authorize(Permission::AuditRead)
 }
 
-fn is_authorized() -> Result<(), String> {
-    let caller = ic_cdk::api::caller();
-    let admins: Vec<Principal> = storage::stable_restore().unwrap().1;
-    if admins.contains(&caller) {
-        Ok(())
-    } else {
-        Err("Unauthorized".to_string())
-    }
+// Record a privileged action. The caller comes from the runtime, never
+// from the arguments.
+fn record_audit(action: &str, target: Option<&str>, details: Vec<String>) {
+    audit::record(
+        &ic_cdk::caller().to_text(),
+        action,
+        target,
+        details,
+        ic_cdk::api::time() / 1_000_000_000,
+    );
 }
 
-fn load_data() -> Result<ElectricityData, String> {
-    storage::stable_restore()
-        .map(|(data, _)| data)
-        .map_err(|e| format!("Storage error: {:?}", e))
+// Hash-chained log of privileged actions, oldest first
+#[query(guard = "can_read_audit")]
+fn get_audit_logs(filter: AuditFilter, request: PageRequest) -> Result<Page<AuditEntry>, String> {
+    audit::query(&filter, &request)
 }
 
-fn validate_reading(timestamp: u64) -> Result<(), String> {
-    let current_time = ic_cdk::api::time();
-    if timestamp > current_time + 60_000_000_000 { // 1 minute future tolerance
-        Err("Invalid timestamp".to_string())
-    } else {
-        Ok(())
+#[query(guard = "can_read_audit")]
+fn get_audit_head() -> AuditHead {
+    audit::head()
+}
+
+#[query(guard = "can_read_audit")]
+fn verify_audit_log() -> Result<u64, String> {
+    audit::verify()
+}
+
+// Readings live in stable memory already. The audit log and roles are
+// appended for the upgrade and read back afterwards.
+#[pre_upgrade]
+fn pre_upgrade() {
+    let data: ElectricityData = storage::stable_restore().map(|(data,)| data).unwrap_or_default();
+    if storage::stable_save((data, audit::export_state(), access::export_state())).is_err() {
+        ic_cdk::trap("Failed to save state before upgrade");
     }
 }
 
-#[update]
-async fn store_reading(kwh: f64) {
-    // Existing storage logic
-    backup_backend::create_backup("energy_data".into(), reading_data).await;
-}
\ No newline at end of file
+#[post_upgrade]
+fn post_upgrade() {
+    let (data, log, roles): (ElectricityData, Option<AuditLog>, Option<RoleAssignments>) = storage::stable_restore()
+        .unwrap_or_else(|_| ic_cdk::trap("Failed to restore state after upgrade"));
+    if let Some(log) = log {
+        audit::import_state(log);
+    }
+    if let Some(roles) = roles {
+        access::import_state(roles);
+    }
+    if storage::stable_save((data,)).is_err() {
+        ic_cdk::trap("Failed to restore stable storage");
+    }
+}
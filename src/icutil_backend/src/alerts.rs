@@ -0,0 +1,331 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+
+use crate::audit;
+use crate::auth::{can_read_readings, can_write_alerts};
+use crate::labels;
+use crate::tenant::{self, TenantScope};
+use crate::water_quality::QualityChannel;
+
+const MAX_ALERTS: usize = 1000; // Per tenant
+const MAX_RULES_PER_TENANT: usize = 200;
+
+// What an alert rule watches
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AlertMetric {
+    WaterQuality(QualityChannel),
+    Channel(String),        // A channel of a schema-defined device type
+    BudgetProjection(u64), // Projected consumption of a budget, in percent of its limit
+    SecondsSinceSeen,      // Silence of a device, checked every few minutes
+    BatteryPercent,        // From device heartbeats
+    Rssi,                  // Signal strength in dBm, from device heartbeats
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+impl Comparison {
+    fn breached(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::Below => value < threshold,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AlertRule {
+    pub id: u64,
+    pub tenant_id: String,
+    pub device_id: Option<String>, // None applies the rule to every device of the tenant
+    pub selector: Option<String>,  // Further limits the devices, e.g. "group=boilers"
+    pub metric: AlertMetric,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    pub enabled: bool,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Alert {
+    pub id: u64,
+    pub rule_id: u64,
+    pub tenant_id: String,
+    pub device_id: String,
+    pub metric: AlertMetric,
+    pub value: f64,
+    pub threshold: f64,
+    pub raised_at: u64,
+    pub acknowledged: bool,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AlertEngine {
+    pub rules: BTreeMap<u64, AlertRule>,
+    pub alerts: VecDeque<Alert>,
+    // (rule, device) pairs currently in breach; an alert is raised only when
+    // a pair enters this set so a sustained breach does not flood the log
+    pub active: HashSet<(u64, String)>,
+    pub next_rule_id: u64,
+    pub next_alert_id: u64,
+}
+
+impl AlertEngine {
+    pub fn add_rule(&mut self, mut rule: AlertRule) -> Result<AlertRule, String> {
+        if !rule.threshold.is_finite() {
+            return Err("Threshold must be a finite number".into());
+        }
+        let count = self.rules.values().filter(|r| r.tenant_id == rule.tenant_id).count();
+        if count >= MAX_RULES_PER_TENANT {
+            return Err(format!("Tenant already has {} alert rules", MAX_RULES_PER_TENANT));
+        }
+        self.next_rule_id += 1;
+        rule.id = self.next_rule_id;
+        self.rules.insert(rule.id, rule.clone());
+        Ok(rule)
+    }
+
+    pub fn remove_rule(&mut self, scope: &TenantScope, id: u64) -> Result<(), String> {
+        match self.rules.get(&id) {
+            Some(rule) if rule.tenant_id == scope.tenant_id() => {
+                self.rules.remove(&id);
+                self.active.retain(|(rule_id, _)| *rule_id != id);
+                Ok(())
+            }
+            _ => Err(format!("Alert rule {} not found", id)),
+        }
+    }
+
+    // Check a new value against every matching rule and return the alerts raised
+    pub fn evaluate(
+        &mut self,
+        tenant_id: &str,
+        device_id: &str,
+        metric: AlertMetric,
+        value: f64,
+        now: u64,
+    ) -> Vec<Alert> {
+        let matching: Vec<AlertRule> = self
+            .rules
+            .values()
+            .filter(|r| {
+                r.enabled
+                    && r.tenant_id == tenant_id
+                    && r.metric == metric
+                    && r.device_id.as_deref().map_or(true, |d| d == device_id)
+                    && r.selector.as_deref().map_or(true, |s| labels::selects(s, device_id))
+            })
+            .cloned()
+            .collect();
+
+        let mut raised = Vec::new();
+        for rule in matching {
+            let key = (rule.id, device_id.to_string());
+            if !rule.comparison.breached(value, rule.threshold) {
+                self.active.remove(&key);
+                continue;
+            }
+            if !self.active.insert(key) {
+                continue;
+            }
+
+            self.next_alert_id += 1;
+            let alert = Alert {
+                id: self.next_alert_id,
+                rule_id: rule.id,
+                tenant_id: tenant_id.to_string(),
+                device_id: device_id.to_string(),
+                metric: metric.clone(),
+                value,
+                threshold: rule.threshold,
+                raised_at: now,
+                acknowledged: false,
+            };
+            self.alerts.push_back(alert.clone());
+            self.rotate(tenant_id);
+            raised.push(alert);
+        }
+        raised
+    }
+
+    // Keep only the last MAX_ALERTS of a tenant, so a noisy tenant never
+    // evicts another tenant's alerts
+    fn rotate(&mut self, tenant_id: &str) {
+        let mut excess = self
+            .alerts
+            .iter()
+            .filter(|a| a.tenant_id == tenant_id)
+            .count()
+            .saturating_sub(MAX_ALERTS);
+        self.alerts.retain(|a| {
+            if excess > 0 && a.tenant_id == tenant_id {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+thread_local! {
+    static ALERTS: RefCell<AlertEngine> = RefCell::new(AlertEngine::default());
+}
+
+pub fn export_state() -> AlertEngine {
+    ALERTS.with(|a| a.borrow().clone())
+}
+
+pub fn import_state(engine: AlertEngine) {
+    ALERTS.with(|a| *a.borrow_mut() = engine);
+}
+
+// Feed a value into the alert engine; called by the ingest paths
+pub fn evaluate(tenant_id: &str, device_id: &str, metric: AlertMetric, value: f64, now: u64) {
+    let raised = ALERTS.with(|a| a.borrow_mut().evaluate(tenant_id, device_id, metric, value, now));
+    for alert in raised {
+        ic_cdk::println!(
+            "ALERT: {:?} on {} is {} (threshold {})",
+            alert.metric, alert.device_id, alert.value, alert.threshold
+        );
+    }
+}
+
+#[update(guard = "can_write_alerts")]
+fn create_alert_rule(
+    device_id: Option<String>,
+    metric: AlertMetric,
+    comparison: Comparison,
+    threshold: f64,
+    selector: Option<String>,
+) -> Result<AlertRule, String> {
+    let scope = tenant::member_scope()?;
+    if let Some(ref device_id) = device_id {
+        tenant::require_device(&scope, device_id)?;
+    }
+    if let Some(ref selector) = selector {
+        labels::parse(selector)?;
+    }
+
+    let rule = AlertRule {
+        id: 0,
+        tenant_id: scope.tenant_id().to_string(),
+        device_id,
+        selector,
+        metric,
+        comparison,
+        threshold,
+        enabled: true,
+        created_at: ic_cdk::api::time() / 1_000_000_000,
+    };
+    let details = vec![format!("{:?} {:?} {}", rule.metric, rule.comparison, rule.threshold)];
+    let result = ALERTS.with(|a| a.borrow_mut().add_rule(rule));
+    let target = result.as_ref().map(|r| r.id.to_string()).unwrap_or_default();
+    audit::on_success(result, "config.alert_rule_create", Some(&target), details)
+}
+
+#[update(guard = "can_write_alerts")]
+fn delete_alert_rule(id: u64) -> Result<(), String> {
+    let scope = tenant::member_scope()?;
+    let result = ALERTS.with(|a| a.borrow_mut().remove_rule(&scope, id));
+    audit::on_success(result, "config.alert_rule_delete", Some(&id.to_string()), Vec::new())
+}
+
+#[query(guard = "can_read_readings")]
+fn list_alert_rules(request: PageRequest) -> Result<Page<AlertRule>, String> {
+    let scope = tenant::member_scope()?;
+    let rules = ALERTS.with(|a| {
+        a.borrow()
+            .rules
+            .values()
+            .filter(|r| r.tenant_id == scope.tenant_id())
+            .map(|r| (r.id, r.clone()))
+            .collect()
+    });
+    pagination::paginate(rules, Order::Ascending, &request)
+}
+
+// Alerts of the caller's tenant, newest first
+#[query(guard = "can_read_readings")]
+fn get_alerts(include_acknowledged: bool, request: PageRequest) -> Result<Page<Alert>, String> {
+    let scope = tenant::member_scope()?;
+    let alerts = ALERTS.with(|a| {
+        a.borrow()
+            .alerts
+            .iter()
+            .filter(|alert| alert.tenant_id == scope.tenant_id())
+            .filter(|alert| include_acknowledged || !alert.acknowledged)
+            .map(|alert| (alert.id, alert.clone()))
+            .collect()
+    });
+    pagination::paginate(alerts, Order::Descending, &request)
+}
+
+#[update(guard = "can_write_alerts")]
+fn acknowledge_alert(id: u64) -> Result<(), String> {
+    let scope = tenant::member_scope()?;
+    let result = ALERTS.with(|a| {
+        a.borrow_mut()
+            .alerts
+            .iter_mut()
+            .find(|alert| alert.id == id && alert.tenant_id == scope.tenant_id())
+            .map(|alert| alert.acknowledged = true)
+            .ok_or_else(|| format!("Alert {} not found", id))
+    });
+    audit::on_success(result, "alert.acknowledge", Some(&id.to_string()), Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(tenant_id: &str) -> AlertRule {
+        AlertRule {
+            id: 0,
+            tenant_id: tenant_id.to_string(),
+            device_id: None,
+            selector: None,
+            metric: AlertMetric::BatteryPercent,
+            comparison: Comparison::Below,
+            threshold: 20.0,
+            enabled: true,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn sustained_breach_raises_one_alert() {
+        let mut engine = AlertEngine::default();
+        engine.add_rule(rule("a")).unwrap();
+        assert_eq!(engine.evaluate("a", "m-1", AlertMetric::BatteryPercent, 10.0, 1).len(), 1);
+        assert!(engine.evaluate("a", "m-1", AlertMetric::BatteryPercent, 5.0, 2).is_empty());
+        assert!(engine.evaluate("a", "m-1", AlertMetric::BatteryPercent, 50.0, 3).is_empty());
+        assert_eq!(engine.evaluate("a", "m-1", AlertMetric::BatteryPercent, 10.0, 4).len(), 1);
+        assert!(engine.evaluate("b", "m-1", AlertMetric::BatteryPercent, 10.0, 5).is_empty());
+    }
+
+    #[test]
+    fn noisy_tenant_only_evicts_its_own_alerts() {
+        let mut engine = AlertEngine::default();
+        engine.add_rule(rule("quiet")).unwrap();
+        engine.add_rule(rule("noisy")).unwrap();
+        engine.evaluate("quiet", "q-1", AlertMetric::BatteryPercent, 10.0, 0);
+        for i in 0..(MAX_ALERTS as u64 + 10) {
+            // Recover and breach again so every round raises a new alert
+            engine.evaluate("noisy", "n-1", AlertMetric::BatteryPercent, 50.0, i);
+            engine.evaluate("noisy", "n-1", AlertMetric::BatteryPercent, 10.0, i);
+        }
+        let count = |tenant: &str| engine.alerts.iter().filter(|a| a.tenant_id == tenant).count();
+        assert_eq!(count("quiet"), 1);
+        assert_eq!(count("noisy"), MAX_ALERTS);
+        let oldest_noisy = engine.alerts.iter().find(|a| a.tenant_id == "noisy").unwrap();
+        assert_eq!(oldest_noisy.raised_at, 10);
+    }
+}
@@ -0,0 +1,46 @@
+use ic_cdk_macros::query;
+use icutil_common::audit::{self, AuditEntry, AuditFilter, AuditHead};
+use icutil_common::pagination::{Page, PageRequest};
+
+use crate::auth::can_read_audit;
+
+// Record a privileged action by the caller of the current call. Actions are
+// named `<area>.<verb>` so they can be filtered by area.
+pub fn record(action: &str, target: Option<&str>, details: Vec<String>) {
+    audit::record(
+        &ic_cdk::caller().to_string(),
+        action,
+        target,
+        details,
+        ic_cdk::api::time() / 1_000_000_000,
+    );
+}
+
+// Record `action` if `result` is Ok and hand the result back
+pub fn on_success<T>(
+    result: Result<T, String>,
+    action: &str,
+    target: Option<&str>,
+    details: Vec<String>,
+) -> Result<T, String> {
+    if result.is_ok() {
+        record(action, target, details);
+    }
+    result
+}
+
+#[query(guard = "can_read_audit")]
+fn get_audit_logs(filter: AuditFilter, request: PageRequest) -> Result<Page<AuditEntry>, String> {
+    audit::query(&filter, &request)
+}
+
+#[query(guard = "can_read_audit")]
+fn get_audit_head() -> AuditHead {
+    audit::head()
+}
+
+// Recompute the hash chain; fails at the first entry that was altered
+#[query(guard = "can_read_audit")]
+fn verify_audit_log() -> Result<u64, String> {
+    audit::verify()
+}
@@ -1,11 +1,25 @@
 use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::main::raw_rand;
 use ic_cdk::{api, caller};
-use std::collections::HashMap;
+use ic_cdk_macros::{query, update};
+use icutil_common::access::{self, Permission, Role};
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::audit;
+use crate::tenant;
+
+const DEFAULT_INVITE_TTL: u64 = 7 * 24 * 3600; // Seconds
+const MAX_INVITE_TTL: u64 = 30 * 24 * 3600;
+const MAX_PENDING_INVITES: usize = 1000;
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct User {
     pub principal: String,
-    pub roles: Vec<String>,
+    pub roles: Vec<String>, // Role names, see `icutil_common::access::Role`
     pub created_at: u64,
 }
 
@@ -15,46 +29,587 @@ pub struct AuthPayload {
     pub exp: u64,
 }
 
-static mut USERS: HashMap<String, User> = HashMap::new();
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserStatus {
+    Active,
+    Disabled,
+}
+
+// A user of this canister. The account is the source of truth for roles;
+// `access` holds a copy of the roles of active users for the guards.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UserAccount {
+    pub principal: String,
+    pub roles: Vec<Role>,
+    pub status: UserStatus,
+    pub created_at: u64,
+    pub invited_by: Option<String>,
+}
+
+// An invite waiting to be accepted. Only the hash of its code is kept.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Invite {
+    pub id: String,
+    pub roles: Vec<Role>,
+    pub tenant_id: Option<String>,
+    pub created_by: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UserInfo {
+    pub principal: String,
+    pub roles: Vec<String>,
+    pub status: UserStatus,
+    pub tenant_id: Option<String>,
+    pub created_at: u64,
+    pub invited_by: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InviteInfo {
+    pub id: String,
+    pub roles: Vec<String>,
+    pub tenant_id: Option<String>,
+    pub created_by: String,
+    pub expires_at: u64,
+}
+
+// Returned once by `invite_user`; the code cannot be recovered later
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InviteCode {
+    pub invite_id: String,
+    pub code: String,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WhoAmI {
+    pub principal: String,
+    pub is_controller: bool,
+    pub user: Option<UserInfo>,
+    pub tenant_id: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Users {
+    pub accounts: BTreeMap<String, UserAccount>, // principal -> account
+    pub invites: BTreeMap<String, Invite>,       // hex sha256 of the code -> invite
+}
+
+thread_local! {
+    static USERS: RefCell<Users> = RefCell::new(Users::default());
+}
+
+fn now() -> u64 {
+    api::time() / 1_000_000_000
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(raw: &str) -> Option<Vec<u8>> {
+    if raw.len() % 2 != 0 {
+        return None;
+    }
+    (0..raw.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(raw.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_roles(names: &[String]) -> Result<Vec<Role>, String> {
+    let mut roles = names.iter().map(|name| Role::parse(name)).collect::<Result<Vec<Role>, String>>()?;
+    roles.sort();
+    roles.dedup();
+    Ok(roles)
+}
+
+fn role_names(roles: &[Role]) -> Vec<String> {
+    roles.iter().map(|role| role.name().to_string()).collect()
+}
 
-pub fn init_admin() {
-    let admin_principal = api::id().to_string();
-    unsafe {
-        USERS.insert(
-            admin_principal.clone(),
-            User {
-                principal: admin_principal,
-                roles: vec!["admin".to_string()],
-                created_at: api::time(),
-            },
-        );
+// Copy an account's roles to `access`. Disabled users keep their roles on
+// the account but lose them for the guards.
+fn sync_access(account: &UserAccount) {
+    match account.status {
+        UserStatus::Active => access::set_roles(&account.principal, account.roles.clone()),
+        UserStatus::Disabled => access::set_roles(&account.principal, Vec::new()),
     }
 }
 
-pub fn authenticate(roles: &[&str]) -> Result<User, String> {
-    let caller_principal = caller().to_string();
-    
-    unsafe {
-        USERS
-            .get(&caller_principal)
-            .filter(|user| roles.iter().any(|r| user.roles.contains(&r.to_string())))
-            .cloned()
-            .ok_or_else(|| "Unauthorized access".to_string())
+impl UserAccount {
+    fn info(&self) -> UserInfo {
+        UserInfo {
+            principal: self.principal.clone(),
+            roles: role_names(&self.roles),
+            status: self.status,
+            tenant_id: tenant::tenant_of_member(&self.principal),
+            created_at: self.created_at,
+            invited_by: self.invited_by.clone(),
+        }
     }
 }
 
-#[update]
-fn create_user(new_user: User) -> Result<(), String> {
-    let auth_user = authenticate(&["admin"])?;
-    
-    unsafe {
-        USERS.insert(new_user.principal.clone(), new_user);
+impl Invite {
+    fn info(&self) -> InviteInfo {
+        InviteInfo {
+            id: self.id.clone(),
+            roles: role_names(&self.roles),
+            tenant_id: self.tenant_id.clone(),
+            created_by: self.created_by.clone(),
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+impl Users {
+    // Create the account or add `roles` to an existing one
+    fn add_roles(&mut self, principal: &str, roles: &[Role], invited_by: Option<String>, now: u64) -> &UserAccount {
+        let account = self.accounts.entry(principal.to_string()).or_insert_with(|| UserAccount {
+            principal: principal.to_string(),
+            roles: Vec::new(),
+            status: UserStatus::Active,
+            created_at: now,
+            invited_by,
+        });
+        account.roles.extend_from_slice(roles);
+        account.roles.sort();
+        account.roles.dedup();
+        sync_access(account);
+        account
+    }
+
+    fn account_mut(&mut self, principal: &str) -> Result<&mut UserAccount, String> {
+        self.accounts
+            .get_mut(principal)
+            .ok_or_else(|| format!("User {} not found", principal))
+    }
+
+    fn drop_expired_invites(&mut self, now: u64) {
+        self.invites.retain(|_, invite| invite.expires_at > now);
+    }
+}
+
+// Make `principal` an admin. Runs from init with the deployer, and from
+// post_upgrade with the principal upgrading when no active admin is left.
+pub fn bootstrap_admin(principal: &str) {
+    USERS.with(|u| {
+        u.borrow_mut().add_roles(principal, &[Role::Admin], None, now());
+    });
+}
+
+// Give `principal` a role, creating their account if needed. Used when a
+// resident claims a device, so no admin has to create the account.
+pub fn ensure_role(principal: &str, role: Role) {
+    USERS.with(|u| {
+        u.borrow_mut().add_roles(principal, &[role], Some(caller().to_string()), now());
+    });
+}
+
+// Take a role away outside of an admin call, e.g. when a resident no
+// longer owns any device
+pub fn remove_role(principal: &str, role: Role) {
+    USERS.with(|u| {
+        if let Ok(account) = u.borrow_mut().account_mut(principal) {
+            account.roles.retain(|r| *r != role);
+            sync_access(account);
+        }
+    });
+}
+
+pub fn has_active_admin() -> bool {
+    USERS.with(|u| {
+        u.borrow()
+            .accounts
+            .values()
+            .any(|a| a.status == UserStatus::Active && a.roles.contains(&Role::Admin))
+    })
+}
+
+pub fn export_state() -> Users {
+    USERS.with(|u| u.borrow().clone())
+}
+
+// Restore users and rebuild the role copy in `access`. State saved before
+// accounts existed only has role assignments; those become accounts.
+pub fn import_state(users: Option<Users>) {
+    let users = users.unwrap_or_else(|| {
+        let mut users = Users::default();
+        for (principal, roles) in access::export_state().roles {
+            users.add_roles(&principal, &roles, None, now());
+        }
+        users
+    });
+    access::import_state(Default::default());
+    users.accounts.values().for_each(sync_access);
+    USERS.with(|u| *u.borrow_mut() = users);
+}
+
+// Fail unless the caller holds `permission`
+pub fn authorize(permission: Permission) -> Result<(), String> {
+    let caller = caller();
+    access::require(&caller.to_string(), api::is_controller(&caller), permission)
+}
+
+// Endpoint guards, one per permission used by this canister
+pub fn can_read_readings() -> Result<(), String> {
+    authorize(Permission::ReadingsRead)
+}
+
+pub fn can_write_readings() -> Result<(), String> {
+    authorize(Permission::ReadingsWrite)
+}
+
+pub fn can_read_devices() -> Result<(), String> {
+    authorize(Permission::DevicesRead)
+}
+
+pub fn can_admin_devices() -> Result<(), String> {
+    authorize(Permission::DevicesAdmin)
+}
+
+pub fn can_write_config() -> Result<(), String> {
+    authorize(Permission::ConfigWrite)
+}
+
+pub fn can_write_alerts() -> Result<(), String> {
+    authorize(Permission::AlertsWrite)
+}
+
+pub fn can_purge_data() -> Result<(), String> {
+    authorize(Permission::DataPurge)
+}
+
+pub fn can_admin_users() -> Result<(), String> {
+    authorize(Permission::UsersAdmin)
+}
+
+// Tenant administration spans every tenant, so a member of a tenant cannot
+// use it even with the admin role
+pub fn can_admin_tenants() -> Result<(), String> {
+    authorize(Permission::TenantsAdmin)?;
+    let caller = caller();
+    if !api::is_controller(&caller) && tenant::tenant_of_member(&caller.to_string()).is_some() {
+        return Err("Tenant members cannot administer tenants".into());
+    }
+    Ok(())
+}
+
+// The log holds the actions of every tenant, so reading it also takes
+// tenant administration
+pub fn can_read_audit() -> Result<(), String> {
+    authorize(Permission::AuditRead)?;
+    can_admin_tenants()
+}
+
+// Any principal with at least one role. Disabled users have none.
+pub fn is_user() -> Result<(), String> {
+    let caller = caller();
+    if api::is_controller(&caller) || !access::roles_of(&caller.to_string()).is_empty() {
+        Ok(())
+    } else {
+        Err("Unauthorized access".to_string())
+    }
+}
+
+fn permissions_of(principal: &candid::Principal) -> Vec<String> {
+    Permission::ALL
+        .into_iter()
+        .filter(|p| access::require(&principal.to_string(), api::is_controller(principal), *p).is_ok())
+        .map(|p| p.name().to_string())
+        .collect()
+}
+
+// Users admins manage the accounts of their own tenant; admins without a
+// tenant manage the accounts without one. Controllers manage every account.
+fn require_same_tenant(principal: &str) -> Result<(), String> {
+    let caller = caller();
+    if api::is_controller(&caller) || tenant::tenant_of_member(&caller.to_string()) == tenant::tenant_of_member(principal) {
+        Ok(())
+    } else {
+        Err(format!("User {} is not in your tenant", principal))
+    }
+}
+
+// Callers can only hand out or take away roles whose permissions they hold
+// themselves. Admin holds every permission, so it needs `tenants:admin`,
+// which tenant members cannot use.
+fn require_assignable(roles: &[Role]) -> Result<(), String> {
+    let caller = caller();
+    let principal = caller.to_string();
+    let is_controller = api::is_controller(&caller);
+    let holds = |permission: Permission| match permission {
+        Permission::TenantsAdmin => can_admin_tenants().is_ok(),
+        _ => access::require(&principal, is_controller, permission).is_ok(),
+    };
+    for role in roles {
+        if !role.permissions().iter().all(|p| holds(*p)) {
+            return Err(format!("You cannot assign the {} role", role.name()));
+        }
     }
     Ok(())
 }
 
+// Accounts and invites visible to the caller, see `require_same_tenant`
+fn visible_tenant() -> Option<Option<String>> {
+    let caller = caller();
+    (!api::is_controller(&caller)).then(|| tenant::tenant_of_member(&caller.to_string()))
+}
+
+// Create or replace a user's roles directly, without an invite. A principal
+// without an account joins the caller's tenant.
+#[update(guard = "can_admin_users")]
+fn create_user(new_user: User) -> Result<(), String> {
+    let roles = parse_roles(&new_user.roles)?;
+    let principal = candid::Principal::from_text(&new_user.principal)
+        .map_err(|_| "Invalid principal".to_string())?
+        .to_string();
+    require_assignable(&roles)?;
+    let is_new = USERS.with(|u| !u.borrow().accounts.contains_key(&principal))
+        && tenant::tenant_of_member(&principal).is_none();
+    match tenant::tenant_of_member(&caller().to_string()) {
+        Some(tenant_id) if is_new => tenant::add_member(&principal, &tenant_id)?,
+        _ => require_same_tenant(&principal)?,
+    }
+    let details = role_names(&roles);
+    let result = USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        users.add_roles(&principal, &[], Some(caller().to_string()), now());
+        let account = users.account_mut(&principal)?;
+        account.roles = roles;
+        sync_access(account);
+        Ok(())
+    });
+    audit::on_success(result, "role.set", Some(&principal), details)
+}
+
+#[update(guard = "can_admin_users")]
+fn grant_role(principal: String, role: String) -> Result<UserInfo, String> {
+    let role = Role::parse(&role)?;
+    require_assignable(&[role])?;
+    require_same_tenant(&principal)?;
+    let result = USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        let account = users.account_mut(&principal)?;
+        if !account.roles.contains(&role) {
+            account.roles.push(role);
+            account.roles.sort();
+        }
+        sync_access(account);
+        Ok(account.info())
+    });
+    audit::on_success(result, "role.grant", Some(&principal), vec![role.name().to_string()])
+}
+
+#[update(guard = "can_admin_users")]
+fn revoke_role(principal: String, role: String) -> Result<UserInfo, String> {
+    let role = Role::parse(&role)?;
+    if principal == caller().to_string() && role == Role::Admin {
+        return Err("You cannot revoke your own admin role".into());
+    }
+    require_assignable(&[role])?;
+    require_same_tenant(&principal)?;
+    let result = USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        let account = users.account_mut(&principal)?;
+        account.roles.retain(|r| *r != role);
+        sync_access(account);
+        Ok(account.info())
+    });
+    audit::on_success(result, "role.revoke", Some(&principal), vec![role.name().to_string()])
+}
+
+fn set_status(principal: String, status: UserStatus) -> Result<UserInfo, String> {
+    if principal == caller().to_string() {
+        return Err("You cannot change the status of your own account".into());
+    }
+    require_same_tenant(&principal)?;
+    let result = USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        let account = users.account_mut(&principal)?;
+        account.status = status;
+        sync_access(account);
+        Ok(account.info())
+    });
+    let action = match status {
+        UserStatus::Active => "user.enable",
+        UserStatus::Disabled => "user.disable",
+    };
+    audit::on_success(result, action, Some(&principal), Vec::new())
+}
+
+// A disabled user keeps their account and roles but fails every guard
+#[update(guard = "can_admin_users")]
+fn disable_user(principal: String) -> Result<UserInfo, String> {
+    set_status(principal, UserStatus::Disabled)
+}
+
+#[update(guard = "can_admin_users")]
+fn enable_user(principal: String) -> Result<UserInfo, String> {
+    set_status(principal, UserStatus::Active)
+}
+
+#[query(guard = "can_admin_users")]
+fn list_users(request: PageRequest) -> Result<Page<UserInfo>, String> {
+    let tenant_id = visible_tenant();
+    let users = USERS.with(|u| {
+        u.borrow()
+            .accounts
+            .values()
+            .map(UserAccount::info)
+            .filter(|info| tenant_id.as_ref().map_or(true, |t| info.tenant_id == *t))
+            .map(|info| (info.principal.clone(), info))
+            .collect()
+    });
+    pagination::paginate(users, Order::Ascending, &request)
+}
+
+// Invite someone to the caller's tenant, with roles the caller could grant.
+// `tenant_id` may only name the caller's own tenant; platform admins add
+// users to other tenants with `add_user_to_tenant`.
+#[update(guard = "can_admin_users")]
+async fn invite_user(roles: Vec<String>, tenant_id: Option<String>, expires_in: Option<u64>) -> Result<InviteCode, String> {
+    let roles = parse_roles(&roles)?;
+    if roles.is_empty() {
+        return Err("An invite needs at least one role".into());
+    }
+    let ttl = expires_in.unwrap_or(DEFAULT_INVITE_TTL);
+    if ttl == 0 || ttl > MAX_INVITE_TTL {
+        return Err(format!("Invites expire after 1 to {} seconds", MAX_INVITE_TTL));
+    }
+    require_assignable(&roles)?;
+    let inviter = caller().to_string();
+    let own_tenant = tenant::tenant_of_member(&inviter).ok_or("Only tenant members can invite users")?;
+    if tenant_id.map_or(false, |id| id != own_tenant) {
+        return Err("Invites can only join your own tenant".into());
+    }
+    let tenant_id = Some(own_tenant);
+
+    let (code,) = raw_rand()
+        .await
+        .map_err(|(_, e)| format!("Failed to generate invite: {}", e))?;
+    let code = &code[..16];
+    let hash = hex(&Sha256::digest(code));
+    let now = now();
+    let invite = Invite {
+        id: format!("inv_{}", &hash[..16]),
+        roles,
+        tenant_id,
+        created_by: inviter,
+        created_at: now,
+        expires_at: now + ttl,
+    };
+    let result = InviteCode {
+        invite_id: invite.id.clone(),
+        code: hex(code),
+        expires_at: invite.expires_at,
+    };
+    let mut details = role_names(&invite.roles);
+    details.extend(invite.tenant_id.iter().map(|id| format!("tenant {}", id)));
+    USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        users.drop_expired_invites(now);
+        if users.invites.len() >= MAX_PENDING_INVITES {
+            return Err("Too many pending invites".to_string());
+        }
+        users.invites.insert(hash, invite);
+        Ok(())
+    })?;
+    audit::record("user.invite", Some(&result.invite_id), details);
+    Ok(result)
+}
+
+// Redeem an invite as the calling principal, e.g. an Internet Identity
+#[update]
+fn accept_invite(code: String) -> Result<UserInfo, String> {
+    let principal = caller();
+    if principal == candid::Principal::anonymous() {
+        return Err("Sign in before accepting an invite".into());
+    }
+    let principal = principal.to_string();
+    let code = unhex(code.trim()).ok_or("Invalid invite code")?;
+    let hash = hex(&Sha256::digest(code));
+    let now = now();
+
+    let result = USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        users.drop_expired_invites(now);
+        let invite = users.invites.get(&hash).cloned().ok_or("Invalid or expired invite")?;
+        if users.accounts.get(&principal).map_or(false, |a| a.status == UserStatus::Disabled) {
+            return Err("Account is disabled".to_string());
+        }
+        if let Some(ref tenant_id) = invite.tenant_id {
+            tenant::add_member(&principal, tenant_id)?;
+        }
+        users.invites.remove(&hash);
+        let info = users.add_roles(&principal, &invite.roles, Some(invite.created_by), now).info();
+        Ok((invite.id, info))
+    });
+    let (invite_id, info) = result?;
+    audit::record("user.accept_invite", Some(&invite_id), info.roles.clone());
+    Ok(info)
+}
+
+#[update(guard = "can_admin_users")]
+fn cancel_invite(invite_id: String) -> Result<(), String> {
+    let tenant_id = visible_tenant();
+    let result = USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        let before = users.invites.len();
+        users.invites.retain(|_, invite| {
+            invite.id != invite_id || tenant_id.as_ref().map_or(false, |t| invite.tenant_id != *t)
+        });
+        if users.invites.len() == before {
+            Err(format!("Invite {} not found", invite_id))
+        } else {
+            Ok(())
+        }
+    });
+    audit::on_success(result, "user.cancel_invite", Some(&invite_id), Vec::new())
+}
+
+#[query(guard = "can_admin_users")]
+fn list_invites(request: PageRequest) -> Result<Page<InviteInfo>, String> {
+    let now = now();
+    let tenant_id = visible_tenant();
+    let invites = USERS.with(|u| {
+        u.borrow()
+            .invites
+            .values()
+            .filter(|invite| invite.expires_at > now)
+            .filter(|invite| tenant_id.as_ref().map_or(true, |t| invite.tenant_id == *t))
+            .map(|invite| (invite.id.clone(), invite.info()))
+            .collect()
+    });
+    pagination::paginate(invites, Order::Ascending, &request)
+}
+
+// Who the caller is to this canister. Open to everyone, including
+// principals without an account, so clients can decide what to show.
 #[query]
+fn whoami() -> WhoAmI {
+    let principal = caller();
+    let id = principal.to_string();
+    WhoAmI {
+        is_controller: api::is_controller(&principal),
+        user: USERS.with(|u| u.borrow().accounts.get(&id).map(UserAccount::info)),
+        tenant_id: tenant::tenant_of_member(&id),
+        permissions: permissions_of(&principal),
+        principal: id,
+    }
+}
+
+#[query(guard = "is_user")]
 fn get_roles() -> Result<Vec<String>, String> {
-    let user = authenticate(&[])?;
-    Ok(user.roles)
-}
\ No newline at end of file
+    Ok(role_names(&access::roles_of(&caller().to_string())))
+}
+
+// Permissions the caller holds, for clients deciding what to show
+#[query(guard = "is_user")]
+fn get_my_permissions() -> Vec<String> {
+    permissions_of(&caller())
+}
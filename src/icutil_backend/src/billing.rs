@@ -0,0 +1,131 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+use serde::Serialize;
+
+use crate::auth::can_read_readings;
+use crate::consumption::Utility;
+use crate::gas;
+use crate::hierarchy;
+use crate::rollup::{self, DAY};
+use crate::tenant;
+
+const MAX_PERIOD_DAYS: u64 = 366;
+
+// Consumption of one meter over a billing period
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct BillingLine {
+    pub device_id: String,
+    pub consumption: f64,        // In the statement's unit
+    pub energy_kwh: Option<f64>, // Gas only, at the meter's current calorific value
+    pub missing_days: u32,       // Days of the period the meter's readings do not cover
+}
+
+// What a billing system needs to price a tenant's usage of one utility.
+// Sub-meters are left out because their flow is billed through their main
+// meter.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BillingStatement {
+    pub tenant_id: String,
+    pub utility: Utility,
+    pub unit: String,
+    pub period_start: u64,
+    pub period_end: u64, // Exclusive
+    pub lines: Vec<BillingLine>,
+    pub total: f64,
+}
+
+fn validate_period(period_start: u64, period_end: u64) -> Result<(), String> {
+    if !period_start.is_multiple_of(DAY) || !period_end.is_multiple_of(DAY) {
+        return Err("Billing periods start and end at midnight UTC".into());
+    }
+    if period_end <= period_start || (period_end - period_start) / DAY > MAX_PERIOD_DAYS {
+        return Err(format!("Billing period must be 1-{} days", MAX_PERIOD_DAYS));
+    }
+    Ok(())
+}
+
+// One line per meter from its daily usage; meters without any covered day
+// are left out
+fn lines(usage: Vec<(String, Vec<Option<f64>>)>, kwh_per_unit: impl Fn(&str) -> Option<f64>) -> Vec<BillingLine> {
+    let mut lines: Vec<BillingLine> = usage
+        .into_iter()
+        .filter(|(_, days)| days.iter().any(Option::is_some))
+        .map(|(device_id, days)| {
+            let consumption: f64 = days.iter().flatten().sum();
+            BillingLine {
+                energy_kwh: kwh_per_unit(&device_id).map(|factor| consumption * factor),
+                missing_days: days.iter().filter(|d| d.is_none()).count() as u32,
+                consumption,
+                device_id,
+            }
+        })
+        .collect();
+    lines.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    lines
+}
+
+// Usage of the caller's tenant per meter for whole UTC days, read from the
+// daily rollups so periods longer than the raw reading buffer are complete
+#[query(guard = "can_read_readings")]
+fn get_billing_statement(utility: Utility, period_start: u64, period_end: u64) -> Result<BillingStatement, String> {
+    let scope = tenant::member_scope()?;
+    validate_period(period_start, period_end)?;
+
+    let devices: Vec<String> = tenant::with_registry(|registry| registry.devices_of(&scope))
+        .into_iter()
+        .filter(|id| !hierarchy::with_hierarchy(|h| h.is_sub_meter(id)))
+        .collect();
+    let first_day = period_start / DAY;
+    let days = ((period_end - period_start) / DAY) as usize;
+    let usage = devices
+        .into_iter()
+        .map(|id| {
+            let daily = rollup::daily_usage(&scope, utility, std::slice::from_ref(&id), first_day, days);
+            (id, daily)
+        })
+        .collect();
+    let lines = lines(usage, |device_id| match utility {
+        Utility::Gas => gas::kwh_per_cubic_meter(device_id),
+        Utility::Water | Utility::Electricity => None,
+    });
+
+    Ok(BillingStatement {
+        tenant_id: scope.tenant_id().to_string(),
+        utility,
+        unit: utility.unit().symbol().to_string(),
+        period_start,
+        period_end,
+        total: lines.iter().map(|l| l.consumption).sum(),
+        lines,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periods_are_whole_days() {
+        assert!(validate_period(0, DAY).is_ok());
+        assert!(validate_period(DAY, DAY).is_err());
+        assert!(validate_period(1, DAY).is_err());
+        assert!(validate_period(0, (MAX_PERIOD_DAYS + 1) * DAY).is_err());
+    }
+
+    #[test]
+    fn lines_sum_covered_days_and_convert_gas_to_energy() {
+        let usage = vec![
+            ("m-2".to_string(), vec![Some(1.5), None, Some(2.5)]),
+            ("m-1".to_string(), vec![Some(3.0), Some(0.0), Some(1.0)]),
+            ("idle".to_string(), vec![None, None, None]),
+        ];
+        let lines = lines(usage, |id| (id == "m-1").then_some(10.0));
+        assert_eq!(
+            lines,
+            vec![
+                BillingLine { device_id: "m-1".into(), consumption: 4.0, energy_kwh: Some(40.0), missing_days: 0 },
+                BillingLine { device_id: "m-2".into(), consumption: 4.0, energy_kwh: None, missing_days: 1 },
+            ]
+        );
+    }
+}
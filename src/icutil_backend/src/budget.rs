@@ -0,0 +1,336 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+
+use crate::audit;
+use crate::alerts::{self, AlertMetric};
+use crate::auth::{can_read_readings, can_write_config};
+use crate::consumption::Utility;
+use crate::hierarchy;
+use crate::rollup;
+use crate::tenant::{self, TenantScope};
+
+const NAME_MAX_LENGTH: usize = 128;
+const MAX_BUDGETS_PER_TENANT: usize = 100;
+const MAX_PERIOD: u64 = 366 * 24 * 3600;
+const CHECK_INTERVAL: u64 = 15 * 60; // Seconds between budget checks from the heartbeat
+
+// What a budget covers. A tenant or location budget only counts top-level
+// meters so sub-metered usage is not counted twice.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum BudgetScope {
+    Tenant,
+    Location(String),
+    Device(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Budget {
+    pub id: u64,
+    pub tenant_id: String,
+    pub name: String,
+    pub scope: BudgetScope,
+    pub utility: Utility,
+    pub limit: f64, // In the utility's unit
+    pub period_start: u64,
+    pub period_end: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BudgetStatus {
+    pub budget: Budget,
+    pub unit: String,
+    pub as_of: u64,
+    pub actual: f64,    // Consumption from the start of the period to `as_of`
+    pub projected: f64, // Expected consumption at the end of the period
+    pub percent_used: f64,
+    pub percent_projected: f64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Budgets {
+    pub budgets: BTreeMap<u64, Budget>,
+    pub next_id: u64,
+    pub last_check: u64,
+}
+
+impl Budget {
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() || self.name.len() > NAME_MAX_LENGTH {
+            return Err(format!("Budget name must be 1-{} characters", NAME_MAX_LENGTH));
+        }
+        if !(self.limit.is_finite() && self.limit > 0.0) {
+            return Err("Budget limit must be a positive number".into());
+        }
+        if self.period_end <= self.period_start || self.period_end - self.period_start > MAX_PERIOD {
+            return Err("Budget period must end after it starts and last at most a year".into());
+        }
+        Ok(())
+    }
+
+    // Alert subject: the budgeted device or location, or the tenant itself
+    fn subject(&self) -> &str {
+        match &self.scope {
+            BudgetScope::Tenant => &self.tenant_id,
+            BudgetScope::Location(id) | BudgetScope::Device(id) => id,
+        }
+    }
+}
+
+// Devices whose consumption counts towards the budget
+fn budget_devices(scope: &TenantScope, budget_scope: &BudgetScope) -> Result<Vec<String>, String> {
+    match budget_scope {
+        BudgetScope::Tenant => {
+            let devices = tenant::with_registry(|registry| registry.devices_of(scope));
+            Ok(hierarchy::with_hierarchy(|h| {
+                devices.into_iter().filter(|id| !h.is_sub_meter(id)).collect()
+            }))
+        }
+        BudgetScope::Location(node_id) => hierarchy::with_hierarchy(|h| {
+            h.top_meters(scope, node_id)
+                .map(|meters| meters.into_iter().map(|m| m.device_id.clone()).collect())
+        }),
+        BudgetScope::Device(device_id) => {
+            tenant::require_device(scope, device_id)?;
+            Ok(vec![device_id.clone()])
+        }
+    }
+}
+
+pub fn status(scope: &TenantScope, budget: &Budget, now: u64) -> Result<BudgetStatus, String> {
+    let devices = budget_devices(scope, &budget.scope)?;
+    Ok(status_of(scope, budget, &devices, now))
+}
+
+// Actual consumption so far and a straight-line projection of the current
+// run rate to the end of the period. Consumption is read from the rollups,
+// which cover the whole period unlike the raw reading buffers.
+fn status_of(scope: &TenantScope, budget: &Budget, devices: &[String], now: u64) -> BudgetStatus {
+    let as_of = now.clamp(budget.period_start, budget.period_end);
+    let actual: f64 = rollup::usage_between(scope, budget.utility, devices, budget.period_start, as_of)
+        .values()
+        .sum();
+    let elapsed = as_of - budget.period_start;
+    let projected = if elapsed > 0 {
+        actual * (budget.period_end - budget.period_start) as f64 / elapsed as f64
+    } else {
+        0.0
+    };
+
+    BudgetStatus {
+        budget: budget.clone(),
+        unit: budget.utility.unit().symbol().to_string(),
+        as_of,
+        actual,
+        projected,
+        percent_used: 100.0 * actual / budget.limit,
+        percent_projected: 100.0 * projected / budget.limit,
+    }
+}
+
+thread_local! {
+    static BUDGETS: RefCell<Budgets> = RefCell::new(Budgets::default());
+}
+
+pub fn export_state() -> Budgets {
+    BUDGETS.with(|b| b.borrow().clone())
+}
+
+pub fn import_state(budgets: Budgets) {
+    BUDGETS.with(|b| *b.borrow_mut() = budgets);
+}
+
+// When the heartbeat last checked budgets, in seconds; 0 before the first check
+pub fn last_check() -> u64 {
+    BUDGETS.with(|b| b.borrow().last_check)
+}
+
+// Feed the projection of every running budget into the alert engine as a
+// percentage of its limit, so a `BudgetProjection` rule above 100 fires when
+// the projection crosses the budget. Called from the heartbeat.
+pub fn check_budgets(now: u64) {
+    let running: Vec<Budget> = BUDGETS.with(|b| {
+        let mut budgets = b.borrow_mut();
+        if now < budgets.last_check + CHECK_INTERVAL {
+            return Vec::new();
+        }
+        budgets.last_check = now;
+        budgets
+            .budgets
+            .values()
+            .filter(|budget| budget.period_start <= now && now <= budget.period_end)
+            .cloned()
+            .collect()
+    });
+
+    for budget in running {
+        let result = tenant::with_registry(|registry| registry.scope_of_tenant(&budget.tenant_id))
+            .and_then(|scope| status(&scope, &budget, now));
+        match result {
+            Ok(status) => alerts::evaluate(
+                &budget.tenant_id,
+                budget.subject(),
+                AlertMetric::BudgetProjection(budget.id),
+                status.percent_projected,
+                now,
+            ),
+            Err(e) => ic_cdk::println!("Budget {} check failed: {}", budget.id, e),
+        }
+    }
+}
+
+fn find_budget(scope: &TenantScope, id: u64) -> Result<Budget, String> {
+    BUDGETS
+        .with(|b| b.borrow().budgets.get(&id).cloned())
+        .filter(|budget| budget.tenant_id == scope.tenant_id())
+        .ok_or_else(|| format!("Budget {} not found", id))
+}
+
+#[update(guard = "can_write_config")]
+fn create_budget(
+    name: String,
+    scope: BudgetScope,
+    utility: Utility,
+    limit: f64,
+    period_start: u64,
+    period_end: u64,
+) -> Result<Budget, String> {
+    let tenant_scope = tenant::member_scope()?;
+    budget_devices(&tenant_scope, &scope)?;
+
+    let mut budget = Budget {
+        id: 0,
+        tenant_id: tenant_scope.tenant_id().to_string(),
+        name,
+        scope,
+        utility,
+        limit,
+        period_start,
+        period_end,
+        created_at: ic_cdk::api::time() / 1_000_000_000,
+    };
+    budget.validate()?;
+
+    let result = BUDGETS.with(|b| {
+        let mut budgets = b.borrow_mut();
+        let count = budgets.budgets.values().filter(|x| x.tenant_id == budget.tenant_id).count();
+        if count >= MAX_BUDGETS_PER_TENANT {
+            return Err(format!("Tenant already has {} budgets", MAX_BUDGETS_PER_TENANT));
+        }
+        budgets.next_id += 1;
+        budget.id = budgets.next_id;
+        budgets.budgets.insert(budget.id, budget.clone());
+        Ok(budget)
+    });
+    let target = result.as_ref().map(|b| b.id.to_string()).unwrap_or_default();
+    let details = result.as_ref().map(|b| vec![b.name.clone(), b.limit.to_string()]).unwrap_or_default();
+    audit::on_success(result, "config.budget_create", Some(&target), details)
+}
+
+#[update(guard = "can_write_config")]
+fn delete_budget(id: u64) -> Result<(), String> {
+    let scope = tenant::member_scope()?;
+    find_budget(&scope, id)?;
+    BUDGETS.with(|b| b.borrow_mut().budgets.remove(&id));
+    audit::record("config.budget_delete", Some(&id.to_string()), Vec::new());
+    Ok(())
+}
+
+#[query(guard = "can_read_readings")]
+fn list_budgets(request: PageRequest) -> Result<Page<Budget>, String> {
+    let scope = tenant::member_scope()?;
+    let budgets = BUDGETS.with(|b| {
+        b.borrow()
+            .budgets
+            .values()
+            .filter(|budget| budget.tenant_id == scope.tenant_id())
+            .map(|budget| (budget.id, budget.clone()))
+            .collect()
+    });
+    pagination::paginate(budgets, Order::Ascending, &request)
+}
+
+#[query(guard = "can_read_readings")]
+fn get_budget_status(id: u64) -> Result<BudgetStatus, String> {
+    let scope = tenant::member_scope()?;
+    let budget = find_budget(&scope, id)?;
+    status(&scope, &budget, ic_cdk::api::time() / 1_000_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::electricity::{ELECTRICITY_DEVICE_TYPE, ENERGY_CHANNEL};
+    use crate::rollup::DAY;
+
+    fn scope(tenant_id: &str) -> TenantScope {
+        let mut registry = tenant::TenantRegistry::default();
+        registry.create_tenant(tenant_id.into(), "Tenant".into(), 0).unwrap();
+        registry.scope_of_tenant(tenant_id).unwrap()
+    }
+
+    fn budget(tenant_id: &str, utility: Utility, limit: f64, days: u64) -> Budget {
+        Budget {
+            id: 1,
+            tenant_id: tenant_id.into(),
+            name: "Monthly".into(),
+            scope: BudgetScope::Tenant,
+            utility,
+            limit,
+            period_start: 10 * DAY,
+            period_end: (10 + days) * DAY,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn run_rate_is_projected_to_the_end_of_the_period() {
+        let scope = scope("budget-a");
+        let devices = vec!["w-1".to_string()];
+        // Two m³ a day, for more days than the raw buffer would hold readings
+        for hour in 0..=(10 * 24) {
+            rollup::record_usage("budget-a", "w-1", Utility::Water, 10 * DAY + hour * 3600, hour as f64 / 12.0);
+        }
+        let status = status_of(&scope, &budget("budget-a", Utility::Water, 50.0, 30), &devices, 20 * DAY);
+        assert!((status.actual - 20.0).abs() < 1e-9);
+        assert!((status.projected - 60.0).abs() < 1e-9);
+        assert!((status.percent_used - 40.0).abs() < 1e-9);
+        assert!(status.percent_projected > 100.0);
+    }
+
+    #[test]
+    fn overrun_is_reported_after_the_period() {
+        let scope = scope("budget-b");
+        let devices = vec!["w-1".to_string()];
+        rollup::record_usage("budget-b", "w-1", Utility::Water, 10 * DAY, 0.0);
+        rollup::record_usage("budget-b", "w-1", Utility::Water, 12 * DAY, 30.0);
+        let status = status_of(&scope, &budget("budget-b", Utility::Water, 20.0, 2), &devices, 40 * DAY);
+        assert_eq!(status.as_of, 12 * DAY);
+        assert_eq!((status.actual, status.projected), (30.0, 30.0));
+        assert_eq!(status.percent_used, 150.0);
+
+        let before = status_of(&scope, &budget("budget-b", Utility::Water, 20.0, 2), &devices, 0);
+        assert_eq!((before.actual, before.projected), (0.0, 0.0));
+    }
+
+    #[test]
+    fn electricity_meters_feed_budgets_through_their_energy_channel() {
+        let scope = scope("budget-c");
+        let devices = vec!["e-1".to_string()];
+        for (day, kwh) in [(10, 1000.0), (11, 1012.0), (12, 1030.0)] {
+            rollup::record_channel("budget-c", "e-1", ELECTRICITY_DEVICE_TYPE, ENERGY_CHANNEL, kwh, day * DAY);
+        }
+        let status = status_of(&scope, &budget("budget-c", Utility::Electricity, 100.0, 4), &devices, 12 * DAY);
+        assert_eq!(status.unit, "kWh");
+        assert_eq!(status.actual, 30.0);
+        assert_eq!(status.projected, 60.0);
+        // Water usage of the same meters does not count
+        let water = status_of(&scope, &budget("budget-c", Utility::Water, 100.0, 4), &devices, 12 * DAY);
+        assert_eq!(water.actual, 0.0);
+    }
+}
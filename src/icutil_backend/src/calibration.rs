@@ -0,0 +1,377 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::audit;
+use crate::consumption::Utility;
+use crate::auth::{can_admin_devices, can_read_devices};
+use crate::rollup;
+use crate::tenant::{self, require_device};
+use crate::units::{self, Unit};
+use crate::VolumeReadings;
+
+const MAX_CALIBRATION_POINTS: usize = 32;
+const MAX_VERSIONS_PER_DEVICE: usize = 100;
+
+// Reference point of a multi-point curve: what the sensor reported vs the
+// value measured by the reference instrument
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CalibrationPoint {
+    pub raw: f64,
+    pub actual: f64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum CalibrationProfile {
+    // actual = raw * gain + offset
+    Linear { gain: f64, offset: f64 },
+    // Piecewise linear interpolation between points, extrapolated from the
+    // outermost segments
+    MultiPoint { points: Vec<CalibrationPoint> },
+    // Pulse flow meters: raw value is a pulse count, actual = pulses / k
+    KFactor { pulses_per_unit: f64 },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CalibrationVersion {
+    pub version: u32,
+    pub profile: CalibrationProfile,
+    pub effective_from: u64, // UNIX epoch seconds
+    pub created_at: u64,
+    pub created_by: String,
+    pub note: Option<String>,
+}
+
+fn finite(value: f64, what: &str) -> Result<(), String> {
+    if value.is_finite() {
+        Ok(())
+    } else {
+        Err(format!("{} must be a finite number", what))
+    }
+}
+
+impl CalibrationProfile {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            CalibrationProfile::Linear { gain, offset } => {
+                finite(*gain, "Gain")?;
+                finite(*offset, "Offset")?;
+                if *gain <= 0.0 {
+                    return Err("Gain must be positive".into());
+                }
+            }
+            CalibrationProfile::MultiPoint { points } => {
+                if points.len() < 2 || points.len() > MAX_CALIBRATION_POINTS {
+                    return Err(format!(
+                        "Multi-point calibration needs 2-{} points",
+                        MAX_CALIBRATION_POINTS
+                    ));
+                }
+                for point in points {
+                    finite(point.raw, "Calibration point")?;
+                    finite(point.actual, "Calibration point")?;
+                }
+                if points.windows(2).any(|w| w[1].raw <= w[0].raw) {
+                    return Err("Calibration points must be sorted by strictly increasing raw value".into());
+                }
+            }
+            CalibrationProfile::KFactor { pulses_per_unit } => {
+                finite(*pulses_per_unit, "K-factor")?;
+                if *pulses_per_unit <= 0.0 {
+                    return Err("K-factor must be positive".into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, raw: f64) -> f64 {
+        match self {
+            CalibrationProfile::Linear { gain, offset } => raw * gain + offset,
+            CalibrationProfile::MultiPoint { points } => {
+                // Pick the segment containing `raw`, or the outermost one
+                let segment = points
+                    .windows(2)
+                    .find(|w| raw <= w[1].raw)
+                    .unwrap_or(&points[points.len() - 2..]);
+                let (a, b) = (&segment[0], &segment[1]);
+                a.actual + (raw - a.raw) * (b.actual - a.actual) / (b.raw - a.raw)
+            }
+            CalibrationProfile::KFactor { pulses_per_unit } => raw / pulses_per_unit,
+        }
+    }
+}
+
+// Versioned calibration history per device
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Calibrations {
+    pub devices: HashMap<String, Vec<CalibrationVersion>>,
+}
+
+impl Calibrations {
+    pub fn add(&mut self, device_id: &str, mut entry: CalibrationVersion) -> Result<CalibrationVersion, String> {
+        entry.profile.validate()?;
+        let versions = self.devices.entry(device_id.to_string()).or_default();
+        if versions.len() >= MAX_VERSIONS_PER_DEVICE {
+            return Err(format!("Device already has {} calibration versions", MAX_VERSIONS_PER_DEVICE));
+        }
+        entry.version = versions.iter().map(|v| v.version).max().unwrap_or(0) + 1;
+        versions.push(entry.clone());
+        Ok(entry)
+    }
+
+    // Withdraw a version that was just added, when applying it failed
+    fn remove(&mut self, device_id: &str, version: u32) {
+        if let Some(versions) = self.devices.get_mut(device_id) {
+            versions.retain(|v| v.version != version);
+        }
+    }
+
+    // Calibration in force at `timestamp`. When several versions share the
+    // same effective date the newest one is a correction and wins.
+    pub fn active_at(&self, device_id: &str, timestamp: u64) -> Option<&CalibrationVersion> {
+        self.devices
+            .get(device_id)?
+            .iter()
+            .filter(|v| v.effective_from <= timestamp)
+            .max_by_key(|v| (v.effective_from, v.version))
+    }
+
+    // Calibrated value and the version used, or the raw value if the device
+    // has no calibration at that time
+    pub fn calibrate(&self, device_id: &str, timestamp: u64, raw: f64) -> (f64, Option<u32>) {
+        match self.active_at(device_id, timestamp) {
+            Some(entry) => (entry.profile.apply(raw), Some(entry.version)),
+            None => (raw, None),
+        }
+    }
+
+    // Recompute stored values of `device_id` from their raw values for every
+    // reading at or after `from`. Returns the number of readings rewritten.
+    pub fn recompute(&self, readings: &mut VolumeReadings, device_id: &str, from: u64) -> usize {
+        let mut updated = 0;
+        for reading in readings.iter_mut() {
+            if reading.device_id.as_deref() != Some(device_id) || reading.timestamp < from {
+                continue;
+            }
+            // Readings stored before calibration existed kept no raw copy;
+            // their stored value is the raw value
+            let raw = *reading.raw_volume.get_or_insert(reading.volume);
+            let unit = *reading.raw_unit.get_or_insert(Unit::CubicMeter);
+            let (calibrated, version) = self.calibrate(device_id, reading.timestamp, raw);
+            let Ok(volume) = units::convert(calibrated, unit, Unit::CubicMeter) else {
+                continue;
+            };
+            reading.volume = volume;
+            reading.calibration_version = version;
+            updated += 1;
+        }
+        updated
+    }
+}
+
+thread_local! {
+    static CALIBRATIONS: RefCell<Calibrations> = RefCell::new(Calibrations::default());
+}
+
+pub fn with_calibrations<R>(f: impl FnOnce(&Calibrations) -> R) -> R {
+    CALIBRATIONS.with(|c| f(&c.borrow()))
+}
+
+pub fn export_state() -> Calibrations {
+    with_calibrations(|c| c.clone())
+}
+
+pub fn import_state(calibrations: Calibrations) {
+    CALIBRATIONS.with(|c| *c.borrow_mut() = calibrations);
+}
+
+// Stored readings of a device in time order, starting with the last one
+// before `from`: the increments from there on depend on readings at or
+// after `from`
+fn usage_series(readings: &VolumeReadings, device_id: &str, from: u64) -> Vec<(String, u64, f64)> {
+    let mut series: Vec<(String, u64, f64)> = readings
+        .iter()
+        .filter(|r| r.device_id.as_deref() == Some(device_id))
+        .filter_map(|r| Some((r.tenant_id.clone()?, r.timestamp, r.volume)))
+        .collect();
+    series.sort_by_key(|(_, timestamp, _)| *timestamp);
+    let start = series.iter().rposition(|(_, timestamp, _)| *timestamp < from).unwrap_or(0);
+    series.split_off(start)
+}
+
+// Recompute the stored readings of a device and the usage rolled up from
+// them, so forecasts, M&V and budgets see the corrected values too
+fn recompute_history(device_id: &str, from: u64) -> Result<u64, String> {
+    let mut readings = crate::load_readings().map_err(|e| format!("{:?}", e))?;
+    let before = usage_series(&readings, device_id, from);
+    let updated = with_calibrations(|c| c.recompute(&mut readings, device_id, from));
+    let after = usage_series(&readings, device_id, from);
+    crate::save_readings(readings).map_err(|e| format!("{:?}", e))?;
+
+    let values = |series: &[(String, u64, f64)], tenant_id: &str| -> Vec<(u64, f64)> {
+        series.iter().filter(|(t, _, _)| t == tenant_id).map(|(_, ts, v)| (*ts, *v)).collect()
+    };
+    let tenants: BTreeSet<&String> = after.iter().map(|(t, _, _)| t).collect();
+    for tenant_id in tenants {
+        rollup::replace_usage(tenant_id, device_id, Utility::Water, &values(&before, tenant_id), &values(&after, tenant_id));
+    }
+    Ok(updated as u64)
+}
+
+// Add a calibration version. An effective date in the past is a retroactive
+// correction, so stored readings from that date on are recomputed.
+#[update(guard = "can_admin_devices")]
+fn set_calibration(
+    device_id: String,
+    profile: CalibrationProfile,
+    effective_from: Option<u64>,
+    note: Option<String>,
+) -> Result<CalibrationVersion, String> {
+    let scope = tenant::caller_scope()?;
+    require_device(&scope, &device_id)?;
+    profile.validate()?;
+
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let effective_from = effective_from.unwrap_or(now);
+    let entry = CalibrationVersion {
+        version: 0,
+        profile,
+        effective_from,
+        created_at: now,
+        created_by: ic_cdk::caller().to_string(),
+        note,
+    };
+    let result = CALIBRATIONS.with(|c| c.borrow_mut().add(&device_id, entry)).and_then(|entry| {
+        if effective_from < now {
+            match recompute_history(&device_id, effective_from) {
+                Ok(updated) => ic_cdk::println!(
+                    "Calibration v{} for {} applied retroactively to {} readings",
+                    entry.version, device_id, updated
+                ),
+                Err(e) => {
+                    // A failed call must not leave the version active
+                    CALIBRATIONS.with(|c| c.borrow_mut().remove(&device_id, entry.version));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(entry)
+    });
+    let details = match &result {
+        Ok(entry) => vec![format!("v{}", entry.version), format!("{:?}", entry.profile), format!("from {}", effective_from)],
+        Err(_) => Vec::new(),
+    };
+    audit::on_success(result, "device.calibrate", Some(&device_id), details)
+}
+
+// Shortcut for a plain gain correction effective immediately
+#[update(guard = "can_admin_devices")]
+fn calibrate_sensor(sensor_id: String, factor: f64) -> Result<CalibrationVersion, String> {
+    set_calibration(
+        sensor_id,
+        CalibrationProfile::Linear { gain: factor, offset: 0.0 },
+        None,
+        None,
+    )
+}
+
+#[update(guard = "can_admin_devices")]
+fn recompute_calibrated_readings(device_id: String, from: u64) -> Result<u64, String> {
+    let scope = tenant::caller_scope()?;
+    require_device(&scope, &device_id)?;
+    let result = recompute_history(&device_id, from);
+    audit::on_success(result, "device.recompute_calibration", Some(&device_id), vec![format!("from {}", from)])
+}
+
+#[query(guard = "can_read_devices")]
+fn get_calibration_history(device_id: String) -> Result<Vec<CalibrationVersion>, String> {
+    let scope = tenant::caller_scope()?;
+    require_device(&scope, &device_id)?;
+    Ok(with_calibrations(|c| c.devices.get(&device_id).cloned().unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VolumeReading;
+
+    fn version(profile: CalibrationProfile, effective_from: u64) -> CalibrationVersion {
+        CalibrationVersion { version: 0, profile, effective_from, created_at: 0, created_by: "admin".into(), note: None }
+    }
+
+    fn linear(gain: f64) -> CalibrationProfile {
+        CalibrationProfile::Linear { gain, offset: 0.0 }
+    }
+
+    fn point(raw: f64, actual: f64) -> CalibrationPoint {
+        CalibrationPoint { raw, actual }
+    }
+
+    #[test]
+    fn profiles_map_raw_values() {
+        assert_eq!(CalibrationProfile::Linear { gain: 2.0, offset: 1.0 }.apply(3.0), 7.0);
+        assert_eq!(CalibrationProfile::KFactor { pulses_per_unit: 400.0 }.apply(1000.0), 2.5);
+
+        let curve = CalibrationProfile::MultiPoint { points: vec![point(0.0, 0.0), point(10.0, 20.0), point(20.0, 30.0)] };
+        assert_eq!(curve.apply(5.0), 10.0);
+        assert_eq!(curve.apply(15.0), 25.0);
+        // Extrapolated from the outermost segments
+        assert_eq!(curve.apply(-1.0), -2.0);
+        assert_eq!(curve.apply(30.0), 40.0);
+    }
+
+    #[test]
+    fn invalid_profiles_are_rejected() {
+        assert!(linear(0.0).validate().is_err());
+        assert!(linear(f64::NAN).validate().is_err());
+        assert!(CalibrationProfile::KFactor { pulses_per_unit: -1.0 }.validate().is_err());
+        assert!(CalibrationProfile::MultiPoint { points: vec![point(0.0, 0.0)] }.validate().is_err());
+        let unsorted = vec![point(0.0, 0.0), point(5.0, 5.0), point(5.0, 6.0)];
+        assert!(CalibrationProfile::MultiPoint { points: unsorted }.validate().is_err());
+    }
+
+    #[test]
+    fn newest_version_wins_for_the_same_effective_date() {
+        let mut calibrations = Calibrations::default();
+        calibrations.add("m-1", version(linear(2.0), 100)).unwrap();
+        calibrations.add("m-1", version(linear(3.0), 200)).unwrap();
+        calibrations.add("m-1", version(linear(4.0), 100)).unwrap(); // Correction of v1
+        calibrations.add("m-1", version(linear(5.0), 300)).unwrap();
+
+        assert!(calibrations.active_at("m-1", 99).is_none());
+        assert_eq!(calibrations.active_at("m-1", 150).unwrap().version, 3);
+        assert_eq!(calibrations.active_at("m-1", 250).unwrap().version, 2);
+        assert_eq!(calibrations.calibrate("m-1", 300, 1.0), (5.0, Some(4)));
+        assert_eq!(calibrations.calibrate("m-2", 300, 1.0), (1.0, None));
+    }
+
+    #[test]
+    fn recompute_rewrites_readings_from_the_effective_date() {
+        let mut calibrations = Calibrations::default();
+        calibrations.add("m-1", version(linear(2.0), 20)).unwrap();
+        let reading = |timestamp: u64, device_id: &str| VolumeReading {
+            timestamp,
+            volume: 1.0,
+            device_id: Some(device_id.into()),
+            tenant_id: Some("acme".into()),
+            raw_volume: None,
+            raw_unit: None,
+            calibration_version: None,
+            seq: None,
+        };
+        let mut readings: VolumeReadings = vec![reading(10, "m-1"), reading(20, "m-1"), reading(30, "m-2")].into();
+
+        assert_eq!(calibrations.recompute(&mut readings, "m-1", 15), 1);
+        let volumes: Vec<f64> = readings.iter().map(|r| r.volume).collect();
+        assert_eq!(volumes, vec![1.0, 2.0, 1.0]);
+        assert_eq!(readings[1].raw_volume, Some(1.0));
+        assert_eq!(readings[1].calibration_version, Some(1));
+
+        // The run to re-aggregate starts at the last reading before the date
+        let series = usage_series(&readings, "m-1", 15);
+        assert_eq!(series.iter().map(|(_, ts, _)| *ts).collect::<Vec<_>>(), vec![10, 20]);
+    }
+}
@@ -0,0 +1,171 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::hierarchy;
+use crate::schema;
+use crate::tenant::{self, TenantScope};
+use crate::units::Unit;
+
+pub const HOUR: u64 = 3600;
+
+// Utility a consumption figure refers to
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum Utility {
+    #[default]
+    Water,
+    Gas,
+    Electricity,
+}
+
+impl Utility {
+    pub fn unit(&self) -> Unit {
+        match self {
+            Utility::Water => Unit::CubicMeter,
+            Utility::Gas => Unit::CubicMeter, // At standard conditions
+            Utility::Electricity => Unit::KilowattHour,
+        }
+    }
+}
+
+// A single meter, or a site, building or unit through its top-most meters
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum ConsumptionTarget {
+    Device(String),
+    Location(String),
+}
+
+impl ConsumptionTarget {
+    pub fn devices(&self, scope: &TenantScope) -> Result<Vec<String>, String> {
+        match self {
+            ConsumptionTarget::Device(device_id) => {
+                tenant::require_device(scope, device_id)?;
+                Ok(vec![device_id.clone()])
+            }
+            ConsumptionTarget::Location(node_id) => {
+                let meters: Vec<String> = hierarchy::with_hierarchy(|h| {
+                    h.top_meters(scope, node_id)
+                        .map(|meters| meters.into_iter().map(|m| m.device_id.clone()).collect())
+                })?;
+                if meters.is_empty() {
+                    return Err(format!("Location {} has no meters", node_id));
+                }
+                Ok(meters)
+            }
+        }
+    }
+}
+
+// Consumption per device between `start` and `end` (inclusive, seconds) from
+// cumulative meter values given as (device_id, timestamp, value).
+//
+// The last value before `start` is used as the baseline so usage that crosses
+// the window boundary is not lost. Only positive increments are counted, so a
+// meter reset or rollover does not produce negative consumption.
+pub fn device_consumption<'a>(
+    points: impl Iterator<Item = (&'a str, u64, f64)>,
+    start: u64,
+    end: u64,
+) -> HashMap<String, f64> {
+    let mut series: HashMap<&str, Vec<(u64, f64)>> = HashMap::new();
+    for (device_id, timestamp, value) in points {
+        if timestamp <= end {
+            series.entry(device_id).or_default().push((timestamp, value));
+        }
+    }
+
+    let mut result = HashMap::new();
+    for (device_id, mut points) in series {
+        points.sort_by_key(|(timestamp, _)| *timestamp);
+
+        let baseline = points.iter().rev().find(|(ts, _)| *ts < start).map(|(_, v)| *v);
+        let mut previous = baseline;
+        let mut total = 0.0;
+        let mut seen = false;
+
+        for &(_, value) in points.iter().filter(|(ts, _)| *ts >= start) {
+            seen = true;
+            if let Some(prev) = previous {
+                if value > prev {
+                    total += value - prev;
+                }
+            }
+            previous = Some(value);
+        }
+
+        if seen {
+            result.insert(device_id.to_string(), total);
+        }
+    }
+    result
+}
+
+// Cumulative meter values of the scope's tenant for one utility, as
+// (device_id, timestamp, value) in the utility's unit
+pub fn tenant_points(scope: &TenantScope, utility: Utility) -> Result<Vec<(String, u64, f64)>, String> {
+    match utility {
+        Utility::Water => {
+            let readings = crate::load_readings().map_err(|e| format!("{:?}", e))?;
+            Ok(scope
+                .filter(readings)
+                .into_iter()
+                .filter_map(|r| r.device_id.map(|id| (id, r.timestamp, r.volume)))
+                .collect())
+        }
+        Utility::Gas => Ok(channel_points(
+            scope,
+            crate::gas::GAS_DEVICE_TYPE,
+            crate::gas::STANDARD_VOLUME_CHANNEL,
+        )),
+        Utility::Electricity => Ok(channel_points(
+            scope,
+            crate::electricity::ELECTRICITY_DEVICE_TYPE,
+            crate::electricity::ENERGY_CHANNEL,
+        )),
+    }
+}
+
+// Utility measured by a channel, if it is the consumption channel of one of
+// the built-in meter types
+pub fn channel_utility(device_type: &str, channel: &str) -> Option<Utility> {
+    match (device_type, channel) {
+        (crate::gas::GAS_DEVICE_TYPE, crate::gas::STANDARD_VOLUME_CHANNEL) => Some(Utility::Gas),
+        (crate::electricity::ELECTRICITY_DEVICE_TYPE, crate::electricity::ENERGY_CHANNEL) => Some(Utility::Electricity),
+        _ => None,
+    }
+}
+
+// Samples of a cumulative channel for the scope's devices of one device type
+fn channel_points(scope: &TenantScope, device_type: &str, channel: &str) -> Vec<(String, u64, f64)> {
+    let devices: Vec<String> = tenant::with_registry(|registry| registry.devices_of(scope))
+        .into_iter()
+        .filter(|id| schema::with_schemas(|s| s.devices.get(id).map_or(false, |t| t == device_type)))
+        .collect();
+    schema::with_channels(|store| {
+        devices
+            .iter()
+            .flat_map(|device_id| {
+                store
+                    .samples(device_id, channel)
+                    .into_iter()
+                    .flatten()
+                    .map(move |s| (device_id.clone(), s.timestamp, s.value))
+            })
+            .collect()
+    })
+}
+
+// Consumption per device of the scope's tenant for one utility
+pub fn tenant_usage(
+    scope: &TenantScope,
+    utility: Utility,
+    start: u64,
+    end: u64,
+) -> Result<HashMap<String, f64>, String> {
+    let points = tenant_points(scope, utility)?;
+    Ok(device_consumption(
+        points.iter().map(|(id, ts, value)| (id.as_str(), *ts, *value)),
+        start,
+        end,
+    ))
+}
@@ -0,0 +1,31 @@
+use crate::schema::{self, ChannelKind, ChannelSpec, DataType, DeviceType};
+
+pub const ELECTRICITY_DEVICE_TYPE: &str = "electricity_meter";
+pub const ENERGY_CHANNEL: &str = "energy";
+
+// Built-in schema for electricity meters. Meters report their energy
+// register through `record_channel_readings` like any other schema device;
+// per-interval readings without a device go to electricity_backend.
+pub fn device_type(now: u64) -> DeviceType {
+    DeviceType {
+        name: ELECTRICITY_DEVICE_TYPE.to_string(),
+        description: "Electricity meter reporting its energy register".to_string(),
+        channels: vec![ChannelSpec {
+            name: ENERGY_CHANNEL.to_string(),
+            unit: Some("kWh".to_string()),
+            data_type: DataType::Float,
+            min: Some(0.0),
+            max: None,
+            kind: ChannelKind::Cumulative,
+        }],
+        updated_at: now,
+    }
+}
+
+// Register the electricity meter schema if it is missing
+pub fn ensure_device_type(now: u64) {
+    if schema::with_schemas(|s| s.types.contains_key(ELECTRICITY_DEVICE_TYPE)) {
+        return;
+    }
+    schema::register_device_type(device_type(now));
+}
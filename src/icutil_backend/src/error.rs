@@ -12,4 +12,15 @@ pub enum SensorError {
 
     #[error("Rate limited: {0}")]
     RateLimit(String)
-}
\ No newline at end of file
+}
+
+impl SensorError {
+    // HTTP status reported by the gateway for this error
+    pub fn status_code(&self) -> u16 {
+        match self {
+            SensorError::Validation(_) => 400,
+            SensorError::RateLimit(_) => 429,
+            SensorError::Storage { .. } => 500,
+        }
+    }
+}
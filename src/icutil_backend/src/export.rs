@@ -0,0 +1,209 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+use serde::Serialize;
+
+use crate::auth::can_read_readings;
+use crate::parquet::{self, Column, ColumnData};
+use crate::tenant;
+use crate::VolumeReading;
+
+const DEFAULT_CHUNK_ROWS: u32 = 500;
+const MAX_CHUNK_ROWS: u32 = 1000;
+const CURSOR_PREFIX: &str = "r";
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,       // Header row in the first chunk only, so chunks can be concatenated
+    JsonLines, // One object per line with the column names as keys
+    Parquet,   // Every chunk is a complete Parquet file with one row group
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ExportRequest {
+    pub format: ExportFormat,
+    pub start: Option<u64>, // Inclusive, seconds
+    pub end: Option<u64>,   // Inclusive, seconds
+    pub device_ids: Option<Vec<String>>,
+    pub cursor: Option<String>, // `next_cursor` of the previous chunk
+    pub limit: Option<u32>,     // Rows per chunk, default 500, at most 1000
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ExportChunk {
+    pub format: ExportFormat,
+    pub data: Vec<u8>,
+    pub rows: u32,
+    pub next_cursor: Option<String>, // None once the export is complete
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ExportColumn {
+    pub name: String,
+    pub data_type: String, // uint64, uint32, float64 or utf8
+    pub nullable: bool,
+    pub description: String,
+}
+
+// Column layout shared by every format. Columns are only ever appended so
+// existing consumers keep working.
+const COLUMNS: [(&str, &str, bool, &str); 7] = [
+    ("seq", "uint64", false, "Insertion order of the reading"),
+    ("timestamp", "uint64", false, "Seconds since the UNIX epoch"),
+    ("device_id", "utf8", true, "Reporting device"),
+    ("volume_m3", "float64", false, "Calibrated volume in cubic meters"),
+    ("raw_volume", "float64", true, "Value as reported by the sensor"),
+    ("raw_unit", "utf8", true, "Unit of raw_volume"),
+    ("calibration_version", "uint32", true, "Calibration applied to volume_m3"),
+];
+
+// One exported row; field order matches COLUMNS
+#[derive(Serialize)]
+struct ExportRow<'a> {
+    seq: u64,
+    timestamp: u64,
+    device_id: Option<&'a str>,
+    volume_m3: f64,
+    raw_volume: Option<f64>,
+    raw_unit: Option<&'static str>,
+    calibration_version: Option<u32>,
+}
+
+impl<'a> ExportRow<'a> {
+    fn new(reading: &'a VolumeReading) -> Self {
+        ExportRow {
+            seq: reading.seq.unwrap_or(0),
+            timestamp: reading.timestamp,
+            device_id: reading.device_id.as_deref(),
+            volume_m3: reading.volume,
+            raw_volume: reading.raw_volume,
+            raw_unit: reading.raw_unit.map(|u| u.symbol()),
+            calibration_version: reading.calibration_version,
+        }
+    }
+}
+
+fn encode_cursor(seq: u64) -> String {
+    format!("{}{}", CURSOR_PREFIX, seq)
+}
+
+fn decode_cursor(cursor: &str) -> Result<u64, String> {
+    cursor
+        .strip_prefix(CURSOR_PREFIX)
+        .and_then(|seq| seq.parse().ok())
+        .ok_or_else(|| "Invalid export cursor".to_string())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn to_csv(rows: &[ExportRow], header: bool) -> Vec<u8> {
+    let optional = |v: Option<String>| v.unwrap_or_default();
+    let mut out = String::new();
+    if header {
+        let names: Vec<&str> = COLUMNS.iter().map(|c| c.0).collect();
+        out.push_str(&names.join(","));
+        out.push('\n');
+    }
+    for row in rows {
+        let fields = [
+            row.seq.to_string(),
+            row.timestamp.to_string(),
+            optional(row.device_id.map(csv_field)),
+            row.volume_m3.to_string(),
+            optional(row.raw_volume.map(|v| v.to_string())),
+            optional(row.raw_unit.map(csv_field)),
+            optional(row.calibration_version.map(|v| v.to_string())),
+        ];
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out.into_bytes()
+}
+
+fn to_json_lines(rows: &[ExportRow]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    for row in rows {
+        serde_json::to_writer(&mut out, row).map_err(|e| format!("JSON encoding failed: {}", e))?;
+        out.push(b'\n');
+    }
+    Ok(out)
+}
+
+fn to_parquet(rows: &[ExportRow]) -> Vec<u8> {
+    let data = vec![
+        ColumnData::UInt64(rows.iter().map(|r| Some(r.seq)).collect()),
+        ColumnData::UInt64(rows.iter().map(|r| Some(r.timestamp)).collect()),
+        ColumnData::Utf8(rows.iter().map(|r| r.device_id).collect()),
+        ColumnData::Float64(rows.iter().map(|r| Some(r.volume_m3)).collect()),
+        ColumnData::Float64(rows.iter().map(|r| r.raw_volume).collect()),
+        ColumnData::Utf8(rows.iter().map(|r| r.raw_unit).collect()),
+        ColumnData::UInt32(rows.iter().map(|r| r.calibration_version).collect()),
+    ];
+    let columns: Vec<Column> = COLUMNS
+        .iter()
+        .zip(data)
+        .map(|((name, _, nullable, _), data)| Column { name, nullable: *nullable, data })
+        .collect();
+    parquet::write(&columns, rows.len())
+}
+
+#[query]
+fn get_export_schema() -> Vec<ExportColumn> {
+    COLUMNS
+        .iter()
+        .map(|(name, data_type, nullable, description)| ExportColumn {
+            name: name.to_string(),
+            data_type: data_type.to_string(),
+            nullable: *nullable,
+            description: description.to_string(),
+        })
+        .collect()
+}
+
+// Export the caller's readings in sequence order, one chunk per call. Pass
+// `next_cursor` back with the same filters to resume where a chunk ended.
+#[query(guard = "can_read_readings")]
+fn export_readings(request: ExportRequest) -> Result<ExportChunk, String> {
+    let scope = tenant::caller_scope()?;
+    let limit = request.limit.unwrap_or(DEFAULT_CHUNK_ROWS);
+    if limit == 0 || limit > MAX_CHUNK_ROWS {
+        return Err(format!("Limit must be 1-{}", MAX_CHUNK_ROWS));
+    }
+    let after = request.cursor.as_deref().map(decode_cursor).transpose()?;
+    if let Some(ref devices) = request.device_ids {
+        for device_id in devices {
+            tenant::require_device(&scope, device_id)?;
+        }
+    }
+
+    let readings = scope.filter(crate::load_readings().map_err(|e| format!("{:?}", e))?);
+    let mut matching = readings.iter().filter(|r| {
+        after.map_or(true, |after| r.seq.map_or(false, |seq| seq > after))
+            && request.start.map_or(true, |start| r.timestamp >= start)
+            && request.end.map_or(true, |end| r.timestamp <= end)
+            && request.device_ids.as_ref().map_or(true, |devices| {
+                r.device_id.as_ref().map_or(false, |id| devices.contains(id))
+            })
+    });
+    let chunk: Vec<&VolumeReading> = matching.by_ref().take(limit as usize).collect();
+    let more = matching.next().is_some();
+
+    let rows: Vec<ExportRow> = chunk.iter().map(|r| ExportRow::new(r)).collect();
+    let data = match request.format {
+        ExportFormat::Csv => to_csv(&rows, request.cursor.is_none()),
+        ExportFormat::JsonLines => to_json_lines(&rows)?,
+        ExportFormat::Parquet => to_parquet(&rows),
+    };
+
+    Ok(ExportChunk {
+        format: request.format,
+        data,
+        rows: rows.len() as u32,
+        next_cursor: if more { rows.last().map(|r| encode_cursor(r.seq)) } else { None },
+    })
+}
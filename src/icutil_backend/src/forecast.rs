@@ -0,0 +1,400 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+use serde::Serialize;
+
+use crate::auth::can_read_readings;
+use crate::consumption::{ConsumptionTarget, Utility, HOUR};
+use crate::rollup;
+use crate::tenant::{self, TenantScope};
+
+const DAY_HOURS: usize = 24;
+const WEEK_HOURS: usize = 168;
+const HISTORY_DAYS: u64 = 28;
+const MIN_HISTORY_HOURS: usize = 48;
+const MAX_HOURLY_PERIODS: u32 = 168;
+const MAX_DAILY_PERIODS: u32 = 14;
+const Z_95: f64 = 1.96;
+
+// Smoothing constants tried when fitting; the pair with the lowest
+// one-step-ahead error on the history is used
+const ALPHAS: [f64; 5] = [0.05, 0.1, 0.2, 0.3, 0.5];
+const GAMMAS: [f64; 3] = [0.05, 0.1, 0.2];
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Granularity {
+    Hourly,
+    Daily, // UTC days
+}
+
+impl Granularity {
+    fn hours(&self) -> usize {
+        match self {
+            Granularity::Hourly => 1,
+            Granularity::Daily => DAY_HOURS,
+        }
+    }
+
+    fn max_periods(&self) -> u32 {
+        match self {
+            Granularity::Hourly => MAX_HOURLY_PERIODS,
+            Granularity::Daily => MAX_DAILY_PERIODS,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ForecastPoint {
+    pub start: u64,
+    pub end: u64,
+    pub value: f64,
+    pub lower: f64, // 95% prediction interval
+    pub upper: f64,
+}
+
+// Accuracy of the model when refitted without the most recent history and
+// asked to forecast it
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Backtest {
+    pub periods: u32,
+    pub mae: f64,
+    pub wape: Option<f64>, // Percent; None when the held-out usage is zero
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Forecast {
+    pub target: ConsumptionTarget,
+    pub utility: Utility,
+    pub granularity: Granularity,
+    pub unit: String,
+    pub history_start: u64,
+    pub history_end: u64,
+    pub alpha: f64,
+    pub gamma: f64,
+    pub points: Vec<ForecastPoint>,
+    pub backtest: Option<Backtest>,
+}
+
+// Additive seasonal exponential smoothing with an hour-of-week profile:
+//   level    l = alpha * (y - s[h]) + (1 - alpha) * l
+//   season   s[h] = gamma * (y - l) + (1 - gamma) * s[h]
+//   forecast y^ = l + s[h]
+#[derive(Clone, Debug)]
+struct SeasonalModel {
+    alpha: f64,
+    gamma: f64,
+    level: f64,
+    seasonal: Vec<f64>,
+    next_hour: u64,
+    sigma: f64, // Std deviation of the one-step-ahead errors
+}
+
+impl SeasonalModel {
+    fn fit(start_hour: u64, values: &[f64], alpha: f64, gamma: f64) -> (SeasonalModel, f64) {
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let slot = |hour: u64| (hour % WEEK_HOURS as u64) as usize;
+
+        // Start from the average of every hour-of-week slot. Slots never seen
+        // yet fall back to the same hour of the day, then to zero.
+        let mut week_sum = vec![(0.0, 0usize); WEEK_HOURS];
+        let mut day_sum = vec![(0.0, 0usize); DAY_HOURS];
+        for (i, value) in values.iter().enumerate() {
+            let hour = start_hour + i as u64;
+            week_sum[slot(hour)].0 += value;
+            week_sum[slot(hour)].1 += 1;
+            day_sum[slot(hour) % DAY_HOURS].0 += value;
+            day_sum[slot(hour) % DAY_HOURS].1 += 1;
+        }
+        let seasonal = (0..WEEK_HOURS)
+            .map(|s| match (week_sum[s], day_sum[s % DAY_HOURS]) {
+                ((sum, n), _) if n > 0 => sum / n as f64 - mean,
+                (_, (sum, n)) if n > 0 => sum / n as f64 - mean,
+                _ => 0.0,
+            })
+            .collect();
+
+        let mut model = SeasonalModel {
+            alpha,
+            gamma,
+            level: mean,
+            seasonal,
+            next_hour: start_hour,
+            sigma: 0.0,
+        };
+        let mut sse = 0.0;
+        for &value in values {
+            let s = slot(model.next_hour);
+            let error = value - (model.level + model.seasonal[s]);
+            sse += error * error;
+            model.level = alpha * (value - model.seasonal[s]) + (1.0 - alpha) * model.level;
+            model.seasonal[s] = gamma * (value - model.level) + (1.0 - gamma) * model.seasonal[s];
+            model.next_hour += 1;
+        }
+        model.sigma = (sse / values.len() as f64).sqrt();
+        (model, sse)
+    }
+
+    fn fit_best(start_hour: u64, values: &[f64]) -> SeasonalModel {
+        let mut best: Option<(SeasonalModel, f64)> = None;
+        for &alpha in ALPHAS.iter() {
+            for &gamma in GAMMAS.iter() {
+                let (model, sse) = SeasonalModel::fit(start_hour, values, alpha, gamma);
+                if best.as_ref().map_or(true, |(_, best_sse)| sse < *best_sse) {
+                    best = Some((model, sse));
+                }
+            }
+        }
+        best.map(|(model, _)| model).expect("smoothing grid is not empty")
+    }
+
+    // Hourly forecasts as (value, variance). The variance of an h-step
+    // forecast grows as sigma^2 * (1 + (h - 1) * alpha^2).
+    fn predict(&self, hours: usize) -> Vec<(f64, f64)> {
+        (0..hours)
+            .map(|h| {
+                let hour = self.next_hour + h as u64;
+                let value = self.level + self.seasonal[(hour % WEEK_HOURS as u64) as usize];
+                let variance = self.sigma.powi(2) * (1.0 + h as f64 * self.alpha.powi(2));
+                (value.max(0.0), variance)
+            })
+            .collect()
+    }
+}
+
+// Forecast `periods` periods following the hourly history. Daily periods
+// start at the first UTC midnight after the history; errors of the hours in
+// a period are treated as independent when summing variances.
+fn forecast_periods(
+    model: &SeasonalModel,
+    granularity: Granularity,
+    periods: usize,
+) -> Vec<ForecastPoint> {
+    let step = granularity.hours();
+    let skip = match granularity {
+        Granularity::Hourly => 0,
+        Granularity::Daily => (DAY_HOURS - (model.next_hour % DAY_HOURS as u64) as usize) % DAY_HOURS,
+    };
+    let hourly = model.predict(skip + periods * step);
+
+    hourly[skip..]
+        .chunks(step)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let value: f64 = chunk.iter().map(|(v, _)| v).sum();
+            let margin = Z_95 * chunk.iter().map(|(_, var)| var).sum::<f64>().sqrt();
+            let start = (model.next_hour + (skip + i * step) as u64) * HOUR;
+            ForecastPoint {
+                start,
+                end: start + step as u64 * HOUR,
+                value,
+                lower: (value - margin).max(0.0),
+                upper: value + margin,
+            }
+        })
+        .collect()
+}
+
+// Refit without the last week (or a quarter of the history if shorter) and
+// compare the forecast for that span with what was actually used
+fn backtest(start_hour: u64, values: &[f64], granularity: Granularity) -> Option<Backtest> {
+    let step = granularity.hours();
+    let holdout = (values.len() / 4).min(WEEK_HOURS) / step * step;
+    if holdout == 0 || values.len() - holdout < MIN_HISTORY_HOURS {
+        return None;
+    }
+    let (train, test) = values.split_at(values.len() - holdout);
+    let model = SeasonalModel::fit_best(start_hour, train);
+    let predicted = model.predict(holdout);
+
+    let mut abs_error = 0.0;
+    let mut actual_total = 0.0;
+    for (actual, forecast) in test.chunks(step).zip(predicted.chunks(step)) {
+        let actual: f64 = actual.iter().sum();
+        let forecast: f64 = forecast.iter().map(|(v, _)| v).sum();
+        abs_error += (actual - forecast).abs();
+        actual_total += actual;
+    }
+    let periods = holdout / step;
+    Some(Backtest {
+        periods: periods as u32,
+        mae: abs_error / periods as f64,
+        wape: (actual_total > 0.0).then(|| 100.0 * abs_error / actual_total),
+    })
+}
+
+fn forecast(
+    target: ConsumptionTarget,
+    utility: Utility,
+    granularity: Granularity,
+    periods: u32,
+    scope: &TenantScope,
+    devices: &[String],
+    now: u64,
+) -> Result<Forecast, String> {
+    if periods == 0 || periods > granularity.max_periods() {
+        return Err(format!(
+            "Periods must be 1-{} for {:?} forecasts",
+            granularity.max_periods(),
+            granularity
+        ));
+    }
+
+    // History covers whole hours from the first reading up to the hour of
+    // the latest one. Hours after it have no usage recorded yet, which is not
+    // the same as using nothing.
+    let first = rollup::first_reading(scope, utility, devices).ok_or("No readings to forecast from")?;
+    let last = rollup::last_reading(scope, utility, devices).unwrap_or(first);
+    let end_hour = last.min(now) / HOUR;
+    let start_hour = (end_hour.saturating_sub(HISTORY_DAYS * 24)).max(first / HOUR);
+    let hours = end_hour.saturating_sub(start_hour) as usize;
+    if hours < MIN_HISTORY_HOURS {
+        return Err(format!("At least {} hours of history are needed", MIN_HISTORY_HOURS));
+    }
+
+    let values = rollup::hourly_usage(scope, utility, devices, start_hour, hours);
+    let model = SeasonalModel::fit_best(start_hour, &values);
+    Ok(Forecast {
+        target,
+        utility,
+        granularity,
+        unit: utility.unit().symbol().to_string(),
+        history_start: start_hour * HOUR,
+        history_end: end_hour * HOUR,
+        alpha: model.alpha,
+        gamma: model.gamma,
+        points: forecast_periods(&model, granularity, periods as usize),
+        backtest: backtest(start_hour, &values, granularity),
+    })
+}
+
+#[query(guard = "can_read_readings")]
+fn forecast_device_consumption(
+    device_id: String,
+    utility: Option<Utility>,
+    granularity: Granularity,
+    periods: u32,
+) -> Result<Forecast, String> {
+    forecast_target(ConsumptionTarget::Device(device_id), utility, granularity, periods)
+}
+
+// Forecast for a site, building or unit from its top-most meters
+#[query(guard = "can_read_readings")]
+fn forecast_location_consumption(
+    node_id: String,
+    utility: Option<Utility>,
+    granularity: Granularity,
+    periods: u32,
+) -> Result<Forecast, String> {
+    forecast_target(ConsumptionTarget::Location(node_id), utility, granularity, periods)
+}
+
+fn forecast_target(
+    target: ConsumptionTarget,
+    utility: Option<Utility>,
+    granularity: Granularity,
+    periods: u32,
+) -> Result<Forecast, String> {
+    let scope = tenant::caller_scope()?;
+    let utility = utility.unwrap_or_default();
+    let devices = target.devices(&scope)?;
+    forecast(target, utility, granularity, periods, &scope, &devices, ic_cdk::api::time() / 1_000_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    // A model with a flat profile and a known error, so intervals can be
+    // worked out by hand
+    fn flat_model(level: f64, sigma: f64, next_hour: u64) -> SeasonalModel {
+        SeasonalModel { alpha: 0.5, gamma: 0.1, level, seasonal: vec![0.0; WEEK_HOURS], next_hour, sigma }
+    }
+
+    #[test]
+    fn constant_usage_is_forecast_exactly() {
+        let start_hour = 24 * 100;
+        let model = SeasonalModel::fit_best(start_hour, &[2.0; 96]);
+        // Every pair fits without error; the first one in the grid wins
+        assert_eq!((model.alpha, model.gamma), (ALPHAS[0], GAMMAS[0]));
+        assert_eq!(model.sigma, 0.0);
+        assert_eq!(model.next_hour, start_hour + 96);
+
+        let points = forecast_periods(&model, Granularity::Daily, 2);
+        assert_eq!(points.len(), 2);
+        assert!(close(points[0].value, 48.0));
+        assert_eq!((points[0].lower, points[0].upper), (points[0].value, points[0].value));
+        assert_eq!(points[0].start, (start_hour + 96) * HOUR);
+        assert_eq!(points[1].start, points[0].end);
+    }
+
+    #[test]
+    fn hourly_intervals_widen_with_the_horizon() {
+        let points = forecast_periods(&flat_model(10.0, 2.0, 500), Granularity::Hourly, 3);
+        // Variance is sigma^2 * (1 + h * alpha^2): 4, 5 and 6
+        for (point, variance) in points.iter().zip([4.0, 5.0, 6.0]) {
+            assert!(close(point.value, 10.0));
+            assert!(close(point.upper - point.value, Z_95 * f64::sqrt(variance)));
+        }
+        assert!(close(points[0].lower, 10.0 - Z_95 * 2.0));
+        assert_eq!(points[0].start, 500 * HOUR);
+    }
+
+    #[test]
+    fn daily_periods_start_at_the_next_midnight() {
+        // History ends at 20:00, so the first four forecast hours are skipped
+        let points = forecast_periods(&flat_model(10.0, 2.0, 24 * 10 + 20), Granularity::Daily, 1);
+        assert_eq!(points[0].start, 24 * 11 * HOUR);
+        assert_eq!(points[0].end, 24 * 12 * HOUR);
+        assert!(close(points[0].value, 240.0));
+        // Variances of hours 4 to 27 ahead: sum of 4 * (1 + h / 4) = 468
+        assert!(close(points[0].upper - points[0].value, Z_95 * f64::sqrt(468.0)));
+    }
+
+    #[test]
+    fn forecasts_never_go_negative() {
+        let points = forecast_periods(&flat_model(-1.0, 3.0, 0), Granularity::Hourly, 1);
+        assert_eq!(points[0].value, 0.0);
+        assert_eq!(points[0].lower, 0.0);
+    }
+
+    #[test]
+    fn backtest_of_a_perfect_fit_has_no_error() {
+        let hourly = backtest(0, &[1.0; 96], Granularity::Hourly).unwrap();
+        // A quarter of 96 hours is held out
+        assert_eq!(hourly.periods, 24);
+        assert!(close(hourly.mae, 0.0));
+        assert_eq!(hourly.wape, Some(0.0));
+
+        let daily = backtest(0, &[1.0; 96], Granularity::Daily).unwrap();
+        assert_eq!(daily.periods, 1);
+    }
+
+    #[test]
+    fn history_ends_at_the_latest_reading() {
+        let mut registry = tenant::TenantRegistry::default();
+        registry.create_tenant("acme".into(), "Acme".into(), 0).unwrap();
+        let scope = registry.scope_of_tenant("acme").unwrap();
+        let devices = vec!["m-1".to_string()];
+        // One unit per hour for four days, then nothing reported for two
+        let start = 24 * 100 * HOUR;
+        for h in 0..=96 {
+            rollup::record_usage("acme", "m-1", Utility::Water, start + h * HOUR, h as f64);
+        }
+        let now = start + 144 * HOUR;
+
+        let target = ConsumptionTarget::Device("m-1".into());
+        let forecast = forecast(target, Utility::Water, Granularity::Hourly, 3, &scope, &devices, now).unwrap();
+        assert_eq!(forecast.history_end, start + 96 * HOUR);
+        assert_eq!(forecast.points[0].start, start + 96 * HOUR);
+        assert!(forecast.points.iter().all(|p| close(p.value, 1.0)));
+    }
+
+    #[test]
+    fn backtest_needs_enough_history() {
+        assert!(backtest(0, &[1.0; 60], Granularity::Hourly).is_none());
+        assert!(backtest(0, &[0.0; 96], Granularity::Hourly).unwrap().wape.is_none());
+    }
+}
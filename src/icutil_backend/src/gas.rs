@@ -0,0 +1,296 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::audit;
+use crate::auth::{can_admin_devices, can_read_readings, can_write_readings};
+use crate::metrics;
+use crate::schema::{self, ChannelKind, ChannelSpec, ChannelValue, DataType, DeviceType};
+use crate::tenant;
+use crate::units::{self, Quantity, Unit};
+
+pub const GAS_DEVICE_TYPE: &str = "gas_meter";
+pub const RAW_VOLUME_CHANNEL: &str = "volume";
+pub const STANDARD_VOLUME_CHANNEL: &str = "volume_std";
+pub const ENERGY_CHANNEL: &str = "energy";
+pub const TEMPERATURE_CHANNEL: &str = "temperature";
+pub const PRESSURE_CHANNEL: &str = "pressure";
+
+const KELVIN_OFFSET: f64 = 273.15;
+const MEGAJOULES_PER_KWH: f64 = 3.6;
+const MAX_CALORIFIC_VALUE: f64 = 120.0; // MJ/m³, above any fuel gas
+
+// Conversion settings of one gas meter
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct GasMeterConfig {
+    pub calorific_value: f64,         // MJ per standard m³
+    pub base_temperature: f64,        // °C of the standard conditions, usually 15
+    pub base_pressure: f64,           // kPa absolute of the standard conditions, usually 101.325
+    pub default_temperature: f64,     // °C used when a reading carries no temperature
+    pub default_pressure: f64,        // kPa absolute used when a reading carries no pressure
+}
+
+impl Default for GasMeterConfig {
+    fn default() -> Self {
+        GasMeterConfig {
+            calorific_value: 39.0,
+            base_temperature: 15.0,
+            base_pressure: 101.325,
+            default_temperature: 15.0,
+            default_pressure: 101.325,
+        }
+    }
+}
+
+impl GasMeterConfig {
+    fn validate(&self) -> Result<(), String> {
+        if !(self.calorific_value > 0.0 && self.calorific_value <= MAX_CALORIFIC_VALUE) {
+            return Err(format!("Calorific value must be in (0, {}] MJ/m3", MAX_CALORIFIC_VALUE));
+        }
+        for temperature in [self.base_temperature, self.default_temperature] {
+            if !temperature.is_finite() || temperature + KELVIN_OFFSET <= 0.0 {
+                return Err("Temperatures must be above absolute zero".into());
+            }
+        }
+        for pressure in [self.base_pressure, self.default_pressure] {
+            if !(pressure.is_finite() && pressure > 0.0) {
+                return Err("Pressures must be positive".into());
+            }
+        }
+        Ok(())
+    }
+
+    // Factor turning metered volume into volume at standard conditions,
+    // from the ideal gas law: Vs = V * (P / Pb) * (Tb / T)
+    pub fn correction_factor(&self, temperature: f64, pressure: f64) -> f64 {
+        (pressure / self.base_pressure)
+            * ((self.base_temperature + KELVIN_OFFSET) / (temperature + KELVIN_OFFSET))
+    }
+
+    pub fn energy_kwh(&self, standard_volume: f64) -> f64 {
+        standard_volume * self.calorific_value / MEGAJOULES_PER_KWH
+    }
+}
+
+// Running totals per meter. Correction is applied to each increment with
+// the conditions at that time, so the totals are kept here rather than
+// recomputed from the raw index.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct GasMeterState {
+    pub config: GasMeterConfig,
+    pub last_raw_volume: Option<f64>,
+    pub standard_volume: f64,
+    pub energy: f64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct GasReadingResult {
+    pub raw_volume: f64,      // m³ as metered
+    pub standard_volume: f64, // m³ at standard conditions, cumulative
+    pub energy: f64,          // kWh, cumulative
+    pub correction_factor: f64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct GasMeters {
+    pub meters: HashMap<String, GasMeterState>,
+}
+
+impl GasMeterState {
+    // Advance the totals with a new meter index; a lower index than the
+    // previous one is treated as a meter replacement or rollover
+    pub fn advance(&mut self, raw_volume: f64, temperature: f64, pressure: f64) -> GasReadingResult {
+        let factor = self.config.correction_factor(temperature, pressure);
+        let increment = match self.last_raw_volume {
+            Some(last) if raw_volume >= last => raw_volume - last,
+            _ => 0.0,
+        };
+        let standard_increment = increment * factor;
+
+        self.last_raw_volume = Some(raw_volume);
+        self.standard_volume += standard_increment;
+        self.energy += self.config.energy_kwh(standard_increment);
+
+        GasReadingResult {
+            raw_volume,
+            standard_volume: self.standard_volume,
+            energy: self.energy,
+            correction_factor: factor,
+        }
+    }
+}
+
+thread_local! {
+    static GAS_METERS: RefCell<GasMeters> = RefCell::new(GasMeters::default());
+}
+
+pub fn export_state() -> GasMeters {
+    GAS_METERS.with(|g| g.borrow().clone())
+}
+
+pub fn import_state(meters: GasMeters) {
+    GAS_METERS.with(|g| *g.borrow_mut() = meters);
+}
+
+// Built-in schema for gas meters; statistics, alerts and rollups work on
+// these channels like on any other device type
+pub fn device_type(now: u64) -> DeviceType {
+    let channel = |name: &str, unit: &str, min: f64, max: Option<f64>, kind: ChannelKind| ChannelSpec {
+        name: name.to_string(),
+        unit: Some(unit.to_string()),
+        data_type: DataType::Float,
+        min: Some(min),
+        max,
+        kind,
+    };
+    DeviceType {
+        name: GAS_DEVICE_TYPE.to_string(),
+        description: "Gas meter with temperature/pressure correction and energy conversion".to_string(),
+        channels: vec![
+            channel(RAW_VOLUME_CHANNEL, "m3", 0.0, None, ChannelKind::Cumulative),
+            channel(STANDARD_VOLUME_CHANNEL, "m3", 0.0, None, ChannelKind::Cumulative),
+            channel(ENERGY_CHANNEL, "kWh", 0.0, None, ChannelKind::Cumulative),
+            channel(TEMPERATURE_CHANNEL, "degC", -50.0, Some(100.0), ChannelKind::Instantaneous),
+            channel(PRESSURE_CHANNEL, "kPa", 0.0, Some(10_000.0), ChannelKind::Instantaneous),
+        ],
+        updated_at: now,
+    }
+}
+
+// Register the gas meter schema unless an operator already defined one
+pub fn ensure_device_type(now: u64) {
+    if schema::with_schemas(|s| s.types.contains_key(GAS_DEVICE_TYPE)) {
+        return;
+    }
+    schema::register_device_type(device_type(now));
+}
+
+// Energy in kWh per standard m³ at the meter's calorific value, for billing
+pub fn kwh_per_cubic_meter(device_id: &str) -> Option<f64> {
+    GAS_METERS.with(|g| g.borrow().meters.get(device_id).map(|m| m.config.energy_kwh(1.0)))
+}
+
+#[update(guard = "can_admin_devices")]
+fn configure_gas_meter(device_id: String, config: GasMeterConfig) -> Result<(), String> {
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    config.validate()?;
+
+    ensure_device_type(ic_cdk::api::time() / 1_000_000_000);
+    schema::assign_device_type(&device_id, GAS_DEVICE_TYPE)?;
+    let details = vec![format!("{:?}", config)];
+    GAS_METERS.with(|g| g.borrow_mut().meters.entry(device_id.clone()).or_default().config = config);
+    audit::record("device.gas_configure", Some(&device_id), details);
+    Ok(())
+}
+
+// Record a gas meter index. Temperature and pressure are optional and fall
+// back to the meter's configured defaults.
+#[update(guard = "can_write_readings")]
+fn record_gas_reading(
+    device_id: String,
+    volume: f64,
+    unit: String,
+    temperature: Option<f64>,
+    pressure: Option<f64>,
+) -> Result<GasReadingResult, String> {
+    let _timer = metrics::timer("record_gas_reading");
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+
+    let unit = units::parse_for(&unit, Quantity::Volume)?;
+    let raw_volume = units::convert(volume, unit, Unit::CubicMeter)?;
+    if !raw_volume.is_finite() || raw_volume < 0.0 {
+        return Err("Gas volume must be a finite, non-negative number".into());
+    }
+
+    let mut state = GAS_METERS
+        .with(|g| g.borrow().meters.get(&device_id).cloned())
+        .ok_or_else(|| format!("Gas meter {} is not configured", device_id))?;
+    let temperature = temperature.unwrap_or(state.config.default_temperature);
+    let pressure = pressure.unwrap_or(state.config.default_pressure);
+    if temperature + KELVIN_OFFSET <= 0.0 || pressure <= 0.0 {
+        return Err("Temperature and pressure must be physically valid".into());
+    }
+
+    let result = state.advance(raw_volume, temperature, pressure);
+    let value = |channel: &str, value: f64| ChannelValue { channel: channel.to_string(), value, unit: None };
+    schema::ingest(
+        scope.tenant_id(),
+        &device_id,
+        &[
+            value(RAW_VOLUME_CHANNEL, result.raw_volume),
+            value(STANDARD_VOLUME_CHANNEL, result.standard_volume),
+            value(ENERGY_CHANNEL, result.energy),
+            value(TEMPERATURE_CHANNEL, temperature),
+            value(PRESSURE_CHANNEL, pressure),
+        ],
+        ic_cdk::api::time() / 1_000_000_000,
+    )?;
+
+    // Only commit the new totals once the samples have been stored
+    GAS_METERS.with(|g| g.borrow_mut().meters.insert(device_id, state));
+    Ok(result)
+}
+
+#[query(guard = "can_read_readings")]
+fn get_gas_meter(device_id: String) -> Result<GasMeterState, String> {
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    GAS_METERS
+        .with(|g| g.borrow().meters.get(&device_id).cloned())
+        .ok_or_else(|| format!("Gas meter {} is not configured", device_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn correction_follows_the_ideal_gas_law() {
+        let config = GasMeterConfig::default();
+        assert!(close(config.correction_factor(15.0, 101.325), 1.0));
+        assert!(close(config.correction_factor(15.0, 202.65), 2.0));
+        // Cold gas is denser, so a metered m³ holds more standard volume
+        assert!(close(config.correction_factor(0.0, 101.325), 288.15 / 273.15));
+        assert!(config.correction_factor(30.0, 101.325) < 1.0);
+    }
+
+    #[test]
+    fn standard_volume_converts_to_energy_by_calorific_value() {
+        let config = GasMeterConfig { calorific_value: 36.0, ..Default::default() };
+        assert!(close(config.energy_kwh(1.0), 10.0));
+        assert!(close(config.energy_kwh(2.5), 25.0));
+    }
+
+    #[test]
+    fn increments_are_corrected_with_the_conditions_at_the_time() {
+        let mut meter = GasMeterState {
+            config: GasMeterConfig { calorific_value: 36.0, ..Default::default() },
+            ..Default::default()
+        };
+        assert_eq!(meter.advance(100.0, 15.0, 101.325).standard_volume, 0.0);
+        let result = meter.advance(110.0, 15.0, 202.65);
+        assert!(close(result.standard_volume, 20.0));
+        assert!(close(result.energy, 200.0));
+        // A lower index is a replaced meter and adds nothing
+        let result = meter.advance(5.0, 15.0, 101.325);
+        assert!(close(result.standard_volume, 20.0));
+        assert!(close(meter.advance(6.0, 15.0, 101.325).standard_volume, 21.0));
+    }
+
+    #[test]
+    fn implausible_configs_are_rejected() {
+        assert!(GasMeterConfig::default().validate().is_ok());
+        assert!(GasMeterConfig { calorific_value: 0.0, ..Default::default() }.validate().is_err());
+        assert!(GasMeterConfig { calorific_value: 121.0, ..Default::default() }.validate().is_err());
+        assert!(GasMeterConfig { base_temperature: -300.0, ..Default::default() }.validate().is_err());
+        assert!(GasMeterConfig { default_pressure: 0.0, ..Default::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,432 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use icutil_common::lifecycle::DeviceState;
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+
+use crate::alerts::{self, AlertMetric};
+use crate::audit;
+use crate::auth::{can_admin_devices, can_read_devices, can_write_readings};
+use crate::lifecycle;
+use crate::tenant::{self, TenantScope};
+
+const DAY: u64 = 24 * 3600;
+const CHECK_INTERVAL: u64 = 5 * 60; // Seconds between offline checks from the heartbeat
+const DEFAULT_READING_INTERVAL: u64 = 15 * 60;
+const DEFAULT_OFFLINE_AFTER: u64 = 3600;
+const MIN_READING_INTERVAL: u64 = 10;
+const COMPLETENESS_DAYS: usize = 30;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthStatus {
+    Online,  // Readings arrive on time
+    Stale,   // Still heard from, but readings are late
+    Offline, // Nothing heard for `offline_after` seconds
+    Unknown, // Never heard from
+}
+
+// Optional diagnostics a device sends with its heartbeat
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Telemetry {
+    pub battery_percent: Option<f64>,
+    pub rssi_dbm: Option<i32>,
+    pub uptime_seconds: Option<u64>,
+    pub error_count: Option<u64>, // Counted by the device since it booted
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct HealthConfig {
+    pub reading_interval: u64, // Expected seconds between readings
+    pub offline_after: u64,    // Seconds of silence before a device is offline
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig { reading_interval: DEFAULT_READING_INTERVAL, offline_after: DEFAULT_OFFLINE_AFTER }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DeviceHealth {
+    pub config: HealthConfig,
+    pub last_heartbeat: Option<u64>,
+    pub last_reading: Option<u64>,
+    pub telemetry: Telemetry,
+    pub reboots: u64, // Uptime went down between heartbeats
+    pub errors_total: u64, // Error counts summed across reboots
+    // Day start -> bitmap of the day's reading slots of `reading_interval`
+    // seconds that got at least one reading; the last 30 days. Late and
+    // backfilled readings fill their own slot.
+    pub daily_slots: BTreeMap<u64, Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct HealthReport {
+    pub device_id: String,
+    pub status: HealthStatus,
+    pub last_seen: Option<u64>,
+    pub last_heartbeat: Option<u64>,
+    pub last_reading: Option<u64>,
+    pub telemetry: Telemetry,
+    pub reboots: u64,
+    pub errors_total: u64,
+    pub completeness_today: f64, // Percent of today's reading slots so far
+    pub config: HealthConfig,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DailyCompleteness {
+    pub day_start: u64,
+    pub slots_received: u32,
+    pub slots_expected: u32,
+    pub percent: f64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct HealthStore {
+    pub devices: HashMap<String, DeviceHealth>,
+    pub last_check: u64,
+}
+
+thread_local! {
+    static HEALTH: RefCell<HealthStore> = RefCell::new(HealthStore::default());
+}
+
+impl DeviceHealth {
+    fn last_seen(&self) -> Option<u64> {
+        self.last_heartbeat.max(self.last_reading)
+    }
+
+    pub fn status(&self, now: u64) -> HealthStatus {
+        let Some(last_seen) = self.last_seen() else {
+            return HealthStatus::Unknown;
+        };
+        if now.saturating_sub(last_seen) > self.config.offline_after {
+            return HealthStatus::Offline;
+        }
+        // A reading may be up to one interval late before it counts as missed
+        match self.last_reading {
+            Some(at) if now.saturating_sub(at) <= 2 * self.config.reading_interval => HealthStatus::Online,
+            _ => HealthStatus::Stale,
+        }
+    }
+
+    fn record_reading(&mut self, timestamp: u64) {
+        self.last_reading = self.last_reading.max(Some(timestamp));
+        let day_start = timestamp - timestamp % DAY;
+        let newest = self.daily_slots.last_key_value().map_or(day_start, |(day, _)| *day.max(&day_start));
+        if day_start + COMPLETENESS_DAYS as u64 * DAY <= newest {
+            return;
+        }
+        let slots = DAY.div_ceil(self.config.reading_interval) as usize;
+        let slot = ((timestamp % DAY) / self.config.reading_interval) as usize;
+        let bitmap = self.daily_slots.entry(day_start).or_insert_with(|| vec![0; slots.div_ceil(8)]);
+        bitmap[slot / 8] |= 1 << (slot % 8);
+        self.daily_slots = self.daily_slots.split_off(&newest.saturating_sub((COMPLETENESS_DAYS as u64 - 1) * DAY));
+    }
+
+    fn record_heartbeat(&mut self, telemetry: Telemetry, now: u64) {
+        let previous = std::mem::replace(&mut self.telemetry, telemetry);
+        let rebooted = match (previous.uptime_seconds, self.telemetry.uptime_seconds) {
+            (Some(before), Some(after)) => after < before,
+            _ => false,
+        };
+        if rebooted {
+            self.reboots += 1;
+        }
+        if let Some(count) = self.telemetry.error_count {
+            // Counters restart with the device
+            let before = if rebooted { 0 } else { previous.error_count.unwrap_or(0) };
+            self.errors_total += count.saturating_sub(before);
+        }
+        self.last_heartbeat = Some(now);
+    }
+
+    // Share of the day's reading slots that got a reading. Today counts only
+    // the slots that have started.
+    fn completeness(&self, day_start: u64, now: u64) -> DailyCompleteness {
+        let elapsed = now.saturating_sub(day_start).min(DAY);
+        let expected = (elapsed / self.config.reading_interval).max(1) as u32;
+        let received = self
+            .daily_slots
+            .get(&day_start)
+            .map_or(0, |bitmap| bitmap.iter().map(|b| b.count_ones()).sum::<u32>())
+            .min(expected);
+        DailyCompleteness {
+            day_start,
+            slots_received: received,
+            slots_expected: expected,
+            percent: received as f64 * 100.0 / expected as f64,
+        }
+    }
+
+    fn report(&self, device_id: &str, now: u64) -> HealthReport {
+        HealthReport {
+            device_id: device_id.to_string(),
+            status: self.status(now),
+            last_seen: self.last_seen(),
+            last_heartbeat: self.last_heartbeat,
+            last_reading: self.last_reading,
+            telemetry: self.telemetry.clone(),
+            reboots: self.reboots,
+            errors_total: self.errors_total,
+            completeness_today: self.completeness(now - now % DAY, now).percent,
+            config: self.config,
+        }
+    }
+}
+
+impl Telemetry {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(battery) = self.battery_percent {
+            if !(0.0..=100.0).contains(&battery) {
+                return Err("Battery level must be 0-100 %".into());
+            }
+        }
+        if let Some(rssi) = self.rssi_dbm {
+            if !(-150..=0).contains(&rssi) {
+                return Err("RSSI must be between -150 and 0 dBm".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    ic_cdk::api::time() / 1_000_000_000
+}
+
+// Note a stored reading; called by the ingest paths
+pub fn record_reading(device_id: &str, timestamp: u64) {
+    HEALTH.with(|h| {
+        h.borrow_mut()
+            .devices
+            .entry(device_id.to_string())
+            .or_default()
+            .record_reading(timestamp)
+    });
+}
+
+// Only active devices raise health alerts; maintenance and suspension are
+// expected silences
+fn watched(device_id: &str) -> bool {
+    lifecycle::state_of(device_id).map_or(true, |state| state == DeviceState::Active)
+}
+
+// Feed how long each device has been silent into the alert engine, so a
+// `SecondsSinceSeen` rule fires when a device goes offline and clears once
+// it is heard from again. Called from the heartbeat.
+pub fn check_health(now: u64) {
+    let silent: Vec<(String, u64)> = HEALTH.with(|h| {
+        let mut store = h.borrow_mut();
+        if now < store.last_check + CHECK_INTERVAL {
+            return Vec::new();
+        }
+        store.last_check = now;
+        store
+            .devices
+            .iter()
+            .filter_map(|(id, health)| health.last_seen().map(|seen| (id.clone(), now.saturating_sub(seen))))
+            .collect()
+    });
+    for (device_id, seconds) in silent {
+        if !watched(&device_id) {
+            continue;
+        }
+        if let Some(tenant_id) = tenant::tenant_of_device(&device_id) {
+            alerts::evaluate(&tenant_id, &device_id, AlertMetric::SecondsSinceSeen, seconds as f64, now);
+        }
+    }
+}
+
+// Devices per health status, for the metrics endpoint
+pub fn count_by_status(now: u64) -> Vec<(HealthStatus, usize)> {
+    HEALTH.with(|h| {
+        let store = h.borrow();
+        [HealthStatus::Online, HealthStatus::Stale, HealthStatus::Offline, HealthStatus::Unknown]
+            .into_iter()
+            .map(|status| (status, store.devices.values().filter(|d| d.status(now) == status).count()))
+            .collect()
+    })
+}
+
+pub fn export_state() -> HealthStore {
+    HEALTH.with(|h| h.borrow().clone())
+}
+
+pub fn import_state(store: HealthStore) {
+    HEALTH.with(|h| *h.borrow_mut() = store);
+}
+
+fn health_of(scope: &TenantScope, device_id: &str) -> Result<DeviceHealth, String> {
+    tenant::require_device(scope, device_id)?;
+    Ok(HEALTH.with(|h| h.borrow().devices.get(device_id).cloned().unwrap_or_default()))
+}
+
+// Sent by the device, e.g. every few minutes. Battery and RSSI are fed into
+// the alert engine.
+#[update(guard = "can_write_readings")]
+fn record_heartbeat(device_id: String, telemetry: Telemetry) -> Result<HealthReport, String> {
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    telemetry.validate()?;
+    let now = now();
+    if watched(&device_id) {
+        if let Some(battery) = telemetry.battery_percent {
+            alerts::evaluate(scope.tenant_id(), &device_id, AlertMetric::BatteryPercent, battery, now);
+        }
+        if let Some(rssi) = telemetry.rssi_dbm {
+            alerts::evaluate(scope.tenant_id(), &device_id, AlertMetric::Rssi, rssi as f64, now);
+        }
+    }
+    Ok(HEALTH.with(|h| {
+        let mut store = h.borrow_mut();
+        let health = store.devices.entry(device_id.clone()).or_default();
+        health.record_heartbeat(telemetry, now);
+        health.report(&device_id, now)
+    }))
+}
+
+#[query(guard = "can_read_devices")]
+fn get_device_health(device_id: String) -> Result<HealthReport, String> {
+    let scope = tenant::caller_scope()?;
+    Ok(health_of(&scope, &device_id)?.report(&device_id, now()))
+}
+
+// Health of the caller's devices ordered by id, optionally only one status
+#[query(guard = "can_read_devices")]
+fn list_device_health(status: Option<HealthStatus>, request: PageRequest) -> Result<Page<HealthReport>, String> {
+    let scope = tenant::caller_scope()?;
+    let now = now();
+    let devices = tenant::with_registry(|registry| registry.devices_of(&scope));
+    let reports = HEALTH.with(|h| {
+        let store = h.borrow();
+        devices
+            .into_iter()
+            .map(|id| {
+                let report = store.devices.get(&id).cloned().unwrap_or_default().report(&id, now);
+                (id, report)
+            })
+            .filter(|(_, report)| status.map_or(true, |s| report.status == s))
+            .collect()
+    });
+    pagination::paginate(reports, Order::Ascending, &request)
+}
+
+// Daily data completeness for up to the last 30 days, oldest first
+#[query(guard = "can_read_devices")]
+fn get_data_completeness(device_id: String, days: u32) -> Result<Vec<DailyCompleteness>, String> {
+    let scope = tenant::caller_scope()?;
+    let health = health_of(&scope, &device_id)?;
+    let now = now();
+    let today = now - now % DAY;
+    let days = (days as u64).clamp(1, COMPLETENESS_DAYS as u64);
+    Ok((0..days)
+        .rev()
+        .map(|back| health.completeness(today - back * DAY, now))
+        .collect())
+}
+
+#[update(guard = "can_admin_devices")]
+fn set_health_config(device_id: String, config: HealthConfig) -> Result<(), String> {
+    let scope = tenant::member_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    if config.reading_interval < MIN_READING_INTERVAL || config.reading_interval > DAY {
+        return Err(format!("Reading interval must be {} to {} seconds", MIN_READING_INTERVAL, DAY));
+    }
+    if config.offline_after < config.reading_interval {
+        return Err("Offline threshold must be at least one reading interval".into());
+    }
+    HEALTH.with(|h| {
+        let mut store = h.borrow_mut();
+        let health = store.devices.entry(device_id.clone()).or_default();
+        if health.config.reading_interval != config.reading_interval {
+            // Slots of the old length cannot be compared with the new ones
+            health.daily_slots.clear();
+        }
+        health.config = config;
+    });
+    audit::record(
+        "device.health_config",
+        Some(&device_id),
+        vec![format!("interval {}s, offline after {}s", config.reading_interval, config.offline_after)],
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: u64 = 1000 * DAY;
+
+    fn health(reading_interval: u64, offline_after: u64) -> DeviceHealth {
+        DeviceHealth { config: HealthConfig { reading_interval, offline_after }, ..Default::default() }
+    }
+
+    #[test]
+    fn status_follows_the_last_reading_and_heartbeat() {
+        let mut device = health(900, 3600);
+        assert_eq!(device.status(T0), HealthStatus::Unknown);
+        device.record_reading(T0);
+        assert_eq!(device.status(T0 + 1800), HealthStatus::Online);
+        assert_eq!(device.status(T0 + 1801), HealthStatus::Stale);
+        // Heartbeats keep a device out of Offline but do not make it Online
+        device.record_heartbeat(Telemetry::default(), T0 + 3000);
+        assert_eq!(device.status(T0 + 4000), HealthStatus::Stale);
+        assert_eq!(device.status(T0 + 6601), HealthStatus::Offline);
+    }
+
+    #[test]
+    fn reboots_restart_the_error_counter() {
+        let mut device = DeviceHealth::default();
+        let telemetry = |uptime, errors| Telemetry {
+            uptime_seconds: Some(uptime),
+            error_count: Some(errors),
+            ..Default::default()
+        };
+        device.record_heartbeat(telemetry(100, 2), T0);
+        device.record_heartbeat(telemetry(200, 5), T0 + 100);
+        assert_eq!((device.reboots, device.errors_total), (0, 5));
+        device.record_heartbeat(telemetry(10, 1), T0 + 200);
+        assert_eq!((device.reboots, device.errors_total), (1, 6));
+        // A counter that went down without a reboot adds nothing
+        device.record_heartbeat(telemetry(20, 0), T0 + 210);
+        assert_eq!((device.reboots, device.errors_total), (1, 6));
+    }
+
+    #[test]
+    fn completeness_counts_each_slot_once() {
+        let mut device = health(3600, 7200);
+        for hour in [0, 1, 1, 3] {
+            device.record_reading(T0 + hour * 3600 + 60);
+        }
+        let today = device.completeness(T0, T0 + 4 * 3600);
+        assert_eq!((today.slots_received, today.slots_expected), (3, 4));
+        assert_eq!(today.percent, 75.0);
+        let full_day = device.completeness(T0, T0 + 2 * DAY);
+        assert_eq!(full_day.slots_expected, 24);
+        assert_eq!(device.completeness(T0 - DAY, T0 + 4 * 3600).slots_received, 0);
+    }
+
+    #[test]
+    fn backfilled_readings_fill_their_slots() {
+        let mut device = health(3600, 7200);
+        device.record_reading(T0 + 5 * 3600);
+        // Buffered readings uploaded after the latest one
+        device.record_reading(T0 + 2 * 3600);
+        device.record_reading(T0 - 3600);
+        assert_eq!(device.completeness(T0, T0 + 6 * 3600).slots_received, 2);
+        assert_eq!(device.completeness(T0 - DAY, T0).slots_received, 1);
+        assert_eq!(device.last_reading, Some(T0 + 5 * 3600));
+
+        // Readings older than the kept days are ignored
+        device.record_reading(T0 - COMPLETENESS_DAYS as u64 * DAY);
+        assert_eq!(device.daily_slots.len(), 2);
+        device.record_reading(T0 + COMPLETENESS_DAYS as u64 * DAY);
+        assert!(!device.daily_slots.contains_key(&(T0 - DAY)));
+    }
+}
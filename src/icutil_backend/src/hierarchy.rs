@@ -0,0 +1,660 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+
+use crate::audit;
+use crate::auth::{can_admin_devices, can_read_devices, can_read_readings, can_write_config};
+use crate::consumption::{self, Utility};
+use crate::tenant::{self, TenantScope};
+
+const LOCATION_ID_MAX_LENGTH: usize = 32;
+const NAME_MAX_LENGTH: usize = 128;
+
+// Level of a location in the tenant -> site -> building -> unit tree
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    Site,
+    Building,
+    Unit,
+}
+
+impl NodeKind {
+    // Kind the parent of a node of this kind must have
+    fn parent_kind(&self) -> Option<NodeKind> {
+        match self {
+            NodeKind::Site => None,
+            NodeKind::Building => Some(NodeKind::Site),
+            NodeKind::Unit => Some(NodeKind::Building),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct LocationNode {
+    pub id: String,
+    pub tenant_id: String,
+    pub kind: NodeKind,
+    pub name: String,
+    pub parent_id: Option<String>,
+    pub created_at: u64,
+}
+
+// A meter installed at a location. A meter with a parent is a sub-meter
+// measuring part of the flow already measured by the parent.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Meter {
+    pub device_id: String,
+    pub tenant_id: String,
+    pub location_id: String,
+    pub parent_meter: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MeterConsumption {
+    pub device_id: String,
+    pub consumption: f64,
+}
+
+// Main meter compared against the sum of its sub-meters
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MeterDiscrepancy {
+    pub device_id: String,
+    pub location_id: String,
+    pub main_consumption: f64,
+    pub submeter_total: f64,
+    pub unaccounted: f64,      // Main minus sub-meters, i.e. distribution loss
+    pub loss_percent: f64,
+    pub submeters: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConsumptionRollup {
+    pub node_id: String,
+    pub kind: NodeKind,
+    pub name: String,
+    pub total: f64,
+    pub meters: Vec<MeterConsumption>,
+    pub discrepancies: Vec<MeterDiscrepancy>,
+    pub children: Vec<ConsumptionRollup>,
+}
+
+fn validate_location_id(id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > LOCATION_ID_MAX_LENGTH {
+        return Err(format!("Location ID must be 1-{} characters", LOCATION_ID_MAX_LENGTH));
+    }
+    if !id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return Err("Location ID contains invalid characters".into());
+    }
+    Ok(())
+}
+
+// Location ids are chosen by each tenant, so nodes are keyed by tenant too
+type NodeKey = (String, String); // (tenant, location)
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Hierarchy {
+    pub nodes: HashMap<NodeKey, LocationNode>,
+    pub meters: HashMap<String, Meter>,
+}
+
+fn key(tenant_id: &str, id: &str) -> NodeKey {
+    (tenant_id.to_string(), id.to_string())
+}
+
+impl Hierarchy {
+    pub fn add_node(
+        &mut self,
+        scope: &TenantScope,
+        id: String,
+        kind: NodeKind,
+        name: String,
+        parent_id: Option<String>,
+        now: u64,
+    ) -> Result<LocationNode, String> {
+        validate_location_id(&id)?;
+        if name.trim().is_empty() || name.len() > NAME_MAX_LENGTH {
+            return Err(format!("Name must be 1-{} characters", NAME_MAX_LENGTH));
+        }
+        if self.nodes.contains_key(&key(scope.tenant_id(), &id)) {
+            return Err(format!("Location {} already exists", id));
+        }
+
+        match (kind.parent_kind(), parent_id.as_deref()) {
+            (None, None) => {}
+            (Some(expected), Some(parent_id)) => {
+                let parent = self.node(scope, parent_id)?;
+                if parent.kind != expected {
+                    return Err(format!("A {:?} must be placed in a {:?}", kind, expected));
+                }
+            }
+            (None, Some(_)) => return Err("Sites cannot have a parent".into()),
+            (Some(expected), None) => {
+                return Err(format!("A {:?} must be placed in a {:?}", kind, expected))
+            }
+        }
+
+        let node = LocationNode {
+            id: id.clone(),
+            tenant_id: scope.tenant_id().to_string(),
+            kind,
+            name,
+            parent_id,
+            created_at: now,
+        };
+        self.nodes.insert(key(scope.tenant_id(), &id), node.clone());
+        Ok(node)
+    }
+
+    // Tenant of a location named in a manifest. An uploader from a tenant
+    // can only use that tenant's locations; without one the id has to be
+    // unambiguous.
+    pub fn location_tenant(&self, location_id: &str, tenant_id: Option<&str>) -> Result<String, String> {
+        if let Some(tenant_id) = tenant_id {
+            return self
+                .nodes
+                .get(&key(tenant_id, location_id))
+                .map(|n| n.tenant_id.clone())
+                .ok_or_else(|| format!("Location {} not found", location_id));
+        }
+        let mut tenants = self.nodes.values().filter(|n| n.id == location_id).map(|n| n.tenant_id.clone());
+        match (tenants.next(), tenants.next()) {
+            (Some(tenant_id), None) => Ok(tenant_id),
+            (Some(_), Some(_)) => Err(format!("Location {} is used by several tenants", location_id)),
+            (None, _) => Err(format!("Location {} not found", location_id)),
+        }
+    }
+
+    pub fn attach_meter(
+        &mut self,
+        scope: &TenantScope,
+        device_id: String,
+        location_id: String,
+        parent_meter: Option<String>,
+    ) -> Result<Meter, String> {
+        self.node(scope, &location_id)?;
+
+        if let Some(ref parent_id) = parent_meter {
+            if *parent_id == device_id {
+                return Err("A meter cannot be its own parent".into());
+            }
+            let parent = self.meter(scope, parent_id)?;
+            // The sub-meter must sit at or below the location of its main meter
+            if !self.ancestors(scope.tenant_id(), &location_id).contains(&parent.location_id) {
+                return Err("Sub-meter must be located below its parent meter".into());
+            }
+            // Re-parenting must not introduce a cycle
+            let mut cursor = Some(parent_id.clone());
+            while let Some(current) = cursor {
+                if current == device_id {
+                    return Err("Meter hierarchy would contain a cycle".into());
+                }
+                cursor = self.meters.get(&current).and_then(|m| m.parent_meter.clone());
+            }
+        }
+        // Moving a main meter must keep its sub-meters below it
+        let moved = self.meters.get(&device_id).map_or(false, |m| m.location_id != location_id);
+        if moved {
+            let subtree = self.subtree(scope.tenant_id(), &location_id);
+            let mut stranded: Vec<&str> = self
+                .meters
+                .values()
+                .filter(|m| m.parent_meter.as_deref() == Some(device_id.as_str()))
+                .filter(|m| !subtree.contains(&m.location_id))
+                .map(|m| m.device_id.as_str())
+                .collect();
+            if !stranded.is_empty() {
+                stranded.sort();
+                return Err(format!("Sub-meters {} would no longer be below their parent meter", stranded.join(", ")));
+            }
+        }
+
+        let meter = Meter {
+            device_id: device_id.clone(),
+            tenant_id: scope.tenant_id().to_string(),
+            location_id,
+            parent_meter,
+        };
+        self.meters.insert(device_id, meter.clone());
+        Ok(meter)
+    }
+
+    fn node(&self, scope: &TenantScope, id: &str) -> Result<&LocationNode, String> {
+        self.nodes
+            .get(&key(scope.tenant_id(), id))
+            .ok_or_else(|| format!("Location {} not found", id))
+    }
+
+    fn meter(&self, scope: &TenantScope, device_id: &str) -> Result<&Meter, String> {
+        self.meters
+            .get(device_id)
+            .filter(|m| m.tenant_id == scope.tenant_id())
+            .ok_or_else(|| format!("Meter {} not found", device_id))
+    }
+
+    pub fn is_sub_meter(&self, device_id: &str) -> bool {
+        self.meters.get(device_id).map_or(false, |m| m.parent_meter.is_some())
+    }
+
+    // `site`, `building` and `unit` labels for the location a meter is
+    // installed at, for device selectors
+    pub fn location_labels(&self, device_id: &str) -> Vec<(String, String)> {
+        let Some(meter) = self.meters.get(device_id) else {
+            return Vec::new();
+        };
+        self.ancestors(&meter.tenant_id, &meter.location_id)
+            .into_iter()
+            .filter_map(|id| self.nodes.get(&key(&meter.tenant_id, &id)))
+            .map(|node| (format!("{:?}", node.kind).to_lowercase(), node.id.clone()))
+            .collect()
+    }
+
+    // The node itself followed by its parents up to the site
+    fn ancestors(&self, tenant_id: &str, id: &str) -> Vec<String> {
+        let mut chain = Vec::new();
+        let mut cursor = self.nodes.get(&key(tenant_id, id));
+        while let Some(node) = cursor {
+            chain.push(node.id.clone());
+            cursor = node.parent_id.as_ref().and_then(|p| self.nodes.get(&key(tenant_id, p)));
+        }
+        chain
+    }
+
+    fn children(&self, tenant_id: &str, id: &str) -> Vec<&LocationNode> {
+        let mut children: Vec<&LocationNode> = self
+            .nodes
+            .values()
+            .filter(|n| n.tenant_id == tenant_id && n.parent_id.as_deref() == Some(id))
+            .collect();
+        children.sort_by(|a, b| a.id.cmp(&b.id));
+        children
+    }
+
+    // Every location id in the subtree rooted at `id`
+    fn subtree(&self, tenant_id: &str, id: &str) -> HashSet<String> {
+        let mut nodes = HashSet::new();
+        let mut stack = vec![id.to_string()];
+        while let Some(current) = stack.pop() {
+            stack.extend(self.children(tenant_id, &current).into_iter().map(|n| n.id.clone()));
+            nodes.insert(current);
+        }
+        nodes
+    }
+
+    // Every meter installed at `location_id` or anywhere below it
+    pub fn meters_below(&self, scope: &TenantScope, location_id: &str) -> Result<Vec<&Meter>, String> {
+        self.node(scope, location_id)?;
+        let subtree = self.subtree(scope.tenant_id(), location_id);
+        let mut meters: Vec<&Meter> = self
+            .meters
+            .values()
+            .filter(|m| m.tenant_id == scope.tenant_id() && subtree.contains(&m.location_id))
+            .collect();
+        meters.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        Ok(meters)
+    }
+
+    // Top-most meters in the subtree. Sub-meters are already included in
+    // their parent's reading, so only these count towards the node's total.
+    pub fn top_meters(&self, scope: &TenantScope, location_id: &str) -> Result<Vec<&Meter>, String> {
+        let meters = self.meters_below(scope, location_id)?;
+        let in_subtree: HashSet<&str> = meters.iter().map(|m| m.device_id.as_str()).collect();
+        Ok(meters
+            .iter()
+            .filter(|m| {
+                m.parent_meter
+                    .as_deref()
+                    .map_or(true, |parent| !in_subtree.contains(parent))
+            })
+            .copied()
+            .collect())
+    }
+
+    pub fn rollup(
+        &self,
+        scope: &TenantScope,
+        node_id: &str,
+        usage: &HashMap<String, f64>,
+    ) -> Result<ConsumptionRollup, String> {
+        let node = self.node(scope, node_id)?;
+        let total = self
+            .top_meters(scope, node_id)?
+            .iter()
+            .map(|m| usage.get(&m.device_id).copied().unwrap_or(0.0))
+            .sum();
+
+        let direct_meters = self
+            .meters_below(scope, node_id)?
+            .iter()
+            .filter(|m| m.location_id == node.id)
+            .map(|m| MeterConsumption {
+                device_id: m.device_id.clone(),
+                consumption: usage.get(&m.device_id).copied().unwrap_or(0.0),
+            })
+            .collect();
+
+        let children = self
+            .children(scope.tenant_id(), &node.id)
+            .into_iter()
+            .map(|child| self.rollup(scope, &child.id, usage))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ConsumptionRollup {
+            node_id: node.id.clone(),
+            kind: node.kind,
+            name: node.name.clone(),
+            total,
+            meters: direct_meters,
+            discrepancies: self.discrepancies(scope, node_id, usage)?,
+            children,
+        })
+    }
+
+    // Main-meter vs sum-of-sub-meters for every main meter located at `node_id`
+    pub fn discrepancies(
+        &self,
+        scope: &TenantScope,
+        node_id: &str,
+        usage: &HashMap<String, f64>,
+    ) -> Result<Vec<MeterDiscrepancy>, String> {
+        self.node(scope, node_id)?;
+        let mut result = Vec::new();
+
+        let mut mains: Vec<&Meter> = self
+            .meters
+            .values()
+            .filter(|m| m.tenant_id == scope.tenant_id() && m.location_id == node_id)
+            .collect();
+        mains.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+
+        for main in mains {
+            let mut submeters: Vec<String> = self
+                .meters
+                .values()
+                .filter(|m| m.parent_meter.as_deref() == Some(main.device_id.as_str()))
+                .map(|m| m.device_id.clone())
+                .collect();
+            if submeters.is_empty() {
+                continue;
+            }
+            submeters.sort();
+
+            let main_consumption = usage.get(&main.device_id).copied().unwrap_or(0.0);
+            let submeter_total: f64 = submeters
+                .iter()
+                .map(|id| usage.get(id).copied().unwrap_or(0.0))
+                .sum();
+            let unaccounted = main_consumption - submeter_total;
+            let loss_percent = if main_consumption > 0.0 {
+                unaccounted / main_consumption * 100.0
+            } else {
+                0.0
+            };
+
+            result.push(MeterDiscrepancy {
+                device_id: main.device_id.clone(),
+                location_id: main.location_id.clone(),
+                main_consumption,
+                submeter_total,
+                unaccounted,
+                loss_percent,
+                submeters,
+            });
+        }
+        Ok(result)
+    }
+}
+
+thread_local! {
+    static HIERARCHY: RefCell<Hierarchy> = RefCell::new(Hierarchy::default());
+}
+
+pub fn with_hierarchy<R>(f: impl FnOnce(&Hierarchy) -> R) -> R {
+    HIERARCHY.with(|h| f(&h.borrow()))
+}
+
+pub fn export_state() -> Hierarchy {
+    with_hierarchy(|h| h.clone())
+}
+
+pub fn import_state(hierarchy: Hierarchy) {
+    HIERARCHY.with(|h| *h.borrow_mut() = hierarchy);
+}
+
+fn create_node(id: String, kind: NodeKind, name: String, parent_id: Option<String>) -> Result<LocationNode, String> {
+    let scope = tenant::member_scope()?;
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let details = vec![format!("{:?}", kind), name.clone()];
+    let result = HIERARCHY.with(|h| h.borrow_mut().add_node(&scope, id.clone(), kind, name, parent_id, now));
+    audit::on_success(result, "config.location_create", Some(&id), details)
+}
+
+#[update(guard = "can_write_config")]
+fn create_site(id: String, name: String) -> Result<LocationNode, String> {
+    create_node(id, NodeKind::Site, name, None)
+}
+
+#[update(guard = "can_write_config")]
+fn create_building(id: String, name: String, site_id: String) -> Result<LocationNode, String> {
+    create_node(id, NodeKind::Building, name, Some(site_id))
+}
+
+#[update(guard = "can_write_config")]
+fn create_unit(id: String, name: String, building_id: String) -> Result<LocationNode, String> {
+    create_node(id, NodeKind::Unit, name, Some(building_id))
+}
+
+#[update(guard = "can_write_config")]
+fn attach_meter(device_id: String, location_id: String, parent_meter: Option<String>) -> Result<Meter, String> {
+    let scope = tenant::member_scope()?;
+
+    // Only devices of the caller's tenant can be placed in its hierarchy
+    tenant::require_device(&scope, &device_id)?;
+
+    let details = vec![location_id.clone()];
+    let result = HIERARCHY.with(|h| h.borrow_mut().attach_meter(&scope, device_id.clone(), location_id, parent_meter));
+    audit::on_success(result, "config.meter_attach", Some(&device_id), details)
+}
+
+// Put a device enrolled from a manifest at its location, assigning it to
+// the location's tenant. device_management_backend calls this with the
+// uploader's tenant, or None for controllers and tenant admins; it needs
+// `devices:admin` here.
+#[update(guard = "can_admin_devices")]
+fn place_device(device_id: String, location_id: String, tenant_id: Option<String>) -> Result<Meter, String> {
+    crate::validate_device_id(&device_id)?;
+    let tenant_id = with_hierarchy(|h| h.location_tenant(&location_id, tenant_id.as_deref()))?;
+    audit::on_success(
+        tenant::assign_device(&device_id, &tenant_id),
+        "tenant.assign_device",
+        Some(&device_id),
+        vec![tenant_id.clone()],
+    )?;
+    let scope = tenant::with_registry(|registry| registry.scope_of_tenant(&tenant_id))?;
+    let details = vec![location_id.clone()];
+    let result = HIERARCHY.with(|h| h.borrow_mut().attach_meter(&scope, device_id.clone(), location_id, None));
+    audit::on_success(result, "config.meter_attach", Some(&device_id), details)
+}
+
+#[query(guard = "can_read_devices")]
+fn list_locations(request: PageRequest) -> Result<Page<LocationNode>, String> {
+    let scope = tenant::member_scope()?;
+    let nodes = with_hierarchy(|h| {
+        h.nodes
+            .values()
+            .filter(|n| n.tenant_id == scope.tenant_id())
+            .map(|n| (n.id.clone(), n.clone()))
+            .collect()
+    });
+    pagination::paginate(nodes, Order::Ascending, &request)
+}
+
+// Consumption of every meter visible to the caller in the given window.
+// Water is assumed when no utility is given.
+fn scoped_usage(
+    start: u64,
+    end: u64,
+    utility: Option<Utility>,
+) -> Result<(TenantScope, HashMap<String, f64>), String> {
+    let scope = tenant::member_scope()?;
+    let usage = consumption::tenant_usage(&scope, utility.unwrap_or_default(), start, end)?;
+    Ok((scope, usage))
+}
+
+#[query(guard = "can_read_readings")]
+fn get_consumption_rollup(
+    node_id: String,
+    start: u64,
+    end: u64,
+    utility: Option<Utility>,
+) -> Result<ConsumptionRollup, String> {
+    let (scope, usage) = scoped_usage(start, end, utility)?;
+    with_hierarchy(|h| h.rollup(&scope, &node_id, &usage))
+}
+
+#[query(guard = "can_read_readings")]
+fn get_meter_discrepancies(
+    node_id: String,
+    start: u64,
+    end: u64,
+    utility: Option<Utility>,
+) -> Result<Vec<MeterDiscrepancy>, String> {
+    let (scope, usage) = scoped_usage(start, end, utility)?;
+    with_hierarchy(|h| {
+        let mut result = Vec::new();
+        for id in h.subtree(scope.tenant_id(), &node_id) {
+            result.extend(h.discrepancies(&scope, &id, &usage)?);
+        }
+        result.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        Ok(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::TenantRegistry;
+
+    fn scopes() -> (TenantScope, TenantScope) {
+        let mut registry = TenantRegistry::default();
+        registry.create_tenant("acme".into(), "Acme Towers".into(), 0).unwrap();
+        registry.create_tenant("globex".into(), "Globex Plaza".into(), 0).unwrap();
+        (registry.scope_of_tenant("acme").unwrap(), registry.scope_of_tenant("globex").unwrap())
+    }
+
+    // Site s1 with buildings b1 (units u1, u2) and b2
+    fn campus(scope: &TenantScope) -> Hierarchy {
+        let mut h = Hierarchy::default();
+        let mut add = |id: &str, kind, parent: Option<&str>| {
+            h.add_node(scope, id.into(), kind, id.to_uppercase(), parent.map(String::from), 0).unwrap();
+        };
+        add("s1", NodeKind::Site, None);
+        add("b1", NodeKind::Building, Some("s1"));
+        add("b2", NodeKind::Building, Some("s1"));
+        add("u1", NodeKind::Unit, Some("b1"));
+        add("u2", NodeKind::Unit, Some("b1"));
+        h
+    }
+
+    fn attach(h: &mut Hierarchy, scope: &TenantScope, device_id: &str, location_id: &str, parent: Option<&str>) -> Result<Meter, String> {
+        h.attach_meter(scope, device_id.into(), location_id.into(), parent.map(String::from))
+    }
+
+    #[test]
+    fn nodes_must_follow_the_site_building_unit_levels() {
+        let (acme, _) = scopes();
+        let mut h = campus(&acme);
+        let mut add = |id: &str, kind, parent: Option<&str>| {
+            h.add_node(&acme, id.into(), kind, "Name".into(), parent.map(String::from), 0)
+        };
+        assert!(add("x", NodeKind::Site, Some("s1")).is_err());
+        assert!(add("x", NodeKind::Building, None).is_err());
+        assert!(add("x", NodeKind::Unit, Some("s1")).is_err());
+        assert!(add("x", NodeKind::Building, Some("missing")).is_err());
+        assert!(add("b1", NodeKind::Building, Some("s1")).is_err());
+        assert!(add("bad id", NodeKind::Site, None).is_err());
+        assert!(add("u3", NodeKind::Unit, Some("b2")).is_ok());
+    }
+
+    #[test]
+    fn location_ids_are_per_tenant() {
+        let (acme, globex) = scopes();
+        let mut h = campus(&acme);
+        assert!(h.add_node(&globex, "s1".into(), NodeKind::Site, "Globex".into(), None, 0).is_ok());
+        // Acme's b1 is not visible to Globex, even under a shared site id
+        let err = h.add_node(&globex, "u9".into(), NodeKind::Unit, "U".into(), Some("b1".into()), 0).unwrap_err();
+        assert_eq!(err, "Location b1 not found");
+        assert_eq!(h.node(&acme, "s1").unwrap().name, "S1");
+        assert_eq!(h.node(&globex, "s1").unwrap().name, "Globex");
+    }
+
+    #[test]
+    fn sub_meters_sit_below_their_parent_without_cycles() {
+        let (acme, _) = scopes();
+        let mut h = campus(&acme);
+        attach(&mut h, &acme, "main", "b1", None).unwrap();
+        attach(&mut h, &acme, "sub", "u1", Some("main")).unwrap();
+        assert!(attach(&mut h, &acme, "x", "b2", Some("main")).is_err());
+        assert!(attach(&mut h, &acme, "x", "b1", Some("x")).is_err());
+        assert!(attach(&mut h, &acme, "x", "u1", Some("missing")).is_err());
+        let err = attach(&mut h, &acme, "main", "u1", Some("sub")).unwrap_err();
+        assert_eq!(err, "Meter hierarchy would contain a cycle");
+    }
+
+    #[test]
+    fn moving_a_main_meter_keeps_its_sub_meters_below_it() {
+        let (acme, _) = scopes();
+        let mut h = campus(&acme);
+        attach(&mut h, &acme, "main", "b1", None).unwrap();
+        attach(&mut h, &acme, "sub", "u2", Some("main")).unwrap();
+        let err = attach(&mut h, &acme, "main", "u1", None).unwrap_err();
+        assert_eq!(err, "Sub-meters sub would no longer be below their parent meter");
+        assert_eq!(h.meters["main"].location_id, "b1");
+        assert!(attach(&mut h, &acme, "main", "s1", None).is_ok());
+    }
+
+    #[test]
+    fn rollups_count_sub_meters_once_and_report_losses() {
+        let (acme, globex) = scopes();
+        let mut h = campus(&acme);
+        attach(&mut h, &acme, "main", "b1", None).unwrap();
+        attach(&mut h, &acme, "sub-1", "u1", Some("main")).unwrap();
+        attach(&mut h, &acme, "sub-2", "u2", Some("main")).unwrap();
+        attach(&mut h, &acme, "b2-meter", "b2", None).unwrap();
+        let usage: HashMap<String, f64> =
+            [("main", 100.0), ("sub-1", 50.0), ("sub-2", 40.0), ("b2-meter", 7.0)]
+                .into_iter()
+                .map(|(id, v)| (id.to_string(), v))
+                .collect();
+
+        let rollup = h.rollup(&acme, "s1", &usage).unwrap();
+        assert_eq!(rollup.total, 107.0);
+        let b1 = &rollup.children[0];
+        assert_eq!((b1.node_id.as_str(), b1.total), ("b1", 100.0));
+        assert_eq!(b1.children.iter().map(|u| u.total).collect::<Vec<_>>(), vec![50.0, 40.0]);
+
+        let discrepancy = &b1.discrepancies[0];
+        assert_eq!(discrepancy.submeters, vec!["sub-1", "sub-2"]);
+        assert_eq!(discrepancy.unaccounted, 10.0);
+        assert_eq!(discrepancy.loss_percent, 10.0);
+        assert!(h.rollup(&globex, "s1", &usage).is_err());
+    }
+
+    #[test]
+    fn manifest_locations_resolve_in_the_uploaders_tenant() {
+        let (acme, globex) = scopes();
+        let mut h = campus(&acme);
+        h.add_node(&globex, "g1".into(), NodeKind::Site, "G1".into(), None, 0).unwrap();
+        assert_eq!(h.location_tenant("b1", Some("acme")), Ok("acme".to_string()));
+        assert!(h.location_tenant("b1", Some("globex")).is_err());
+        assert!(h.location_tenant("g1", Some("acme")).is_err());
+        // Tenant admins name a location that only one tenant uses
+        assert_eq!(h.location_tenant("g1", None), Ok("globex".to_string()));
+        h.add_node(&globex, "s1".into(), NodeKind::Site, "S1".into(), None, 0).unwrap();
+        assert!(h.location_tenant("s1", None).is_err());
+        assert_eq!(h.location_tenant("s1", Some("globex")), Ok("globex".to_string()));
+    }
+}
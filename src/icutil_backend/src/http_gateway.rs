@@ -0,0 +1,707 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use candid::{CandidType, Deserialize};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk_macros::{query, update};
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+use icutil_common::http::{HttpRequest, HttpResponse};
+use icutil_common::metrics::METRICS_PATH;
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+
+use crate::audit;
+use crate::auth::can_admin_users;
+use crate::metrics;
+use crate::tenant::{self, TenantScope};
+use crate::units::{self, Quantity, Unit};
+use crate::{VolumeError, VolumeReading};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAX_BODY_BYTES: usize = 16 * 1024;
+const SIGNATURE_WINDOW: u64 = 300; // Seconds a signed request stays valid
+const MAX_KEYS_PER_TENANT: usize = 1000;
+
+const KEY_HEADER: &str = "x-icutil-key";
+const TIMESTAMP_HEADER: &str = "x-icutil-timestamp";
+const SIGNATURE_HEADER: &str = "x-icutil-signature";
+const CONSENSUS_HEADER: &str = "x-icutil-consensus";
+
+// The API index is the same for every caller, so it is the one response
+// that can be certified for query calls
+const INDEX_PATHS: [&str; 2] = ["/v1", "/v1/"];
+const INDEX_BODY: &str = r#"{"version":"v1","endpoints":["POST /v1/readings","GET /v1/readings","GET /v1/stats","GET /v1/devices"]}"#;
+
+// Shared secret an integration or device signs its requests with. Device
+// keys may only read and write that device's data.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub tenant_id: String,
+    pub device_id: Option<String>,
+    pub secret: Vec<u8>,
+    pub created_at: u64,
+}
+
+// An API key without its secret
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub device_id: Option<String>,
+    pub created_at: u64,
+}
+
+// Returned once when a key is created; the secret cannot be read back
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ApiKeySecret {
+    pub key_id: String,
+    pub secret: String, // Hex
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ApiKeys {
+    pub keys: BTreeMap<String, ApiKey>,
+    pub seen: VecDeque<(u64, String)>, // (expiry, signature) of accepted writes
+}
+
+// An HTTP error with a stable machine-readable code
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, code, message: message.into() }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(400, "invalid_request", message)
+    }
+
+    fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(401, "unauthenticated", message)
+    }
+
+    fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(403, "forbidden", message)
+    }
+}
+
+// HTTP status for each canister error
+pub fn status_of(error: &VolumeError) -> u16 {
+    match error {
+        VolumeError::InvalidVolume(_) => 400,
+        VolumeError::Unauthorized(_) => 403,
+        VolumeError::DataNotFound => 404,
+        VolumeError::RateLimit(_) => 429,
+        VolumeError::StorageError(_) => 500,
+    }
+}
+
+impl From<VolumeError> for ApiError {
+    fn from(error: VolumeError) -> Self {
+        let status = status_of(&error);
+        match error {
+            VolumeError::InvalidVolume(m) => Self::new(status, "invalid_request", m),
+            VolumeError::Unauthorized(m) => Self::new(status, "forbidden", m),
+            VolumeError::DataNotFound => Self::new(status, "not_found", "No readings found"),
+            VolumeError::RateLimit(m) => Self::new(status, "rate_limited", m),
+            VolumeError::StorageError(m) => Self::new(status, "storage_error", m),
+        }
+    }
+}
+
+thread_local! {
+    static API_KEYS: RefCell<ApiKeys> = RefCell::new(ApiKeys::default());
+    static CERTIFIED: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::new());
+}
+
+pub fn export_state() -> ApiKeys {
+    API_KEYS.with(|k| k.borrow().clone())
+}
+
+pub fn import_state(keys: ApiKeys) {
+    API_KEYS.with(|k| *k.borrow_mut() = keys);
+}
+
+fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+// Publish the hashes of the certified responses. Certified data does not
+// survive upgrades, so this runs from both init and post_upgrade.
+pub fn certify_responses() {
+    CERTIFIED.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for path in INDEX_PATHS {
+            tree.insert(path.to_string(), sha256(INDEX_BODY.as_bytes()));
+        }
+        ic_cdk::api::set_certified_data(&labeled_hash(b"http_assets", &tree.root_hash()));
+    });
+}
+
+// IC-Certificate header proving `path`'s response; only available in queries
+fn certificate_header(path: &str) -> Option<(String, String)> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let tree = CERTIFIED.with(|tree| {
+        let tree = tree.borrow();
+        let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+        serializer.self_describe().ok()?;
+        labeled(b"http_assets", tree.witness(path.as_bytes()))
+            .serialize(&mut serializer)
+            .ok()?;
+        Some(serializer.into_inner())
+    })?;
+    Some((
+        "IC-Certificate".to_string(),
+        format!("certificate=:{}:, tree=:{}:", BASE64.encode(certificate), BASE64.encode(tree)),
+    ))
+}
+
+fn json_response<T: Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    match serde_json::to_vec(value) {
+        Ok(body) => HttpResponse {
+            status_code,
+            headers: vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            body,
+            upgrade: None,
+        },
+        Err(e) => error_response(ApiError::new(500, "internal_error", format!("JSON encoding failed: {}", e))),
+    }
+}
+
+fn error_response(error: ApiError) -> HttpResponse {
+    #[derive(Serialize)]
+    struct Body<'a> {
+        code: &'a str,
+        message: &'a str,
+    }
+    #[derive(Serialize)]
+    struct Envelope<'a> {
+        error: Body<'a>,
+    }
+    let envelope = Envelope { error: Body { code: error.code, message: &error.message } };
+    HttpResponse {
+        status_code: error.status,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: serde_json::to_vec(&envelope).unwrap_or_default(),
+        upgrade: None,
+    }
+}
+
+fn upgrade_response() -> HttpResponse {
+    HttpResponse { status_code: 200, headers: Vec::new(), body: Vec::new(), upgrade: Some(true) }
+}
+
+fn index_response(path: &str) -> HttpResponse {
+    let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    headers.extend(certificate_header(path));
+    HttpResponse { status_code: 200, headers, body: INDEX_BODY.as_bytes().to_vec(), upgrade: None }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Decode one `application/x-www-form-urlencoded` component
+fn decode_component(raw: &str) -> Result<String, ApiError> {
+    let invalid = || ApiError::bad_request(format!("Invalid query string component: {}", raw));
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = raw.get(i + 1..i + 3).ok_or_else(invalid)?;
+                out.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                i += 2;
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8(out).map_err(|_| invalid())
+}
+
+struct Route {
+    path: String,
+    params: Vec<(String, String)>,
+}
+
+impl Route {
+    fn parse(url: &str) -> Result<Self, ApiError> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let params = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Ok((decode_component(key)?, decode_component(value)?))
+            })
+            .collect::<Result<_, ApiError>>()?;
+        Ok(Route { path: path.to_string(), params })
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn page_request(&self) -> Result<PageRequest, ApiError> {
+        let limit = self
+            .param("limit")
+            .map(|limit| limit.parse().map_err(|_| ApiError::bad_request("limit must be a positive integer")))
+            .transpose()?;
+        Ok(PageRequest { cursor: self.param("cursor").map(str::to_string), limit })
+    }
+}
+
+// A request whose signature checked out
+#[derive(Debug)]
+struct Credential {
+    scope: TenantScope,
+    device_id: Option<String>,
+    timestamp: u64,
+    signature: String,
+}
+
+impl Credential {
+    // The device a request may act on. Device keys are pinned to their own
+    // device, which must still belong to the key's tenant; tenant keys may
+    // name any device of the tenant, or none.
+    fn device(&self, requested: Option<&str>) -> Result<Option<String>, ApiError> {
+        match (&self.device_id, requested) {
+            (Some(own), Some(requested)) if own != requested => {
+                Err(ApiError::forbidden(format!("Key is restricted to device {}", own)))
+            }
+            (Some(own), _) => {
+                tenant::require_device(&self.scope, own).map_err(ApiError::forbidden)?;
+                Ok(Some(own.clone()))
+            }
+            (None, Some(requested)) => {
+                tenant::require_device(&self.scope, requested).map_err(ApiError::forbidden)?;
+                Ok(Some(requested.to_string()))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+// The string a client signs: method, URL as sent, timestamp and the
+// SHA-256 of the body, separated by newlines
+fn canonical_request(request: &HttpRequest, timestamp: &str) -> String {
+    let body_hash: String = sha256(&request.body).iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}\n{}\n{}\n{}", request.method.to_ascii_uppercase(), request.url, timestamp, body_hash)
+}
+
+fn verify_signature(request: &HttpRequest, now: u64) -> Result<Credential, ApiError> {
+    let missing = |name: &str| ApiError::unauthorized(format!("Missing {} header", name));
+    let key_id = request.header(KEY_HEADER).ok_or_else(|| missing(KEY_HEADER))?;
+    let raw_timestamp = request.header(TIMESTAMP_HEADER).ok_or_else(|| missing(TIMESTAMP_HEADER))?;
+    let signature = request.header(SIGNATURE_HEADER).ok_or_else(|| missing(SIGNATURE_HEADER))?;
+
+    let timestamp: u64 = raw_timestamp
+        .parse()
+        .map_err(|_| ApiError::unauthorized("Timestamp must be seconds since the UNIX epoch"))?;
+    if now.abs_diff(timestamp) > SIGNATURE_WINDOW {
+        return Err(ApiError::unauthorized("Request timestamp is outside the allowed window"));
+    }
+
+    let key = API_KEYS
+        .with(|k| k.borrow().keys.get(key_id).cloned())
+        .ok_or_else(|| ApiError::unauthorized("Unknown API key"))?;
+    let signature_bytes =
+        decode_hex(signature).ok_or_else(|| ApiError::unauthorized("Signature must be hex"))?;
+    let mut mac = HmacSha256::new_from_slice(&key.secret)
+        .map_err(|_| ApiError::new(500, "internal_error", "Invalid key material"))?;
+    mac.update(canonical_request(request, raw_timestamp).as_bytes());
+    mac.verify_slice(&signature_bytes)
+        .map_err(|_| ApiError::unauthorized("Signature does not match"))?;
+
+    let scope = tenant::with_registry(|registry| registry.scope_of_tenant(&key.tenant_id))
+        .map_err(ApiError::unauthorized)?;
+    Ok(Credential {
+        scope,
+        device_id: key.device_id,
+        timestamp,
+        signature: signature.to_ascii_lowercase(),
+    })
+}
+
+// Reject a write whose signature was already accepted. Only update calls
+// can remember signatures, which is why writes always run as updates.
+fn check_replay(credential: &Credential, now: u64) -> Result<(), ApiError> {
+    API_KEYS.with(|k| {
+        let mut keys = k.borrow_mut();
+        keys.seen.retain(|(expires, _)| *expires >= now);
+        if keys.seen.iter().any(|(_, signature)| *signature == credential.signature) {
+            return Err(ApiError::new(409, "replayed", "Request was already processed"));
+        }
+        keys.seen.push_back((credential.timestamp + SIGNATURE_WINDOW, credential.signature.clone()));
+        Ok(())
+    })
+}
+
+fn scoped_readings(credential: &Credential, device: Option<&str>) -> Result<Vec<VolumeReading>, ApiError> {
+    let device = credential.device(device)?;
+    let readings = credential.scope.filter(crate::load_readings()?);
+    Ok(readings
+        .into_iter()
+        .filter(|r| device.is_none() || r.device_id == device)
+        .collect())
+}
+
+// Body of POST /v1/readings. `unit` defaults to cubic meters; `temperature`
+// is in °C.
+#[derive(Deserialize)]
+struct ReadingBody {
+    device_id: Option<String>,
+    value: f64,
+    unit: Option<String>,
+    temperature: Option<f64>,
+}
+
+fn post_reading(request: &HttpRequest, credential: &Credential, now: u64) -> Result<HttpResponse, ApiError> {
+    let body: ReadingBody = serde_json::from_slice(&request.body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid reading: {}", e)))?;
+    let unit = match body.unit {
+        Some(ref symbol) => units::parse_for(symbol, Quantity::Volume).map_err(ApiError::bad_request)?,
+        None => Unit::CubicMeter,
+    };
+    let device = credential.device(body.device_id.as_deref())?;
+    check_replay(credential, now)?;
+    let reading = crate::ingest_volume(&credential.scope, body.value, unit, device, body.temperature)?;
+    Ok(json_response(201, &reading))
+}
+
+fn get_readings(route: &Route, credential: &Credential) -> Result<HttpResponse, ApiError> {
+    let request = route.page_request()?;
+    let readings = scoped_readings(credential, route.param("device_id"))?
+        .into_iter()
+        .map(|r| (r.seq.unwrap_or(0), r))
+        .collect();
+    let page = pagination::paginate(readings, Order::Descending, &request).map_err(ApiError::bad_request)?;
+    Ok(json_response(200, &page))
+}
+
+fn get_stats(route: &Route, credential: &Credential) -> Result<HttpResponse, ApiError> {
+    let readings = scoped_readings(credential, route.param("device_id"))?;
+    let stats = crate::compute_statistics(&readings.into_iter().collect())?;
+    Ok(json_response(200, &stats))
+}
+
+fn get_devices(route: &Route, credential: &Credential) -> Result<HttpResponse, ApiError> {
+    let request = route.page_request()?;
+    let devices = match credential.device_id {
+        Some(ref own) => vec![own.clone()],
+        None => tenant::with_registry(|registry| registry.devices_of(&credential.scope)),
+    };
+    let page: Page<String> = pagination::paginate(
+        devices.into_iter().map(|id| (id.clone(), id)).collect(),
+        Order::Ascending,
+        &request,
+    )
+    .map_err(ApiError::bad_request)?;
+    Ok(json_response(200, &page))
+}
+
+fn method_not_allowed(allowed: &str) -> ApiError {
+    ApiError::new(405, "method_not_allowed", format!("Allowed methods: {}", allowed))
+}
+
+// Serve one request. Queries answer reads directly and hand writes, and
+// reads that ask for consensus, back to the gateway as update calls.
+fn handle(request: &HttpRequest, in_update: bool) -> Result<HttpResponse, ApiError> {
+    if request.body.len() > MAX_BODY_BYTES {
+        return Err(ApiError::new(413, "payload_too_large", format!("Body exceeds {} bytes", MAX_BODY_BYTES)));
+    }
+    let route = Route::parse(&request.url)?;
+    let method = request.method.to_ascii_uppercase();
+
+    // Scraped by Prometheus without a signature; holds no tenant data
+    if route.path == METRICS_PATH {
+        return match method.as_str() {
+            "GET" => Ok(metrics::serve()),
+            _ => Err(method_not_allowed("GET")),
+        };
+    }
+    if INDEX_PATHS.contains(&route.path.as_str()) {
+        return match method.as_str() {
+            "GET" => Ok(index_response(&route.path)),
+            _ => Err(method_not_allowed("GET")),
+        };
+    }
+
+    let allowed = match route.path.as_str() {
+        "/v1/readings" => "GET, POST",
+        "/v1/stats" | "/v1/devices" => "GET",
+        _ => return Err(ApiError::new(404, "not_found", format!("No route for {}", route.path))),
+    };
+    if !allowed.split(", ").any(|m| m == method) {
+        return Err(method_not_allowed(allowed));
+    }
+    let wants_consensus = request.header(CONSENSUS_HEADER).map_or(false, |v| v.eq_ignore_ascii_case("true"));
+    if !in_update && (method != "GET" || wants_consensus) {
+        return Ok(upgrade_response());
+    }
+
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let credential = verify_signature(request, now)?;
+    match (method.as_str(), route.path.as_str()) {
+        ("POST", "/v1/readings") => post_reading(request, &credential, now),
+        ("GET", "/v1/readings") => get_readings(&route, &credential),
+        ("GET", "/v1/stats") => get_stats(&route, &credential),
+        ("GET", "/v1/devices") => get_devices(&route, &credential),
+        _ => Err(method_not_allowed(allowed)),
+    }
+}
+
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    handle(&request, false).unwrap_or_else(error_response)
+}
+
+#[update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    let _timer = metrics::timer("http_request_update");
+    handle(&request, true).unwrap_or_else(error_response)
+}
+
+// Create a signing key for the caller's tenant, optionally pinned to one
+// device. The secret is only returned here.
+#[update(guard = "can_admin_users")]
+async fn create_api_key(device_id: Option<String>) -> Result<ApiKeySecret, String> {
+    let scope = tenant::member_scope()?;
+    if let Some(ref device_id) = device_id {
+        tenant::require_device(&scope, device_id)?;
+    }
+
+    let (secret,) = raw_rand()
+        .await
+        .map_err(|(_, e)| format!("Failed to generate key: {}", e))?;
+    let id: String = sha256(&secret)[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    let key = ApiKey {
+        id: format!("key_{}", id),
+        tenant_id: scope.tenant_id().to_string(),
+        device_id,
+        secret,
+        created_at: ic_cdk::api::time() / 1_000_000_000,
+    };
+
+    let result = API_KEYS.with(|k| {
+        let mut keys = k.borrow_mut();
+        let count = keys.keys.values().filter(|x| x.tenant_id == key.tenant_id).count();
+        if count >= MAX_KEYS_PER_TENANT {
+            return Err(format!("Tenant already has {} API keys", MAX_KEYS_PER_TENANT));
+        }
+        let response = ApiKeySecret {
+            key_id: key.id.clone(),
+            secret: key.secret.iter().map(|b| format!("{:02x}", b)).collect(),
+        };
+        keys.keys.insert(key.id.clone(), key);
+        Ok(response)
+    });
+    let target = result.as_ref().map(|r| r.key_id.clone()).unwrap_or_default();
+    audit::on_success(result, "api_key.create", Some(&target), Vec::new())
+}
+
+#[update(guard = "can_admin_users")]
+fn revoke_api_key(key_id: String) -> Result<(), String> {
+    let scope = tenant::member_scope()?;
+    let result = API_KEYS.with(|k| {
+        let mut keys = k.borrow_mut();
+        match keys.keys.get(&key_id) {
+            Some(key) if key.tenant_id == scope.tenant_id() => {
+                keys.keys.remove(&key_id);
+                Ok(())
+            }
+            _ => Err(format!("API key {} not found", key_id)),
+        }
+    });
+    audit::on_success(result, "api_key.revoke", Some(&key_id), Vec::new())
+}
+
+#[query(guard = "can_admin_users")]
+fn list_api_keys(request: PageRequest) -> Result<Page<ApiKeyInfo>, String> {
+    let scope = tenant::member_scope()?;
+    let keys = API_KEYS.with(|k| {
+        k.borrow()
+            .keys
+            .values()
+            .filter(|key| key.tenant_id == scope.tenant_id())
+            .map(|key| {
+                let info = ApiKeyInfo {
+                    id: key.id.clone(),
+                    device_id: key.device_id.clone(),
+                    created_at: key.created_at,
+                };
+                (key.id.clone(), info)
+            })
+            .collect()
+    });
+    pagination::paginate(keys, Order::Ascending, &request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn setup() {
+        let mut registry = tenant::TenantRegistry::default();
+        registry.create_tenant("acme".into(), "Acme".into(), 0).unwrap();
+        registry.create_tenant("globex".into(), "Globex".into(), 0).unwrap();
+        registry.assign_device("m-1".into(), "acme").unwrap();
+        registry.assign_device("m-2".into(), "acme").unwrap();
+        registry.assign_device("g-1".into(), "globex").unwrap();
+        tenant::import_state(registry);
+
+        let mut keys = ApiKeys::default();
+        for (id, device_id) in [("k-tenant", None), ("k-device", Some("m-1".to_string()))] {
+            keys.keys.insert(
+                id.into(),
+                ApiKey { id: id.into(), tenant_id: "acme".into(), device_id, secret: b"secret".to_vec(), created_at: 0 },
+            );
+        }
+        import_state(keys);
+    }
+
+    fn sign(secret: &[u8], request: &HttpRequest, timestamp: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(canonical_request(request, timestamp).as_bytes());
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn signed_request(key_id: &str, secret: &[u8], timestamp: u64) -> HttpRequest {
+        let mut request = HttpRequest {
+            method: "post".into(),
+            url: "/v1/readings?unit=l".into(),
+            headers: Vec::new(),
+            body: br#"{"volume":1.5}"#.to_vec(),
+            certificate_version: None,
+        };
+        let signature = sign(secret, &request, &timestamp.to_string());
+        request.headers = vec![
+            (KEY_HEADER.into(), key_id.into()),
+            (TIMESTAMP_HEADER.into(), timestamp.to_string()),
+            (SIGNATURE_HEADER.into(), signature),
+        ];
+        request
+    }
+
+    fn set_header(request: &mut HttpRequest, name: &str, value: &str) {
+        request.headers.iter_mut().find(|(key, _)| key == name).unwrap().1 = value.into();
+    }
+
+    #[test]
+    fn canonical_request_covers_method_url_timestamp_and_body() {
+        let request = signed_request("k-tenant", b"secret", NOW);
+        let canonical = canonical_request(&request, "1700000000");
+        let lines: Vec<&str> = canonical.split('\n').collect();
+        assert_eq!(lines[..3], ["POST", "/v1/readings?unit=l", "1700000000"]);
+        assert_eq!(lines[3].len(), 64);
+
+        let mut other = request.clone();
+        other.body = br#"{"volume":2.5}"#.to_vec();
+        assert_ne!(canonical_request(&other, "1700000000"), canonical);
+    }
+
+    #[test]
+    fn valid_signatures_are_accepted_within_the_window() {
+        setup();
+        let request = signed_request("k-tenant", b"secret", NOW);
+        let credential = verify_signature(&request, NOW + SIGNATURE_WINDOW).unwrap();
+        assert_eq!(credential.scope.tenant_id(), "acme");
+        assert_eq!(credential.device_id, None);
+        assert_eq!(credential.timestamp, NOW);
+
+        let error = verify_signature(&request, NOW + SIGNATURE_WINDOW + 1).unwrap_err();
+        assert_eq!(error.message, "Request timestamp is outside the allowed window");
+        assert!(verify_signature(&request, NOW - SIGNATURE_WINDOW - 1).is_err());
+    }
+
+    #[test]
+    fn bad_signatures_are_rejected() {
+        setup();
+        let unauthorized = |request: &HttpRequest| {
+            let error = verify_signature(request, NOW).unwrap_err();
+            assert_eq!(error.status, 401);
+            error.message
+        };
+
+        let mut request = signed_request("k-tenant", b"secret", NOW);
+        set_header(&mut request, SIGNATURE_HEADER, "not-hex");
+        assert_eq!(unauthorized(&request), "Signature must be hex");
+
+        let request = signed_request("k-tenant", b"other secret", NOW);
+        assert_eq!(unauthorized(&request), "Signature does not match");
+
+        let request = signed_request("k-unknown", b"secret", NOW);
+        assert_eq!(unauthorized(&request), "Unknown API key");
+
+        let mut request = signed_request("k-tenant", b"secret", NOW);
+        request.body.push(b' ');
+        assert_eq!(unauthorized(&request), "Signature does not match");
+
+        let mut request = signed_request("k-tenant", b"secret", NOW);
+        request.headers.retain(|(key, _)| key != TIMESTAMP_HEADER);
+        assert_eq!(unauthorized(&request), format!("Missing {} header", TIMESTAMP_HEADER));
+    }
+
+    #[test]
+    fn replayed_signatures_are_rejected_until_they_expire() {
+        setup();
+        let credential = verify_signature(&signed_request("k-tenant", b"secret", NOW), NOW).unwrap();
+        assert!(check_replay(&credential, NOW).is_ok());
+        assert_eq!(check_replay(&credential, NOW + 1).unwrap_err().status, 409);
+        // Once expired the signature is forgotten, and the window rejects it anyway
+        assert!(check_replay(&credential, NOW + SIGNATURE_WINDOW + 1).is_ok());
+    }
+
+    #[test]
+    fn query_components_are_form_decoded() {
+        assert_eq!(decode_component("a+b%2Fc").unwrap(), "a b/c");
+        assert_eq!(decode_component("%C3%A9").unwrap(), "é");
+        assert!(decode_component("%2").is_err());
+        assert!(decode_component("%zz").is_err());
+        assert!(decode_component("%FF").is_err()); // Not UTF-8
+    }
+
+    #[test]
+    fn device_keys_are_pinned_to_a_device_of_their_tenant() {
+        setup();
+        let tenant_key = verify_signature(&signed_request("k-tenant", b"secret", NOW), NOW).unwrap();
+        assert_eq!(tenant_key.device(None).unwrap(), None);
+        assert_eq!(tenant_key.device(Some("m-2")).unwrap(), Some("m-2".into()));
+        assert_eq!(tenant_key.device(Some("g-1")).unwrap_err().status, 403);
+
+        let device_key = verify_signature(&signed_request("k-device", b"secret", NOW), NOW).unwrap();
+        assert_eq!(device_key.device(None).unwrap(), Some("m-1".into()));
+        assert_eq!(device_key.device(Some("m-1")).unwrap(), Some("m-1".into()));
+        assert_eq!(device_key.device(Some("m-2")).unwrap_err().status, 403);
+
+        // A device that no longer belongs to the key's tenant is refused
+        let mut registry = tenant::export_state();
+        registry.devices.insert("m-1".into(), "globex".into());
+        tenant::import_state(registry);
+        assert_eq!(device_key.device(None).unwrap_err().status, 403);
+    }
+}
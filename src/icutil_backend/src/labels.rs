@@ -0,0 +1,96 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+use icutil_common::selector::{self, Selector};
+
+use crate::audit;
+use crate::auth::{can_admin_devices, can_read_readings};
+use crate::hierarchy;
+use crate::lifecycle;
+use crate::schema;
+use crate::tenant;
+use crate::validate_device_id;
+
+const MAX_LABELS: usize = 64; // Per device: tags plus groups
+
+// Tags and group memberships pushed by device_management_backend, which
+// owns them
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DeviceLabels {
+    pub labels: BTreeMap<String, Vec<(String, String)>>,
+}
+
+thread_local! {
+    static LABELS: RefCell<DeviceLabels> = RefCell::new(DeviceLabels::default());
+}
+
+// Everything a selector can test here: `id`, `state`, `type`, the `site`,
+// `building` and `unit` the device is placed at, and the synced labels
+pub fn labels_of(device_id: &str) -> Vec<(String, String)> {
+    let mut labels = vec![("id".to_string(), device_id.to_string())];
+    labels.extend(lifecycle::state_of(device_id).map(|s| ("state".to_string(), s.name().to_string())));
+    labels.extend(schema::with_schemas(|s| s.devices.get(device_id).cloned()).map(|t| ("type".to_string(), t)));
+    labels.extend(hierarchy::with_hierarchy(|h| h.location_labels(device_id)));
+    labels.extend(LABELS.with(|l| l.borrow().labels.get(device_id).cloned().unwrap_or_default()));
+    labels
+}
+
+pub fn parse(text: &str) -> Result<Selector, String> {
+    Selector::parse(text).map_err(|e| format!("Invalid selector: {}", e))
+}
+
+// Whether a stored selector picks a device
+pub fn selects(text: &str, device_id: &str) -> bool {
+    Selector::parse(text).map_or(false, |s| s.matches(&labels_of(device_id)))
+}
+
+// device_management_backend calls this whenever tags or groups change; it
+// needs `devices:admin` here
+#[update(guard = "can_admin_devices")]
+fn sync_device_labels(device_id: String, labels: Vec<(String, String)>) -> Result<(), String> {
+    validate_device_id(&device_id)?;
+    if labels.len() > MAX_LABELS {
+        return Err(format!("At most {} labels per device", MAX_LABELS));
+    }
+    for (key, value) in &labels {
+        selector::validate_label(key, value)?;
+    }
+    let previous = LABELS.with(|l| {
+        let mut store = l.borrow_mut();
+        if labels.is_empty() {
+            store.labels.remove(&device_id)
+        } else {
+            store.labels.insert(device_id.clone(), labels.clone())
+        }
+    });
+    if previous.unwrap_or_default() != labels {
+        let details = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        audit::record("device.labels", Some(&device_id), details);
+    }
+    Ok(())
+}
+
+// Devices of the caller's tenant a selector picks, by id
+#[query(guard = "can_read_readings")]
+fn select_devices(selector: String, request: PageRequest) -> Result<Page<String>, String> {
+    let selector = parse(&selector)?;
+    let scope = tenant::caller_scope()?;
+    let devices = tenant::with_registry(|r| r.devices_of(&scope))
+        .into_iter()
+        .filter(|id| selector.matches(&labels_of(id)))
+        .map(|id| (id.clone(), id))
+        .collect();
+    pagination::paginate(devices, Order::Ascending, &request)
+}
+
+pub fn export_state() -> DeviceLabels {
+    LABELS.with(|l| l.borrow().clone())
+}
+
+pub fn import_state(labels: DeviceLabels) {
+    LABELS.with(|l| *l.borrow_mut() = labels);
+}
@@ -1,18 +1,66 @@
 use candid::{CandidType, Deserialize};
 use ic_cdk::storage;
-use ic_cdk_macros::{init, query, update};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use icutil_common::access::{self, RoleAssignments};
+use icutil_common::audit::AuditLog;
+use icutil_common::pagination::{self, Order, Page, PageRequest};
 use ic_cdk::api::print;
-use lazy_static::lazy_static;
-use metrics::{Gauge, register_gauge};
 use serde::Serialize;
+use std::cell::Cell;
 use std::collections::VecDeque;
 
+mod alerts;
+mod audit;
+mod auth;
+mod billing;
+mod budget;
+mod calibration;
+mod consumption;
+mod electricity;
+mod export;
+mod forecast;
+mod gas;
+mod health;
+mod hierarchy;
+mod http_gateway;
+mod labels;
+mod lifecycle;
+mod metrics;
+mod mv;
+mod parquet;
+mod rollup;
+mod schema;
+pub mod tenant;
+mod units;
+mod water_quality;
+
+use alerts::AlertEngine;
+use auth::{can_purge_data, can_read_readings, can_write_readings, Users};
+use budget::Budgets;
+use calibration::Calibrations;
+use gas::GasMeters;
+use health::HealthStore;
+use hierarchy::Hierarchy;
+use http_gateway::ApiKeys;
+use labels::DeviceLabels;
+use lifecycle::DeviceStates;
+use rollup::Rollups;
+use schema::{ChannelStore, SchemaRegistry};
+use tenant::{DeviceOwners, TenantRegistry};
+use units::{Measurement, Quantity, Unit};
+use water_quality::QualityStore;
+
 // Structure to store water volume readings
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct VolumeReading {
     pub timestamp: u64,  // Timestamp as UNIX epoch time (in seconds)
-    pub volume: f64,     // Total water volume in cubic meters
+    pub volume: f64,     // Total water volume, always in cubic meters
     pub device_id: Option<String>, // Optional device identifier
+    pub tenant_id: Option<String>, // Owning tenant, resolved at ingest
+    pub raw_volume: Option<f64>,   // Value as reported by the sensor, before calibration
+    pub raw_unit: Option<Unit>,    // Unit of `raw_volume`; readings without one were m³
+    pub calibration_version: Option<u32>, // Calibration applied to `volume`, if any
+    pub seq: Option<u64>, // Insertion order, used as the export cursor
 }
 
 // Error types for better error handling
@@ -29,10 +77,10 @@ pub enum VolumeError {
 type VolumeResult<T> = Result<T, VolumeError>;
 
 // Type alias for volume readings using VecDeque for better performance
-type VolumeReadings = VecDeque<VolumeReading>;
+pub type VolumeReadings = VecDeque<VolumeReading>;
 
 // Constants for configuration
-const MAX_READINGS: usize = 1000;
+pub(crate) const MAX_READINGS: usize = 1000; // Per tenant
 const MIN_VOLUME: f64 = 0.0;
 const MAX_VOLUME: f64 = 10000.0; // Maximum reasonable volume in cubic meters
 const DEVICE_ID_MAX_LENGTH: usize = 32;
@@ -44,6 +92,172 @@ fn init() {
     if let Err(_) = storage::stable_save((initial_readings,)) {
         ic_cdk::trap("Failed to initialize stable storage");
     }
+    gas::ensure_device_type(ic_cdk::api::time() / 1_000_000_000);
+    electricity::ensure_device_type(ic_cdk::api::time() / 1_000_000_000);
+    http_gateway::certify_responses();
+    auth::bootstrap_admin(&ic_cdk::caller().to_string());
+}
+
+// Heap state that has to survive upgrades. The readings stay the first
+// element in stable memory so the regular load/save path keeps working.
+#[derive(CandidType, Deserialize, Default)]
+struct UpgradeState {
+    tenants: Option<TenantRegistry>,
+    device_owners: Option<DeviceOwners>,
+    device_states: Option<DeviceStates>,
+    device_labels: Option<DeviceLabels>,
+    device_health: Option<HealthStore>,
+    hierarchy: Option<Hierarchy>,
+    calibrations: Option<Calibrations>,
+    water_quality: Option<QualityStore>,
+    alerts: Option<AlertEngine>,
+    schemas: Option<SchemaRegistry>,
+    channels: Option<ChannelStore>,
+    rollups: Option<Rollups>,
+    gas_meters: Option<GasMeters>,
+    budgets: Option<Budgets>,
+    api_keys: Option<ApiKeys>,
+    roles: Option<RoleAssignments>,
+    users: Option<Users>,
+    audit_log: Option<AuditLog>,
+    next_reading_seq: Option<u64>,
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let readings = load_readings().unwrap_or_default();
+    let (schemas, channels) = schema::export_state();
+    let state = UpgradeState {
+        tenants: Some(tenant::export_state()),
+        device_owners: Some(tenant::export_owners()),
+        device_states: Some(lifecycle::export_state()),
+        device_labels: Some(labels::export_state()),
+        device_health: Some(health::export_state()),
+        hierarchy: Some(hierarchy::export_state()),
+        calibrations: Some(calibration::export_state()),
+        water_quality: Some(water_quality::export_state()),
+        alerts: Some(alerts::export_state()),
+        schemas: Some(schemas),
+        channels: Some(channels),
+        rollups: Some(rollup::export_state()),
+        gas_meters: Some(gas::export_state()),
+        budgets: Some(budget::export_state()),
+        api_keys: Some(http_gateway::export_state()),
+        roles: Some(access::export_state()),
+        users: Some(auth::export_state()),
+        audit_log: Some(icutil_common::audit::export_state()),
+        next_reading_seq: Some(NEXT_READING_SEQ.with(|s| s.get())),
+    };
+    if storage::stable_save((readings, state)).is_err() {
+        ic_cdk::trap("Failed to save state before upgrade");
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (readings, state): (VolumeReadings, Option<UpgradeState>) = storage::stable_restore()
+        .unwrap_or_else(|_| ic_cdk::trap("Failed to restore state after upgrade"));
+    let state = state.unwrap_or_default();
+    let readings = number_readings(readings, state.next_reading_seq.unwrap_or(0));
+
+    if let Some(tenants) = state.tenants {
+        tenant::import_state(tenants);
+    }
+    if let Some(owners) = state.device_owners {
+        tenant::import_owners(owners);
+    }
+    if let Some(states) = state.device_states {
+        lifecycle::import_state(states);
+    }
+    if let Some(labels) = state.device_labels {
+        labels::import_state(labels);
+    }
+    if let Some(store) = state.device_health {
+        health::import_state(store);
+    }
+    if let Some(locations) = state.hierarchy {
+        hierarchy::import_state(locations);
+    }
+    if let Some(calibrations) = state.calibrations {
+        calibration::import_state(calibrations);
+    }
+    if let Some(store) = state.water_quality {
+        water_quality::import_state(store);
+    }
+    if let Some(engine) = state.alerts {
+        alerts::import_state(engine);
+    }
+    if let (Some(schemas), Some(channels)) = (state.schemas, state.channels) {
+        schema::import_state(schemas, channels);
+    }
+    match state.rollups {
+        Some(rollups) => rollup::import_state(rollups),
+        None => rollup::rebuild(&readings),
+    }
+    if let Some(meters) = state.gas_meters {
+        gas::import_state(meters);
+    }
+    if let Some(budgets) = state.budgets {
+        budget::import_state(budgets);
+    }
+    if let Some(keys) = state.api_keys {
+        http_gateway::import_state(keys);
+    }
+    if let Some(roles) = state.roles {
+        access::import_state(roles);
+    }
+    auth::import_state(state.users);
+    if let Some(log) = state.audit_log {
+        icutil_common::audit::import_state(log);
+    }
+    if !auth::has_active_admin() {
+        auth::bootstrap_admin(&ic_cdk::caller().to_string());
+    }
+    gas::ensure_device_type(ic_cdk::api::time() / 1_000_000_000);
+    electricity::ensure_device_type(ic_cdk::api::time() / 1_000_000_000);
+    http_gateway::certify_responses();
+    if storage::stable_save((readings,)).is_err() {
+        ic_cdk::trap("Failed to restore stable storage");
+    }
+}
+
+thread_local! {
+    static NEXT_READING_SEQ: Cell<u64> = Cell::new(0);
+}
+
+// Give readings stored before sequence numbers existed one, continuing after
+// the highest number in use, and move the counter past every reading
+fn number_readings(mut readings: VolumeReadings, next_seq: u64) -> VolumeReadings {
+    let mut next = readings
+        .iter()
+        .filter_map(|r| r.seq)
+        .max()
+        .map_or(next_seq, |max| next_seq.max(max + 1));
+    for reading in readings.iter_mut().filter(|r| r.seq.is_none()) {
+        reading.seq = Some(next);
+        next += 1;
+    }
+    NEXT_READING_SEQ.with(|s| s.set(next));
+    readings
+}
+
+// Load every stored reading regardless of tenant. Only internal jobs may use
+// this; API calls must go through `load_scoped_readings`.
+pub(crate) fn load_readings() -> VolumeResult<VolumeReadings> {
+    storage::stable_restore::<(VolumeReadings,)>()
+        .map(|(readings,)| readings)
+        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))
+}
+
+pub(crate) fn save_readings(readings: VolumeReadings) -> VolumeResult<()> {
+    storage::stable_save((readings,))
+        .map_err(|_| VolumeError::StorageError("Failed to save to stable storage".to_string()))
+}
+
+// Load the readings visible to the caller's tenant
+fn load_scoped_readings() -> VolumeResult<VolumeReadings> {
+    let scope = tenant::caller_scope().map_err(VolumeError::Unauthorized)?;
+    Ok(scope.filter(load_readings()?))
 }
 
 // Validation functions
@@ -63,7 +277,7 @@ fn validate_volume(volume: f64) -> Result<(), String> {
     Ok(())
 }
 
-fn validate_device_id(id: &str) -> Result<(), String> {
+pub(crate) fn validate_device_id(id: &str) -> Result<(), String> {
     if id.is_empty() {
         return Err("Device ID cannot be empty".into());
     }
@@ -76,48 +290,168 @@ fn validate_device_id(id: &str) -> Result<(), String> {
     Ok(())
 }
 
-// Update function to record a new volume reading with validation
-#[update]
+// Update function to record a new volume reading in cubic meters
+#[update(guard = "can_write_readings")]
 fn record_volume_data(volume: f64, device_id: Option<String>) -> VolumeResult<String> {
+    let _timer = metrics::timer("record_volume_data");
+    let scope = tenant::caller_scope().map_err(VolumeError::Unauthorized)?;
+    ingest_volume(&scope, volume, Unit::CubicMeter, device_id, None)?;
+    Ok("Volume data recorded successfully".to_string())
+}
+
+// Update function to record a volume reading in any volume unit (m3, L, gal).
+// Water meters that also measure temperature pass it in °C.
+#[update(guard = "can_write_readings")]
+fn record_volume(
+    value: f64,
+    unit: String,
+    device_id: Option<String>,
+    temperature: Option<f64>,
+) -> VolumeResult<String> {
+    let _timer = metrics::timer("record_volume");
+    let unit = units::parse_for(&unit, Quantity::Volume).map_err(VolumeError::InvalidVolume)?;
+    let scope = tenant::caller_scope().map_err(VolumeError::Unauthorized)?;
+    ingest_volume(&scope, value, unit, device_id, temperature)?;
+    Ok("Volume data recorded successfully".to_string())
+}
+
+// Keep only the last MAX_READINGS of a tenant. Rotation never evicts
+// another tenant's history, however busy this one is.
+fn rotate_readings(readings: &mut VolumeReadings, tenant_id: Option<&str>) {
+    let mut excess = readings
+        .iter()
+        .filter(|r| r.tenant_id.as_deref() == tenant_id)
+        .count()
+        .saturating_sub(MAX_READINGS);
+    readings.retain(|r| {
+        if excess > 0 && r.tenant_id.as_deref() == tenant_id {
+            excess -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+// Store a reading on behalf of `scope` and return it. Candid callers get
+// their scope from the caller principal, HTTP callers from their API key.
+// A temperature sent with the volume becomes a sample of the device's
+// temperature channel.
+pub(crate) fn ingest_volume(
+    scope: &tenant::TenantScope,
+    volume: f64,
+    unit: Unit,
+    device_id: Option<String>,
+    temperature: Option<f64>,
+) -> VolumeResult<VolumeReading> {
+    let result = check_temperature(temperature, device_id.as_deref())
+        .and_then(|_| store_volume(scope, volume, unit, device_id))
+        .and_then(|reading| {
+            if let (Some(temperature), Some(device_id)) = (temperature, reading.device_id.as_deref()) {
+                let channel = water_quality::QualityChannel::Temperature;
+                water_quality::record_sample(scope.tenant_id(), device_id, channel, temperature, reading.timestamp)
+                    .map_err(VolumeError::InvalidVolume)?;
+            }
+            Ok(reading)
+        });
+    match result {
+        Ok(_) => metrics::reading_accepted(),
+        Err(ref e) => metrics::reading_rejected(e),
+    }
+    result
+}
+
+// Checked before the volume is stored so a bad temperature stores nothing
+fn check_temperature(temperature: Option<f64>, device_id: Option<&str>) -> VolumeResult<()> {
+    let Some(temperature) = temperature else {
+        return Ok(());
+    };
+    if device_id.is_none() {
+        return Err(VolumeError::InvalidVolume("A temperature needs a device id".into()));
+    }
+    water_quality::QualityChannel::Temperature
+        .validate(temperature)
+        .map_err(VolumeError::InvalidVolume)
+}
+
+fn store_volume(
+    scope: &tenant::TenantScope,
+    volume: f64,
+    unit: Unit,
+    device_id: Option<String>,
+) -> VolumeResult<VolumeReading> {
     // Validate input parameters
-    validate_volume(volume).map_err(|e| VolumeError::InvalidVolume(e))?;
-    
+    if !volume.is_finite() || volume < 0.0 {
+        return Err(VolumeError::InvalidVolume("Raw value must be a finite, non-negative number".into()));
+    }
+
     if let Some(ref device_id) = device_id {
         validate_device_id(device_id).map_err(|e| VolumeError::InvalidVolume(e))?;
+        // Volume readings raise no alerts, so maintenance makes no difference
+        lifecycle::admit(device_id).map_err(VolumeError::Unauthorized)?;
     }
 
+    // Stamp the reading with the tenant that owns the device
+    let tenant_id = tenant::with_registry(|registry| {
+        registry.resolve_ingest_tenant(scope, device_id.as_deref())
+    })
+    .map_err(VolumeError::Unauthorized)?;
+
+    // Apply the device calibration in force in the sensor's own unit, then
+    // store the result in cubic meters while keeping the raw value
+    let timestamp = ic_cdk::api::time() / 1_000_000_000;  // Convert nanoseconds to seconds
+    let raw_volume = volume;
+    let (calibrated, calibration_version) = match device_id.as_deref() {
+        Some(id) => calibration::with_calibrations(|c| c.calibrate(id, timestamp, raw_volume)),
+        None => (raw_volume, None),
+    };
+    let volume = units::convert(calibrated, unit, Unit::CubicMeter).map_err(VolumeError::InvalidVolume)?;
+    validate_volume(volume).map_err(|e| VolumeError::InvalidVolume(e))?;
+
     // Retrieve the current list of volume readings
-    let mut volume_readings: VolumeReadings = storage::stable_restore()
-        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))?;
+    let mut volume_readings = load_readings()?;
 
     // Create a new volume reading
     let new_reading = VolumeReading {
-        timestamp: ic_cdk::api::time() / 1_000_000_000,  // Convert nanoseconds to seconds
+        timestamp,
         volume,
         device_id,
+        tenant_id: Some(tenant_id),
+        raw_volume: Some(raw_volume),
+        raw_unit: Some(unit),
+        calibration_version,
+        seq: Some(NEXT_READING_SEQ.with(|s| s.replace(s.get() + 1))),
     };
 
     // Append the new reading
-    volume_readings.push_back(new_reading);
+    volume_readings.push_back(new_reading.clone());
+    rotate_readings(&mut volume_readings, new_reading.tenant_id.as_deref());
 
-    // Keep only the last MAX_READINGS to avoid unbounded growth
-    while volume_readings.len() > MAX_READINGS {
-        volume_readings.pop_front();
+    // Save the updated list back to stable storage
+    save_readings(volume_readings)?;
+    if let (Some(device_id), Some(tenant_id)) = (&new_reading.device_id, &new_reading.tenant_id) {
+        health::record_reading(device_id, timestamp);
+        rollup::record_usage(tenant_id, device_id, consumption::Utility::Water, timestamp, volume);
     }
 
-    // Save the updated list back to stable storage
-    storage::stable_save((volume_readings,))
-        .map_err(|_| VolumeError::StorageError("Failed to save to stable storage".to_string()))?;
+    ic_cdk::println!("Recording volume: {} cubic meters ({} {})", volume, raw_volume, unit.symbol());
+    Ok(new_reading)
+}
 
-    ic_cdk::println!("Recording volume: {} cubic meters", volume);
-    Ok("Volume data recorded successfully".to_string())
+// Readings of the caller's tenant, newest first, one page at a time
+#[query(guard = "can_read_readings")]
+fn list_readings(request: PageRequest) -> VolumeResult<Page<VolumeReading>> {
+    let readings = load_scoped_readings()?
+        .into_iter()
+        .map(|r| (r.seq.unwrap_or(0), r))
+        .collect();
+    pagination::paginate(readings, Order::Descending, &request).map_err(VolumeError::InvalidVolume)
 }
 
-// Query function to retrieve recent volume readings with error handling
-#[query]
+// Query function to retrieve recent volume readings with error handling.
+// Capped at MAX_READINGS; use `list_readings` to walk the whole history.
+#[query(guard = "can_read_readings")]
 fn get_recent_readings(count: usize) -> VolumeResult<Vec<VolumeReading>> {
-    let volume_readings: VolumeReadings = storage::stable_restore()
-        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))?;
+    let volume_readings = load_scoped_readings()?;
 
     if volume_readings.is_empty() {
         return Err(VolumeError::DataNotFound);
@@ -135,11 +469,36 @@ fn get_recent_readings(count: usize) -> VolumeResult<Vec<VolumeReading>> {
     Ok(result)
 }
 
+// A reading converted to the unit requested by the caller
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UnitReading {
+    pub timestamp: u64,
+    pub device_id: Option<String>,
+    pub volume: Measurement,
+}
+
+// Query function to retrieve recent readings converted to `unit`
+#[query(guard = "can_read_readings")]
+fn get_recent_readings_in_unit(count: usize, unit: String) -> VolumeResult<Vec<UnitReading>> {
+    let unit = units::parse_for(&unit, Quantity::Volume).map_err(VolumeError::InvalidVolume)?;
+
+    get_recent_readings(count)?
+        .into_iter()
+        .map(|r| {
+            let value = units::convert(r.volume, Unit::CubicMeter, unit).map_err(VolumeError::InvalidVolume)?;
+            Ok(UnitReading {
+                timestamp: r.timestamp,
+                device_id: r.device_id,
+                volume: Measurement::new(value, unit),
+            })
+        })
+        .collect()
+}
+
 // Query function to calculate the average volume with error handling
-#[query]
+#[query(guard = "can_read_readings")]
 fn get_average_volume() -> VolumeResult<f64> {
-    let volume_readings: VolumeReadings = storage::stable_restore()
-        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))?;
+    let volume_readings = load_scoped_readings()?;
 
     if volume_readings.is_empty() {
         return Err(VolumeError::DataNotFound);
@@ -164,11 +523,21 @@ pub struct VolumeStatistics {
 }
 
 // Query function to get volume statistics
-#[query]
+#[query(guard = "can_read_readings")]
 fn get_volume_statistics() -> VolumeResult<VolumeStatistics> {
-    let volume_readings: VolumeReadings = storage::stable_restore()
-        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))?;
+    compute_statistics(&load_scoped_readings()?)
+}
+
+// Statistics over the devices a selector picks, e.g. "site=A AND type=water"
+#[query(guard = "can_read_readings")]
+fn get_volume_statistics_by_selector(selector: String) -> VolumeResult<VolumeStatistics> {
+    let selector = labels::parse(&selector).map_err(VolumeError::InvalidVolume)?;
+    let mut readings = load_scoped_readings()?;
+    readings.retain(|r| r.device_id.as_deref().map_or(false, |id| selector.matches(&labels::labels_of(id))));
+    compute_statistics(&readings)
+}
 
+pub(crate) fn compute_statistics(volume_readings: &VolumeReadings) -> VolumeResult<VolumeStatistics> {
     if volume_readings.is_empty() {
         return Err(VolumeError::DataNotFound);
     }
@@ -202,10 +571,9 @@ fn get_volume_statistics() -> VolumeResult<VolumeStatistics> {
 }
 
 // Query function to get volume consumed over a time period
-#[query]
+#[query(guard = "can_read_readings")]
 fn get_volume_consumed(start_timestamp: u64, end_timestamp: u64) -> VolumeResult<f64> {
-    let volume_readings: VolumeReadings = storage::stable_restore()
-        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))?;
+    let volume_readings = load_scoped_readings()?;
 
     if volume_readings.is_empty() {
         return Err(VolumeError::DataNotFound);
@@ -229,11 +597,20 @@ fn get_volume_consumed(start_timestamp: u64, end_timestamp: u64) -> VolumeResult
     Ok(max_volume - min_volume)
 }
 
-// Query function to export all data as JSON with error handling
-#[query]
+// Query function to get volume consumed over a time period in `unit`
+#[query(guard = "can_read_readings")]
+fn get_volume_consumed_in_unit(start_timestamp: u64, end_timestamp: u64, unit: String) -> VolumeResult<Measurement> {
+    let unit = units::parse_for(&unit, Quantity::Volume).map_err(VolumeError::InvalidVolume)?;
+    let consumed = get_volume_consumed(start_timestamp, end_timestamp)?;
+    let value = units::convert(consumed, Unit::CubicMeter, unit).map_err(VolumeError::InvalidVolume)?;
+    Ok(Measurement::new(value, unit))
+}
+
+// Query function to export all data as JSON with error handling.
+// Deprecated: the reply grows with the history; use `export_readings`.
+#[query(guard = "can_read_readings")]
 fn export_all_readings() -> VolumeResult<String> {
-    let volume_readings: VolumeReadings = storage::stable_restore()
-        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))?;
+    let volume_readings = load_scoped_readings()?;
 
     if volume_readings.is_empty() {
         return Err(VolumeError::DataNotFound);
@@ -247,19 +624,17 @@ fn export_all_readings() -> VolumeResult<String> {
 }
 
 // Query function to get readings count
-#[query]
+#[query(guard = "can_read_readings")]
 fn get_readings_count() -> VolumeResult<usize> {
-    let volume_readings: VolumeReadings = storage::stable_restore()
-        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))?;
+    let volume_readings = load_scoped_readings()?;
     
     Ok(volume_readings.len())
 }
 
 // Query function to get current total volume
-#[query]
+#[query(guard = "can_read_readings")]
 fn get_current_total_volume() -> VolumeResult<f64> {
-    let volume_readings: VolumeReadings = storage::stable_restore()
-        .map_err(|_| VolumeError::StorageError("Failed to retrieve stable storage".to_string()))?;
+    let volume_readings = load_scoped_readings()?;
 
     if volume_readings.is_empty() {
         return Err(VolumeError::DataNotFound);
@@ -277,45 +652,88 @@ fn documentation() -> String {
 This canister tracks total water volume in cubic meters.
 
 ## Main Functions:
-- `record_volume_data(volume: f64, device_id: Option<String>)` - Record new volume reading
+- `record_volume_data(volume: f64, device_id: Option<String>)` - Record new volume reading in m³
+- `record_volume(value: f64, unit: String, device_id: Option<String>, temperature: Option<f64>)` - Record a volume reading in m3, L or gal, with an optional temperature in °C
+- `get_recent_readings_in_unit(count: usize, unit: String)` - Recent readings converted to a volume unit
 - `get_recent_readings(count: usize)` - Get recent volume readings
 - `get_current_total_volume()` - Get the latest total volume
 - `get_volume_statistics()` - Get comprehensive volume statistics
 - `get_volume_consumed(start: u64, end: u64)` - Get volume consumed in time period
 
 ## Units:
-- Volume: cubic meters (m³)
+- Volume: stored in cubic meters (m³); m3, L and gal accepted on input and output
+- Flow: L/min (also m3/h, gal/min)
+- Energy: kWh (also Wh, MJ)
+- Power: kW (also W)
+- Temperature: °C (also degF, K)
+- Unknown unit symbols are rejected
 - Timestamps: UNIX epoch seconds
 "#.to_string()
 }
 
-// Update function to clear all readings (admin function)
-#[update]
+// Update function to clear the caller's tenant readings (admin function)
+#[update(guard = "can_purge_data")]
 fn clear_all_readings() -> VolumeResult<String> {
-    let empty_readings: VolumeReadings = VecDeque::new();
-    storage::stable_save((empty_readings,))
+    let _timer = metrics::timer("clear_all_readings");
+    let scope = tenant::caller_scope().map_err(VolumeError::Unauthorized)?;
+    let mut volume_readings = load_readings()?;
+    volume_readings.retain(|r| !scope.owns(r));
+    storage::stable_save((volume_readings,))
         .map_err(|_| VolumeError::StorageError("Failed to clear storage".to_string()))?;
+    rollup::purge(scope.tenant_id(), consumption::Utility::Water);
+    audit::record("data.purge", Some(scope.tenant_id()), vec!["volume readings".to_string()]);
     Ok("All volume readings cleared successfully".to_string())
 }
 
 // Alert system for high volume usage
-lazy_static! {
-    static ref CRITICAL_VOLUME_ALERT: Gauge = register_gauge!(
-        "volume_critical",
-        "Critical volume threshold"
-    ).unwrap();
-}
-
 #[ic_cdk::heartbeat]
 fn check_alerts() {
-    let stats = match get_volume_statistics() {
+    let _timer = metrics::timer("heartbeat");
+    budget::check_budgets(ic_cdk::api::time() / 1_000_000_000);
+    health::check_health(ic_cdk::api::time() / 1_000_000_000);
+
+    let stats = match load_readings().and_then(|readings| compute_statistics(&readings)) {
         Ok(s) => s,
         Err(_) => return,
     };
     
     // Alert if current volume exceeds 90% of maximum allowed
-    if stats.latest_volume > MAX_VOLUME * 0.9 {
-        CRITICAL_VOLUME_ALERT.set(1.0);
+    let critical = stats.latest_volume > MAX_VOLUME * 0.9;
+    metrics::set_volume_critical(critical);
+    if critical {
         ic_cdk::println!("ALERT: Critical volume threshold exceeded - {} m³", stats.latest_volume);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(seq: u64, tenant_id: &str) -> VolumeReading {
+        VolumeReading {
+            timestamp: seq,
+            volume: 1.0,
+            device_id: None,
+            tenant_id: Some(tenant_id.into()),
+            raw_volume: None,
+            raw_unit: None,
+            calibration_version: None,
+            seq: Some(seq),
+        }
+    }
+
+    #[test]
+    fn rotation_only_evicts_the_busy_tenants_readings() {
+        let mut readings: VolumeReadings = (0..5).map(|i| reading(i, "quiet")).collect();
+        for i in 5..(5 + MAX_READINGS as u64 + 10) {
+            readings.push_back(reading(i, "busy"));
+            rotate_readings(&mut readings, Some("busy"));
+        }
+        let count = |tenant: &str| readings.iter().filter(|r| r.tenant_id.as_deref() == Some(tenant)).count();
+        assert_eq!(count("quiet"), 5);
+        assert_eq!(count("busy"), MAX_READINGS);
+        // The oldest readings of the busy tenant went first
+        let oldest_busy = readings.iter().find(|r| r.tenant_id.as_deref() == Some("busy")).unwrap();
+        assert_eq!(oldest_busy.seq, Some(15));
+    }
+}
@@ -0,0 +1,61 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use icutil_common::lifecycle::{DeviceState, IngestPolicy};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::audit;
+use crate::auth::{can_admin_devices, can_read_readings};
+use crate::tenant;
+use crate::validate_device_id;
+
+// Lifecycle states pushed by device_management_backend, which owns them.
+// Devices it never reported keep the behaviour from before lifecycles.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DeviceStates {
+    pub states: BTreeMap<String, DeviceState>,
+}
+
+thread_local! {
+    static STATES: RefCell<DeviceStates> = RefCell::new(DeviceStates::default());
+}
+
+pub fn state_of(device_id: &str) -> Option<DeviceState> {
+    STATES.with(|s| s.borrow().states.get(device_id).copied())
+}
+
+// How to treat a reading from `device_id`, or why it is rejected
+pub fn admit(device_id: &str) -> Result<IngestPolicy, String> {
+    match state_of(device_id) {
+        Some(state) => state.admit(device_id),
+        None => Ok(IngestPolicy::Accept),
+    }
+}
+
+// device_management_backend calls this on every registration and state
+// change; it needs `devices:admin` here
+#[update(guard = "can_admin_devices")]
+fn sync_device_state(device_id: String, state: DeviceState) -> Result<(), String> {
+    validate_device_id(&device_id)?;
+    let previous = STATES.with(|s| s.borrow_mut().states.insert(device_id.clone(), state));
+    if previous != Some(state) {
+        let from = previous.map_or("unknown", |p| p.name());
+        audit::record("device.state", Some(&device_id), vec![format!("{} -> {}", from, state.name())]);
+    }
+    Ok(())
+}
+
+#[query(guard = "can_read_readings")]
+fn get_device_state(device_id: String) -> Result<Option<DeviceState>, String> {
+    tenant::require_device(&tenant::caller_scope()?, &device_id)?;
+    Ok(state_of(&device_id))
+}
+
+pub fn export_state() -> DeviceStates {
+    STATES.with(|s| s.borrow().clone())
+}
+
+pub fn import_state(states: DeviceStates) {
+    STATES.with(|s| *s.borrow_mut() = states);
+}
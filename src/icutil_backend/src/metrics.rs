@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+
+use icutil_common::http::HttpResponse;
+use icutil_common::metrics::{counter_add, gauge_set, scrape, CallTimer, RuntimeStats};
+
+use crate::{budget, health, VolumeError, VolumeReadings, MAX_READINGS};
+
+const FLOW_WINDOW: u64 = 3600; // Seconds of readings behind flow_rate_average
+
+fn instructions() -> u64 {
+    ic_cdk::api::performance_counter(0)
+}
+
+// Time an endpoint; the call is recorded when the timer goes out of scope
+pub fn timer(endpoint: &'static str) -> CallTimer {
+    CallTimer::start(endpoint, instructions)
+}
+
+pub fn reading_accepted() {
+    counter_add("flow_readings", "Volume readings accepted", &[], 1);
+}
+
+pub fn reading_rejected(error: &VolumeError) {
+    let reason = match error {
+        VolumeError::InvalidVolume(_) => "invalid_volume",
+        VolumeError::Unauthorized(_) => "unauthorized",
+        VolumeError::RateLimit(_) => "rate_limit",
+        VolumeError::StorageError(_) => "storage_error",
+        VolumeError::DataNotFound => "not_found",
+    };
+    counter_add("flow_readings_rejected", "Volume readings rejected, by reason", &[("reason", reason)], 1);
+}
+
+pub fn set_volume_critical(critical: bool) {
+    gauge_set("volume_critical", "1 while the latest volume is above 90% of the maximum", &[], critical as u8 as f64);
+}
+
+// Mean flow in L/min over the last hour, averaged over the devices with at
+// least two readings in that window. Meter resets are skipped.
+fn average_flow_rate(readings: &VolumeReadings, now: u64) -> f64 {
+    let mut by_device: BTreeMap<&str, Vec<(u64, f64)>> = BTreeMap::new();
+    for r in readings.iter().filter(|r| r.timestamp + FLOW_WINDOW >= now) {
+        if let Some(ref device_id) = r.device_id {
+            by_device.entry(device_id).or_default().push((r.timestamp, r.volume));
+        }
+    }
+    let rates: Vec<f64> = by_device
+        .values()
+        .filter_map(|samples| {
+            let (first, last) = (samples.first()?, samples.last()?);
+            let minutes = last.0.saturating_sub(first.0) as f64 / 60.0;
+            let liters = (last.1 - first.1) * 1000.0;
+            (minutes > 0.0 && liters >= 0.0).then(|| liters / minutes)
+        })
+        .collect();
+    if rates.is_empty() {
+        0.0
+    } else {
+        rates.iter().sum::<f64>() / rates.len() as f64
+    }
+}
+
+// GET /metrics. Gauges that describe current state are computed here, the
+// counters and histograms were recorded as calls came in.
+pub fn serve() -> HttpResponse {
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let readings = crate::load_readings().unwrap_or_default();
+
+    gauge_set("flow_readings_buffered", "Readings held in the rotating reading buffer", &[], readings.len() as f64);
+    gauge_set("flow_readings_buffer_capacity", "Readings kept per tenant in the rotating buffer", &[], MAX_READINGS as f64);
+    gauge_set("flow_rate_average", "Mean flow over the last hour in L/min", &[], average_flow_rate(&readings, now));
+    for (status, count) in health::count_by_status(now) {
+        let status = format!("{:?}", status).to_lowercase();
+        gauge_set("devices_health", "Devices per health status", &[("status", status.as_str())], count as f64);
+    }
+    let last_check = budget::last_check();
+    if last_check > 0 {
+        gauge_set(
+            "rollup_lag_seconds",
+            "Seconds since the heartbeat last rolled up consumption for budgets",
+            &[],
+            now.saturating_sub(last_check) as f64,
+        );
+    }
+
+    scrape(RuntimeStats {
+        cycles: ic_cdk::api::canister_balance128(),
+        stable_pages: ic_cdk::api::stable::stable64_size(),
+    })
+}
@@ -0,0 +1,477 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+use serde::Serialize;
+
+use crate::auth::can_read_readings;
+use crate::consumption::{ConsumptionTarget, Utility};
+use crate::rollup::{self, DAY};
+use crate::schema;
+use crate::tenant::{self, TenantScope};
+use crate::units::{self, Quantity};
+
+const MIN_BASELINE_DAYS: usize = 14;
+const MAX_PERIOD_DAYS: u64 = 366;
+const DEFAULT_BASE_TEMPERATURE: f64 = 18.0; // °C, for heating and cooling degree days
+const CONFIDENCE: f64 = 90.0;
+// ASHRAE Guideline 14 correction for autocorrelation of daily data
+const AUTOCORRELATION_FACTOR: f64 = 1.26;
+// Two-sided 90% Student t values for 1-30 degrees of freedom
+const T_90: [f64; 30] = [
+    6.314, 2.920, 2.353, 2.132, 2.015, 1.943, 1.895, 1.860, 1.833, 1.812, 1.796, 1.782, 1.771, 1.761,
+    1.753, 1.746, 1.740, 1.734, 1.729, 1.725, 1.721, 1.717, 1.714, 1.711, 1.708, 1.706, 1.703, 1.701,
+    1.699, 1.697,
+];
+const T_90_LARGE: f64 = 1.645;
+
+// Temperature series a baseline is normalized by: a temperature channel of
+// a schema-defined device, such as an outdoor sensor
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct TemperatureSource {
+    pub device_id: String,
+    pub channel: String,
+    pub base_temperature: Option<f64>, // °C, defaults to 18
+}
+
+// Daily consumption model fitted by least squares:
+//   usage = intercept + heating_slope * HDD + cooling_slope * CDD
+// Without a temperature source only the intercept (mean daily usage) is fitted.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BaselineModel {
+    pub target: ConsumptionTarget,
+    pub utility: Utility,
+    pub unit: String,
+    pub reference_start: u64,
+    pub reference_end: u64,
+    pub days: u32,
+    pub intercept: f64,
+    pub heating_slope: Option<f64>, // None when the reference period had no heating degree days
+    pub cooling_slope: Option<f64>,
+    pub base_temperature: Option<f64>,
+    pub r_squared: f64,
+    pub cv_rmse: f64, // Percent of the mean daily usage
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SavingsDay {
+    pub day_start: u64,
+    pub adjusted_baseline: f64,
+    pub actual: f64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SavingsReport {
+    pub baseline: BaselineModel,
+    pub reporting_start: u64,
+    pub reporting_end: u64,
+    pub days: u32,
+    pub adjusted_baseline: f64, // What the baseline predicts for the reporting period
+    pub actual: f64,
+    pub avoided: f64, // adjusted_baseline - actual
+    pub savings_percent: Option<f64>,
+    pub uncertainty: f64, // Absolute, at `confidence` percent
+    pub confidence: f64,
+    pub daily: Vec<SavingsDay>,
+}
+
+// One day of data: consumption and, when normalizing, mean temperature
+struct DailyObservation {
+    day_start: u64,
+    usage: f64,
+    temperature: Option<f64>,
+}
+
+fn degree_days(temperature: f64, base: f64) -> (f64, f64) {
+    ((base - temperature).max(0.0), (temperature - base).max(0.0))
+}
+
+// Whole UTC days inside [start, end)
+fn day_range(start: u64, end: u64) -> Result<(u64, usize), String> {
+    let first = start.div_ceil(DAY);
+    let last = end / DAY;
+    if last <= first {
+        return Err("Period must contain at least one whole UTC day".into());
+    }
+    if last - first > MAX_PERIOD_DAYS {
+        return Err(format!("Period must not exceed {} days", MAX_PERIOD_DAYS));
+    }
+    Ok((first, (last - first) as usize))
+}
+
+// Mean temperature in °C per day from a schema channel
+fn daily_temperature(
+    scope: &TenantScope,
+    source: &TemperatureSource,
+    first_day: u64,
+    days: usize,
+) -> Result<Vec<Option<f64>>, String> {
+    tenant::require_device(scope, &source.device_id)?;
+    schema::with_schemas(|s| {
+        let spec = s.type_of(&source.device_id)?.channel(&source.channel)?;
+        let unit = spec.unit.as_deref().ok_or_else(|| format!("{} has no unit", spec.name))?;
+        units::parse_for(unit, Quantity::Temperature)
+    })?;
+    Ok(rollup::daily_temperature(scope, &source.device_id, &source.channel, first_day, days))
+}
+
+fn observations(
+    scope: &TenantScope,
+    utility: Utility,
+    devices: &[String],
+    temperature: Option<&TemperatureSource>,
+    start: u64,
+    end: u64,
+) -> Result<Vec<DailyObservation>, String> {
+    let (first_day, days) = day_range(start, end)?;
+    let usage = rollup::daily_usage(scope, utility, devices, first_day, days);
+    let temperatures = match temperature {
+        Some(source) => Some(daily_temperature(scope, source, first_day, days)?),
+        None => None,
+    };
+
+    Ok(usage
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, usage)| {
+            let temperature = match &temperatures {
+                Some(t) => Some(t[i]?),
+                None => None,
+            };
+            Some(DailyObservation { day_start: (first_day + i as u64) * DAY, usage: usage?, temperature })
+        })
+        .collect())
+}
+
+// Solve the normal equations (X'X) b = X'y by Gaussian elimination with
+// partial pivoting. Returns None when the columns are linearly dependent.
+fn least_squares(rows: &[Vec<f64>], y: &[f64]) -> Option<Vec<f64>> {
+    let p = rows.first()?.len();
+    let mut a = vec![vec![0.0; p + 1]; p];
+    for (row, &target) in rows.iter().zip(y) {
+        for i in 0..p {
+            for j in 0..p {
+                a[i][j] += row[i] * row[j];
+            }
+            a[i][p] += row[i] * target;
+        }
+    }
+
+    for col in 0..p {
+        let pivot = (col..p).max_by(|&r1, &r2| a[r1][col].abs().total_cmp(&a[r2][col].abs()))?;
+        if a[pivot][col].abs() < 1e-9 {
+            return None;
+        }
+        a.swap(col, pivot);
+        for row in 0..p {
+            if row != col {
+                let factor = a[row][col] / a[col][col];
+                for k in col..=p {
+                    a[row][k] -= factor * a[col][k];
+                }
+            }
+        }
+    }
+    Some((0..p).map(|i| a[i][p] / a[i][i]).collect())
+}
+
+fn t_value(degrees_of_freedom: usize) -> f64 {
+    match degrees_of_freedom {
+        0 => f64::INFINITY,
+        n if n <= T_90.len() => T_90[n - 1],
+        _ => T_90_LARGE,
+    }
+}
+
+impl BaselineModel {
+    fn predict(&self, temperature: Option<f64>) -> f64 {
+        let mut value = self.intercept;
+        if let (Some(t), Some(base)) = (temperature, self.base_temperature) {
+            let (hdd, cdd) = degree_days(t, base);
+            value += self.heating_slope.unwrap_or(0.0) * hdd + self.cooling_slope.unwrap_or(0.0) * cdd;
+        }
+        value.max(0.0)
+    }
+
+    // Number of fitted parameters
+    fn parameters(&self) -> usize {
+        1 + self.heating_slope.is_some() as usize + self.cooling_slope.is_some() as usize
+    }
+}
+
+fn fit(
+    target: ConsumptionTarget,
+    utility: Utility,
+    reference_start: u64,
+    reference_end: u64,
+    observations: &[DailyObservation],
+    base_temperature: Option<f64>,
+) -> Result<BaselineModel, String> {
+    if observations.len() < MIN_BASELINE_DAYS {
+        return Err(format!(
+            "Reference period has {} days with data, at least {} are needed",
+            observations.len(),
+            MIN_BASELINE_DAYS
+        ));
+    }
+
+    // Only keep a degree-day term if the reference period actually varies
+    // in it; otherwise the slope cannot be estimated
+    let degree_days: Vec<(f64, f64)> = observations
+        .iter()
+        .map(|o| match (o.temperature, base_temperature) {
+            (Some(t), Some(base)) => degree_days(t, base),
+            _ => (0.0, 0.0),
+        })
+        .collect();
+    let varies = |f: fn(&(f64, f64)) -> f64| {
+        let first = f(&degree_days[0]);
+        degree_days.iter().any(|d| (f(d) - first).abs() > 1e-9)
+    };
+    let use_heating = base_temperature.is_some() && varies(|d| d.0);
+    let use_cooling = base_temperature.is_some() && varies(|d| d.1);
+
+    let rows: Vec<Vec<f64>> = degree_days
+        .iter()
+        .map(|&(hdd, cdd)| {
+            let mut row = vec![1.0];
+            if use_heating {
+                row.push(hdd);
+            }
+            if use_cooling {
+                row.push(cdd);
+            }
+            row
+        })
+        .collect();
+    let y: Vec<f64> = observations.iter().map(|o| o.usage).collect();
+    let coefficients = least_squares(&rows, &y).ok_or("Baseline regression is singular")?;
+
+    let mut coefficients = coefficients.into_iter();
+    let mut model = BaselineModel {
+        target,
+        utility,
+        unit: utility.unit().symbol().to_string(),
+        reference_start,
+        reference_end,
+        days: observations.len() as u32,
+        intercept: coefficients.next().unwrap_or(0.0),
+        heating_slope: if use_heating { coefficients.next() } else { None },
+        cooling_slope: if use_cooling { coefficients.next() } else { None },
+        base_temperature,
+        r_squared: 0.0,
+        cv_rmse: 0.0,
+    };
+
+    let n = observations.len();
+    let mean = y.iter().sum::<f64>() / n as f64;
+    if mean <= 0.0 {
+        return Err("Reference period has no consumption".into());
+    }
+    let sse: f64 = observations
+        .iter()
+        .map(|o| (o.usage - model.predict(o.temperature)).powi(2))
+        .sum();
+    let sst: f64 = y.iter().map(|v| (v - mean).powi(2)).sum();
+    model.r_squared = if sst > 0.0 { (1.0 - sse / sst).max(0.0) } else { 0.0 };
+    model.cv_rmse = 100.0 * (sse / (n - model.parameters()) as f64).sqrt() / mean;
+    Ok(model)
+}
+
+// Avoided consumption per ASHRAE Guideline 14: the baseline adjusted to the
+// reporting period's conditions minus what was actually used
+fn savings(
+    baseline: BaselineModel,
+    reporting_start: u64,
+    reporting_end: u64,
+    observations: &[DailyObservation],
+) -> Result<SavingsReport, String> {
+    if observations.is_empty() {
+        return Err("Reporting period has no days with data".into());
+    }
+    let daily: Vec<SavingsDay> = observations
+        .iter()
+        .map(|o| SavingsDay {
+            day_start: o.day_start,
+            adjusted_baseline: baseline.predict(o.temperature),
+            actual: o.usage,
+        })
+        .collect();
+    let adjusted_baseline: f64 = daily.iter().map(|d| d.adjusted_baseline).sum();
+    let actual: f64 = daily.iter().map(|d| d.actual).sum();
+
+    let n = baseline.days as f64;
+    let m = daily.len() as f64;
+    let uncertainty = t_value(baseline.days as usize - baseline.parameters())
+        * AUTOCORRELATION_FACTOR
+        * (baseline.cv_rmse / 100.0)
+        * ((n / m) * (1.0 + 2.0 / n)).sqrt()
+        * adjusted_baseline;
+
+    Ok(SavingsReport {
+        baseline,
+        reporting_start,
+        reporting_end,
+        days: daily.len() as u32,
+        adjusted_baseline,
+        actual,
+        avoided: adjusted_baseline - actual,
+        savings_percent: (adjusted_baseline > 0.0)
+            .then(|| 100.0 * (adjusted_baseline - actual) / adjusted_baseline),
+        uncertainty,
+        confidence: CONFIDENCE,
+        daily,
+    })
+}
+
+fn fit_for_caller(
+    scope: &TenantScope,
+    target: ConsumptionTarget,
+    utility: Utility,
+    reference_start: u64,
+    reference_end: u64,
+    temperature: Option<&TemperatureSource>,
+) -> Result<(BaselineModel, Vec<String>), String> {
+    let devices = target.devices(scope)?;
+    let base_temperature =
+        temperature.map(|t| t.base_temperature.unwrap_or(DEFAULT_BASE_TEMPERATURE));
+    let reference = observations(scope, utility, &devices, temperature, reference_start, reference_end)?;
+    let model = fit(target, utility, reference_start, reference_end, &reference, base_temperature)?;
+    Ok((model, devices))
+}
+
+#[query(guard = "can_read_readings")]
+fn fit_baseline(
+    target: ConsumptionTarget,
+    utility: Option<Utility>,
+    reference_start: u64,
+    reference_end: u64,
+    temperature: Option<TemperatureSource>,
+) -> Result<BaselineModel, String> {
+    let scope = tenant::caller_scope()?;
+    let utility = utility.unwrap_or_default();
+    fit_for_caller(&scope, target, utility, reference_start, reference_end, temperature.as_ref())
+        .map(|(model, _)| model)
+}
+
+#[query(guard = "can_read_readings")]
+fn get_savings_report(
+    target: ConsumptionTarget,
+    utility: Option<Utility>,
+    reference_start: u64,
+    reference_end: u64,
+    reporting_start: u64,
+    reporting_end: u64,
+    temperature: Option<TemperatureSource>,
+) -> Result<SavingsReport, String> {
+    if reporting_start < reference_end {
+        return Err("Reporting period must start after the reference period ends".into());
+    }
+    let scope = tenant::caller_scope()?;
+    let utility = utility.unwrap_or_default();
+    let (baseline, devices) = fit_for_caller(
+        &scope,
+        target,
+        utility,
+        reference_start,
+        reference_end,
+        temperature.as_ref(),
+    )?;
+    let reporting = observations(
+        &scope,
+        utility,
+        &devices,
+        temperature.as_ref(),
+        reporting_start,
+        reporting_end,
+    )?;
+    savings(baseline, reporting_start, reporting_end, &reporting)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    fn day(i: u64, usage: f64, temperature: Option<f64>) -> DailyObservation {
+        DailyObservation { day_start: i * DAY, usage, temperature }
+    }
+
+    fn baseline(observations: &[DailyObservation], base_temperature: Option<f64>) -> BaselineModel {
+        let target = ConsumptionTarget::Device("meter-1".into());
+        fit(target, Utility::Water, 0, 14 * DAY, observations, base_temperature).unwrap()
+    }
+
+    #[test]
+    fn least_squares_recovers_a_line() {
+        let rows: Vec<Vec<f64>> = (0..6).map(|x| vec![1.0, x as f64]).collect();
+        let y: Vec<f64> = (0..6).map(|x| 3.0 + 2.0 * x as f64).collect();
+        let b = least_squares(&rows, &y).unwrap();
+        assert!(close(b[0], 3.0) && close(b[1], 2.0));
+        // A repeated column cannot be solved
+        assert!(least_squares(&[vec![1.0, 1.0], vec![2.0, 2.0]], &[1.0, 2.0]).is_none());
+    }
+
+    #[test]
+    fn mean_baseline_without_temperature() {
+        let observations: Vec<_> = (0..14).map(|i| day(i, if i % 2 == 0 { 8.0 } else { 12.0 }, None)).collect();
+        let model = baseline(&observations, None);
+        assert!(close(model.intercept, 10.0));
+        assert_eq!((model.heating_slope, model.cooling_slope), (None, None));
+        assert_eq!(model.r_squared, 0.0);
+        // sqrt(SSE / (n - p)) / mean = sqrt(56 / 13) / 10
+        assert!(close(model.cv_rmse, 100.0 * (56.0f64 / 13.0).sqrt() / 10.0));
+    }
+
+    #[test]
+    fn heating_slope_is_fitted_from_degree_days() {
+        // usage = 10 + 2 * HDD below 18 °C; no day is warm enough for cooling
+        let observations: Vec<_> = (0..14)
+            .map(|i| {
+                let temperature = 4.0 + i as f64;
+                day(i, 10.0 + 2.0 * (18.0 - temperature), Some(temperature))
+            })
+            .collect();
+        let model = baseline(&observations, Some(18.0));
+        assert!(close(model.intercept, 10.0));
+        assert!(close(model.heating_slope.unwrap(), 2.0));
+        assert_eq!(model.cooling_slope, None);
+        assert!(close(model.r_squared, 1.0));
+        assert!(close(model.predict(Some(15.0)), 16.0));
+        assert!(close(model.predict(Some(25.0)), 10.0));
+    }
+
+    #[test]
+    fn savings_against_a_mean_baseline() {
+        let reference: Vec<_> = (0..14).map(|i| day(i, if i % 2 == 0 { 8.0 } else { 12.0 }, None)).collect();
+        let model = baseline(&reference, None);
+        let cv_rmse = model.cv_rmse;
+        let reporting: Vec<_> = (20..27).map(|i| day(i, 9.0, None)).collect();
+        let report = savings(model, 20 * DAY, 27 * DAY, &reporting).unwrap();
+        assert!(close(report.adjusted_baseline, 70.0));
+        assert!(close(report.actual, 63.0));
+        assert!(close(report.avoided, 7.0));
+        assert!(close(report.savings_percent.unwrap(), 10.0));
+        // t(13) * 1.26 * CV * sqrt(n / m * (1 + 2 / n)) * baseline, n = 14, m = 7
+        let expected = 1.771 * 1.26 * (cv_rmse / 100.0) * (2.0 * (1.0 + 2.0 / 14.0f64)).sqrt() * 70.0;
+        assert!(close(report.uncertainty, expected));
+    }
+
+    #[test]
+    fn too_few_reference_days_are_rejected() {
+        let observations: Vec<_> = (0..13).map(|i| day(i, 10.0, None)).collect();
+        let target = ConsumptionTarget::Device("meter-1".into());
+        assert!(fit(target, Utility::Water, 0, 13 * DAY, &observations, None).is_err());
+    }
+
+    #[test]
+    fn day_range_counts_whole_days() {
+        assert_eq!(day_range(DAY + 1, 4 * DAY), Ok((2, 2)));
+        assert_eq!(day_range(DAY, 4 * DAY + 5), Ok((1, 3)));
+        assert!(day_range(DAY + 1, 2 * DAY).is_err());
+        assert!(day_range(0, (MAX_PERIOD_DAYS + 1) * DAY).is_err());
+        // Rounding up the start must not overflow
+        assert!(day_range(u64::MAX - 1, u64::MAX).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+// Minimal Parquet writer for exports: one row group, one uncompressed
+// PLAIN-encoded data page per column and flat columns only. That is all the
+// export needs, and it keeps the arrow and parquet crates, which would add
+// megabytes to the canister's Wasm module, out of the build.
+//
+// File layout: "PAR1", the column chunks, the Thrift-encoded (compact
+// protocol) file metadata, its length as a little-endian u32, then "PAR1".
+
+const MAGIC: &[u8] = b"PAR1";
+
+// Physical types
+const INT32: i32 = 1;
+const INT64: i32 = 2;
+const DOUBLE: i32 = 5;
+const BYTE_ARRAY: i32 = 6;
+
+// Converted types describing how a physical type is interpreted
+const UTF8: i32 = 0;
+const UINT_32: i32 = 13;
+const UINT_64: i32 = 14;
+
+const REQUIRED: i32 = 0;
+const OPTIONAL: i32 = 1;
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+const UNCOMPRESSED: i32 = 0;
+const DATA_PAGE: i32 = 0;
+
+// Thrift compact protocol type ids
+const T_I32: u8 = 5;
+const T_I64: u8 = 6;
+const T_BINARY: u8 = 8;
+const T_LIST: u8 = 9;
+const T_STRUCT: u8 = 12;
+
+pub enum ColumnData<'a> {
+    UInt64(Vec<Option<u64>>),
+    UInt32(Vec<Option<u32>>),
+    Float64(Vec<Option<f64>>),
+    Utf8(Vec<Option<&'a str>>),
+}
+
+pub struct Column<'a> {
+    pub name: &'a str,
+    pub nullable: bool,
+    pub data: ColumnData<'a>,
+}
+
+impl ColumnData<'_> {
+    fn len(&self) -> usize {
+        match self {
+            ColumnData::UInt64(v) => v.len(),
+            ColumnData::UInt32(v) => v.len(),
+            ColumnData::Float64(v) => v.len(),
+            ColumnData::Utf8(v) => v.len(),
+        }
+    }
+
+    // Physical and converted type
+    fn types(&self) -> (i32, Option<i32>) {
+        match self {
+            ColumnData::UInt64(_) => (INT64, Some(UINT_64)),
+            ColumnData::UInt32(_) => (INT32, Some(UINT_32)),
+            ColumnData::Float64(_) => (DOUBLE, None),
+            ColumnData::Utf8(_) => (BYTE_ARRAY, Some(UTF8)),
+        }
+    }
+
+    fn present(&self) -> Vec<bool> {
+        match self {
+            ColumnData::UInt64(v) => v.iter().map(Option::is_some).collect(),
+            ColumnData::UInt32(v) => v.iter().map(Option::is_some).collect(),
+            ColumnData::Float64(v) => v.iter().map(Option::is_some).collect(),
+            ColumnData::Utf8(v) => v.iter().map(Option::is_some).collect(),
+        }
+    }
+
+    // PLAIN encoding of the non-null values: little-endian numbers, strings
+    // prefixed with their length
+    fn plain(&self, out: &mut Vec<u8>) {
+        match self {
+            ColumnData::UInt64(v) => v.iter().flatten().for_each(|x| out.extend_from_slice(&x.to_le_bytes())),
+            ColumnData::UInt32(v) => v.iter().flatten().for_each(|x| out.extend_from_slice(&x.to_le_bytes())),
+            ColumnData::Float64(v) => v.iter().flatten().for_each(|x| out.extend_from_slice(&x.to_le_bytes())),
+            ColumnData::Utf8(v) => v.iter().flatten().for_each(|s| {
+                out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                out.extend_from_slice(s.as_bytes());
+            }),
+        }
+    }
+}
+
+// Thrift compact protocol encoder. Field ids are written as deltas from the
+// previous field of the same struct, so fields must be written in order.
+struct Thrift {
+    out: Vec<u8>,
+    last_field: Vec<i16>,
+}
+
+impl Thrift {
+    fn new() -> Self {
+        Thrift { out: Vec::new(), last_field: vec![0] }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.out.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.out.push(value as u8);
+    }
+
+    fn zigzag(&mut self, value: i64) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.last_field.last_mut().expect("inside a struct");
+        let delta = id - *last;
+        *last = id;
+        if (1..=15).contains(&delta) {
+            self.out.push(((delta as u8) << 4) | kind);
+        } else {
+            self.out.push(kind);
+            self.zigzag(id as i64);
+        }
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, T_I32);
+        self.zigzag(value as i64);
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, T_I64);
+        self.zigzag(value);
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, T_BINARY);
+        self.raw_binary(value);
+    }
+
+    fn raw_binary(&mut self, value: &[u8]) {
+        self.varint(value.len() as u64);
+        self.out.extend_from_slice(value);
+    }
+
+    fn list(&mut self, id: i16, element: u8, len: usize) {
+        self.field(id, T_LIST);
+        if len < 15 {
+            self.out.push(((len as u8) << 4) | element);
+        } else {
+            self.out.push(0xf0 | element);
+            self.varint(len as u64);
+        }
+    }
+
+    // A struct field; struct elements of a list use `begin` alone
+    fn struct_field(&mut self, id: i16) {
+        self.field(id, T_STRUCT);
+        self.begin();
+    }
+
+    fn begin(&mut self) {
+        self.last_field.push(0);
+    }
+
+    fn end(&mut self) {
+        self.out.push(0);
+        self.last_field.pop();
+    }
+}
+
+// Definition levels (1 = present) in the RLE/bit-packing hybrid encoding as
+// one bit-packed run, prefixed with its length
+fn definition_levels(present: &[bool], out: &mut Vec<u8>) {
+    let mut run = Thrift::new();
+    if !present.is_empty() {
+        let groups = present.len().div_ceil(8);
+        run.varint(((groups as u64) << 1) | 1);
+        for group in present.chunks(8) {
+            run.out.push(group.iter().enumerate().fold(0u8, |byte, (i, &p)| byte | ((p as u8) << i)));
+        }
+    }
+    out.extend_from_slice(&(run.out.len() as u32).to_le_bytes());
+    out.extend_from_slice(&run.out);
+}
+
+struct ChunkInfo {
+    offset: usize,
+    size: usize,
+}
+
+// One data page holding the whole column
+fn write_chunk(column: &Column, rows: usize, file: &mut Vec<u8>) -> ChunkInfo {
+    let mut page = Vec::new();
+    if column.nullable {
+        definition_levels(&column.data.present(), &mut page);
+    }
+    column.data.plain(&mut page);
+
+    let mut header = Thrift::new();
+    header.i32(1, DATA_PAGE);
+    header.i32(2, page.len() as i32);
+    header.i32(3, page.len() as i32);
+    header.struct_field(5);
+    header.i32(1, rows as i32);
+    header.i32(2, PLAIN);
+    header.i32(3, RLE);
+    header.i32(4, RLE);
+    header.end();
+    header.end();
+
+    let offset = file.len();
+    file.extend_from_slice(&header.out);
+    file.extend_from_slice(&page);
+    ChunkInfo { offset, size: header.out.len() + page.len() }
+}
+
+fn file_metadata(columns: &[Column], chunks: &[ChunkInfo], rows: usize) -> Vec<u8> {
+    let mut meta = Thrift::new();
+    meta.i32(1, 1); // Format version
+
+    meta.list(2, T_STRUCT, columns.len() + 1);
+    meta.begin();
+    meta.binary(4, b"schema");
+    meta.i32(5, columns.len() as i32);
+    meta.end();
+    for column in columns {
+        let (physical, converted) = column.data.types();
+        meta.begin();
+        meta.i32(1, physical);
+        meta.i32(3, if column.nullable { OPTIONAL } else { REQUIRED });
+        meta.binary(4, column.name.as_bytes());
+        if let Some(converted) = converted {
+            meta.i32(6, converted);
+        }
+        meta.end();
+    }
+
+    meta.i64(3, rows as i64);
+
+    meta.list(4, T_STRUCT, 1);
+    meta.begin();
+    meta.list(1, T_STRUCT, columns.len());
+    for (column, chunk) in columns.iter().zip(chunks) {
+        meta.begin();
+        meta.i64(2, chunk.offset as i64);
+        meta.struct_field(3);
+        meta.i32(1, column.data.types().0);
+        meta.list(2, T_I32, 2);
+        meta.zigzag(PLAIN as i64);
+        meta.zigzag(RLE as i64);
+        meta.list(3, T_BINARY, 1);
+        meta.raw_binary(column.name.as_bytes());
+        meta.i32(4, UNCOMPRESSED);
+        meta.i64(5, rows as i64);
+        meta.i64(6, chunk.size as i64);
+        meta.i64(7, chunk.size as i64);
+        meta.i64(9, chunk.offset as i64);
+        meta.end();
+        meta.end();
+    }
+    meta.i64(2, chunks.iter().map(|c| c.size as i64).sum());
+    meta.i64(3, rows as i64);
+    meta.end();
+
+    meta.binary(6, b"icutil_backend");
+    meta.end();
+    meta.out
+}
+
+// A complete Parquet file. Every column must hold `rows` values, and only
+// nullable columns may hold None.
+pub fn write(columns: &[Column], rows: usize) -> Vec<u8> {
+    debug_assert!(columns.iter().all(|c| c.data.len() == rows));
+    debug_assert!(columns.iter().all(|c| c.nullable || c.data.present().iter().all(|p| *p)));
+    let mut file = MAGIC.to_vec();
+    let chunks: Vec<ChunkInfo> = columns.iter().map(|column| write_chunk(column, rows, &mut file)).collect();
+    let meta = file_metadata(columns, &chunks, rows);
+    file.extend_from_slice(&meta);
+    file.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    file.extend_from_slice(MAGIC);
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn definition_levels_are_one_bit_packed_run() {
+        let mut out = Vec::new();
+        let present = [true, false, true, true, false, false, false, false, true];
+        definition_levels(&present, &mut out);
+        // Length 3, header (2 groups << 1) | 1, then the bits LSB first
+        assert_eq!(out, vec![3, 0, 0, 0, 0b101, 0b0000_1101, 0b0000_0001]);
+    }
+
+    #[test]
+    fn file_is_framed_by_magic_and_footer() {
+        let columns = [
+            Column { name: "seq", nullable: false, data: ColumnData::UInt64(vec![Some(1), Some(2)]) },
+            Column { name: "device_id", nullable: true, data: ColumnData::Utf8(vec![Some("m-1"), None]) },
+        ];
+        let file = write(&columns, 2);
+        assert_eq!(&file[..4], MAGIC);
+        assert_eq!(&file[file.len() - 4..], MAGIC);
+        let footer = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap()) as usize;
+        let meta = &file[file.len() - 8 - footer..file.len() - 8];
+        assert_eq!(meta, file_metadata_after_chunks(&columns).as_slice());
+        // Both seq values, little-endian, in the first page
+        assert!(file.windows(16).any(|w| w == [1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    fn file_metadata_after_chunks(columns: &[Column]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        let chunks: Vec<ChunkInfo> = columns.iter().map(|c| write_chunk(c, 2, &mut file)).collect();
+        file_metadata(columns, &chunks, 2)
+    }
+
+    #[test]
+    fn thrift_fields_use_short_and_long_headers() {
+        let mut thrift = Thrift::new();
+        thrift.i32(1, -1);
+        thrift.i64(20, 300);
+        thrift.end();
+        // Delta 1 with type i32, zigzag(-1) = 1; then a long header for
+        // field 20 and zigzag(300) = 600 as a varint
+        assert_eq!(thrift.out, vec![0x15, 0x01, 0x06, 40, 0xd8, 0x04, 0x00]);
+    }
+}
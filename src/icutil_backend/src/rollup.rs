@@ -0,0 +1,389 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::consumption::{self, Utility, HOUR};
+use crate::schema;
+use crate::tenant::{self, TenantScope};
+use crate::units::{self, Quantity, Unit};
+use crate::{VolumeReading, VolumeReadings};
+
+pub const DAY: u64 = 24 * HOUR;
+const HOURS_KEPT: u64 = 31 * 24; // Covers the forecast history
+const DAYS_KEPT: u64 = 2 * 366 + 1; // A reference and a reporting year
+
+// Consumption of one meter per hour and per UTC day, updated as its
+// cumulative readings arrive. The raw reading buffers only hold the latest
+// samples, so analysis over weeks or months reads these instead.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UsageRollup {
+    pub first_reading: u64,
+    pub last_reading: Option<(u64, f64)>, // Timestamp and cumulative value
+    pub hours: BTreeMap<u64, f64>,        // Hour since the epoch -> usage
+    pub days: BTreeMap<u64, f64>,         // Day since the epoch -> usage
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Rollups {
+    pub usage: HashMap<(String, String, Utility), UsageRollup>, // (tenant, device, utility)
+    // (tenant, device, channel) -> day -> sum in °C and sample count
+    pub temperatures: HashMap<(String, String, String), BTreeMap<u64, (f64, u64)>>,
+}
+
+thread_local! {
+    static ROLLUPS: RefCell<Rollups> = RefCell::new(Rollups::default());
+}
+
+// Split an increment between two readings over the periods of `length`
+// seconds it spans, from period `from` on. A gap in reporting is spread out
+// instead of showing up as a spike.
+fn split(t0: u64, t1: u64, increment: f64, length: u64, from: u64) -> Vec<(u64, f64)> {
+    if t1 == t0 {
+        return if t1 / length >= from { vec![(t1 / length, increment)] } else { Vec::new() };
+    }
+    let rate = increment / (t1 - t0) as f64;
+    let mut parts = Vec::new();
+    let mut cursor = t0.max(from * length);
+    while cursor < t1 {
+        let end = ((cursor / length + 1) * length).min(t1);
+        parts.push((cursor / length, rate * (end - cursor) as f64));
+        cursor = end;
+    }
+    parts
+}
+
+impl UsageRollup {
+    // Only positive increments count, so a meter reset does not produce
+    // negative usage. Readings older than the last one are ignored.
+    pub fn add(&mut self, timestamp: u64, value: f64) {
+        match self.last_reading {
+            None => self.first_reading = timestamp,
+            Some((last, _)) if timestamp < last => return,
+            Some((last, previous)) if value > previous => {
+                let first_hour = (timestamp / HOUR).saturating_sub(HOURS_KEPT - 1);
+                let first_day = (timestamp / DAY).saturating_sub(DAYS_KEPT - 1);
+                for (hour, usage) in split(last, timestamp, value - previous, HOUR, first_hour) {
+                    *self.hours.entry(hour).or_default() += usage;
+                }
+                for (day, usage) in split(last, timestamp, value - previous, DAY, first_day) {
+                    *self.days.entry(day).or_default() += usage;
+                }
+                self.hours = self.hours.split_off(&first_hour);
+                self.days = self.days.split_off(&first_day);
+            }
+            Some(_) => {}
+        }
+        self.last_reading = Some((timestamp, value));
+    }
+
+    // Swap the usage rolled up from a run of readings for the usage of the
+    // same readings with corrected values, e.g. after a retroactive
+    // calibration. Both runs are (timestamp, cumulative value) in time order.
+    pub fn replace(&mut self, old: &[(u64, f64)], new: &[(u64, f64)]) {
+        let Some((last, _)) = self.last_reading else {
+            return;
+        };
+        let first_hour = (last / HOUR).saturating_sub(HOURS_KEPT - 1);
+        let first_day = (last / DAY).saturating_sub(DAYS_KEPT - 1);
+        for (run, sign) in [(old, -1.0), (new, 1.0)] {
+            for pair in run.windows(2) {
+                let ((t0, previous), (t1, value)) = (pair[0], pair[1]);
+                if value <= previous {
+                    continue;
+                }
+                for (hour, usage) in split(t0, t1, value - previous, HOUR, first_hour) {
+                    *self.hours.entry(hour).or_default() += sign * usage;
+                }
+                for (day, usage) in split(t0, t1, value - previous, DAY, first_day) {
+                    *self.days.entry(day).or_default() += sign * usage;
+                }
+            }
+        }
+        // Rounding must not leave negative usage behind
+        self.hours.values_mut().chain(self.days.values_mut()).for_each(|usage| *usage = usage.max(0.0));
+        if let Some(&(timestamp, value)) = new.last().filter(|(timestamp, _)| *timestamp == last) {
+            self.last_reading = Some((timestamp, value));
+        }
+    }
+
+    // Usage between two times. Whole UTC days come from the daily totals and
+    // the partial days at either end from the hours, or pro rata from their
+    // day once those hours have been dropped.
+    pub fn usage_between(&self, from: u64, to: u64) -> f64 {
+        if to <= from {
+            return 0.0;
+        }
+        let first_day = from.div_ceil(DAY);
+        let last_day = to / DAY;
+        if first_day > last_day {
+            return self.part_of_day(from, to);
+        }
+        self.part_of_day(from, first_day * DAY)
+            + self.days.range(first_day..last_day).map(|(_, usage)| usage).sum::<f64>()
+            + self.part_of_day(last_day * DAY, to)
+    }
+
+    // Usage within one UTC day, pro rata for partial hours
+    fn part_of_day(&self, from: u64, to: u64) -> f64 {
+        if to <= from {
+            return 0.0;
+        }
+        let last = self.last_reading.map_or(0, |(ts, _)| ts);
+        if from / HOUR < (last / HOUR).saturating_sub(HOURS_KEPT - 1) {
+            let day = self.days.get(&(from / DAY)).copied().unwrap_or_default();
+            return day * (to - from) as f64 / DAY as f64;
+        }
+        self.hours
+            .range(from / HOUR..to.div_ceil(HOUR))
+            .map(|(hour, usage)| {
+                let overlap = to.min((hour + 1) * HOUR) - from.max(hour * HOUR);
+                usage * overlap as f64 / HOUR as f64
+            })
+            .sum()
+    }
+}
+
+pub fn record_usage(tenant_id: &str, device_id: &str, utility: Utility, timestamp: u64, value: f64) {
+    ROLLUPS.with(|r| {
+        r.borrow_mut()
+            .usage
+            .entry((tenant_id.to_string(), device_id.to_string(), utility))
+            .or_default()
+            .add(timestamp, value)
+    });
+}
+
+pub fn replace_usage(tenant_id: &str, device_id: &str, utility: Utility, old: &[(u64, f64)], new: &[(u64, f64)]) {
+    ROLLUPS.with(|r| {
+        if let Some(rollup) = r.borrow_mut().usage.get_mut(&(tenant_id.to_string(), device_id.to_string(), utility)) {
+            rollup.replace(old, new);
+        }
+    });
+}
+
+// Record a channel sample if it is the consumption channel of a meter type
+// or a temperature, which baselines are normalized by
+pub fn record_channel(tenant_id: &str, device_id: &str, device_type: &str, channel: &str, value: f64, timestamp: u64) {
+    if let Some(utility) = consumption::channel_utility(device_type, channel) {
+        record_usage(tenant_id, device_id, utility, timestamp, value);
+        return;
+    }
+    let celsius = schema::with_schemas(|s| {
+        let spec = s.types.get(device_type)?.channel(channel).ok()?;
+        let unit = units::parse_for(spec.unit.as_deref()?, Quantity::Temperature).ok()?;
+        units::convert(value, unit, Unit::Celsius).ok()
+    });
+    let Some(celsius) = celsius else {
+        return;
+    };
+    ROLLUPS.with(|r| {
+        let mut rollups = r.borrow_mut();
+        let days = rollups
+            .temperatures
+            .entry((tenant_id.to_string(), device_id.to_string(), channel.to_string()))
+            .or_default();
+        let day = days.entry(timestamp / DAY).or_default();
+        day.0 += celsius;
+        day.1 += 1;
+        *days = days.split_off(&(timestamp / DAY).saturating_sub(DAYS_KEPT - 1));
+    });
+}
+
+fn with_usage<R>(
+    scope: &TenantScope,
+    utility: Utility,
+    devices: &[String],
+    f: impl FnOnce(Vec<&UsageRollup>) -> R,
+) -> R {
+    ROLLUPS.with(|r| {
+        let rollups = r.borrow();
+        let tenant_id = scope.tenant_id().to_string();
+        let selected = devices
+            .iter()
+            .filter_map(|device_id| rollups.usage.get(&(tenant_id.clone(), device_id.clone(), utility)))
+            .collect();
+        f(selected)
+    })
+}
+
+// Time of the first reading of any of the devices
+pub fn first_reading(scope: &TenantScope, utility: Utility, devices: &[String]) -> Option<u64> {
+    with_usage(scope, utility, devices, |rollups| rollups.iter().map(|r| r.first_reading).min())
+}
+
+// Time of the latest reading of any of the devices
+pub fn last_reading(scope: &TenantScope, utility: Utility, devices: &[String]) -> Option<u64> {
+    with_usage(scope, utility, devices, |rollups| rollups.iter().filter_map(|r| r.last_reading.map(|(ts, _)| ts)).max())
+}
+
+// Usage per device between two times, for the devices with readings
+pub fn usage_between(scope: &TenantScope, utility: Utility, devices: &[String], from: u64, to: u64) -> HashMap<String, f64> {
+    ROLLUPS.with(|r| {
+        let rollups = r.borrow();
+        devices
+            .iter()
+            .filter_map(|device_id| {
+                let rollup = rollups.usage.get(&(scope.tenant_id().to_string(), device_id.clone(), utility))?;
+                Some((device_id.clone(), rollup.usage_between(from, to)))
+            })
+            .collect()
+    })
+}
+
+// Combined usage of the devices per hour from `start_hour`
+pub fn hourly_usage(scope: &TenantScope, utility: Utility, devices: &[String], start_hour: u64, hours: usize) -> Vec<f64> {
+    with_usage(scope, utility, devices, |rollups| {
+        let mut values = vec![0.0; hours];
+        for rollup in rollups {
+            for (hour, usage) in rollup.hours.range(start_hour..start_hour + hours as u64) {
+                values[(hour - start_hour) as usize] += usage;
+            }
+        }
+        values
+    })
+}
+
+// Combined usage of the devices per UTC day from `first_day`. Days outside
+// the span covered by readings are None rather than zero usage.
+pub fn daily_usage(scope: &TenantScope, utility: Utility, devices: &[String], first_day: u64, days: usize) -> Vec<Option<f64>> {
+    with_usage(scope, utility, devices, |rollups| {
+        let covered_from = rollups.iter().map(|r| r.first_reading).min();
+        let covered_to = rollups.iter().filter_map(|r| r.last_reading.map(|(ts, _)| ts)).max();
+        let (Some(covered_from), Some(covered_to)) = (covered_from, covered_to) else {
+            return vec![None; days];
+        };
+        (first_day..first_day + days as u64)
+            .map(|day| {
+                let covered = day * DAY >= covered_from && (day + 1) * DAY <= covered_to;
+                covered.then(|| rollups.iter().filter_map(|r| r.days.get(&day)).sum())
+            })
+            .collect()
+    })
+}
+
+// Mean temperature in °C of a channel per UTC day from `first_day`
+pub fn daily_temperature(scope: &TenantScope, device_id: &str, channel: &str, first_day: u64, days: usize) -> Vec<Option<f64>> {
+    ROLLUPS.with(|r| {
+        let rollups = r.borrow();
+        let key = (scope.tenant_id().to_string(), device_id.to_string(), channel.to_string());
+        let series = rollups.temperatures.get(&key);
+        (first_day..first_day + days as u64)
+            .map(|day| series.and_then(|s| s.get(&day)).map(|(sum, n)| sum / *n as f64))
+            .collect()
+    })
+}
+
+// Drop the water rollups of a tenant whose readings were purged
+pub fn purge(tenant_id: &str, utility: Utility) {
+    ROLLUPS.with(|r| r.borrow_mut().usage.retain(|(t, _, u), _| !(t == tenant_id && *u == utility)));
+}
+
+// Build the rollups from whatever raw data is still stored, for state saved
+// before rollups existed
+pub fn rebuild(readings: &VolumeReadings) {
+    let mut water: Vec<&VolumeReading> = readings.iter().collect();
+    water.sort_by_key(|r| r.timestamp);
+    for reading in water {
+        if let (Some(tenant_id), Some(device_id)) = (&reading.tenant_id, &reading.device_id) {
+            record_usage(tenant_id, device_id, Utility::Water, reading.timestamp, reading.volume);
+        }
+    }
+    let types = schema::with_schemas(|s| s.devices.clone());
+    schema::with_channels(|store| {
+        for (device_id, series) in &store.series {
+            let (Some(device_type), Some(tenant_id)) = (types.get(device_id), tenant::tenant_of_device(device_id)) else {
+                continue;
+            };
+            for (channel, samples) in series {
+                for sample in samples {
+                    record_channel(&tenant_id, device_id, device_type, channel, sample.value, sample.timestamp);
+                }
+            }
+        }
+    });
+}
+
+pub fn export_state() -> Rollups {
+    ROLLUPS.with(|r| r.borrow().clone())
+}
+
+pub fn import_state(rollups: Rollups) {
+    ROLLUPS.with(|r| *r.borrow_mut() = rollups);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_between_readings_is_spread_over_the_hours() {
+        let mut rollup = UsageRollup::default();
+        rollup.add(10 * HOUR + 1800, 100.0);
+        rollup.add(12 * HOUR + 1800, 104.0);
+        let hours: Vec<(u64, f64)> = rollup.hours.iter().map(|(h, u)| (*h, *u)).collect();
+        assert_eq!(hours, vec![(10, 1.0), (11, 2.0), (12, 1.0)]);
+        assert_eq!(rollup.days.get(&0), Some(&4.0));
+        assert_eq!(rollup.first_reading, 10 * HOUR + 1800);
+    }
+
+    #[test]
+    fn resets_and_late_readings_add_no_usage() {
+        let mut rollup = UsageRollup::default();
+        rollup.add(HOUR, 50.0);
+        rollup.add(2 * HOUR, 5.0); // Meter reset
+        rollup.add(HOUR + 60, 80.0); // Older than the last reading
+        rollup.add(3 * HOUR, 7.0);
+        assert_eq!(rollup.days.values().sum::<f64>(), 2.0);
+        assert_eq!(rollup.last_reading, Some((3 * HOUR, 7.0)));
+    }
+
+    #[test]
+    fn old_periods_are_dropped() {
+        let mut rollup = UsageRollup::default();
+        rollup.add(0, 0.0);
+        rollup.add(DAY, 1.0);
+        rollup.add((DAYS_KEPT + 1) * DAY + HOUR / 2, 2.0);
+        assert_eq!(rollup.hours.len() as u64, HOURS_KEPT);
+        assert_eq!(rollup.days.len() as u64, DAYS_KEPT);
+        assert!(!rollup.days.contains_key(&0));
+    }
+
+    #[test]
+    fn usage_between_combines_days_and_hours() {
+        let mut rollup = UsageRollup::default();
+        // One unit per hour for three days
+        for h in 0..=72 {
+            rollup.add(DAY + h * HOUR, h as f64);
+        }
+        assert_eq!(rollup.usage_between(DAY, 4 * DAY), 72.0);
+        assert_eq!(rollup.usage_between(DAY + 6 * HOUR, 3 * DAY + 18 * HOUR), 60.0);
+        assert_eq!(rollup.usage_between(DAY + HOUR / 2, DAY + 2 * HOUR), 1.5);
+        assert_eq!(rollup.usage_between(0, DAY), 0.0);
+        assert_eq!(rollup.usage_between(2 * DAY, DAY), 0.0);
+    }
+
+    #[test]
+    fn usage_before_the_kept_hours_is_taken_pro_rata_from_its_day() {
+        let mut rollup = UsageRollup::default();
+        rollup.add(0, 0.0);
+        rollup.add(DAY, 48.0);
+        rollup.add(40 * DAY, 49.0);
+        assert!(!rollup.hours.contains_key(&6));
+        assert_eq!(rollup.usage_between(6 * HOUR, 18 * HOUR), 24.0);
+    }
+
+    #[test]
+    fn replacing_a_run_swaps_its_usage() {
+        let mut rollup = UsageRollup::default();
+        rollup.add(0, 10.0);
+        rollup.add(HOUR, 12.0);
+        rollup.add(2 * HOUR, 15.0);
+        // The last two readings were recalibrated upwards
+        rollup.replace(&[(0, 10.0), (HOUR, 12.0), (2 * HOUR, 15.0)], &[(0, 10.0), (HOUR, 14.0), (2 * HOUR, 20.0)]);
+        let hours: Vec<(u64, f64)> = rollup.hours.iter().map(|(h, u)| (*h, *u)).collect();
+        assert_eq!(hours, vec![(0, 4.0), (1, 6.0)]);
+        assert_eq!(rollup.days.get(&0), Some(&10.0));
+        assert_eq!(rollup.last_reading, Some((2 * HOUR, 20.0)));
+    }
+}
@@ -0,0 +1,480 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use icutil_common::lifecycle::IngestPolicy;
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+
+use crate::audit;
+use crate::alerts::{self, AlertMetric};
+use crate::auth::{can_admin_devices, can_admin_tenants, can_read_devices, can_read_readings, can_write_readings};
+use crate::electricity::ELECTRICITY_DEVICE_TYPE;
+use crate::consumption;
+use crate::gas::GAS_DEVICE_TYPE;
+use crate::health;
+use crate::lifecycle;
+use crate::metrics;
+use crate::rollup;
+use crate::tenant;
+use crate::units::{self, Unit};
+
+const NAME_MAX_LENGTH: usize = 32;
+const MAX_CHANNELS: usize = 32;
+const MAX_SAMPLES_PER_CHANNEL: usize = 1000;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataType {
+    Float,
+    Integer,
+    Boolean, // Stored as 0.0 / 1.0
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelKind {
+    Cumulative,    // Meter totals; statistics report consumption over the window
+    Instantaneous, // Point measurements; statistics report avg/min/max
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ChannelSpec {
+    pub name: String,
+    pub unit: Option<String>, // None for dimensionless and boolean channels
+    pub data_type: DataType,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub kind: ChannelKind,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DeviceType {
+    pub name: String,
+    pub description: String,
+    pub channels: Vec<ChannelSpec>,
+    pub updated_at: u64,
+}
+
+// One value in an ingest batch. `unit` may differ from the channel's unit as
+// long as it measures the same quantity; it is converted before storage.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ChannelValue {
+    pub channel: String,
+    pub value: f64,
+    pub unit: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ChannelSample {
+    pub timestamp: u64,
+    pub value: f64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ChannelStatistics {
+    pub channel: String,
+    pub unit: Option<String>,
+    pub kind: ChannelKind,
+    pub count: u64,
+    pub average: f64,
+    pub min: f64,
+    pub max: f64,
+    pub std_deviation: f64,
+    pub latest: f64,
+    pub consumption: Option<f64>, // Cumulative channels only
+}
+
+fn validate_name(name: &str, what: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > NAME_MAX_LENGTH {
+        return Err(format!("{} name must be 1-{} characters", what, NAME_MAX_LENGTH));
+    }
+    if !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+        return Err(format!("{} name may only contain a-z, 0-9 and _", what));
+    }
+    Ok(())
+}
+
+impl ChannelSpec {
+    fn validate(&self) -> Result<(), String> {
+        validate_name(&self.name, "Channel")?;
+        if let Some(ref unit) = self.unit {
+            Unit::parse(unit)?;
+        }
+        if self.data_type == DataType::Boolean && self.kind == ChannelKind::Cumulative {
+            return Err(format!("Boolean channel {} cannot be cumulative", self.name));
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if !(min < max) {
+                return Err(format!("Channel {} has an empty range", self.name));
+            }
+        }
+        Ok(())
+    }
+
+    // Convert `value` to the channel's unit and check type and range
+    pub fn normalize(&self, value: f64, unit: Option<&str>) -> Result<f64, String> {
+        if !value.is_finite() {
+            return Err(format!("{} must be a finite number", self.name));
+        }
+        let value = match (unit, self.unit.as_deref()) {
+            (Some(from), Some(to)) => units::convert(value, Unit::parse(from)?, Unit::parse(to)?)?,
+            (Some(from), None) => return Err(format!("{} is dimensionless, got {}", self.name, from)),
+            (None, _) => value,
+        };
+
+        match self.data_type {
+            DataType::Float => {}
+            DataType::Integer if value.fract() != 0.0 => {
+                return Err(format!("{} must be an integer", self.name))
+            }
+            DataType::Integer => {}
+            DataType::Boolean if value != 0.0 && value != 1.0 => {
+                return Err(format!("{} must be 0 or 1", self.name))
+            }
+            DataType::Boolean => {}
+        }
+        if self.min.map_or(false, |min| value < min) || self.max.map_or(false, |max| value > max) {
+            return Err(format!(
+                "{} out of range [{}, {}]",
+                self.name,
+                self.min.map_or("-inf".into(), |v| v.to_string()),
+                self.max.map_or("inf".into(), |v| v.to_string())
+            ));
+        }
+        Ok(value)
+    }
+}
+
+impl DeviceType {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_name(&self.name, "Device type")?;
+        if self.channels.is_empty() || self.channels.len() > MAX_CHANNELS {
+            return Err(format!("A device type needs 1-{} channels", MAX_CHANNELS));
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            channel.validate()?;
+            if self.channels[..i].iter().any(|c| c.name == channel.name) {
+                return Err(format!("Duplicate channel {}", channel.name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn channel(&self, name: &str) -> Result<&ChannelSpec, String> {
+        self.channels
+            .iter()
+            .find(|c| c.name == name)
+            .ok_or_else(|| format!("Device type {} has no channel {}", self.name, name))
+    }
+}
+
+// Device types defined by operators and the type each device declared
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct SchemaRegistry {
+    pub types: HashMap<String, DeviceType>,
+    pub devices: HashMap<String, String>, // device_id -> type name
+}
+
+impl SchemaRegistry {
+    // Add or replace an operator-defined type. The built-in meter types
+    // cannot be redefined.
+    pub fn define(&mut self, device_type: DeviceType) -> Result<(), String> {
+        if [GAS_DEVICE_TYPE, ELECTRICITY_DEVICE_TYPE].contains(&device_type.name.as_str()) {
+            return Err(format!("{} is a built-in device type", device_type.name));
+        }
+        device_type.validate()?;
+        self.types.insert(device_type.name.clone(), device_type);
+        Ok(())
+    }
+
+    pub fn type_of(&self, device_id: &str) -> Result<&DeviceType, String> {
+        self.devices
+            .get(device_id)
+            .and_then(|name| self.types.get(name))
+            .ok_or_else(|| format!("Device {} has not declared a device type", device_id))
+    }
+}
+
+// Samples per device and channel, in the channel's unit
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ChannelStore {
+    pub series: HashMap<String, HashMap<String, VecDeque<ChannelSample>>>,
+}
+
+impl ChannelStore {
+    pub fn push(&mut self, device_id: &str, channel: &str, sample: ChannelSample) {
+        let series = self
+            .series
+            .entry(device_id.to_string())
+            .or_default()
+            .entry(channel.to_string())
+            .or_default();
+        series.push_back(sample);
+        while series.len() > MAX_SAMPLES_PER_CHANNEL {
+            series.pop_front();
+        }
+    }
+
+    pub fn samples(&self, device_id: &str, channel: &str) -> Option<&VecDeque<ChannelSample>> {
+        self.series.get(device_id)?.get(channel)
+    }
+
+    pub fn statistics(
+        &self,
+        device_id: &str,
+        spec: &ChannelSpec,
+        start: u64,
+        end: u64,
+    ) -> Option<ChannelStatistics> {
+        let samples = self.samples(device_id, &spec.name)?;
+        let values: Vec<f64> = samples
+            .iter()
+            .filter(|s| s.timestamp >= start && s.timestamp <= end)
+            .map(|s| s.value)
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+
+        let count = values.len() as f64;
+        let average = values.iter().sum::<f64>() / count;
+        let variance = values.iter().map(|v| (v - average).powi(2)).sum::<f64>() / count;
+        let consumption = match spec.kind {
+            ChannelKind::Cumulative => consumption::device_consumption(
+                samples.iter().map(|s| (device_id, s.timestamp, s.value)),
+                start,
+                end,
+            )
+            .remove(device_id),
+            ChannelKind::Instantaneous => None,
+        };
+
+        Some(ChannelStatistics {
+            channel: spec.name.clone(),
+            unit: spec.unit.clone(),
+            kind: spec.kind,
+            count: values.len() as u64,
+            average,
+            min: values.iter().fold(f64::INFINITY, |a, &b| a.min(b)),
+            max: values.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b)),
+            std_deviation: variance.sqrt(),
+            latest: *values.last().unwrap(),
+            consumption,
+        })
+    }
+}
+
+thread_local! {
+    static SCHEMAS: RefCell<SchemaRegistry> = RefCell::new(SchemaRegistry::default());
+    static CHANNELS: RefCell<ChannelStore> = RefCell::new(ChannelStore::default());
+}
+
+pub fn with_schemas<R>(f: impl FnOnce(&SchemaRegistry) -> R) -> R {
+    SCHEMAS.with(|s| f(&s.borrow()))
+}
+
+pub fn with_channels<R>(f: impl FnOnce(&ChannelStore) -> R) -> R {
+    CHANNELS.with(|c| f(&c.borrow()))
+}
+
+pub fn export_state() -> (SchemaRegistry, ChannelStore) {
+    (with_schemas(|s| s.clone()), with_channels(|c| c.clone()))
+}
+
+pub fn import_state(schemas: SchemaRegistry, channels: ChannelStore) {
+    SCHEMAS.with(|s| *s.borrow_mut() = schemas);
+    CHANNELS.with(|c| *c.borrow_mut() = channels);
+}
+
+pub fn register_device_type(device_type: DeviceType) {
+    SCHEMAS.with(|s| s.borrow_mut().types.insert(device_type.name.clone(), device_type));
+}
+
+pub fn assign_device_type(device_id: &str, type_name: &str) -> Result<(), String> {
+    SCHEMAS.with(|s| {
+        let mut schemas = s.borrow_mut();
+        if !schemas.types.contains_key(type_name) {
+            return Err(format!("Device type {} not found", type_name));
+        }
+        schemas.devices.insert(device_id.to_string(), type_name.to_string());
+        Ok(())
+    })
+}
+
+// Validate a batch against the device's schema and store it. The batch is
+// rejected as a whole if any value fails, so a device never ends up with a
+// partially stored sample.
+pub fn ingest(tenant_id: &str, device_id: &str, values: &[ChannelValue], timestamp: u64) -> Result<u64, String> {
+    if values.is_empty() {
+        return Err("No channel values given".into());
+    }
+    let policy = lifecycle::admit(device_id)?;
+
+    let normalized = with_schemas(|schemas| {
+        let device_type = schemas.type_of(device_id)?;
+        values
+            .iter()
+            .map(|v| {
+                let value = device_type.channel(&v.channel)?.normalize(v.value, v.unit.as_deref())?;
+                Ok((v.channel.clone(), value))
+            })
+            .collect::<Result<Vec<(String, f64)>, String>>()
+    })?;
+
+    CHANNELS.with(|c| {
+        let mut store = c.borrow_mut();
+        for (channel, value) in &normalized {
+            store.push(device_id, channel, ChannelSample { timestamp, value: *value });
+        }
+    });
+    if let Some(device_type) = with_schemas(|s| s.devices.get(device_id).cloned()) {
+        for (channel, value) in &normalized {
+            rollup::record_channel(tenant_id, device_id, &device_type, channel, *value, timestamp);
+        }
+    }
+    health::record_reading(device_id, timestamp);
+    if policy == IngestPolicy::Accept {
+        for (channel, value) in &normalized {
+            alerts::evaluate(tenant_id, device_id, AlertMetric::Channel(channel.clone()), *value, timestamp);
+        }
+    }
+    Ok(normalized.len() as u64)
+}
+
+// Create or replace a device type. Samples of channels that are removed are
+// kept but no longer accepted. Types are shared by every tenant, so this
+// needs `tenants:admin`; the built-in meter types cannot be redefined.
+#[update(guard = "can_admin_tenants")]
+fn define_device_type(name: String, description: String, channels: Vec<ChannelSpec>) -> Result<DeviceType, String> {
+    let device_type = DeviceType {
+        name,
+        description,
+        channels,
+        updated_at: ic_cdk::api::time() / 1_000_000_000,
+    };
+    SCHEMAS.with(|s| s.borrow_mut().define(device_type.clone()))?;
+    let channels = device_type.channels.iter().map(|c| c.name.clone()).collect();
+    audit::record("device.type_define", Some(&device_type.name), channels);
+    Ok(device_type)
+}
+
+#[query(guard = "can_read_devices")]
+fn list_device_types(request: PageRequest) -> Result<Page<DeviceType>, String> {
+    let types = with_schemas(|s| s.types.values().map(|t| (t.name.clone(), t.clone())).collect());
+    pagination::paginate(types, Order::Ascending, &request)
+}
+
+#[update(guard = "can_admin_devices")]
+fn set_device_type(device_id: String, type_name: String) -> Result<(), String> {
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    let result = assign_device_type(&device_id, &type_name);
+    audit::on_success(result, "device.type_set", Some(&device_id), vec![type_name])
+}
+
+#[update(guard = "can_write_readings")]
+fn record_channel_readings(device_id: String, values: Vec<ChannelValue>) -> Result<u64, String> {
+    let _timer = metrics::timer("record_channel_readings");
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    // Gas totals are derived from the corrected index, so they only come
+    // through record_gas_reading
+    if with_schemas(|s| s.devices.get(&device_id).map_or(false, |t| t == GAS_DEVICE_TYPE)) {
+        return Err("Gas meters report through record_gas_reading".into());
+    }
+    ingest(scope.tenant_id(), &device_id, &values, ic_cdk::api::time() / 1_000_000_000)
+}
+
+// Most recent samples of a channel, newest first
+#[query(guard = "can_read_readings")]
+fn get_channel_samples(device_id: String, channel: String, count: u64) -> Result<Vec<ChannelSample>, String> {
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    Ok(with_channels(|c| {
+        c.samples(&device_id, &channel)
+            .map(|s| s.iter().rev().take(count as usize).cloned().collect())
+            .unwrap_or_default()
+    }))
+}
+
+#[query(guard = "can_read_readings")]
+fn get_channel_statistics(device_id: String, channel: String, start: u64, end: u64) -> Result<ChannelStatistics, String> {
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    let spec = with_schemas(|s| s.type_of(&device_id)?.channel(&channel).cloned())?;
+    with_channels(|c| c.statistics(&device_id, &spec, start, end))
+        .ok_or_else(|| format!("No {} samples for {} in range", channel, device_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, unit: Option<&str>, data_type: DataType, kind: ChannelKind) -> ChannelSpec {
+        ChannelSpec { name: name.into(), unit: unit.map(String::from), data_type, min: None, max: None, kind }
+    }
+
+    fn float(name: &str, unit: &str) -> ChannelSpec {
+        channel(name, Some(unit), DataType::Float, ChannelKind::Instantaneous)
+    }
+
+    fn device_type(name: &str, channels: Vec<ChannelSpec>) -> DeviceType {
+        DeviceType { name: name.into(), description: String::new(), channels, updated_at: 0 }
+    }
+
+    #[test]
+    fn channel_specs_are_validated() {
+        assert!(float("temp", "degC").validate().is_ok());
+        assert!(float("Temp", "degC").validate().is_err());
+        assert!(float("", "degC").validate().is_err());
+        assert!(float("temp", "furlong").validate().is_err());
+        let flag = channel("open", None, DataType::Boolean, ChannelKind::Cumulative);
+        assert_eq!(flag.validate().unwrap_err(), "Boolean channel open cannot be cumulative");
+        let empty = ChannelSpec { min: Some(5.0), max: Some(5.0), ..float("temp", "degC") };
+        assert_eq!(empty.validate().unwrap_err(), "Channel temp has an empty range");
+    }
+
+    #[test]
+    fn device_types_need_unique_channels() {
+        assert!(device_type("boiler", vec![float("temp", "degC")]).validate().is_ok());
+        assert!(device_type("boiler", Vec::new()).validate().is_err());
+        let duplicate = device_type("boiler", vec![float("temp", "degC"), float("temp", "K")]);
+        assert_eq!(duplicate.validate().unwrap_err(), "Duplicate channel temp");
+        let many = (0..=MAX_CHANNELS).map(|i| float(&format!("c{}", i), "W")).collect();
+        assert!(device_type("boiler", many).validate().is_err());
+    }
+
+    #[test]
+    fn values_are_converted_and_range_checked() {
+        let temp = ChannelSpec { min: Some(-20.0), max: Some(120.0), ..float("temp", "degC") };
+        assert_eq!(temp.normalize(50.0, None), Ok(50.0));
+        assert!((temp.normalize(212.0, Some("degF")).unwrap() - 100.0).abs() < 1e-9);
+        assert_eq!(temp.normalize(121.0, None).unwrap_err(), "temp out of range [-20, 120]");
+        assert!(temp.normalize(-30.0, None).is_err());
+        assert!(temp.normalize(f64::NAN, None).is_err());
+        assert!(temp.normalize(1.0, Some("kWh")).is_err());
+
+        let count = channel("pulses", None, DataType::Integer, ChannelKind::Cumulative);
+        assert_eq!(count.normalize(3.0, None), Ok(3.0));
+        assert!(count.normalize(3.5, None).is_err());
+        assert!(count.normalize(3.0, Some("L")).is_err());
+
+        let open = channel("open", None, DataType::Boolean, ChannelKind::Instantaneous);
+        assert_eq!(open.normalize(1.0, None), Ok(1.0));
+        assert!(open.normalize(2.0, None).is_err());
+    }
+
+    #[test]
+    fn built_in_types_cannot_be_redefined() {
+        let mut registry = SchemaRegistry::default();
+        for name in [GAS_DEVICE_TYPE, ELECTRICITY_DEVICE_TYPE] {
+            let err = registry.define(device_type(name, vec![float("volume", "m3")])).unwrap_err();
+            assert_eq!(err, format!("{} is a built-in device type", name));
+        }
+        assert!(registry.define(device_type("boiler", Vec::new())).is_err());
+        registry.define(device_type("boiler", vec![float("temp", "degC")])).unwrap();
+        registry.devices.insert("b-1".into(), "boiler".into());
+        assert!(registry.type_of("b-1").unwrap().channel("temp").is_ok());
+        assert!(registry.type_of("b-2").is_err());
+    }
+}
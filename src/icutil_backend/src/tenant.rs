@@ -0,0 +1,460 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use icutil_common::access::Role;
+use icutil_common::pagination::{self, Order, Page, PageRequest};
+
+use crate::audit;
+use crate::auth::{self, can_admin_devices, can_admin_tenants, can_read_devices, is_user};
+use crate::{VolumeReading, VolumeReadings};
+
+const TENANT_ID_MAX_LENGTH: usize = 32;
+const TENANT_NAME_MAX_LENGTH: usize = 128;
+
+// A customer account that owns devices, users and their readings
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Tenant {
+    pub id: String,
+    pub name: String,
+    pub created_at: u64,
+}
+
+// Tenants together with device and user membership
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct TenantRegistry {
+    pub tenants: HashMap<String, Tenant>,
+    pub devices: HashMap<String, String>, // device_id -> tenant_id
+    pub members: HashMap<String, String>, // principal -> tenant_id
+}
+
+// The tenant a call is restricted to. The field is private so a scope can
+// only be obtained from `TenantRegistry::scope_for`, which means every data
+// path has to go through a membership check first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantScope {
+    tenant_id: String,
+    devices: Option<BTreeSet<String>>, // Only these devices, for residents
+}
+
+impl TenantScope {
+    pub fn tenant_id(&self) -> &str {
+        &self.tenant_id
+    }
+
+    // Residents see the devices they claimed, not the whole tenant
+    pub fn is_resident(&self) -> bool {
+        self.devices.is_some()
+    }
+
+    pub fn allows_device(&self, device_id: &str) -> bool {
+        self.devices.as_ref().map_or(true, |devices| devices.contains(device_id))
+    }
+
+    pub fn owns(&self, reading: &VolumeReading) -> bool {
+        reading.tenant_id.as_deref() == Some(self.tenant_id.as_str())
+            && (!self.is_resident() || reading.device_id.as_deref().map_or(false, |id| self.allows_device(id)))
+    }
+
+    // Keep only the readings that belong to this tenant, preserving order
+    pub fn filter(&self, readings: VolumeReadings) -> VolumeReadings {
+        readings.into_iter().filter(|r| self.owns(r)).collect()
+    }
+}
+
+fn validate_tenant_id(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("Tenant ID cannot be empty".into());
+    }
+    if id.len() > TENANT_ID_MAX_LENGTH {
+        return Err(format!("Tenant ID exceeds {} characters", TENANT_ID_MAX_LENGTH));
+    }
+    if !id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return Err("Tenant ID contains invalid characters".into());
+    }
+    Ok(())
+}
+
+impl TenantRegistry {
+    pub fn create_tenant(&mut self, id: String, name: String, now: u64) -> Result<Tenant, String> {
+        validate_tenant_id(&id)?;
+        if name.trim().is_empty() || name.len() > TENANT_NAME_MAX_LENGTH {
+            return Err(format!("Tenant name must be 1-{} characters", TENANT_NAME_MAX_LENGTH));
+        }
+        if self.tenants.contains_key(&id) {
+            return Err(format!("Tenant {} already exists", id));
+        }
+
+        let tenant = Tenant { id: id.clone(), name, created_at: now };
+        self.tenants.insert(id, tenant.clone());
+        Ok(tenant)
+    }
+
+    // Devices never move between tenants, otherwise their history would
+    // become visible to the new owner
+    pub fn assign_device(&mut self, device_id: String, tenant_id: &str) -> Result<(), String> {
+        if !self.tenants.contains_key(tenant_id) {
+            return Err(format!("Tenant {} not found", tenant_id));
+        }
+        match self.devices.get(&device_id) {
+            Some(owner) if owner == tenant_id => Ok(()),
+            Some(_) => Err(format!("Device {} belongs to another tenant", device_id)),
+            None => {
+                self.devices.insert(device_id, tenant_id.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn add_member(&mut self, principal: String, tenant_id: &str) -> Result<(), String> {
+        if !self.tenants.contains_key(tenant_id) {
+            return Err(format!("Tenant {} not found", tenant_id));
+        }
+        match self.members.get(&principal) {
+            Some(current) if current == tenant_id => Ok(()),
+            Some(_) => Err("User is already a member of another tenant".into()),
+            None => {
+                self.members.insert(principal, tenant_id.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn scope_for(&self, principal: &str) -> Result<TenantScope, String> {
+        self.members
+            .get(principal)
+            .map(|tenant_id| TenantScope { tenant_id: tenant_id.clone(), devices: None })
+            .ok_or_else(|| "Caller is not a member of any tenant".to_string())
+    }
+
+    // Scope of a principal that owns devices but is not a tenant member
+    pub fn resident_scope(&self, devices: BTreeSet<String>) -> Result<TenantScope, String> {
+        let tenants: BTreeSet<&String> = devices.iter().filter_map(|id| self.devices.get(id)).collect();
+        match tenants.into_iter().collect::<Vec<_>>().as_slice() {
+            [tenant_id] => Ok(TenantScope { tenant_id: tenant_id.to_string(), devices: Some(devices) }),
+            _ => Err("Claimed devices are not assigned to a single tenant".to_string()),
+        }
+    }
+
+    // Scope for background jobs that act on behalf of a tenant, such as the
+    // budget checks run from the heartbeat. Never use it for caller requests.
+    pub fn scope_of_tenant(&self, tenant_id: &str) -> Result<TenantScope, String> {
+        if !self.tenants.contains_key(tenant_id) {
+            return Err(format!("Tenant {} not found", tenant_id));
+        }
+        Ok(TenantScope { tenant_id: tenant_id.to_string(), devices: None })
+    }
+
+    // Work out which tenant a new reading belongs to. Readings from a device
+    // are stamped with the device's tenant, which must match the caller's.
+    pub fn resolve_ingest_tenant(
+        &self,
+        scope: &TenantScope,
+        device_id: Option<&str>,
+    ) -> Result<String, String> {
+        let Some(device_id) = device_id else {
+            return Ok(scope.tenant_id.clone());
+        };
+        match self.devices.get(device_id) {
+            Some(owner) if *owner == scope.tenant_id => Ok(owner.clone()),
+            Some(_) => Err(format!("Device {} belongs to another tenant", device_id)),
+            None => Err(format!("Device {} is not assigned to a tenant", device_id)),
+        }
+    }
+
+    pub fn devices_of(&self, scope: &TenantScope) -> Vec<String> {
+        let mut devices: Vec<String> = self
+            .devices
+            .iter()
+            .filter(|(device_id, owner)| **owner == scope.tenant_id && scope.allows_device(device_id))
+            .map(|(device_id, _)| device_id.clone())
+            .collect();
+        devices.sort();
+        devices
+    }
+}
+
+// Devices claimed by residents with a pairing code from
+// device_management_backend. Kept apart from membership: an owner sees
+// their own devices and nothing else of the tenant.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct DeviceOwners {
+    pub owners: HashMap<String, String>, // device_id -> principal
+    pub pairing_canister: Option<String>, // device_management_backend
+}
+
+impl DeviceOwners {
+    pub fn devices_of(&self, principal: &str) -> BTreeSet<String> {
+        self.owners
+            .iter()
+            .filter(|(_, owner)| *owner == principal)
+            .map(|(device_id, _)| device_id.clone())
+            .collect()
+    }
+}
+
+thread_local! {
+    static TENANTS: RefCell<TenantRegistry> = RefCell::new(TenantRegistry::default());
+    static OWNERS: RefCell<DeviceOwners> = RefCell::new(DeviceOwners::default());
+}
+
+pub fn with_registry<R>(f: impl FnOnce(&TenantRegistry) -> R) -> R {
+    TENANTS.with(|t| f(&t.borrow()))
+}
+
+// Scope of the current caller: the tenant of a member, or the claimed
+// devices of a resident. Fails for everyone else.
+pub fn caller_scope() -> Result<TenantScope, String> {
+    let caller = ic_cdk::caller().to_string();
+    with_registry(|registry| {
+        registry.scope_for(&caller).or_else(|e| {
+            let devices = OWNERS.with(|o| o.borrow().devices_of(&caller));
+            if devices.is_empty() {
+                Err(e)
+            } else {
+                registry.resident_scope(devices)
+            }
+        })
+    })
+}
+
+// Scope for tenant-wide data such as alert rules, budgets and locations,
+// which residents do not see
+pub fn member_scope() -> Result<TenantScope, String> {
+    let caller = ic_cdk::caller().to_string();
+    with_registry(|registry| registry.scope_for(&caller))
+}
+
+// Fail unless `device_id` is assigned to the scope's tenant
+pub fn require_device(scope: &TenantScope, device_id: &str) -> Result<(), String> {
+    with_registry(|registry| match registry.devices.get(device_id) {
+        Some(owner) if owner == scope.tenant_id() && scope.allows_device(device_id) => Ok(()),
+        _ => Err(format!("Device {} is not assigned to your tenant", device_id)),
+    })
+}
+
+// Add a user to a tenant outside of an admin call, e.g. on accepting an invite
+pub fn add_member(principal: &str, tenant_id: &str) -> Result<(), String> {
+    TENANTS.with(|t| t.borrow_mut().add_member(principal.to_string(), tenant_id))
+}
+
+pub fn assign_device(device_id: &str, tenant_id: &str) -> Result<(), String> {
+    TENANTS.with(|t| t.borrow_mut().assign_device(device_id.to_string(), tenant_id))
+}
+
+pub fn tenant_of_member(principal: &str) -> Option<String> {
+    with_registry(|registry| registry.members.get(principal).cloned())
+}
+
+pub fn tenant_of_device(device_id: &str) -> Option<String> {
+    with_registry(|registry| registry.devices.get(device_id).cloned())
+}
+
+pub fn export_state() -> TenantRegistry {
+    with_registry(|registry| registry.clone())
+}
+
+pub fn import_state(registry: TenantRegistry) {
+    TENANTS.with(|t| *t.borrow_mut() = registry);
+}
+
+pub fn export_owners() -> DeviceOwners {
+    OWNERS.with(|o| o.borrow().clone())
+}
+
+pub fn import_owners(owners: DeviceOwners) {
+    OWNERS.with(|o| *o.borrow_mut() = owners);
+}
+
+#[update(guard = "can_admin_tenants")]
+fn create_tenant(id: String, name: String) -> Result<Tenant, String> {
+    let result = TENANTS.with(|t| t.borrow_mut().create_tenant(id.clone(), name.clone(), ic_cdk::api::time() / 1_000_000_000));
+    audit::on_success(result, "tenant.create", Some(&id), vec![name])
+}
+
+#[update(guard = "can_admin_tenants")]
+fn assign_device_to_tenant(device_id: String, tenant_id: String) -> Result<(), String> {
+    crate::validate_device_id(&device_id)?;
+    let result = TENANTS.with(|t| t.borrow_mut().assign_device(device_id.clone(), &tenant_id));
+    audit::on_success(result, "tenant.assign_device", Some(&device_id), vec![tenant_id])
+}
+
+#[update(guard = "can_admin_tenants")]
+fn add_user_to_tenant(principal: String, tenant_id: String) -> Result<(), String> {
+    let result = TENANTS.with(|t| t.borrow_mut().add_member(principal.clone(), &tenant_id));
+    audit::on_success(result, "tenant.add_member", Some(&principal), vec![tenant_id])
+}
+
+#[query(guard = "is_user")]
+fn get_my_tenant() -> Result<Tenant, String> {
+    let scope = caller_scope()?;
+    with_registry(|registry| {
+        registry
+            .tenants
+            .get(scope.tenant_id())
+            .cloned()
+            .ok_or_else(|| "Tenant not found".to_string())
+    })
+}
+
+#[query(guard = "can_read_devices")]
+fn list_tenant_devices(request: PageRequest) -> Result<Page<String>, String> {
+    let scope = caller_scope()?;
+    let devices = with_registry(|registry| registry.devices_of(&scope))
+        .into_iter()
+        .map(|id| (id.clone(), id))
+        .collect();
+    pagination::paginate(devices, Order::Ascending, &request)
+}
+
+// Register device_management_backend, which binds owners on behalf of
+// residents and is not a member of any tenant
+#[update(guard = "can_admin_tenants")]
+fn set_pairing_canister(canister: candid::Principal) -> Result<(), String> {
+    let id = canister.to_string();
+    OWNERS.with(|o| o.borrow_mut().pairing_canister = Some(id.clone()));
+    audit::record("tenant.pairing_canister", Some(&id), Vec::new());
+    Ok(())
+}
+
+// Owners are changed by the pairing canister, by controllers, or by a
+// member of the device's tenant
+fn require_owner_admin(device_id: &str) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let id = caller.to_string();
+    if ic_cdk::api::is_controller(&caller) || OWNERS.with(|o| o.borrow().pairing_canister.as_ref() == Some(&id)) {
+        return Ok(());
+    }
+    require_device(&member_scope()?, device_id)
+}
+
+// Record that `principal` claimed `device_id`. device_management_backend
+// calls this after a pairing code is redeemed; it needs `devices:admin`
+// here. The owner gets the viewer role so they can read their readings.
+#[update(guard = "can_admin_devices")]
+fn bind_device_owner(device_id: String, principal: String) -> Result<(), String> {
+    candid::Principal::from_text(&principal).map_err(|_| "Invalid principal".to_string())?;
+    require_owner_admin(&device_id)?;
+    let tenant_id = tenant_of_device(&device_id)
+        .ok_or_else(|| format!("Device {} is not assigned to a tenant", device_id))?;
+    if tenant_of_member(&principal).map_or(false, |t| t != tenant_id) {
+        return Err("User is a member of another tenant".into());
+    }
+    OWNERS.with(|o| {
+        let mut owners = o.borrow_mut();
+        match owners.owners.get(&device_id) {
+            Some(owner) if *owner == principal => return Ok(()),
+            Some(_) => return Err(format!("Device {} already has an owner", device_id)),
+            None => {}
+        }
+        let mut devices = owners.devices_of(&principal);
+        devices.insert(device_id.clone());
+        with_registry(|registry| registry.resident_scope(devices))?;
+        owners.owners.insert(device_id.clone(), principal.clone());
+        Ok(())
+    })?;
+    auth::ensure_role(&principal, Role::Viewer);
+    audit::record("device.bind_owner", Some(&device_id), vec![principal]);
+    Ok(())
+}
+
+// The owner keeps the viewer role while they own other devices or belong
+// to a tenant
+#[update(guard = "can_admin_devices")]
+fn unbind_device_owner(device_id: String) -> Result<(), String> {
+    require_owner_admin(&device_id)?;
+    let (owner, owns_more) = OWNERS.with(|o| {
+        let mut owners = o.borrow_mut();
+        let owner = owners
+            .owners
+            .remove(&device_id)
+            .ok_or_else(|| format!("Device {} has no owner", device_id))?;
+        let owns_more = !owners.devices_of(&owner).is_empty();
+        Ok::<_, String>((owner, owns_more))
+    })?;
+    audit::record("device.unbind_owner", Some(&device_id), vec![owner.clone()]);
+    if !owns_more && tenant_of_member(&owner).is_none() {
+        auth::remove_role(&owner, Role::Viewer);
+        audit::record("role.revoke", Some(&owner), vec![Role::Viewer.name().to_string()]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(timestamp: u64, device_id: &str, tenant_id: &str) -> VolumeReading {
+        VolumeReading {
+            timestamp,
+            volume: timestamp as f64,
+            device_id: Some(device_id.into()),
+            tenant_id: Some(tenant_id.into()),
+            raw_volume: None,
+            raw_unit: None,
+            calibration_version: None,
+            seq: None,
+        }
+    }
+
+    fn two_tenants() -> TenantRegistry {
+        let mut registry = TenantRegistry::default();
+        registry.create_tenant("acme".into(), "Acme Towers".into(), 0).unwrap();
+        registry.create_tenant("globex".into(), "Globex Plaza".into(), 0).unwrap();
+        registry.assign_device("acme-meter".into(), "acme").unwrap();
+        registry.assign_device("globex-meter".into(), "globex").unwrap();
+        registry.add_member("alice".into(), "acme").unwrap();
+        registry.add_member("bob".into(), "globex").unwrap();
+        registry
+    }
+
+    #[test]
+    fn queries_only_return_own_tenant_readings() {
+        let registry = two_tenants();
+        let readings: VolumeReadings = vec![
+            reading(1, "acme-meter", "acme"),
+            reading(2, "globex-meter", "globex"),
+            reading(3, "acme-meter", "acme"),
+        ]
+        .into();
+
+        let alice = registry.scope_for("alice").unwrap();
+        let visible = alice.filter(readings.clone());
+        assert_eq!(visible.len(), 2);
+        assert!(visible.iter().all(|r| r.tenant_id.as_deref() == Some("acme")));
+
+        let bob = registry.scope_for("bob").unwrap();
+        let visible = bob.filter(readings);
+        assert_eq!(visible.len(), 1);
+        assert_eq!(visible[0].device_id.as_deref(), Some("globex-meter"));
+    }
+
+    #[test]
+    fn non_members_have_no_scope() {
+        let registry = two_tenants();
+        assert!(registry.scope_for("mallory").is_err());
+    }
+
+    #[test]
+    fn cannot_ingest_for_another_tenants_device() {
+        let registry = two_tenants();
+        let alice = registry.scope_for("alice").unwrap();
+
+        assert_eq!(registry.resolve_ingest_tenant(&alice, Some("acme-meter")).unwrap(), "acme");
+        assert!(registry.resolve_ingest_tenant(&alice, Some("globex-meter")).is_err());
+        assert!(registry.resolve_ingest_tenant(&alice, Some("unassigned")).is_err());
+        assert_eq!(registry.resolve_ingest_tenant(&alice, None).unwrap(), "acme");
+    }
+
+    #[test]
+    fn devices_and_users_cannot_switch_tenants() {
+        let mut registry = two_tenants();
+        assert!(registry.assign_device("acme-meter".into(), "globex").is_err());
+        assert!(registry.add_member("alice".into(), "globex").is_err());
+
+        let bob = registry.scope_for("bob").unwrap();
+        assert_eq!(registry.devices_of(&bob), vec!["globex-meter".to_string()]);
+    }
+}
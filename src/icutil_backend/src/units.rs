@@ -0,0 +1,296 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::query;
+use serde::Serialize;
+
+const LITERS_PER_CUBIC_METER: f64 = 1000.0;
+const CUBIC_METERS_PER_GALLON: f64 = 0.003_785_411_784; // US liquid gallon
+const MEGAJOULES_PER_KWH: f64 = 3.6;
+const KPA_PER_BAR: f64 = 100.0;
+const KPA_PER_PSI: f64 = 6.894_757_293;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Quantity {
+    Volume,
+    Flow,
+    Energy,
+    Power,
+    Temperature,
+    Pressure,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Unit {
+    // Volume
+    CubicMeter,
+    Liter,
+    Gallon,
+    // Flow
+    LitersPerMinute,
+    CubicMetersPerHour,
+    GallonsPerMinute,
+    // Energy
+    KilowattHour,
+    WattHour,
+    Megajoule,
+    // Power
+    Kilowatt,
+    Watt,
+    // Temperature
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    // Pressure (absolute)
+    Kilopascal,
+    Bar,
+    Psi,
+}
+
+const ALL_UNITS: [Unit; 17] = [
+    Unit::CubicMeter,
+    Unit::Liter,
+    Unit::Gallon,
+    Unit::LitersPerMinute,
+    Unit::CubicMetersPerHour,
+    Unit::GallonsPerMinute,
+    Unit::KilowattHour,
+    Unit::WattHour,
+    Unit::Megajoule,
+    Unit::Kilowatt,
+    Unit::Watt,
+    Unit::Celsius,
+    Unit::Fahrenheit,
+    Unit::Kelvin,
+    Unit::Kilopascal,
+    Unit::Bar,
+    Unit::Psi,
+];
+
+impl Unit {
+    pub fn quantity(&self) -> Quantity {
+        match self {
+            Unit::CubicMeter | Unit::Liter | Unit::Gallon => Quantity::Volume,
+            Unit::LitersPerMinute | Unit::CubicMetersPerHour | Unit::GallonsPerMinute => Quantity::Flow,
+            Unit::KilowattHour | Unit::WattHour | Unit::Megajoule => Quantity::Energy,
+            Unit::Kilowatt | Unit::Watt => Quantity::Power,
+            Unit::Celsius | Unit::Fahrenheit | Unit::Kelvin => Quantity::Temperature,
+            Unit::Kilopascal | Unit::Bar | Unit::Psi => Quantity::Pressure,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::CubicMeter => "m3",
+            Unit::Liter => "L",
+            Unit::Gallon => "gal",
+            Unit::LitersPerMinute => "L/min",
+            Unit::CubicMetersPerHour => "m3/h",
+            Unit::GallonsPerMinute => "gal/min",
+            Unit::KilowattHour => "kWh",
+            Unit::WattHour => "Wh",
+            Unit::Megajoule => "MJ",
+            Unit::Kilowatt => "kW",
+            Unit::Watt => "W",
+            Unit::Celsius => "degC",
+            Unit::Fahrenheit => "degF",
+            Unit::Kelvin => "K",
+            Unit::Kilopascal => "kPa",
+            Unit::Bar => "bar",
+            Unit::Psi => "psi",
+        }
+    }
+
+    // Parse a unit symbol. Anything not listed here is rejected rather than
+    // guessed, so a reading in an unknown unit never gets stored.
+    pub fn parse(symbol: &str) -> Result<Unit, String> {
+        let unit = match symbol.trim() {
+            "m3" | "m³" => Unit::CubicMeter,
+            "L" | "l" => Unit::Liter,
+            "gal" => Unit::Gallon,
+            "L/min" | "l/min" | "lpm" => Unit::LitersPerMinute,
+            "m3/h" | "m³/h" => Unit::CubicMetersPerHour,
+            "gal/min" | "gpm" => Unit::GallonsPerMinute,
+            "kWh" => Unit::KilowattHour,
+            "Wh" => Unit::WattHour,
+            "MJ" => Unit::Megajoule,
+            "kW" => Unit::Kilowatt,
+            "W" => Unit::Watt,
+            "degC" | "°C" | "C" => Unit::Celsius,
+            "degF" | "°F" | "F" => Unit::Fahrenheit,
+            "K" => Unit::Kelvin,
+            "kPa" => Unit::Kilopascal,
+            "bar" => Unit::Bar,
+            "psi" => Unit::Psi,
+            other => return Err(format!("Unknown unit: {}", other)),
+        };
+        Ok(unit)
+    }
+
+    // Unit values of this quantity are stored in
+    pub fn canonical(quantity: Quantity) -> Unit {
+        match quantity {
+            Quantity::Volume => Unit::CubicMeter,
+            Quantity::Flow => Unit::LitersPerMinute,
+            Quantity::Energy => Unit::KilowattHour,
+            Quantity::Power => Unit::Kilowatt,
+            Quantity::Temperature => Unit::Celsius,
+            Quantity::Pressure => Unit::Kilopascal,
+        }
+    }
+
+    fn to_canonical(&self, value: f64) -> f64 {
+        match self {
+            Unit::CubicMeter => value,
+            Unit::Liter => value / LITERS_PER_CUBIC_METER,
+            Unit::Gallon => value * CUBIC_METERS_PER_GALLON,
+            Unit::LitersPerMinute => value,
+            Unit::CubicMetersPerHour => value * LITERS_PER_CUBIC_METER / 60.0,
+            Unit::GallonsPerMinute => value * CUBIC_METERS_PER_GALLON * LITERS_PER_CUBIC_METER,
+            Unit::KilowattHour => value,
+            Unit::WattHour => value / 1000.0,
+            Unit::Megajoule => value / MEGAJOULES_PER_KWH,
+            Unit::Kilowatt => value,
+            Unit::Watt => value / 1000.0,
+            Unit::Celsius => value,
+            Unit::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+            Unit::Kelvin => value - 273.15,
+            Unit::Kilopascal => value,
+            Unit::Bar => value * KPA_PER_BAR,
+            Unit::Psi => value * KPA_PER_PSI,
+        }
+    }
+
+    fn from_canonical(&self, value: f64) -> f64 {
+        match self {
+            Unit::CubicMeter => value,
+            Unit::Liter => value * LITERS_PER_CUBIC_METER,
+            Unit::Gallon => value / CUBIC_METERS_PER_GALLON,
+            Unit::LitersPerMinute => value,
+            Unit::CubicMetersPerHour => value * 60.0 / LITERS_PER_CUBIC_METER,
+            Unit::GallonsPerMinute => value / (CUBIC_METERS_PER_GALLON * LITERS_PER_CUBIC_METER),
+            Unit::KilowattHour => value,
+            Unit::WattHour => value * 1000.0,
+            Unit::Megajoule => value * MEGAJOULES_PER_KWH,
+            Unit::Kilowatt => value,
+            Unit::Watt => value * 1000.0,
+            Unit::Celsius => value,
+            Unit::Fahrenheit => value * 9.0 / 5.0 + 32.0,
+            Unit::Kelvin => value + 273.15,
+            Unit::Kilopascal => value,
+            Unit::Bar => value / KPA_PER_BAR,
+            Unit::Psi => value / KPA_PER_PSI,
+        }
+    }
+}
+
+pub fn convert(value: f64, from: Unit, to: Unit) -> Result<f64, String> {
+    if from.quantity() != to.quantity() {
+        return Err(format!(
+            "Cannot convert {:?} ({}) to {:?} ({})",
+            from.quantity(),
+            from.symbol(),
+            to.quantity(),
+            to.symbol()
+        ));
+    }
+    Ok(to.from_canonical(from.to_canonical(value)))
+}
+
+// Parse `symbol` and check it measures the expected quantity
+pub fn parse_for(symbol: &str, quantity: Quantity) -> Result<Unit, String> {
+    let unit = Unit::parse(symbol)?;
+    if unit.quantity() != quantity {
+        return Err(format!("{} is not a unit of {:?}", unit.symbol(), quantity));
+    }
+    Ok(unit)
+}
+
+// A value with its unit, as returned by unit-aware queries
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Measurement {
+    pub value: f64,
+    pub unit: String,
+}
+
+impl Measurement {
+    pub fn new(value: f64, unit: Unit) -> Self {
+        Measurement { value, unit: unit.symbol().to_string() }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UnitInfo {
+    pub symbol: String,
+    pub quantity: Quantity,
+    pub canonical: bool,
+}
+
+#[query]
+fn list_units() -> Vec<UnitInfo> {
+    ALL_UNITS
+        .iter()
+        .map(|unit| UnitInfo {
+            symbol: unit.symbol().to_string(),
+            quantity: unit.quantity(),
+            canonical: Unit::canonical(unit.quantity()) == *unit,
+        })
+        .collect()
+}
+
+#[query]
+fn convert_units(value: f64, from: String, to: String) -> Result<Measurement, String> {
+    let to = Unit::parse(&to)?;
+    let converted = convert(value, Unit::parse(&from)?, to)?;
+    Ok(Measurement::new(converted, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn every_unit_parses_from_its_symbol_and_round_trips() {
+        for unit in ALL_UNITS {
+            assert_eq!(Unit::parse(unit.symbol()), Ok(unit));
+            let canonical = Unit::canonical(unit.quantity());
+            let there = convert(123.456, unit, canonical).unwrap();
+            assert!(close(convert(there, canonical, unit).unwrap(), 123.456), "{:?}", unit);
+        }
+    }
+
+    #[test]
+    fn conversion_factors() {
+        let cases = [
+            (1.0, Unit::CubicMeter, Unit::Liter, 1000.0),
+            (1.0, Unit::Gallon, Unit::Liter, 3.785_411_784),
+            (6.0, Unit::CubicMetersPerHour, Unit::LitersPerMinute, 100.0),
+            (1.0, Unit::GallonsPerMinute, Unit::LitersPerMinute, 3.785_411_784),
+            (1.0, Unit::KilowattHour, Unit::Megajoule, 3.6),
+            (1500.0, Unit::WattHour, Unit::KilowattHour, 1.5),
+            (2.5, Unit::Kilowatt, Unit::Watt, 2500.0),
+            (212.0, Unit::Fahrenheit, Unit::Celsius, 100.0),
+            (0.0, Unit::Kelvin, Unit::Celsius, -273.15),
+            (-40.0, Unit::Celsius, Unit::Fahrenheit, -40.0),
+            (1.0, Unit::Bar, Unit::Kilopascal, 100.0),
+            (1.0, Unit::Psi, Unit::Kilopascal, 6.894_757_293),
+        ];
+        for (value, from, to, expected) in cases {
+            let converted = convert(value, from, to).unwrap();
+            assert!(close(converted, expected), "{} {:?} -> {} {:?}", value, from, converted, to);
+        }
+    }
+
+    #[test]
+    fn unknown_units_and_mixed_quantities_are_rejected() {
+        assert_eq!(Unit::parse("furlong"), Err("Unknown unit: furlong".to_string()));
+        assert!(Unit::parse("").is_err());
+        assert!(Unit::parse("KWH").is_err());
+        assert_eq!(Unit::parse(" m³ "), Ok(Unit::CubicMeter));
+        assert!(convert(1.0, Unit::Liter, Unit::KilowattHour).is_err());
+        assert_eq!(parse_for("bar", Quantity::Pressure), Ok(Unit::Bar));
+        assert!(parse_for("bar", Quantity::Volume).is_err());
+    }
+}
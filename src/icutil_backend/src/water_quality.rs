@@ -0,0 +1,206 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use icutil_common::lifecycle::IngestPolicy;
+
+use crate::auth::{can_read_readings, can_write_readings};
+use crate::alerts::{self, AlertMetric};
+use crate::health;
+use crate::lifecycle;
+use crate::metrics;
+use crate::tenant;
+
+const MAX_SAMPLES_PER_CHANNEL: usize = 1000;
+
+// Water channels measured alongside volume
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QualityChannel {
+    Temperature,  // °C
+    Pressure,     // kPa
+    Ph,           // pH
+    Turbidity,    // NTU
+    Conductivity, // µS/cm
+}
+
+impl QualityChannel {
+    pub fn unit(&self) -> &'static str {
+        match self {
+            QualityChannel::Temperature => "degC",
+            QualityChannel::Pressure => "kPa",
+            QualityChannel::Ph => "pH",
+            QualityChannel::Turbidity => "NTU",
+            QualityChannel::Conductivity => "uS/cm",
+        }
+    }
+
+    // Physically plausible range; anything outside is a sensor fault
+    pub fn valid_range(&self) -> (f64, f64) {
+        match self {
+            QualityChannel::Temperature => (0.0, 100.0),
+            QualityChannel::Pressure => (0.0, 2500.0),
+            QualityChannel::Ph => (0.0, 14.0),
+            QualityChannel::Turbidity => (0.0, 4000.0),
+            QualityChannel::Conductivity => (0.0, 200_000.0),
+        }
+    }
+
+    pub fn validate(&self, value: f64) -> Result<(), String> {
+        if !value.is_finite() {
+            return Err(format!("{:?} must be a finite number", self));
+        }
+        let (min, max) = self.valid_range();
+        if value < min || value > max {
+            return Err(format!(
+                "{:?} must be between {} and {} {}",
+                self, min, max, self.unit()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct QualitySample {
+    pub timestamp: u64,
+    pub value: f64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct QualityStatistics {
+    pub channel: QualityChannel,
+    pub unit: String,
+    pub count: u64,
+    pub average: f64,
+    pub min: f64,
+    pub max: f64,
+    pub std_deviation: f64,
+    pub latest: f64,
+}
+
+// One bounded series per device and channel
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct QualityStore {
+    pub series: HashMap<String, HashMap<QualityChannel, VecDeque<QualitySample>>>,
+}
+
+impl QualityStore {
+    pub fn push(&mut self, device_id: &str, channel: QualityChannel, sample: QualitySample) {
+        let series = self
+            .series
+            .entry(device_id.to_string())
+            .or_default()
+            .entry(channel)
+            .or_default();
+        series.push_back(sample);
+        while series.len() > MAX_SAMPLES_PER_CHANNEL {
+            series.pop_front();
+        }
+    }
+
+    pub fn samples(&self, device_id: &str, channel: QualityChannel) -> Option<&VecDeque<QualitySample>> {
+        self.series.get(device_id)?.get(&channel)
+    }
+
+    pub fn statistics(
+        &self,
+        device_id: &str,
+        channel: QualityChannel,
+        start: u64,
+        end: u64,
+    ) -> Option<QualityStatistics> {
+        let values: Vec<f64> = self
+            .samples(device_id, channel)?
+            .iter()
+            .filter(|s| s.timestamp >= start && s.timestamp <= end)
+            .map(|s| s.value)
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+
+        let count = values.len() as f64;
+        let average = values.iter().sum::<f64>() / count;
+        let variance = values.iter().map(|v| (v - average).powi(2)).sum::<f64>() / count;
+
+        Some(QualityStatistics {
+            channel,
+            unit: channel.unit().to_string(),
+            count: values.len() as u64,
+            average,
+            min: values.iter().fold(f64::INFINITY, |a, &b| a.min(b)),
+            max: values.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b)),
+            std_deviation: variance.sqrt(),
+            latest: *values.last().unwrap(),
+        })
+    }
+}
+
+thread_local! {
+    static QUALITY: RefCell<QualityStore> = RefCell::new(QualityStore::default());
+}
+
+pub fn export_state() -> QualityStore {
+    QUALITY.with(|q| q.borrow().clone())
+}
+
+pub fn import_state(store: QualityStore) {
+    QUALITY.with(|q| *q.borrow_mut() = store);
+}
+
+// Validate, store and evaluate alert rules for one sample. The device must
+// already have been checked against the caller's tenant.
+pub fn record_sample(
+    tenant_id: &str,
+    device_id: &str,
+    channel: QualityChannel,
+    value: f64,
+    timestamp: u64,
+) -> Result<(), String> {
+    channel.validate(value)?;
+    let policy = lifecycle::admit(device_id)?;
+    QUALITY.with(|q| q.borrow_mut().push(device_id, channel, QualitySample { timestamp, value }));
+    health::record_reading(device_id, timestamp);
+    if policy == IngestPolicy::Accept {
+        alerts::evaluate(tenant_id, device_id, AlertMetric::WaterQuality(channel), value, timestamp);
+    }
+    Ok(())
+}
+
+#[update(guard = "can_write_readings")]
+fn record_water_quality(device_id: String, channel: QualityChannel, value: f64) -> Result<(), String> {
+    let _timer = metrics::timer("record_water_quality");
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    let timestamp = ic_cdk::api::time() / 1_000_000_000;
+    record_sample(scope.tenant_id(), &device_id, channel, value, timestamp)
+}
+
+// Most recent samples of a channel, newest first
+#[query(guard = "can_read_readings")]
+fn get_water_quality(device_id: String, channel: QualityChannel, count: u64) -> Result<Vec<QualitySample>, String> {
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    Ok(QUALITY.with(|q| {
+        q.borrow()
+            .samples(&device_id, channel)
+            .map(|s| s.iter().rev().take(count as usize).cloned().collect())
+            .unwrap_or_default()
+    }))
+}
+
+#[query(guard = "can_read_readings")]
+fn get_water_quality_statistics(
+    device_id: String,
+    channel: QualityChannel,
+    start: u64,
+    end: u64,
+) -> Result<QualityStatistics, String> {
+    let scope = tenant::caller_scope()?;
+    tenant::require_device(&scope, &device_id)?;
+    QUALITY
+        .with(|q| q.borrow().statistics(&device_id, channel, start, end))
+        .ok_or_else(|| format!("No {:?} samples for {} in range", channel, device_id))
+}
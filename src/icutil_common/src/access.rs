@@ -0,0 +1,175 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// What an endpoint needs. Guards check permissions, never role names, so a
+// role can be widened or narrowed in one place.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Permission {
+    ReadingsRead,
+    ReadingsWrite,
+    DevicesRead,
+    DevicesAdmin,
+    FirmwareWrite,
+    ConfigWrite,
+    AlertsWrite,
+    DataPurge,
+    BackupsAdmin,
+    UsersAdmin,
+    TenantsAdmin,
+    AuditRead,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 12] = [
+        Permission::ReadingsRead,
+        Permission::ReadingsWrite,
+        Permission::DevicesRead,
+        Permission::DevicesAdmin,
+        Permission::FirmwareWrite,
+        Permission::ConfigWrite,
+        Permission::AlertsWrite,
+        Permission::DataPurge,
+        Permission::BackupsAdmin,
+        Permission::UsersAdmin,
+        Permission::TenantsAdmin,
+        Permission::AuditRead,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::ReadingsRead => "readings:read",
+            Permission::ReadingsWrite => "readings:write",
+            Permission::DevicesRead => "devices:read",
+            Permission::DevicesAdmin => "devices:admin",
+            Permission::FirmwareWrite => "firmware:write",
+            Permission::ConfigWrite => "config:write",
+            Permission::AlertsWrite => "alerts:write",
+            Permission::DataPurge => "data:purge",
+            Permission::BackupsAdmin => "backups:admin",
+            Permission::UsersAdmin => "users:admin",
+            Permission::TenantsAdmin => "tenants:admin",
+            Permission::AuditRead => "audit:read",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|p| p.name() == name)
+            .ok_or_else(|| format!("Unknown permission: {}", name))
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    Admin,
+    Operator,      // Runs a site: readings, devices, configuration and alerts
+    DeviceManager, // Provisions devices and rolls out firmware
+    Viewer,
+    Device, // A sensor or gateway that only submits readings
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [Role::Admin, Role::Operator, Role::DeviceManager, Role::Viewer, Role::Device];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Operator => "operator",
+            Role::DeviceManager => "device_manager",
+            Role::Viewer => "viewer",
+            Role::Device => "device",
+        }
+    }
+
+    // Accepts the snake_case names and the variant names used by older clients
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|r| r.name() == name || format!("{:?}", r) == name)
+            .ok_or_else(|| format!("Unknown role: {}", name))
+    }
+
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Admin => &Permission::ALL,
+            Role::Operator => &[
+                ReadingsRead,
+                ReadingsWrite,
+                DevicesRead,
+                DevicesAdmin,
+                ConfigWrite,
+                AlertsWrite,
+                AuditRead,
+            ],
+            Role::DeviceManager => &[ReadingsRead, DevicesRead, DevicesAdmin, FirmwareWrite],
+            Role::Viewer => &[ReadingsRead, DevicesRead],
+            Role::Device => &[ReadingsWrite],
+        }
+    }
+}
+
+pub fn grants(roles: &[Role], permission: Permission) -> bool {
+    roles.iter().any(|role| role.permissions().contains(&permission))
+}
+
+// Roles of each principal in one canister. Canisters persist this through
+// `export_state`/`import_state` in their upgrade hooks.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct RoleAssignments {
+    pub roles: BTreeMap<String, Vec<Role>>, // principal -> roles
+}
+
+thread_local! {
+    static ASSIGNMENTS: RefCell<RoleAssignments> = RefCell::new(RoleAssignments::default());
+}
+
+pub fn roles_of(principal: &str) -> Vec<Role> {
+    ASSIGNMENTS.with(|a| a.borrow().roles.get(principal).cloned().unwrap_or_default())
+}
+
+// Replace a principal's roles; an empty list removes the principal
+pub fn set_roles(principal: &str, mut roles: Vec<Role>) {
+    roles.sort();
+    roles.dedup();
+    ASSIGNMENTS.with(|a| {
+        let mut assignments = a.borrow_mut();
+        if roles.is_empty() {
+            assignments.roles.remove(principal);
+        } else {
+            assignments.roles.insert(principal.to_string(), roles);
+        }
+    });
+}
+
+pub fn grant(principal: &str, role: Role) {
+    let mut roles = roles_of(principal);
+    roles.push(role);
+    set_roles(principal, roles);
+}
+
+pub fn revoke(principal: &str, role: Role) {
+    let roles = roles_of(principal).into_iter().filter(|r| *r != role).collect();
+    set_roles(principal, roles);
+}
+
+pub fn export_state() -> RoleAssignments {
+    ASSIGNMENTS.with(|a| a.borrow().clone())
+}
+
+pub fn import_state(assignments: RoleAssignments) {
+    ASSIGNMENTS.with(|a| *a.borrow_mut() = assignments);
+}
+
+// Fail unless `principal` holds `permission`. Controllers always pass, so a
+// canister can never lock out the people who deploy it.
+pub fn require(principal: &str, is_controller: bool, permission: Permission) -> Result<(), String> {
+    if is_controller || grants(&roles_of(principal), permission) {
+        Ok(())
+    } else {
+        Err(format!("Unauthorized: {} permission required", permission.name()))
+    }
+}
@@ -0,0 +1,219 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::pagination::{self, Order, Page, PageRequest};
+
+// Hash that precedes the first entry
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Entries kept on the heap. Once the log grows past this the oldest
+// ARCHIVE_BATCH entries are dropped at once, so archivers that page through
+// `query` have at least MAX_ENTRIES - ARCHIVE_BATCH entries to catch up.
+pub const MAX_ENTRIES: usize = 20_000;
+const ARCHIVE_BATCH: usize = 1_000;
+
+// One privileged action. `hash` covers every other field, including the
+// previous entry's hash, so changing or removing an entry breaks the chain
+// from there on.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64, // Seconds
+    pub caller: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub details: Vec<String>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+// Leave a field empty to match every entry
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub caller: Option<String>,
+    pub action: Option<String>, // Exact name, or a prefix ending in '.' such as "device."
+    pub target: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+// The latest entry's position and hash. Copying this somewhere else lets a
+// reader later prove that nothing before it changed.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AuditHead {
+    pub length: u64,
+    pub hash: String,
+}
+
+// Append-only log of one canister. Canisters persist it through
+// `export_state`/`import_state` in their upgrade hooks. Only the latest
+// entries are kept; `archived` is the head as of the last dropped entry, so
+// the chain still verifies from there.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AuditLog {
+    pub entries: Vec<AuditEntry>,
+    pub archived: Option<AuditHead>,
+}
+
+thread_local! {
+    static LOG: RefCell<AuditLog> = RefCell::new(AuditLog::default());
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Fields are length-prefixed so no two entries hash the same input
+fn entry_hash(entry: &AuditEntry) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |value: &[u8]| {
+        hasher.update((value.len() as u64).to_be_bytes());
+        hasher.update(value);
+    };
+    field(&entry.seq.to_be_bytes());
+    field(&entry.timestamp.to_be_bytes());
+    field(entry.caller.as_bytes());
+    field(entry.action.as_bytes());
+    field(entry.target.as_deref().unwrap_or("").as_bytes());
+    field(&[entry.target.is_some() as u8]);
+    field(&(entry.details.len() as u64).to_be_bytes());
+    for detail in &entry.details {
+        field(detail.as_bytes());
+    }
+    field(entry.prev_hash.as_bytes());
+    hex(&hasher.finalize())
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let action = match self.action.as_deref() {
+            None => true,
+            Some(prefix) if prefix.ends_with('.') => entry.action.starts_with(prefix),
+            Some(action) => entry.action == action,
+        };
+        action
+            && self.caller.as_ref().map_or(true, |c| *c == entry.caller)
+            && self.target.as_ref().map_or(true, |t| entry.target.as_ref() == Some(t))
+            && self.from.map_or(true, |from| entry.timestamp >= from)
+            && self.to.map_or(true, |to| entry.timestamp <= to)
+    }
+}
+
+impl AuditLog {
+    pub fn head(&self) -> AuditHead {
+        match (self.entries.last(), &self.archived) {
+            (Some(last), _) => AuditHead { length: last.seq + 1, hash: last.hash.clone() },
+            (None, Some(archived)) => archived.clone(),
+            (None, None) => AuditHead { length: 0, hash: GENESIS_HASH.to_string() },
+        }
+    }
+
+    pub fn append(&mut self, caller: &str, action: &str, target: Option<&str>, details: Vec<String>, now: u64) {
+        let head = self.head();
+        let mut entry = AuditEntry {
+            seq: head.length,
+            timestamp: now,
+            caller: caller.to_string(),
+            action: action.to_string(),
+            target: target.map(str::to_string),
+            details,
+            prev_hash: head.hash,
+            hash: String::new(),
+        };
+        entry.hash = entry_hash(&entry);
+        self.entries.push(entry);
+        if self.entries.len() > MAX_ENTRIES {
+            let last = self.entries.drain(..ARCHIVE_BATCH).last().expect("batch is not empty");
+            self.archived = Some(AuditHead { length: last.seq + 1, hash: last.hash });
+        }
+    }
+
+    // Recompute the chain from the last archived entry. Returns the number
+    // of entries checked, or the first entry that does not match.
+    pub fn verify(&self) -> Result<u64, String> {
+        let start = self.archived.as_ref().map_or(0, |a| a.length);
+        let mut prev = self.archived.as_ref().map_or(GENESIS_HASH.to_string(), |a| a.hash.clone());
+        for (i, entry) in self.entries.iter().enumerate() {
+            let expected = start + i as u64;
+            if entry.seq != expected {
+                return Err(format!("Audit entry {} is out of sequence", expected));
+            }
+            if entry.prev_hash != prev || entry_hash(entry) != entry.hash {
+                return Err(format!("Audit entry {} does not match the chain", entry.seq));
+            }
+            prev = entry.hash.clone();
+        }
+        Ok(self.entries.len() as u64)
+    }
+}
+
+// Append an entry. `caller` must be the principal of the current call as
+// reported by the runtime, never a value taken from the arguments.
+pub fn record(caller: &str, action: &str, target: Option<&str>, details: Vec<String>, now: u64) {
+    LOG.with(|log| log.borrow_mut().append(caller, action, target, details, now));
+}
+
+// Matching entries, oldest first, with the sequence number as cursor key
+pub fn query(filter: &AuditFilter, request: &PageRequest) -> Result<Page<AuditEntry>, String> {
+    let entries = LOG.with(|log| {
+        log.borrow()
+            .entries
+            .iter()
+            .filter(|entry| filter.matches(entry))
+            .map(|entry| (entry.seq, entry.clone()))
+            .collect()
+    });
+    pagination::paginate(entries, Order::Ascending, request)
+}
+
+pub fn head() -> AuditHead {
+    LOG.with(|log| log.borrow().head())
+}
+
+pub fn verify() -> Result<u64, String> {
+    LOG.with(|log| log.borrow().verify())
+}
+
+pub fn export_state() -> AuditLog {
+    LOG.with(|log| log.borrow().clone())
+}
+
+pub fn import_state(log: AuditLog) {
+    LOG.with(|l| *l.borrow_mut() = log);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_detects_edits() {
+        let mut log = AuditLog::default();
+        for i in 0..3 {
+            log.append("alice", "role.grant", Some("bob"), vec![format!("r{}", i)], i);
+        }
+        assert_eq!(log.verify(), Ok(3));
+        log.entries[1].details[0] = "admin".into();
+        assert_eq!(log.verify(), Err("Audit entry 1 does not match the chain".to_string()));
+    }
+
+    #[test]
+    fn old_entries_are_archived_and_the_chain_still_verifies() {
+        let mut log = AuditLog::default();
+        for i in 0..=MAX_ENTRIES as u64 {
+            log.append("alice", "device.update", None, Vec::new(), i);
+        }
+        assert_eq!(log.entries.len(), MAX_ENTRIES + 1 - ARCHIVE_BATCH);
+        assert_eq!(log.entries[0].seq, ARCHIVE_BATCH as u64);
+        let archived = log.archived.clone().unwrap();
+        assert_eq!(archived.length, ARCHIVE_BATCH as u64);
+        assert_eq!(archived.hash, log.entries[0].prev_hash);
+        assert_eq!(log.head().length, MAX_ENTRIES as u64 + 1);
+        assert_eq!(log.verify(), Ok(log.entries.len() as u64));
+
+        log.entries.remove(0);
+        assert!(log.verify().is_err());
+    }
+}
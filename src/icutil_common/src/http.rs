@@ -0,0 +1,48 @@
+use candid::{CandidType, Deserialize};
+
+// Request and response records of the IC HTTP gateway interface
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>, // Asks the gateway to repeat the request as an update call
+}
+
+impl HttpRequest {
+    // The URL without its query string
+    pub fn path(&self) -> &str {
+        self.url.split_once('?').map_or(self.url.as_str(), |(path, _)| path)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+}
+
+impl HttpResponse {
+    pub fn new(status_code: u16, content_type: &str, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+            upgrade: None,
+        }
+    }
+
+    pub fn not_found(path: &str) -> Self {
+        Self::new(404, "text/plain; charset=utf-8", format!("No route for {}\n", path).into_bytes())
+    }
+}
@@ -0,0 +1,9 @@
+// Building blocks shared by every icutil canister so their Candid
+// interfaces stay consistent
+pub mod access;
+pub mod audit;
+pub mod http;
+pub mod lifecycle;
+pub mod metrics;
+pub mod pagination;
+pub mod selector;
@@ -0,0 +1,84 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+// Where a device is in its life. device_management_backend owns the state;
+// canisters that store readings keep a copy to apply the ingest policy.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceState {
+    Provisioned, // Registered but not installed yet
+    Active,
+    Maintenance,
+    Suspended,
+    Decommissioned, // Final; the id is never reused
+}
+
+// What happens to readings from a device in a given state
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IngestPolicy {
+    Accept,
+    AcceptWithoutAlerts, // Stored, but alert rules are not evaluated
+    Reject,
+}
+
+impl DeviceState {
+    pub const ALL: [DeviceState; 5] = [
+        DeviceState::Provisioned,
+        DeviceState::Active,
+        DeviceState::Maintenance,
+        DeviceState::Suspended,
+        DeviceState::Decommissioned,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceState::Provisioned => "provisioned",
+            DeviceState::Active => "active",
+            DeviceState::Maintenance => "maintenance",
+            DeviceState::Suspended => "suspended",
+            DeviceState::Decommissioned => "decommissioned",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|s| s.name() == name || format!("{:?}", s) == name)
+            .ok_or_else(|| format!("Unknown device state: {}", name))
+    }
+
+    // States reachable in one step
+    pub fn transitions(&self) -> &'static [DeviceState] {
+        use DeviceState::*;
+        match self {
+            Provisioned => &[Active, Decommissioned],
+            Active => &[Maintenance, Suspended, Decommissioned],
+            Maintenance => &[Active, Suspended, Decommissioned],
+            Suspended => &[Active, Decommissioned],
+            Decommissioned => &[],
+        }
+    }
+
+    pub fn check_transition(&self, next: DeviceState) -> Result<(), String> {
+        if self.transitions().contains(&next) {
+            Ok(())
+        } else {
+            Err(format!("A device cannot go from {} to {}", self.name(), next.name()))
+        }
+    }
+
+    pub fn ingest_policy(&self) -> IngestPolicy {
+        match self {
+            DeviceState::Active => IngestPolicy::Accept,
+            DeviceState::Maintenance => IngestPolicy::AcceptWithoutAlerts,
+            DeviceState::Provisioned | DeviceState::Suspended | DeviceState::Decommissioned => IngestPolicy::Reject,
+        }
+    }
+
+    // The policy for a reading, or the reason it is rejected
+    pub fn admit(&self, device_id: &str) -> Result<IngestPolicy, String> {
+        match self.ingest_policy() {
+            IngestPolicy::Reject => Err(format!("Device {} is {}; its readings are rejected", device_id, self.name())),
+            policy => Ok(policy),
+        }
+    }
+}
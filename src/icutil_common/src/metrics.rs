@@ -0,0 +1,227 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::http::{HttpRequest, HttpResponse};
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+pub const METRICS_PATH: &str = "/metrics";
+
+// Time does not advance while a canister handles a message, so the cost of
+// a call is measured in instructions. The last bucket is the update limit.
+pub const INSTRUCTION_BUCKETS: [f64; 8] = [1e5, 1e6, 1e7, 1e8, 1e9, 5e9, 2e10, 4e10];
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+enum Series {
+    Value(f64),
+    Histogram { buckets: &'static [f64], counts: Vec<u64>, sum: f64, count: u64 },
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    series: BTreeMap<Labels, Series>,
+}
+
+// Metrics of one canister. Only update calls, timers and the heartbeat keep
+// their changes, so counters never include query calls.
+#[derive(Default)]
+struct Registry {
+    families: BTreeMap<&'static str, Family>,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+fn with_series(
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &[(&'static str, &str)],
+    init: impl FnOnce() -> Series,
+    f: impl FnOnce(&mut Series),
+) {
+    REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        let family = registry
+            .families
+            .entry(name)
+            .or_insert_with(|| Family { help, kind, series: BTreeMap::new() });
+        if family.kind != kind {
+            return; // A name is registered with one type only
+        }
+        let labels: Labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        f(family.series.entry(labels).or_insert_with(init));
+    });
+}
+
+// Add to a counter. `name` is the family name; samples get a `_total` suffix.
+pub fn counter_add(name: &'static str, help: &'static str, labels: &[(&'static str, &str)], by: u64) {
+    with_series(name, help, Kind::Counter, labels, || Series::Value(0.0), |series| {
+        if let Series::Value(value) = series {
+            *value += by as f64;
+        }
+    });
+}
+
+pub fn gauge_set(name: &'static str, help: &'static str, labels: &[(&'static str, &str)], value: f64) {
+    with_series(name, help, Kind::Gauge, labels, || Series::Value(0.0), |series| {
+        *series = Series::Value(value);
+    });
+}
+
+pub fn histogram_observe(
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    labels: &[(&'static str, &str)],
+    value: f64,
+) {
+    let init = || Series::Histogram { buckets, counts: vec![0; buckets.len()], sum: 0.0, count: 0 };
+    with_series(name, help, Kind::Histogram, labels, init, |series| {
+        if let Series::Histogram { buckets, counts, sum, count } = series {
+            for (bound, n) in buckets.iter().zip(counts.iter_mut()) {
+                if value <= *bound {
+                    *n += 1;
+                }
+            }
+            *sum += value;
+            *count += 1;
+        }
+    });
+}
+
+// Records the instructions an endpoint used when dropped. `counter` is the
+// canister's `performance_counter(0)`, passed in so this crate does not
+// depend on a particular ic-cdk version.
+pub struct CallTimer {
+    endpoint: &'static str,
+    counter: fn() -> u64,
+}
+
+impl CallTimer {
+    pub fn start(endpoint: &'static str, counter: fn() -> u64) -> Self {
+        CallTimer { endpoint, counter }
+    }
+}
+
+impl Drop for CallTimer {
+    fn drop(&mut self) {
+        histogram_observe(
+            "canister_call_instructions",
+            "Instructions used per call, by endpoint",
+            &INSTRUCTION_BUCKETS,
+            &[("endpoint", self.endpoint)],
+            (self.counter)() as f64,
+        );
+    }
+}
+
+// Resource usage read by the canister at scrape time
+pub struct RuntimeStats {
+    pub cycles: u128,
+    pub stable_pages: u64,
+}
+
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+fn record_runtime(runtime: &RuntimeStats) {
+    gauge_set("canister_cycles_balance", "Cycles held by the canister", &[], runtime.cycles as f64);
+    gauge_set("canister_heap_memory_bytes", "Wasm heap memory in use", &[], heap_memory_bytes() as f64);
+    gauge_set(
+        "canister_stable_memory_bytes",
+        "Stable memory in use",
+        &[],
+        (runtime.stable_pages * WASM_PAGE_SIZE) as f64,
+    );
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_sample(out: &mut String, name: &str, suffix: &str, labels: &[(&str, String)], value: f64) {
+    let _ = write!(out, "{}{}", name, suffix);
+    if !labels.is_empty() {
+        let pairs: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+        let _ = write!(out, "{{{}}}", pairs.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+// Every metric in the OpenMetrics text format
+pub fn encode() -> String {
+    let mut out = String::new();
+    REGISTRY.with(|r| {
+        for (name, family) in &r.borrow().families {
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind.name());
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            for (labels, series) in &family.series {
+                match series {
+                    Series::Value(value) => {
+                        let suffix = if family.kind == Kind::Counter { "_total" } else { "" };
+                        write_sample(&mut out, name, suffix, labels, *value);
+                    }
+                    Series::Histogram { buckets, counts, sum, count } => {
+                        for (bound, n) in buckets.iter().zip(counts) {
+                            let mut bucket_labels = labels.clone();
+                            bucket_labels.push(("le", bound.to_string()));
+                            write_sample(&mut out, name, "_bucket", &bucket_labels, *n as f64);
+                        }
+                        let mut inf_labels = labels.clone();
+                        inf_labels.push(("le", "+Inf".to_string()));
+                        write_sample(&mut out, name, "_bucket", &inf_labels, *count as f64);
+                        write_sample(&mut out, name, "_sum", labels, *sum);
+                        write_sample(&mut out, name, "_count", labels, *count as f64);
+                    }
+                }
+            }
+        }
+    });
+    out.push_str("# EOF\n");
+    out
+}
+
+// The scrape response, with the runtime gauges refreshed first
+pub fn scrape(runtime: RuntimeStats) -> HttpResponse {
+    record_runtime(&runtime);
+    HttpResponse::new(200, CONTENT_TYPE, encode().into_bytes())
+}
+
+// `http_request` of canisters whose only HTTP route is /metrics
+pub fn serve(request: &HttpRequest, runtime: RuntimeStats) -> HttpResponse {
+    match (request.method.to_ascii_uppercase().as_str(), request.path()) {
+        ("GET", METRICS_PATH) => scrape(runtime),
+        (_, path) => HttpResponse::not_found(path),
+    }
+}
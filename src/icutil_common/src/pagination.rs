@@ -0,0 +1,201 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+// Arguments of every paginated list endpoint. Leave `cursor` empty for the
+// first page and pass the previous page's `next_cursor` afterwards.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PageRequest {
+    pub cursor: Option<String>,
+    pub limit: Option<u32>, // Defaults to 100, at most 1000
+}
+
+// Response of every paginated list endpoint. `next_cursor` is None on the
+// last page.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Ascending,
+    Descending, // Newest first for time-ordered lists
+}
+
+// Sort key of a list. Pages are cut after the key of the last item
+// returned, so inserts and removals elsewhere never shift a page.
+pub trait CursorKey: Ord + Sized {
+    fn encode(&self) -> String;
+    fn decode(raw: &str) -> Option<Self>;
+}
+
+impl CursorKey for u64 {
+    fn encode(&self) -> String {
+        self.to_string()
+    }
+
+    fn decode(raw: &str) -> Option<Self> {
+        raw.parse().ok()
+    }
+}
+
+impl CursorKey for String {
+    fn encode(&self) -> String {
+        self.clone()
+    }
+
+    fn decode(raw: &str) -> Option<Self> {
+        Some(raw.to_string())
+    }
+}
+
+// Timestamps are not unique, so time-ordered lists break ties on an id
+impl CursorKey for (u64, String) {
+    fn encode(&self) -> String {
+        format!("{}:{}", self.0, self.1)
+    }
+
+    fn decode(raw: &str) -> Option<Self> {
+        let (first, second) = raw.split_once(':')?;
+        Some((first.parse().ok()?, second.to_string()))
+    }
+}
+
+// Cursors are hex so clients treat them as opaque
+fn encode_cursor<K: CursorKey>(key: &K) -> String {
+    key.encode().bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor<K: CursorKey>(cursor: &str) -> Result<K, String> {
+    let invalid = || "Invalid page cursor".to_string();
+    if cursor.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(invalid)?;
+    let raw = String::from_utf8(bytes).map_err(|_| invalid())?;
+    K::decode(&raw).ok_or_else(invalid)
+}
+
+impl PageRequest {
+    pub fn limit(&self) -> Result<usize, String> {
+        match self.limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+            0 => Err("Page limit must be at least 1".into()),
+            limit => Ok(limit.min(MAX_PAGE_SIZE) as usize),
+        }
+    }
+}
+
+// Sort `items` by key and return the page following the request's cursor
+pub fn paginate<K: CursorKey, T>(
+    mut items: Vec<(K, T)>,
+    order: Order,
+    request: &PageRequest,
+) -> Result<Page<T>, String> {
+    let limit = request.limit()?;
+    let after = request.cursor.as_deref().map(decode_cursor::<K>).transpose()?;
+
+    items.sort_by(|(a, _), (b, _)| match order {
+        Order::Ascending => a.cmp(b),
+        Order::Descending => b.cmp(a),
+    });
+    let mut remaining = items.into_iter().filter(|(key, _)| match (&after, order) {
+        (None, _) => true,
+        (Some(after), Order::Ascending) => key > after,
+        (Some(after), Order::Descending) => key < after,
+    });
+
+    let page: Vec<(K, T)> = remaining.by_ref().take(limit).collect();
+    let more = remaining.next().is_some();
+    let next_cursor = if more { page.last().map(|(key, _)| encode_cursor(key)) } else { None };
+    Ok(Page {
+        items: page.into_iter().map(|(_, item)| item).collect(),
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cursor: Option<String>, limit: Option<u32>) -> PageRequest {
+        PageRequest { cursor, limit }
+    }
+
+    #[test]
+    fn cursors_round_trip_through_hex() {
+        assert_eq!(encode_cursor(&42u64), "3432");
+        assert_eq!(decode_cursor::<u64>("3432"), Ok(42));
+        let key = (1_700_000_000u64, "dev:1".to_string());
+        assert_eq!(decode_cursor::<(u64, String)>(&encode_cursor(&key)), Ok(key));
+        assert_eq!(decode_cursor::<String>(&encode_cursor(&"a/b".to_string())), Ok("a/b".to_string()));
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let invalid = "Invalid page cursor".to_string();
+        assert_eq!(decode_cursor::<u64>("343"), Err(invalid.clone()));
+        assert_eq!(decode_cursor::<u64>("zz"), Err(invalid.clone()));
+        assert_eq!(decode_cursor::<u64>("61"), Err(invalid.clone())); // "a" is not a number
+        assert_eq!(decode_cursor::<String>("ff"), Err(invalid.clone())); // Not UTF-8
+        assert_eq!(decode_cursor::<(u64, String)>("3432"), Err(invalid.clone())); // No separator
+        assert_eq!(decode_cursor::<u64>("é1"), Err(invalid));
+        let result = paginate(vec![(1u64, ())], Order::Ascending, &request(Some("xyz".into()), None));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn limits_default_and_are_capped() {
+        assert_eq!(request(None, None).limit(), Ok(DEFAULT_PAGE_SIZE as usize));
+        assert_eq!(request(None, Some(1)).limit(), Ok(1));
+        assert_eq!(request(None, Some(MAX_PAGE_SIZE + 1)).limit(), Ok(MAX_PAGE_SIZE as usize));
+        assert!(request(None, Some(0)).limit().is_err());
+    }
+
+    #[test]
+    fn ascending_pages_cover_every_item_once() {
+        let items: Vec<(u64, u64)> = (1..=5).rev().map(|i| (i, i * 10)).collect();
+        let first = paginate(items.clone(), Order::Ascending, &request(None, Some(2))).unwrap();
+        assert_eq!(first.items, vec![10, 20]);
+        let second = paginate(items.clone(), Order::Ascending, &request(first.next_cursor, Some(2))).unwrap();
+        assert_eq!(second.items, vec![30, 40]);
+        let last = paginate(items, Order::Ascending, &request(second.next_cursor, Some(2))).unwrap();
+        assert_eq!(last.items, vec![50]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn descending_pages_break_timestamp_ties_on_the_id() {
+        let items: Vec<((u64, String), &str)> = vec![
+            ((100, "a".into()), "a"),
+            ((200, "b".into()), "b"),
+            ((200, "c".into()), "c"),
+            ((300, "d".into()), "d"),
+        ];
+        let first = paginate(items.clone(), Order::Descending, &request(None, Some(2))).unwrap();
+        assert_eq!(first.items, vec!["d", "c"]);
+        // An item inserted before the cursor does not shift the next page
+        let mut grown = items.clone();
+        grown.push(((400, "e".into()), "e"));
+        let second = paginate(grown, Order::Descending, &request(first.next_cursor, Some(2))).unwrap();
+        assert_eq!(second.items, vec!["b", "a"]);
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[test]
+    fn an_exactly_full_page_has_no_next_cursor() {
+        let items: Vec<(u64, ())> = (0..3).map(|i| (i, ())).collect();
+        let page = paginate(items, Order::Ascending, &request(None, Some(3))).unwrap();
+        assert_eq!(page.items.len(), 3);
+        assert_eq!(page.next_cursor, None);
+        let empty = paginate(Vec::<(u64, ())>::new(), Order::Descending, &PageRequest::default()).unwrap();
+        assert!(empty.items.is_empty() && empty.next_cursor.is_none());
+    }
+}
@@ -0,0 +1,295 @@
+// Selectors pick devices by their labels, e.g.
+// `site=A AND type=water AND NOT state=suspended`. A label is a key/value
+// pair; a device may have several values for one key, such as `group`.
+//
+//   selector := or
+//   or       := and ("OR" and)*
+//   and      := unary ("AND" unary)*
+//   unary    := "NOT" unary | "(" or ")" | term
+//   term     := key | key "=" value | key "!=" value
+//
+// Keywords are case-insensitive. Values with spaces go in double quotes.
+
+const MAX_LENGTH: usize = 512;
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selector {
+    Has(String),            // Any value for the key
+    Equals(String, String), // Some value of the key is equal
+    NotEquals(String, String),
+    Not(Box<Selector>),
+    And(Vec<Selector>),
+    Or(Vec<Selector>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Eq,
+    NotEq,
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '=' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Eq,
+                });
+            }
+            '!' => {
+                chars.next();
+                if chars.next() != Some('=') {
+                    return Err("Expected '=' after '!'".to_string());
+                }
+                tokens.push(Token::NotEq);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => return Err("Unterminated quote".to_string()),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            c if is_word_char(c) => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek().filter(|c| is_word_char(**c)) {
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+            c => return Err(format!("Unexpected character '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(word) => format!("'{}'", word),
+            Token::Quoted(value) => format!("\"{}\"", value),
+            Token::Eq => "'='".to_string(),
+            Token::NotEq => "'!='".to_string(),
+            Token::Open => "'('".to_string(),
+            Token::Close => "')'".to_string(),
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':' | '/')
+}
+
+fn is_keyword(token: Option<&Token>, keyword: &str) -> bool {
+    matches!(token, Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self, depth: usize) -> Result<Selector, String> {
+        let mut terms = vec![self.and(depth)?];
+        while is_keyword(self.peek(), "or") {
+            self.next();
+            terms.push(self.and(depth)?);
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { Selector::Or(terms) })
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Selector, String> {
+        let mut terms = vec![self.unary(depth)?];
+        while is_keyword(self.peek(), "and") {
+            self.next();
+            terms.push(self.unary(depth)?);
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { Selector::And(terms) })
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Selector, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("Selector is nested deeper than {} levels", MAX_DEPTH));
+        }
+        if is_keyword(self.peek(), "not") {
+            self.next();
+            return Ok(Selector::Not(Box::new(self.unary(depth + 1)?)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let inner = self.or(depth + 1)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err("Missing ')'".to_string()),
+                }
+            }
+            Some(Token::Word(key)) if !["and", "or"].iter().any(|k| key.eq_ignore_ascii_case(k)) => {
+                let key = key.to_ascii_lowercase();
+                match self.peek() {
+                    Some(Token::Eq) | Some(Token::NotEq) => {
+                        let negate = self.next() == Some(Token::NotEq);
+                        let value = match self.next() {
+                            Some(Token::Word(value)) | Some(Token::Quoted(value)) => value,
+                            _ => return Err(format!("Missing value for '{}'", key)),
+                        };
+                        Ok(if negate { Selector::NotEquals(key, value) } else { Selector::Equals(key, value) })
+                    }
+                    _ => Ok(Selector::Has(key)),
+                }
+            }
+            Some(token) => Err(format!("Unexpected {}", token.describe())),
+            None => Err("Selector ended early".to_string()),
+        }
+    }
+}
+
+impl Selector {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text.len() > MAX_LENGTH {
+            return Err(format!("Selector is longer than {} characters", MAX_LENGTH));
+        }
+        let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+        if parser.tokens.is_empty() {
+            return Err("Selector is empty".to_string());
+        }
+        let selector = parser.or(0)?;
+        match parser.peek() {
+            None => Ok(selector),
+            Some(token) => Err(format!("Unexpected {}", token.describe())),
+        }
+    }
+
+    // Keys are compared case-insensitively, values exactly
+    pub fn matches(&self, labels: &[(String, String)]) -> bool {
+        match self {
+            Selector::Has(key) => values(labels, key).next().is_some(),
+            Selector::Equals(key, value) => values(labels, key).any(|v| v == value),
+            Selector::NotEquals(key, value) => !values(labels, key).any(|v| v == value),
+            Selector::Not(inner) => !inner.matches(labels),
+            Selector::And(terms) => terms.iter().all(|t| t.matches(labels)),
+            Selector::Or(terms) => terms.iter().any(|t| t.matches(labels)),
+        }
+    }
+}
+
+fn values<'a>(labels: &'a [(String, String)], key: &'a str) -> impl Iterator<Item = &'a String> {
+    labels.iter().filter(move |(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v)
+}
+
+// Tags set by operators: lowercase keys, short values
+pub fn validate_label(key: &str, value: &str) -> Result<(), String> {
+    let valid_key = key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if key.is_empty() || key.len() > 32 || !valid_key {
+        return Err(format!("Label key '{}' must be 1 to 32 lowercase letters, digits, '_' or '-'", key));
+    }
+    if value.is_empty() || value.len() > 64 || value.contains('"') {
+        return Err(format!("Value of '{}' must be 1 to 64 characters without quotes", key));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn error(text: &str) -> String {
+        Selector::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let selector = Selector::parse("site=A or Site = B AND NOT state!=\"in service\"").unwrap();
+        assert_eq!(
+            selector,
+            Selector::Or(vec![
+                Selector::Equals("site".to_string(), "A".to_string()),
+                Selector::And(vec![
+                    Selector::Equals("site".to_string(), "B".to_string()),
+                    Selector::Not(Box::new(Selector::NotEquals("state".to_string(), "in service".to_string()))),
+                ]),
+            ])
+        );
+        assert!(selector.matches(&labels(&[("site", "B"), ("state", "in service")])));
+        assert!(!selector.matches(&labels(&[("site", "B"), ("state", "suspended")])));
+    }
+
+    #[test]
+    fn any_value_of_a_key_can_match() {
+        let selector = Selector::parse("group=pilot AND (battery OR NOT group=east)").unwrap();
+        assert!(selector.matches(&labels(&[("group", "east"), ("GROUP", "pilot"), ("battery", "low")])));
+        assert!(!selector.matches(&labels(&[("group", "east"), ("group", "pilot")])));
+    }
+
+    #[test]
+    fn malformed_selectors_are_rejected() {
+        assert_eq!(error(""), "Selector is empty");
+        assert_eq!(error("   "), "Selector is empty");
+        assert_eq!(error("site=A AND"), "Selector ended early");
+        assert_eq!(error("NOT"), "Selector ended early");
+        assert_eq!(error("(site=A"), "Missing ')'");
+        assert_eq!(error("site=A)"), "Unexpected ')'");
+        assert_eq!(error("site="), "Missing value for 'site'");
+        assert_eq!(error("site!=)"), "Missing value for 'site'");
+        assert_eq!(error("site ! A"), "Expected '=' after '!'");
+        assert_eq!(error("name=\"pump"), "Unterminated quote");
+        assert_eq!(error("site=A & type=water"), "Unexpected character '&'");
+        assert_eq!(error("AND site=A"), "Unexpected 'AND'");
+        assert_eq!(error("site=A type=water"), "Unexpected 'type'");
+        assert_eq!(error("=A"), "Unexpected '='");
+    }
+
+    #[test]
+    fn oversized_selectors_are_rejected() {
+        let long = format!("site={}", "a".repeat(MAX_LENGTH));
+        assert_eq!(error(&long), format!("Selector is longer than {} characters", MAX_LENGTH));
+        let deep = format!("{}site=A{}", "(".repeat(MAX_DEPTH + 1), ")".repeat(MAX_DEPTH + 1));
+        assert_eq!(error(&deep), format!("Selector is nested deeper than {} levels", MAX_DEPTH));
+        let nots = format!("{}site=A", "NOT ".repeat(MAX_DEPTH + 1));
+        assert!(error(&nots).starts_with("Selector is nested deeper"));
+        let within = format!("{}site=A{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert!(Selector::parse(&within).is_ok());
+    }
+
+    #[test]
+    fn labels_are_validated() {
+        assert!(validate_label("group", "pilot east").is_ok());
+        assert!(validate_label("Group", "pilot").is_err());
+        assert!(validate_label("", "pilot").is_err());
+        assert!(validate_label("group", "").is_err());
+        assert!(validate_label("group", "\"pilot\"").is_err());
+    }
+}
@@ -11,6 +11,7 @@ import {
   Badge
 } from '@tremor/react';
 import { device_management_backend } from 'declarations/device_management_backend';
+import { fetchAllPages } from '../utils/pagination';
 
 Chart.register(...registerables);
 
@@ -81,8 +82,8 @@ const Dashboard = () => {
       try {
         setIsLoading(true);
         const [energy, water] = await Promise.all([
-          electricity_backend.get_electricity_readings(),
-          water_backend.get_water_readings(0, 1000)
+          fetchAllPages((page) => electricity_backend.get_electricity_readings(page)),
+          fetchAllPages((page) => water_backend.get_water_readings(page))
         ]);
         
         setEnergyData(energy);
@@ -261,7 +262,7 @@ const [devices, setDevices] = useState([]);
 // Device fetching in useEffect
 async function fetchDevices() {
   try {
-    const deviceList = await device_management_backend.list_devices();
+    const deviceList = await fetchAllPages((page) => device_management_backend.list_devices(page));
     setDevices(deviceList);
   } catch (err) {
     setError(new Error(`Device fetch failed: ${err.message}`));
@@ -0,0 +1,15 @@
+// Walk a paginated canister endpoint until the last page.
+// `fetchPage` receives a PageRequest and returns { Ok: Page } or { Err }.
+export async function fetchAllPages(fetchPage, limit = 1000) {
+  const items = [];
+  let cursor = [];
+  do {
+    const result = await fetchPage({ cursor, limit: [limit] });
+    if ('Err' in result) {
+      throw new Error(result.Err);
+    }
+    items.push(...result.Ok.items);
+    cursor = result.Ok.next_cursor;
+  } while (cursor.length > 0);
+  return items;
+}
@@ -2,11 +2,16 @@
 extern crate serde_derive;
 extern crate serde;
 
-use ic_cdk::export::candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize};
 use ic_cdk::storage;
 use ic_cdk_macros::*;
 use std::collections::{HashMap, BTreeMap};
-use ic_cdk::api::Principal;
+use candid::Principal;
+use icutil_common::access::{self, Permission, Role, RoleAssignments};
+use icutil_common::audit::{self, AuditEntry, AuditFilter, AuditHead, AuditLog};
+use icutil_common::http::{HttpRequest, HttpResponse};
+use icutil_common::metrics::{self, CallTimer, RuntimeStats};
+use icutil_common::pagination::{self, Order, Page, PageRequest};
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 struct WaterReading {
@@ -30,50 +35,50 @@ fn init() {
     storage::stable_save((data,)).unwrap();
 }
 
-#[update]
+#[update(guard = "can_write_readings")]
 fn add_water_reading(liters: f64) -> Result<bool, String> {
+    let _timer = CallTimer::start("add_water_reading", instructions);
     if liters < 0.0 {
+        metrics::counter_add("water_readings_rejected", "Water readings rejected, by reason", &[("reason", "negative")], 1);
         return Err("Negative water usage impossible".into());
     }
     // Flow validation and metrics tracking
 }
 
-#[query]
+#[query(guard = "can_read_readings")]
 fn get_total_water_usage() -> f64 {
     let data: WaterData = storage::stable_restore().unwrap().0;
     data.total_liters
 }
 
-#[query]
-fn get_water_readings(skip: u64, limit: u64) -> Vec<WaterReading> {
+// Readings in timestamp order; the timestamp is the cursor key
+#[query(guard = "can_read_readings")]
+fn get_water_readings(request: PageRequest) -> Result<Page<WaterReading>, String> {
     let data: WaterData = storage::stable_restore().unwrap().0;
-    data.readings.values()
-       .skip(skip as usize)
-       .take(limit as usize)
-       .cloned()
-       .collect()
+    let readings = data.readings.into_iter().collect();
+    pagination::paginate(readings, Order::Ascending, &request)
 }
 
-#[update]
+#[update(guard = "can_purge_data")]
 fn reset_water_data() -> bool {
     let mut data = WaterData::default();
     data.total_liters = 0.0;
     storage::stable_save((data,)).unwrap();
+    record_audit("data.purge", None, vec!["water readings".to_string()]);
     true
 }
 
-#[query]
-fn get_water_readings_filtered(skip: u64, limit: u64, from: u64, to: u64) -> Vec<WaterReading> {
+#[query(guard = "can_read_readings")]
+fn get_water_readings_filtered(from: u64, to: u64, request: PageRequest) -> Result<Page<WaterReading>, String> {
     let data: WaterData = storage::stable_restore().unwrap().0;
-    data.readings.values()
-        .filter(|r| r.timestamp >= from && r.timestamp <= to)
-        .skip(skip as usize)
-        .take(limit as usize)
-        .cloned()
-        .collect()
+    let readings = data.readings
+        .into_iter()
+        .filter(|(_, r)| r.timestamp >= from && r.timestamp <= to)
+        .collect();
+    pagination::paginate(readings, Order::Ascending, &request)
 }
 
-#[query]
+#[query(guard = "can_read_readings")]
 fn get_daily_usage() -> Vec<(u64, f64)> {
     let data: WaterData = storage::stable_restore().unwrap().0;
     let mut daily: BTreeMap<u64, f64> = BTreeMap::new();
@@ -86,10 +91,23 @@ fn get_daily_usage() -> Vec<(u64, f64)> {
     daily.into_iter().collect()
 }
 
+fn instructions() -> u64 {
+    ic_cdk::api::performance_counter(0)
+}
+
+// Prometheus scrapes /metrics through the HTTP gateway
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    metrics::serve(&request, RuntimeStats {
+        cycles: ic_cdk::api::canister_balance128(),
+        stable_pages: ic_cdk::api::stable::stable64_size(),
+    })
+}
+
 const RATE_LIMIT_WINDOW: u64 = 60_000_000_000; // 1 minute in nanoseconds
 const MAX_REQUESTS_PER_MINUTE: u64 = 100;
 
-#[update]
+#[update(guard = "can_write_readings")]
 fn check_rate_limit(caller: Principal) -> Result<(), String> {
     let mut data = storage::stable_restore()
     .map_err(|e| format!("Failed to restore data: {:?}", e))?.0;
@@ -128,8 +146,100 @@ IC_COUNTERS.with(|c| {
     Ok(())
 }
 
-#[update]
+#[update(guard = "can_write_readings")]
 async fn record_reading(flow_rate: f64) -> Result<(), String> {
     // Existing recording logic
     backup_backend::create_backup("water_data".into(), data).await?;
-}
\ No newline at end of file
+}
+
+fn authorize(permission: Permission) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    access::require(&caller.to_string(), ic_cdk::api::is_controller(&caller), permission)
+}
+
+fn can_read_readings() -> Result<(), String> {
+    authorize(Permission::ReadingsRead)
+}
+
+fn can_write_readings() -> Result<(), String> {
+    authorize(Permission::ReadingsWrite)
+}
+
+fn can_purge_data() -> Result<(), String> {
+    authorize(Permission::DataPurge)
+}
+
+fn can_admin_users() -> Result<(), String> {
+    authorize(Permission::UsersAdmin)
+}
+
+// Roles are kept per canister. Controllers always have every permission
+// and assign roles to everyone else.
+#[update(guard = "can_admin_users")]
+fn set_roles(principal: Principal, roles: Vec<Role>) {
+    let names = roles.iter().map(|role| role.name().to_string()).collect();
+    access::set_roles(&principal.to_text(), roles);
+    record_audit("role.set", Some(&principal.to_text()), names);
+}
+
+#[query]
+fn get_my_roles() -> Vec<Role> {
+    access::roles_of(&ic_cdk::caller().to_text())
+}
+
+fn can_read_audit() -> Result<(), String> {
+    authorize(Permission::AuditRead)
+}
+
+// Record a privileged action. The caller comes from the runtime, never
+// from the arguments.
+fn record_audit(action: &str, target: Option<&str>, details: Vec<String>) {
+    audit::record(
+        &ic_cdk::caller().to_text(),
+        action,
+        target,
+        details,
+        ic_cdk::api::time() / 1_000_000_000,
+    );
+}
+
+// Hash-chained log of privileged actions, oldest first
+#[query(guard = "can_read_audit")]
+fn get_audit_logs(filter: AuditFilter, request: PageRequest) -> Result<Page<AuditEntry>, String> {
+    audit::query(&filter, &request)
+}
+
+#[query(guard = "can_read_audit")]
+fn get_audit_head() -> AuditHead {
+    audit::head()
+}
+
+#[query(guard = "can_read_audit")]
+fn verify_audit_log() -> Result<u64, String> {
+    audit::verify()
+}
+
+// Readings live in stable memory already. The audit log and roles are
+// appended for the upgrade and read back afterwards.
+#[pre_upgrade]
+fn pre_upgrade() {
+    let data: WaterData = storage::stable_restore().map(|(data,)| data).unwrap_or_default();
+    if storage::stable_save((data, audit::export_state(), access::export_state())).is_err() {
+        ic_cdk::trap("Failed to save state before upgrade");
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (data, log, roles): (WaterData, Option<AuditLog>, Option<RoleAssignments>) = storage::stable_restore()
+        .unwrap_or_else(|_| ic_cdk::trap("Failed to restore state after upgrade"));
+    if let Some(log) = log {
+        audit::import_state(log);
+    }
+    if let Some(roles) = roles {
+        access::import_state(roles);
+    }
+    if storage::stable_save((data,)).is_err() {
+        ic_cdk::trap("Failed to restore stable storage");
+    }
+}